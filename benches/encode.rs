@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use myproto::envelope::ServerMessage;
+use myproto::frame::{Compression, FrameCodec};
+use myproto::protocol::{ClientProtocol, ServerProtocol};
+use myproto::{Codec, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Payload(Vec<u8>);
+
+#[typetag::serde]
+impl Response for Payload {}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BytesPayload(Bytes);
+
+#[typetag::serde]
+impl Response for BytesPayload {}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SharedPayload(#[serde(with = "myproto::shared_bytes")] Bytes);
+
+#[typetag::serde]
+impl Response for SharedPayload {}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in [16, 512, 16 * 1024] {
+        let message = ServerMessage::Push(Box::new(Payload(vec![7; size])));
+        for codec in Codec::ALL {
+            let name = format!("{codec:?}/{size}");
+            group.bench_with_input(BenchmarkId::new("vec", &name), &message, |b, message| {
+                b.iter(|| Bytes::from(codec.encode(message).unwrap()))
+            });
+            group.bench_with_input(BenchmarkId::new("pooled", &name), &message, |b, message| {
+                b.iter(|| codec.encode_bytes(message).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for size in [16, 512, 16 * 1024] {
+        let message = ServerMessage::Push(Box::new(Payload(vec![7; size])));
+        for codec in Codec::ALL {
+            let bytes = codec.encode_bytes(&message).unwrap();
+            let name = format!("{codec:?}/{size}");
+            group.bench_with_input(BenchmarkId::from_parameter(name), &bytes, |b, bytes| {
+                b.iter(|| codec.decode::<ServerMessage>(bytes).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Whole frames read off a connection, with the payload read into a `Vec`,
+/// copied into a `Bytes` or sliced out of the frame's buffer.
+fn decode_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_frame");
+    for size in [16 * 1024, 1024 * 1024] {
+        for kind in ["vec", "bytes", "shared"] {
+            for codec in [Codec::Bincode, Codec::MessagePack] {
+                let response: Box<dyn Response> = match kind {
+                    "vec" => Box::new(Payload(vec![7; size])),
+                    "bytes" => Box::new(BytesPayload(Bytes::from(vec![7; size]))),
+                    _ => Box::new(SharedPayload(Bytes::from(vec![7; size]))),
+                };
+                let mut frames = FrameCodec::new();
+                frames.set_compression(Compression::None);
+                let mut server = ServerProtocol::new(frames, codec);
+                server.send(&ServerMessage::Push(response)).unwrap();
+                let wire = server.transmit().unwrap();
+                let mut client = ClientProtocol::new(FrameCodec::new(), codec);
+                let name = format!("{kind}/{codec:?}/{size}");
+                group.bench_with_input(BenchmarkId::from_parameter(name), &wire, |b, wire| {
+                    b.iter(|| {
+                        client.receive(wire);
+                        client.poll_inbound().unwrap().unwrap()
+                    })
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode, decode_frame);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::future::join_all;
+use myproto::testing::TestServer;
+use myproto::{ClientConfig, Codec, Context, Request, Response, Server, TypedRequest};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Bounce(Vec<u8>);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Bounced(Vec<u8>);
+
+#[typetag::serde]
+impl Response for Bounced {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Bounce {
+    async fn handle(&self, _ctx: &Context) -> anyhow::Result<Box<dyn Response>> {
+        Ok(Box::new(Bounced(self.0.clone())))
+    }
+}
+
+impl TypedRequest for Bounce {
+    type Response = Bounced;
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// One call at a time over an in-memory connection, through the whole
+/// client, session and dispatch path.
+fn round_trip(c: &mut Criterion) {
+    let runtime = runtime();
+    let server = TestServer::new(Server::builder().build());
+    let mut group = c.benchmark_group("round_trip");
+    for codec in Codec::ALL {
+        let config = ClientConfig {
+            codecs: vec![codec],
+            ..ClientConfig::default()
+        };
+        let client = runtime.block_on(server.connect_with(config)).unwrap();
+        for size in [16, 16 * 1024] {
+            let request = Bounce(vec![7; size]);
+            let name = format!("{codec:?}/{size}");
+            group.bench_with_input(BenchmarkId::from_parameter(name), &request, |b, request| {
+                b.to_async(&runtime)
+                    .iter(|| client.call_typed(request.clone()))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Many calls in flight on one connection at once.
+fn throughput(c: &mut Criterion) {
+    let runtime = runtime();
+    let server = TestServer::new(Server::builder().build());
+    let client = runtime.block_on(server.connect()).unwrap();
+    let mut group = c.benchmark_group("throughput");
+    for concurrency in [1, 16, 128] {
+        group.throughput(Throughput::Elements(concurrency));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &concurrency,
+            |b, &concurrency| {
+                b.to_async(&runtime).iter(|| {
+                    join_all((0..concurrency).map(|_| client.call_typed(Bounce(vec![7; 64]))))
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_trip, throughput);
+criterion_main!(benches);
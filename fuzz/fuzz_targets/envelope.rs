@@ -0,0 +1,32 @@
+//! Runs arbitrary bytes through both ends of the sans-IO protocol, as a
+//! server reads them off the network and as a client reads a server's replies.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use myproto::Codec;
+use myproto::frame::FrameCodec;
+use myproto::protocol::{ClientProtocol, Inbound, ServerProtocol};
+
+fuzz_target!(|input: (Codec, bool, Vec<u8>)| {
+    let (codec, untyped, data) = input;
+
+    let mut server = ServerProtocol::new(FrameCodec::with_max_frame_length(64 * 1024), codec);
+    server.receive(&data);
+    while let Ok(Some(inbound)) = server.poll_inbound() {
+        if let Inbound::Message(message, _) = inbound {
+            let _ = format!("{message:?}");
+        }
+    }
+
+    let mut client = ClientProtocol::new(FrameCodec::with_max_frame_length(64 * 1024), codec);
+    if untyped && codec.is_self_describing() {
+        client = client.untyped();
+    }
+    client.receive(&data);
+    while let Ok(Some(inbound)) = client.poll_inbound() {
+        if let Inbound::Message(message, _) = inbound {
+            let _ = format!("{message:?}");
+        }
+    }
+});
@@ -0,0 +1,34 @@
+//! Feeds arbitrary bytes, split into arbitrary reads, to the frame decoder.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use myproto::frame::{Frame, FrameCodec};
+use tokio_util::codec::Decoder;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    max_frame_length: u16,
+    reads: Vec<Vec<u8>>,
+}
+
+fuzz_target!(|input: Input| {
+    let max_frame_length = usize::from(input.max_frame_length);
+    let mut codec = FrameCodec::with_max_frame_length(max_frame_length);
+    let mut buffer = BytesMut::new();
+    for read in input.reads {
+        buffer.extend_from_slice(&read);
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(Frame::Payload(payload))) => assert!(payload.len() <= max_frame_length),
+                Ok(Some(Frame::Oversized { len })) => assert!(len > max_frame_length),
+                Ok(Some(Frame::Corrupt { len })) => assert!(len <= max_frame_length),
+                Ok(None) => break,
+                // A broken frame ends the connection, as it does in a session.
+                Err(_) => return,
+            }
+        }
+    }
+});
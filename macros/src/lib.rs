@@ -0,0 +1,812 @@
+//! Attribute macros re-exported as `myproto::request` and `myproto::response`,
+//! the `Describe` derive re-exported from `myproto::schema` and the `Redact`
+//! derive from `myproto::redact`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{
+    Attribute, Data, DeriveInput, Fields, GenericArgument, Ident, LitInt, LitStr, PathArguments,
+    Token, Type, parse_macro_input,
+};
+
+struct RequestArgs {
+    response: Type,
+    idempotent: bool,
+    sharded: bool,
+    blocking: bool,
+    mutating: bool,
+    uncompressed: bool,
+    version: Option<u32>,
+    namespace: Option<String>,
+}
+
+impl Parse for RequestArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+        if key != "response" {
+            return Err(syn::Error::new(key.span(), "expected `response = Type`"));
+        }
+        input.parse::<Token![=]>()?;
+        let response = input.parse()?;
+
+        let mut idempotent = false;
+        let mut sharded = false;
+        let mut blocking = false;
+        let mut mutating = false;
+        let mut uncompressed = false;
+        let mut version = None;
+        let mut namespace = None;
+        while input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let flag: Ident = input.parse()?;
+            if flag == "namespace" {
+                input.parse::<Token![=]>()?;
+                namespace = Some(parse_namespace(input.parse()?)?);
+            } else if flag == "version" {
+                input.parse::<Token![=]>()?;
+                let lit: LitInt = input.parse()?;
+                let number: u32 = lit.base10_parse()?;
+                if number < 2 {
+                    return Err(syn::Error::new(
+                        lit.span(),
+                        "versions start at 1, which needs no `version`",
+                    ));
+                }
+                version = Some(number);
+            } else if flag == "idempotent" {
+                idempotent = true;
+            } else if flag == "sharded" {
+                sharded = true;
+            } else if flag == "blocking" {
+                blocking = true;
+            } else if flag == "mutating" {
+                mutating = true;
+            } else if flag == "uncompressed" {
+                uncompressed = true;
+            } else {
+                return Err(syn::Error::new(
+                    flag.span(),
+                    "expected `idempotent`, `sharded`, `blocking`, `mutating`, `uncompressed`, `version = N` or `namespace = \"..\"`",
+                ));
+            }
+        }
+        Ok(Self {
+            response,
+            idempotent,
+            sharded,
+            blocking,
+            mutating,
+            uncompressed,
+            version,
+            namespace,
+        })
+    }
+}
+
+/// A namespace's segments are identifiers, joined by dots.
+fn parse_namespace(lit: LitStr) -> syn::Result<String> {
+    let namespace = lit.value();
+    let valid = namespace.split('.').all(|segment| {
+        segment
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+    });
+    if !valid {
+        return Err(syn::Error::new(
+            lit.span(),
+            "expected a namespace such as \"billing\" or \"billing.v2\"",
+        ));
+    }
+    Ok(namespace)
+}
+
+/// The derives every message type needs, with serde pointed at myproto's copy.
+fn derives() -> TokenStream2 {
+    quote! {
+        #[derive(
+            ::myproto::__private::serde::Serialize,
+            ::myproto::__private::serde::Deserialize,
+            ::std::fmt::Debug,
+            ::myproto::schema::Describe,
+            ::myproto::redact::Redact,
+        )]
+        #[serde(crate = "::myproto::__private::serde")]
+    }
+}
+
+/// Declares a request answered by `response`.
+///
+/// Adds the serde, `Debug`, `Describe` and `Redact` derives, registers the type with typetag as a
+/// `Request` handled by its `myproto::Handler` impl, and implements
+/// `TypedRequest` so `Client::call_typed` knows what comes back. The crate
+/// using it must depend on `typetag`.
+///
+/// `#[request(response = Type, idempotent)]` also marks the request safe to
+/// retry, `sharded` takes its `Request::shard_key` from the type's
+/// `myproto::ShardedRequest` impl, `blocking` runs its handler on the
+/// blocking thread pool, `mutating` journals it for replay on a server
+/// with a `RequestJournal`, and `uncompressed` keeps calls carrying it from
+/// being compressed.
+///
+/// `version = N` tags the type as `Name@N` on the wire and keeps reading
+/// the previous version's tag, upgrading what arrives under it through the
+/// type's `myproto::versioning::Versioned` impl.
+///
+/// `namespace = "billing"` tags the type as `billing.Name`, so modules can
+/// each have a `Create` of their own, and puts it under the middleware and
+/// authorization of the server's `myproto::Namespace` of that name.
+#[proc_macro_attribute]
+pub fn request(args: TokenStream, item: TokenStream) -> TokenStream {
+    let RequestArgs {
+        response,
+        idempotent,
+        sharded,
+        blocking,
+        mutating,
+        uncompressed,
+        version,
+        namespace,
+    } = parse_macro_input!(args as RequestArgs);
+    let compressible = !uncompressed;
+    let input = parse_macro_input!(item as DeriveInput);
+    let name = &input.ident;
+    if version.is_some() && !input.generics.params.is_empty() {
+        return syn::Error::new_spanned(&input.generics, "generic requests can't have versions")
+            .to_compile_error()
+            .into();
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let derives = derives();
+    let shard_key = if sharded {
+        quote! {
+            fn shard_key(&self) -> ::std::option::Option<::std::string::String> {
+                ::std::option::Option::Some(<Self as ::myproto::ShardedRequest>::shard_key(self))
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let base = match &namespace {
+        Some(namespace) => format!("{namespace}.{name}"),
+        None => name.to_string(),
+    };
+    let schema_namespace = match &namespace {
+        Some(namespace) => quote! { , namespace = #namespace },
+        None => quote! {},
+    };
+    let (typetag, schema, previous) = match version {
+        Some(version) => {
+            let tag = LitStr::new(&format!("{base}@{version}"), name.span());
+            let previous = previous_version(name, &base, version);
+            let version = proc_macro2::Literal::u32_unsuffixed(version);
+            (
+                quote! { #[::typetag::serde(name = #tag)] },
+                quote! { #[schema(response = #response, version = #version #schema_namespace)] },
+                previous,
+            )
+        }
+        None if namespace.is_some() => {
+            let tag = LitStr::new(&base, name.span());
+            (
+                quote! { #[::typetag::serde(name = #tag)] },
+                quote! { #[schema(response = #response #schema_namespace)] },
+                quote! {},
+            )
+        }
+        None => (
+            quote! { #[::typetag::serde] },
+            quote! { #[schema(response = #response)] },
+            quote! {},
+        ),
+    };
+
+    quote! {
+        #derives
+        #schema
+        #input
+
+        #typetag
+        #[::myproto::__private::async_trait::async_trait]
+        impl #impl_generics ::myproto::Request for #name #ty_generics #where_clause {
+            async fn handle(
+                &self,
+                ctx: &::myproto::Context,
+            ) -> ::myproto::__private::anyhow::Result<::std::boxed::Box<dyn ::myproto::Response>> {
+                let response = <Self as ::myproto::Handler>::handle(self, ctx).await?;
+                ::std::result::Result::Ok(::std::boxed::Box::new(response))
+            }
+
+            fn validate(&self) -> ::std::result::Result<(), ::myproto::ValidationError> {
+                <Self as ::myproto::Handler>::validate(self)
+            }
+
+            fn idempotent(&self) -> bool {
+                #idempotent
+            }
+
+            fn blocking(&self) -> bool {
+                #blocking
+            }
+
+            fn mutating(&self) -> bool {
+                #mutating
+            }
+
+            fn compressible(&self) -> bool {
+                #compressible
+            }
+
+            fn cache_ttl(&self) -> ::std::option::Option<::std::time::Duration> {
+                <Self as ::myproto::Handler>::cache_ttl(self)
+            }
+
+            fn priority(&self) -> ::myproto::Priority {
+                <Self as ::myproto::Handler>::priority(self)
+            }
+
+            fn redacted_fields(&self) -> &'static [&'static str] {
+                <Self as ::myproto::redact::Redact>::redacted_fields()
+            }
+
+            #shard_key
+        }
+
+        impl #impl_generics ::myproto::TypedRequest for #name #ty_generics #where_clause {
+            type Response = #response;
+        }
+
+        #previous
+    }
+    .into()
+}
+
+/// Registers the tag of version `version - 1` of `name`, tagged `base`
+/// without a version, as a request that reads the old layout and upgrades
+/// it to `name`.
+fn previous_version(name: &Ident, base: &str, version: u32) -> TokenStream2 {
+    let tag = if version == 2 {
+        base.to_string()
+    } else {
+        format!("{base}@{}", version - 1)
+    };
+    let tag = LitStr::new(&tag, name.span());
+    let serde = quote! { ::myproto::__private::serde };
+    quote! {
+        const _: () = {
+            #[derive(::std::fmt::Debug)]
+            struct Previous(#name);
+
+            impl<'de> #serde::Deserialize<'de> for Previous {
+                fn deserialize<D: #serde::Deserializer<'de>>(
+                    deserializer: D,
+                ) -> ::std::result::Result<Self, D::Error> {
+                    ::myproto::versioning::upgrade::<#name, D>(deserializer).map(Previous)
+                }
+            }
+
+            impl #serde::Serialize for Previous {
+                fn serialize<S: #serde::Serializer>(
+                    &self,
+                    serializer: S,
+                ) -> ::std::result::Result<S::Ok, S::Error> {
+                    ::myproto::versioning::reencode(serializer, #tag)
+                }
+            }
+
+            #[::typetag::serde(name = #tag)]
+            #[::myproto::__private::async_trait::async_trait]
+            impl ::myproto::Request for Previous {
+                async fn handle(
+                    &self,
+                    ctx: &::myproto::Context,
+                ) -> ::myproto::__private::anyhow::Result<::std::boxed::Box<dyn ::myproto::Response>> {
+                    <#name as ::myproto::Request>::handle(&self.0, ctx).await
+                }
+
+                fn validate(&self) -> ::std::result::Result<(), ::myproto::ValidationError> {
+                    <#name as ::myproto::Request>::validate(&self.0)
+                }
+
+                fn idempotent(&self) -> bool {
+                    <#name as ::myproto::Request>::idempotent(&self.0)
+                }
+
+                fn blocking(&self) -> bool {
+                    <#name as ::myproto::Request>::blocking(&self.0)
+                }
+
+                fn mutating(&self) -> bool {
+                    <#name as ::myproto::Request>::mutating(&self.0)
+                }
+
+                fn cache_ttl(&self) -> ::std::option::Option<::std::time::Duration> {
+                    <#name as ::myproto::Request>::cache_ttl(&self.0)
+                }
+
+                fn priority(&self) -> ::myproto::Priority {
+                    <#name as ::myproto::Request>::priority(&self.0)
+                }
+
+                fn shard_key(&self) -> ::std::option::Option<::std::string::String> {
+                    <#name as ::myproto::Request>::shard_key(&self.0)
+                }
+
+                fn redacted_fields(&self) -> &'static [&'static str] {
+                    <#name as ::myproto::Request>::redacted_fields(&self.0)
+                }
+
+                fn compressible(&self) -> bool {
+                    <#name as ::myproto::Request>::compressible(&self.0)
+                }
+            }
+        };
+    }
+}
+
+/// Declares a response type: adds the serde, `Debug`, `Describe` and `Redact` derives and
+/// registers it with typetag as a `Response`.
+///
+/// `#[response(uncompressed)]` keeps messages carrying it from being compressed.
+#[proc_macro_attribute]
+pub fn response(args: TokenStream, item: TokenStream) -> TokenStream {
+    let compressible = if args.is_empty() {
+        quote! {}
+    } else {
+        match syn::parse::<Ident>(args.clone()) {
+            Ok(flag) if flag == "uncompressed" => quote! {
+                fn compressible(&self) -> bool {
+                    false
+                }
+            },
+            _ => {
+                let args = TokenStream2::from(args);
+                return syn::Error::new_spanned(args, "expected `uncompressed`")
+                    .to_compile_error()
+                    .into();
+            }
+        }
+    };
+    let input = parse_macro_input!(item as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let derives = derives();
+
+    quote! {
+        #derives
+        #input
+
+        #[::typetag::serde]
+        impl #impl_generics ::myproto::Response for #name #ty_generics #where_clause {
+            #compressible
+        }
+    }
+    .into()
+}
+
+/// Implements `myproto::schema::Describe` from the type's fields and doc
+/// comments, and registers non-generic types for `Schema::registered`.
+///
+/// `#[schema(response = Type)]` records what a request is answered with, and
+/// `#[schema(version = N)]` and `#[schema(namespace = "..")]` name it by
+/// its tag. Fields'
+/// `#[serde(rename = "..")]`, `#[serde(skip)]` and `#[serde(default)]` are followed.
+#[proc_macro_derive(Describe, attributes(schema))]
+pub fn describe(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match expand_describe(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Implements `myproto::redact::Redact` naming the fields marked `#[redact]`,
+/// by their serialized names.
+#[proc_macro_derive(Redact, attributes(redact))]
+pub fn redact(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match expand_redact(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_redact(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut fields = Vec::new();
+    match &input.data {
+        Data::Struct(data) => {
+            for field in &data.fields {
+                let Some(attr) = marked(&field.attrs) else {
+                    continue;
+                };
+                let Some(ident) = &field.ident else {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "only named fields can be redacted",
+                    ));
+                };
+                fields.push(serde_name(&field.attrs).unwrap_or_else(|| ident.to_string()));
+            }
+        }
+        Data::Enum(data) => {
+            let variant_fields = data.variants.iter().flat_map(|variant| &variant.fields);
+            if let Some(attr) = variant_fields
+                .filter_map(|field| marked(&field.attrs))
+                .next()
+            {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "only a struct's fields can be redacted",
+                ));
+            }
+        }
+        Data::Union(_) => {}
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::myproto::redact::Redact for #name #ty_generics #where_clause {
+            fn redacted_fields() -> &'static [&'static str] {
+                &[#(#fields),*]
+            }
+        }
+    })
+}
+
+/// The `#[redact]` among `attrs`, if any.
+fn marked(attrs: &[Attribute]) -> Option<&Attribute> {
+    attrs.iter().find(|attr| attr.path().is_ident("redact"))
+}
+
+fn expand_describe(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let docs = option_string(doc_comment(&input.attrs));
+    let SchemaArgs {
+        response,
+        version,
+        namespace,
+    } = schema_args(&input.attrs)?;
+    let response = option_string(response);
+    let name_str = match namespace {
+        Some(namespace) => format!("{namespace}.{name_str}"),
+        None => name_str,
+    };
+    let name_str = match version {
+        Some(version) => format!("{name_str}@{version}"),
+        None => name_str,
+    };
+    let shape = match &input.data {
+        Data::Struct(data) => fields_shape(&data.fields, serde_default(&input.attrs))?,
+        Data::Enum(data) => {
+            let variants = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let name =
+                        serde_name(&variant.attrs).unwrap_or_else(|| variant.ident.to_string());
+                    let docs = option_string(doc_comment(&variant.attrs));
+                    let shape = fields_shape(&variant.fields, false)?;
+                    Ok(quote! {
+                        ::myproto::schema::Variant {
+                            name: ::std::string::String::from(#name),
+                            docs: #docs,
+                            shape: #shape,
+                        }
+                    })
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! { ::myproto::schema::Shape::Enum(::std::vec![#(#variants),*]) }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "unions can't be described",
+            ));
+        }
+    };
+    // Generic types have no single schema to register.
+    let register = if input.generics.params.is_empty() {
+        quote! {
+            ::myproto::__private::inventory::submit! {
+                ::myproto::schema::Described(<#name as ::myproto::schema::Describe>::describe)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::myproto::schema::Describe for #name #ty_generics #where_clause {
+            fn describe() -> ::myproto::schema::TypeSchema {
+                ::myproto::schema::TypeSchema {
+                    name: ::std::string::String::from(#name_str),
+                    docs: #docs,
+                    response: #response,
+                    shape: ::std::option::Option::Some(#shape),
+                }
+            }
+        }
+
+        #register
+    })
+}
+
+/// `all_default` is whether the container has `#[serde(default)]`, which
+/// lets any of its fields be left out.
+fn fields_shape(fields: &Fields, all_default: bool) -> syn::Result<TokenStream2> {
+    Ok(match fields {
+        Fields::Unit => quote! { ::myproto::schema::Shape::Unit },
+        Fields::Named(fields) => {
+            let fields = fields
+                .named
+                .iter()
+                .filter(|field| !serde_skipped(&field.attrs))
+                .map(|field| {
+                    let ident = field.ident.as_ref().expect("named fields have names");
+                    let name = serde_name(&field.attrs).unwrap_or_else(|| ident.to_string());
+                    let docs = option_string(doc_comment(&field.attrs));
+                    let ty = type_ref(&field.ty);
+                    let optional = all_default || serde_default(&field.attrs);
+                    quote! {
+                        ::myproto::schema::Field {
+                            name: ::std::string::String::from(#name),
+                            docs: #docs,
+                            ty: #ty,
+                            optional: #optional,
+                        }
+                    }
+                });
+            quote! { ::myproto::schema::Shape::Struct(::std::vec![#(#fields),*]) }
+        }
+        Fields::Unnamed(fields) => {
+            let types = fields
+                .unnamed
+                .iter()
+                .filter(|field| !serde_skipped(&field.attrs))
+                .map(|field| type_ref(&field.ty));
+            quote! { ::myproto::schema::Shape::Tuple(::std::vec![#(#types),*]) }
+        }
+    })
+}
+
+/// The `TypeRef` for `ty`, as an expression building it.
+fn type_ref(ty: &Type) -> TokenStream2 {
+    let schema = quote! { ::myproto::schema::TypeRef };
+    let boxed = |ty: &Type| {
+        let inner = type_ref(ty);
+        quote! { ::std::boxed::Box::new(#inner) }
+    };
+    match ty {
+        Type::Reference(reference) => type_ref(&reference.elem),
+        Type::Paren(paren) => type_ref(&paren.elem),
+        Type::Group(group) => type_ref(&group.elem),
+        Type::Slice(slice) => {
+            let inner = boxed(&slice.elem);
+            quote! { #schema::List(#inner) }
+        }
+        Type::Array(array) => {
+            let inner = boxed(&array.elem);
+            quote! { #schema::List(#inner) }
+        }
+        Type::Tuple(tuple) if tuple.elems.is_empty() => quote! { #schema::Unit },
+        Type::Tuple(tuple) => {
+            let elems = tuple.elems.iter().map(type_ref);
+            quote! { #schema::Tuple(::std::vec![#(#elems),*]) }
+        }
+        Type::TraitObject(object) => {
+            let traits: Vec<String> = object
+                .bounds
+                .iter()
+                .filter_map(|bound| match bound {
+                    syn::TypeParamBound::Trait(bound) => {
+                        bound.path.segments.last().map(|s| s.ident.to_string())
+                    }
+                    _ => None,
+                })
+                .collect();
+            if traits.iter().any(|t| t == "Response") {
+                quote! { #schema::Response }
+            } else if traits.iter().any(|t| t == "Request") {
+                quote! { #schema::Request }
+            } else {
+                let name = traits.join(" + ");
+                quote! { #schema::Named(::std::string::String::from(#name)) }
+            }
+        }
+        Type::Path(path) => {
+            let Some(segment) = path.path.segments.last() else {
+                return quote! { #schema::Unit };
+            };
+            let args: Vec<&Type> = match &segment.arguments {
+                PathArguments::AngleBracketed(args) => args
+                    .args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        GenericArgument::Type(ty) => Some(ty),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let ident = segment.ident.to_string();
+            match (ident.as_str(), args.as_slice()) {
+                ("bool", []) => quote! { #schema::Bool },
+                ("i8", []) => quote! { #schema::I8 },
+                ("i16", []) => quote! { #schema::I16 },
+                ("i32", []) => quote! { #schema::I32 },
+                ("i64" | "isize", []) => quote! { #schema::I64 },
+                ("i128", []) => quote! { #schema::I128 },
+                ("u8", []) => quote! { #schema::U8 },
+                ("u16", []) => quote! { #schema::U16 },
+                ("u32", []) => quote! { #schema::U32 },
+                ("u64" | "usize", []) => quote! { #schema::U64 },
+                ("u128", []) => quote! { #schema::U128 },
+                ("f32", []) => quote! { #schema::F32 },
+                ("f64", []) => quote! { #schema::F64 },
+                ("char", []) => quote! { #schema::Char },
+                ("String" | "str" | "PathBuf" | "Path", []) => quote! { #schema::String },
+                ("Bytes", []) => quote! { #schema::Bytes },
+                ("Duration", []) => quote! { #schema::Duration },
+                ("Value", []) => quote! { #schema::Json },
+                ("ResponseResult", []) => quote! {
+                    #schema::Result(
+                        ::std::boxed::Box::new(#schema::Response),
+                        ::std::boxed::Box::new(#schema::Named(::std::string::String::from("ProtocolError"))),
+                    )
+                },
+                ("Box" | "Arc" | "Rc" | "Cow", [inner]) => type_ref(inner),
+                ("Option", [inner]) => {
+                    let inner = boxed(inner);
+                    quote! { #schema::Option(#inner) }
+                }
+                ("Vec" | "VecDeque" | "HashSet" | "BTreeSet", [inner]) => {
+                    let inner = boxed(inner);
+                    quote! { #schema::List(#inner) }
+                }
+                ("HashMap" | "BTreeMap", [key, value]) => {
+                    let (key, value) = (boxed(key), boxed(value));
+                    quote! { #schema::Map(#key, #value) }
+                }
+                ("Result", [ok, err]) => {
+                    let (ok, err) = (boxed(ok), boxed(err));
+                    quote! { #schema::Result(#ok, #err) }
+                }
+                _ => quote! { #schema::Named(::std::string::String::from(#ident)) },
+            }
+        }
+        _ => {
+            let name = quote!(#ty).to_string();
+            quote! { #schema::Named(::std::string::String::from(#name)) }
+        }
+    }
+}
+
+/// The doc comment in `attrs`, one line per `///` line.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(meta) => match &meta.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(doc),
+                    ..
+                }) => Some(doc.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|line| {
+            line.strip_prefix(' ')
+                .unwrap_or(&line)
+                .trim_end()
+                .to_string()
+        })
+        .collect();
+    let docs = lines.join("\n").trim().to_string();
+    (!docs.is_empty()).then_some(docs)
+}
+
+struct SchemaArgs {
+    response: Option<String>,
+    version: Option<u32>,
+    namespace: Option<String>,
+}
+
+fn schema_args(attrs: &[Attribute]) -> syn::Result<SchemaArgs> {
+    let mut response = None;
+    let mut version = None;
+    let mut namespace = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("schema")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("response") {
+                let ty: Type = meta.value()?.parse()?;
+                response = Some(match &ty {
+                    Type::Path(path) => path
+                        .path
+                        .segments
+                        .last()
+                        .map_or_else(String::new, |s| s.ident.to_string()),
+                    _ => quote!(#ty).to_string(),
+                });
+                Ok(())
+            } else if meta.path.is_ident("version") {
+                version = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+                Ok(())
+            } else if meta.path.is_ident("namespace") {
+                namespace = Some(parse_namespace(meta.value()?.parse()?)?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `response = Type`, `version = N` or `namespace = \"..\"`"))
+            }
+        })?;
+    }
+    Ok(SchemaArgs {
+        response,
+        version,
+        namespace,
+    })
+}
+
+/// The name `#[serde(rename = "..")]` gives a field or variant, if any.
+fn serde_name(attrs: &[Attribute]) -> Option<String> {
+    let mut name = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        // Other serde options are none of this derive's business.
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.input.peek(Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            }
+            Ok(())
+        });
+    }
+    name
+}
+
+/// Whether `#[serde(default)]`, or `default = ".."`, is among the attributes.
+fn serde_default(attrs: &[Attribute]) -> bool {
+    let mut default = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("default") {
+                default = true;
+            }
+            if meta.input.peek(Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            }
+            Ok(())
+        });
+    }
+    default
+}
+
+fn serde_skipped(attrs: &[Attribute]) -> bool {
+    let mut skipped = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skipped = true;
+            } else if meta.input.peek(Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            }
+            Ok(())
+        });
+    }
+    skipped
+}
+
+fn option_string(value: Option<String>) -> TokenStream2 {
+    match value {
+        Some(value) => quote! { ::std::option::Option::Some(::std::string::String::from(#value)) },
+        None => quote! { ::std::option::Option::None },
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Stdout, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+use crate::{Context, ErrorCode};
+
+/// Which of the four request shapes an [`AccessRecord`] describes.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Call,
+    Stream,
+    Upload,
+    /// Requests sent with `Client::notify`, which get no response.
+    Notify,
+}
+
+/// One finished request, as handed to an [`AccessLog`].
+#[derive(Serialize, Debug, Clone)]
+pub struct AccessRecord {
+    /// Milliseconds since the Unix epoch at which the request finished.
+    pub timestamp_ms: u64,
+    pub connection_id: u64,
+    pub peer_addr: SocketAddr,
+    /// The subject the client authenticated as; `tenant/subject` on a
+    /// multi-tenant server.
+    pub identity: Option<String>,
+    pub request_id: u64,
+    pub kind: RequestKind,
+    pub request_type: &'static str,
+    /// The code the request failed with; `None` if it succeeded.
+    pub error: Option<ErrorCode>,
+    /// Bytes received for the request: the whole frame for a call (shared by
+    /// every request batched in it) or stream, the body for an upload.
+    pub request_bytes: usize,
+    /// Bytes sent in reply: the whole response frame for a call, every item
+    /// frame for a stream.
+    pub response_bytes: usize,
+    #[serde(rename = "duration_ms", serialize_with = "millis")]
+    pub duration: Duration,
+}
+
+impl AccessRecord {
+    pub(crate) fn new(
+        ctx: &Context,
+        request_id: u64,
+        kind: RequestKind,
+        request_type: &'static str,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            timestamp_ms,
+            connection_id: ctx.connection_id,
+            peer_addr: ctx.peer_addr,
+            identity: ctx.identity().map(|identity| identity.key().into_owned()),
+            request_id,
+            kind,
+            request_type,
+            error: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// Receives a record for every request the server finishes. Installed with
+/// `ServerBuilder::access_log`; it runs on the request's task, so slow sinks
+/// should hand records off rather than block.
+pub trait AccessLog: Send + Sync + 'static {
+    fn record(&self, record: &AccessRecord);
+}
+
+/// Writes each record as a line of JSON.
+pub struct JsonLines<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl JsonLines<Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl JsonLines<File> {
+    /// Appends to `path`, creating it if needed.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write + Send + 'static> JsonLines<W> {
+    pub(crate) fn write_line(&self, mut line: Vec<u8>) {
+        line.push(b'\n');
+        // One write per line keeps records whole when several processes append to a file.
+        if let Err(e) = self.out.lock().unwrap().write_all(&line) {
+            tracing::warn!(error = %e, "Failed to write log record");
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> AccessLog for JsonLines<W> {
+    fn record(&self, record: &AccessRecord) {
+        let line = serde_json::to_vec(record).expect("access records always serialize");
+        self.write_line(line);
+    }
+}
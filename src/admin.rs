@@ -0,0 +1,159 @@
+use std::io;
+use std::net::IpAddr;
+
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::Server;
+
+const HELP: &str = "commands: connections | latency | disconnect <id> | bans | unban <ip>|all | log <filter> | reload | drain | upgrade | help";
+
+/// Answers admin commands, one JSON object per line of input, until the task is aborted.
+pub(crate) async fn serve(listener: UnixListener, server: Server) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept admin connection");
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(stream, &server).await {
+                tracing::debug!(error = %e, "Admin connection closed");
+            }
+        });
+    }
+}
+
+async fn session(stream: UnixStream, server: &Server) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match command(server, line).await {
+            Ok(reply) => reply,
+            Err(e) => json!({ "ok": false, "error": format!("{e:#}") }),
+        };
+        let mut out = reply.to_string();
+        out.push('\n');
+        write.write_all(out.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn command(server: &Server, line: &str) -> Result<Value> {
+    let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    tracing::info!(command = name, arg, "Admin command");
+
+    match name {
+        "connections" => {
+            let mut connections = server.connections();
+            connections.sort_by_key(|connection| connection.id());
+            let connections: Vec<Value> = connections
+                .iter()
+                .map(|connection| {
+                    let stats = connection.stats();
+                    json!({
+                        "id": connection.id(),
+                        "peer_addr": connection.peer_addr().to_string(),
+                        "codec": format!("{:?}", stats.codec),
+                        "requests": stats.requests,
+                        "errors": stats.errors,
+                        "malformed": stats.malformed,
+                        "bytes_received": stats.bytes_received,
+                        "bytes_sent": stats.bytes_sent,
+                        "connected_secs": stats.connected_for.as_secs(),
+                        "idle_secs": stats.idle_for.as_secs(),
+                    })
+                })
+                .collect();
+            Ok(json!({ "ok": true, "connections": connections }))
+        }
+        "latency" => {
+            let micros = |duration: std::time::Duration| duration.as_micros() as u64;
+            let types: Vec<Value> = server
+                .latencies()
+                .iter()
+                .map(|latency| {
+                    json!({
+                        "type": latency.request_type,
+                        "count": latency.count,
+                        "mean_us": micros(latency.mean),
+                        "p50_us": micros(latency.p50),
+                        "p90_us": micros(latency.p90),
+                        "p99_us": micros(latency.p99),
+                        "max_us": micros(latency.max),
+                    })
+                })
+                .collect();
+            Ok(json!({ "ok": true, "latency": types }))
+        }
+        "disconnect" => {
+            let id: u64 = arg
+                .parse()
+                .map_err(|_| anyhow::anyhow!("usage: disconnect <id>"))?;
+            let Some(connection) = server.connection(id) else {
+                bail!("no connection with id {id}");
+            };
+            connection.close();
+            Ok(json!({ "ok": true }))
+        }
+        "bans" => {
+            let bans: Vec<Value> = server
+                .bans()
+                .iter()
+                .map(|ban| {
+                    json!({
+                        "ip": ban.ip.to_string(),
+                        "remaining_secs": ban.remaining.as_secs(),
+                    })
+                })
+                .collect();
+            Ok(json!({ "ok": true, "bans": bans }))
+        }
+        "unban" => {
+            if arg == "all" {
+                return Ok(json!({ "ok": true, "unbanned": server.clear_bans() }));
+            }
+            let ip: IpAddr = arg
+                .parse()
+                .map_err(|_| anyhow::anyhow!("usage: unban <ip>|all"))?;
+            if !server.unban(ip) {
+                bail!("{ip} is not banned");
+            }
+            Ok(json!({ "ok": true, "unbanned": 1 }))
+        }
+        "log" => {
+            let Some(set_filter) = &server.log_filter else {
+                bail!("this server has no log filter hook installed");
+            };
+            if arg.is_empty() {
+                bail!("usage: log <filter>");
+            }
+            set_filter(arg)?;
+            Ok(json!({ "ok": true }))
+        }
+        "reload" => {
+            server.reload()?;
+            Ok(json!({ "ok": true }))
+        }
+        "drain" => {
+            server.begin_drain();
+            Ok(json!({ "ok": true }))
+        }
+        "upgrade" => {
+            let pid = server.upgrade().await?;
+            Ok(json!({ "ok": true, "pid": pid }))
+        }
+        "help" => Ok(json!({ "ok": true, "help": HELP })),
+        _ => bail!("unknown command {name:?}; {HELP}"),
+    }
+}
@@ -0,0 +1,252 @@
+//! A record of who ran the requests that matter for security, with what
+//! arguments and how it went, kept apart from the access log.
+//!
+//! ```ignore
+//! let audit = Audit::new(JsonLines::file("/var/log/myproto/audit.log")?)
+//!     .request("PutFile")
+//!     .request("Login")
+//!     .redact("Login", "password");
+//! let server = Server::builder().audit(audit).build();
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::access_log::{JsonLines, RequestKind};
+use crate::redact::{self, REDACTED};
+use crate::{Context, ErrorCode};
+
+/// One audited request, as handed to an [`AuditSink`].
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch at which the request finished.
+    pub timestamp_ms: u64,
+    pub connection_id: u64,
+    pub peer_addr: SocketAddr,
+    /// The subject the client authenticated as; `tenant/subject` on a
+    /// multi-tenant server.
+    pub identity: Option<String>,
+    pub kind: RequestKind,
+    pub request_type: &'static str,
+    /// The request's fields, with its `#[redact]` ones and the audit's
+    /// masked; `None` when the
+    /// audit leaves arguments out.
+    pub arguments: Option<Value>,
+    /// The code the request failed with; `None` if it succeeded.
+    pub error: Option<ErrorCode>,
+    #[serde(rename = "duration_ms", serialize_with = "millis")]
+    pub duration: Duration,
+}
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// Where audit records go. Like an [`AccessLog`](crate::AccessLog) it runs
+/// on the request's task, so slow sinks should hand records off.
+pub trait AuditSink: Send + Sync + 'static {
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<W: Write + Send + 'static> AuditSink for JsonLines<W> {
+    fn record(&self, record: &AuditRecord) {
+        let line = serde_json::to_vec(record).expect("audit records always serialize");
+        self.write_line(line);
+    }
+}
+
+/// Sends each record as JSON to the local syslog daemon, through `/dev/log`.
+#[cfg(unix)]
+pub struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    /// The syslog facility, `authpriv` (10) unless set otherwise.
+    facility: u8,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    pub fn new() -> std::io::Result<Self> {
+        Self::at("/dev/log")
+    }
+
+    /// Sends to the daemon listening on `path` instead of `/dev/log`.
+    pub fn at(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            facility: 10,
+        })
+    }
+
+    pub fn facility(mut self, facility: u8) -> Self {
+        self.facility = facility;
+        self
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for Syslog {
+    fn record(&self, record: &AuditRecord) {
+        // Failures are a warning, successes a notice.
+        let severity = if record.error.is_some() { 4 } else { 5 };
+        let priority = u16::from(self.facility) * 8 + severity;
+        let json = serde_json::to_string(record).expect("audit records always serialize");
+        let line = format!("<{priority}>myproto[{}]: {json}", std::process::id());
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            tracing::warn!(error = %e, "Failed to send audit record to syslog");
+        }
+    }
+}
+
+/// Which requests are audited and what of them is kept, installed with
+/// `ServerBuilder::audit`.
+pub struct Audit {
+    sink: Arc<dyn AuditSink>,
+    /// `None` audits every request.
+    types: Option<HashSet<String>>,
+    redact: HashMap<String, HashSet<String>>,
+    arguments: bool,
+}
+
+impl Audit {
+    /// Audits nothing until given the request types to audit.
+    pub fn new(sink: impl AuditSink) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            types: Some(HashSet::new()),
+            redact: HashMap::new(),
+            arguments: true,
+        }
+    }
+
+    /// Audits requests of type `request_type`, calls, streams and uploads alike.
+    pub fn request(mut self, request_type: impl Into<String>) -> Self {
+        if let Some(types) = &mut self.types {
+            types.insert(request_type.into());
+        }
+        self
+    }
+
+    /// Audits every request, whatever its type.
+    pub fn every_request(mut self) -> Self {
+        self.types = None;
+        self
+    }
+
+    /// Masks `field` of `request_type`'s arguments, at any depth, as well as
+    /// the fields the type redacts itself.
+    pub fn redact(mut self, request_type: impl Into<String>, field: impl Into<String>) -> Self {
+        self.redact
+            .entry(request_type.into())
+            .or_default()
+            .insert(field.into());
+        self
+    }
+
+    /// Leaves every request's arguments out of its records.
+    pub fn without_arguments(mut self) -> Self {
+        self.arguments = false;
+        self
+    }
+
+    fn covers(&self, request_type: &str) -> bool {
+        self.types
+            .as_ref()
+            .is_none_or(|types| types.contains(request_type))
+    }
+
+    fn arguments<T: Serialize + ?Sized>(
+        &self,
+        request_type: &str,
+        request: &T,
+        redacted_fields: &[&str],
+    ) -> Option<Value> {
+        if !self.arguments {
+            return None;
+        }
+        // Requests serialize tagged with their type, `{"Login": {...}}`.
+        let mut arguments = match redact::redacted(request, redacted_fields) {
+            Ok(Value::Object(tagged)) if tagged.len() == 1 => tagged.into_iter().next()?.1,
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!(request_type, error = %e, "Failed to serialize audited request");
+                return None;
+            }
+        };
+        if let Some(fields) = self.redact.get(request_type) {
+            mask(&mut arguments, fields);
+        }
+        Some(arguments)
+    }
+}
+
+fn mask(value: &mut Value, fields: &HashSet<String>) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                if fields.contains(name) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    mask(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| mask(item, fields)),
+        _ => {}
+    }
+}
+
+/// A request being audited, waiting for its outcome.
+pub(crate) struct Pending {
+    audit: Arc<Audit>,
+    kind: RequestKind,
+    request_type: &'static str,
+    arguments: Option<Value>,
+}
+
+/// Starts auditing `request` if the server audits its type, taking its
+/// arguments before the handler has it.
+pub(crate) fn begin<T: Serialize + ?Sized>(
+    ctx: &Context,
+    kind: RequestKind,
+    request_type: &'static str,
+    request: &T,
+    redacted_fields: &[&str],
+) -> Option<Pending> {
+    let audit = ctx.server().audit.as_ref()?;
+    if !audit.covers(request_type) {
+        return None;
+    }
+    Some(Pending {
+        arguments: audit.arguments(request_type, request, redacted_fields),
+        audit: audit.clone(),
+        kind,
+        request_type,
+    })
+}
+
+impl Pending {
+    pub(crate) fn finish(self, ctx: &Context, error: Option<ErrorCode>, duration: Duration) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.audit.sink.record(&AuditRecord {
+            timestamp_ms,
+            connection_id: ctx.connection_id,
+            peer_addr: ctx.peer_addr,
+            identity: ctx.identity().map(|identity| identity.key().into_owned()),
+            kind: self.kind,
+            request_type: self.request_type,
+            arguments: self.arguments,
+            error,
+            duration,
+        });
+    }
+}
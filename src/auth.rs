@@ -0,0 +1,265 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use async_trait::async_trait;
+use futures::SinkExt;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::frame::FrameCodec;
+use crate::{ErrorCode, ProtocolError};
+use crate::{handshake, tenant};
+
+const CHALLENGE_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who an authenticated connection belongs to, as decided by the [`Authenticator`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub subject: String,
+    /// The customer the identity belongs to, on a server in
+    /// [multi-tenant mode](crate::tenant).
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl Identity {
+    pub fn new(subject: impl Into<String>) -> Self {
+        Self {
+            subject: subject.into(),
+            tenant: None,
+        }
+    }
+
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// The subject, under the tenant's prefix if it has one, for keeping
+    /// per-identity state by.
+    pub(crate) fn key(&self) -> Cow<'_, str> {
+        match &self.tenant {
+            Some(tenant) => Cow::Owned(tenant::scoped(tenant, &self.subject)),
+            None => Cow::Borrowed(&self.subject),
+        }
+    }
+}
+
+/// What the client presents in answer to the server's challenge.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Credential {
+    Bearer(String),
+    /// HMAC-SHA256 of the challenge under the secret registered as `key_id`.
+    Hmac {
+        key_id: String,
+        mac: Vec<u8>,
+    },
+}
+
+/// The secrets a client authenticates with, turned into a [`Credential`]
+/// once the server's challenge is known.
+#[derive(Clone)]
+pub enum Credentials {
+    Bearer(String),
+    Hmac { key_id: String, key: Vec<u8> },
+}
+
+impl Credentials {
+    pub fn respond(&self, challenge: &[u8]) -> Credential {
+        match self {
+            Credentials::Bearer(token) => Credential::Bearer(token.clone()),
+            Credentials::Hmac { key_id, key } => Credential::Hmac {
+                key_id: key_id.clone(),
+                mac: sign(key, challenge),
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Bearer(_) => f.write_str("Bearer(..)"),
+            Credentials::Hmac { key_id, .. } => f
+                .debug_struct("Hmac")
+                .field("key_id", key_id)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Decides whether a connecting client may use the server. Installed with
+/// `ServerBuilder::authenticator`; without one every client is let in.
+#[async_trait]
+pub trait Authenticator: Send + Sync + 'static {
+    /// `challenge` is the nonce this connection was issued, which an HMAC
+    /// credential must be computed over.
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        challenge: &[u8],
+        peer_addr: SocketAddr,
+    ) -> Result<Identity>;
+}
+
+/// Accepts a fixed set of bearer tokens.
+#[derive(Default)]
+pub struct StaticTokens {
+    tokens: HashMap<String, Identity>,
+}
+
+impl StaticTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(mut self, token: impl Into<String>, identity: Identity) -> Self {
+        self.tokens.insert(token.into(), identity);
+        self
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokens {
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        _: &[u8],
+        _: SocketAddr,
+    ) -> Result<Identity> {
+        let Credential::Bearer(presented) = credential else {
+            bail!("expected a bearer token");
+        };
+        // Compare against every token so the time taken doesn't reveal which one nearly matched.
+        self.tokens
+            .iter()
+            .fold(None, |found, (token, identity)| {
+                let matches = constant_time_eq(token.as_bytes(), presented.as_bytes());
+                if matches { Some(identity) } else { found }
+            })
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown token"))
+    }
+}
+
+/// Verifies HMAC-SHA256 challenge responses against shared secrets.
+#[derive(Default)]
+pub struct HmacKeys {
+    keys: HashMap<String, (Vec<u8>, Identity)>,
+}
+
+impl HmacKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(
+        mut self,
+        key_id: impl Into<String>,
+        key: impl Into<Vec<u8>>,
+        identity: Identity,
+    ) -> Self {
+        self.keys.insert(key_id.into(), (key.into(), identity));
+        self
+    }
+}
+
+#[async_trait]
+impl Authenticator for HmacKeys {
+    async fn authenticate(
+        &self,
+        credential: &Credential,
+        challenge: &[u8],
+        _: SocketAddr,
+    ) -> Result<Identity> {
+        let Credential::Hmac { key_id, mac } = credential else {
+            bail!("expected an HMAC response");
+        };
+        let (key, identity) = self.keys.get(key_id).context("unknown key")?;
+        let mut expected =
+            HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        expected.update(challenge);
+        expected
+            .verify_slice(mac)
+            .map_err(|_| anyhow!("signature mismatch"))?;
+        Ok(identity.clone())
+    }
+}
+
+fn sign(key: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub(crate) fn challenge() -> Vec<u8> {
+    let mut nonce = vec![0; CHALLENGE_LEN];
+    getrandom::fill(&mut nonce).expect("the OS random number generator is available");
+    nonce
+}
+
+/// Reads the client's credential and checks it, and the identity it
+/// proves against `multi_tenant` mode, telling the client the outcome.
+pub(crate) async fn verify<S>(
+    framed: &mut Framed<S, FrameCodec>,
+    authenticator: &dyn Authenticator,
+    challenge: &[u8],
+    peer_addr: SocketAddr,
+    multi_tenant: bool,
+) -> Result<Identity>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let bytes = handshake::recv(framed)
+        .await
+        .context("connection closed before authenticating")?;
+    let result = match bincode::deserialize::<Credential>(&bytes) {
+        Ok(credential) => authenticator
+            .authenticate(&credential, challenge, peer_addr)
+            .await
+            .map_err(|e| {
+                tracing::warn!(%peer_addr, error = %e, "Authentication failed");
+                // The reason stays in the server log; clients only learn that it failed.
+                ProtocolError::new(ErrorCode::Unauthenticated, "authentication failed")
+            })
+            .and_then(|identity| tenant::admit(identity, multi_tenant)),
+        Err(e) => Err(ProtocolError::new(
+            ErrorCode::Malformed,
+            format!("malformed credential: {e}"),
+        )),
+    };
+
+    framed.send(bincode::serialize(&result)?.into()).await?;
+    Ok(result?)
+}
+
+/// Answers the server's challenge and waits for it to accept the credential.
+pub(crate) async fn present<S>(
+    framed: &mut Framed<S, FrameCodec>,
+    credentials: &Credentials,
+    challenge: &[u8],
+) -> Result<Identity>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let credential = credentials.respond(challenge);
+    framed.send(bincode::serialize(&credential)?.into()).await?;
+
+    let bytes = handshake::recv(framed)
+        .await
+        .context("connection closed while authenticating")?;
+    let result: Result<Identity, ProtocolError> =
+        bincode::deserialize(&bytes).context("malformed authentication result")?;
+    Ok(result?)
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{ErrorCode, Identity, ProtocolError, versioning};
+
+/// Stands for every request type in a role's or `public`'s list.
+pub const ANY_REQUEST: &str = "*";
+
+/// Which request types each authenticated identity may call, by role. Every
+/// kind of request is checked, by its type name, before middleware and
+/// validation see it, and one that isn't allowed fails with
+/// [`ErrorCode::PermissionDenied`].
+///
+/// ```ignore
+/// let policy = Authorization::new()
+///     .public(["HealthCheck"])
+///     .role("admin", [ANY_REQUEST])
+///     .role("reader", ["GetFile", "ServerInfo"])
+///     .grant("alice", "admin")
+///     .grant("bob", "reader");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Authorization {
+    roles: HashMap<String, HashSet<String>>,
+    grants: HashMap<String, HashSet<String>>,
+    public: HashSet<String>,
+}
+
+impl Authorization {
+    /// Denies everything until roles are defined and granted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets holders of `role` call `request_types`, on top of whatever it
+    /// was already allowed.
+    pub fn role<I, T>(mut self, role: impl Into<String>, request_types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.roles
+            .entry(role.into())
+            .or_default()
+            .extend(request_types.into_iter().map(Into::into));
+        self
+    }
+
+    /// Gives the identity whose subject is `subject` the permissions of
+    /// `role`. On a multi-tenant server, name it `tenant/subject`, so the
+    /// grant doesn't reach a same-named subject of another tenant.
+    pub fn grant(mut self, subject: impl Into<String>, role: impl Into<String>) -> Self {
+        self.grants
+            .entry(subject.into())
+            .or_default()
+            .insert(role.into());
+        self
+    }
+
+    /// Lets any connection call `request_types`, authenticated or not.
+    pub fn public<I, T>(mut self, request_types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.public
+            .extend(request_types.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn permits(&self, identity: Option<&Identity>, request_type: &str) -> bool {
+        // Rules name a request by its type, whatever version of it arrives.
+        let request_type = versioning::base_name(request_type);
+        let allows =
+            |types: &HashSet<String>| types.contains(request_type) || types.contains(ANY_REQUEST);
+        if allows(&self.public) {
+            return true;
+        }
+        let Some(roles) = identity.and_then(|identity| self.grants.get(identity.key().as_ref()))
+        else {
+            return false;
+        };
+        roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .any(allows)
+    }
+
+    /// Fails with `PermissionDenied` unless `identity` may call `request_type`.
+    pub fn check(
+        &self,
+        identity: Option<&Identity>,
+        request_type: &str,
+    ) -> Result<(), ProtocolError> {
+        if self.permits(identity, request_type) {
+            return Ok(());
+        }
+        let who = identity.map_or("unauthenticated clients", |identity| {
+            identity.subject.as_str()
+        });
+        tracing::debug!(who, request_type, "Request denied");
+        Err(ProtocolError::new(
+            ErrorCode::PermissionDenied,
+            format!("{who} may not call {request_type}"),
+        ))
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use tokio::task::JoinHandle;
+
+use crate::builtin::{HealthCheck, HealthStatus};
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+use crate::client::downcast_response;
+use crate::discovery::Discovery;
+use crate::hedge::Hedger;
+use crate::{Pool, PoolConfig, Request, Response, RetryPolicy, TypedRequest};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each call goes to the next endpoint in turn.
+    #[default]
+    RoundRobin,
+    /// Each call goes to the endpoint running the fewest calls from here.
+    LeastInFlight,
+}
+
+#[derive(Debug, Clone)]
+pub struct BalancerConfig {
+    pub strategy: Strategy,
+    /// How often to send each endpoint a `HealthCheck`; endpoints that fail
+    /// it or report draining get no calls until they pass again.
+    pub health_check_interval: Option<Duration>,
+    /// How often a balancer built with [`Balancer::discover`] looks its
+    /// endpoints up again.
+    pub resolve_interval: Duration,
+    /// Takes endpoints whose calls keep failing out of rotation until a probe succeeds.
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// The connection pool kept for each endpoint. Its client's retry and
+    /// hedge policies apply across endpoints, so a retried or hedged call
+    /// goes to another server.
+    pub pool: PoolConfig,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            health_check_interval: Some(Duration::from_secs(5)),
+            resolve_interval: Duration::from_secs(30),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+/// A client for several servers offering the same service, spreading calls
+/// across the ones that are currently healthy.
+///
+/// When no endpoint looks healthy, calls are spread across all of them
+/// anyway, so the first server to recover is found by the calls themselves.
+#[derive(Clone)]
+pub struct Balancer {
+    inner: Arc<Inner>,
+    _background: Arc<Background>,
+}
+
+struct Inner {
+    strategy: Strategy,
+    retry: Option<RetryPolicy>,
+    hedge: Option<Hedger>,
+    /// For endpoints added when discovery finds new addresses.
+    pool: PoolConfig,
+    circuit_breaker: CircuitBreakerConfig,
+    endpoints: RwLock<Vec<Arc<Endpoint>>>,
+    next: AtomicUsize,
+}
+
+struct Endpoint {
+    addr: String,
+    pool: Pool,
+    breaker: CircuitBreaker,
+    healthy: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStatus {
+    pub addr: String,
+    /// Whether the last health check passed; endpoints start out healthy.
+    pub healthy: bool,
+    pub circuit: CircuitState,
+    pub in_flight: usize,
+}
+
+/// Stops health checks and re-resolution once the last handle is dropped.
+struct Background(Vec<JoinHandle<()>>);
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+impl Balancer {
+    /// Sets up a balancer for `addrs`, connecting to each on first use. Must
+    /// be called from within a Tokio runtime when health checks are enabled.
+    pub fn new<A: Into<String>>(
+        addrs: impl IntoIterator<Item = A>,
+        config: BalancerConfig,
+    ) -> Result<Self> {
+        let addrs: BTreeSet<String> = addrs.into_iter().map(Into::into).collect();
+        if addrs.is_empty() {
+            bail!("a balancer needs at least one address");
+        }
+        Ok(Self::start(addrs, None, config))
+    }
+
+    /// Sets up a balancer for the addresses `discovery` resolves to, looking
+    /// them up again every `BalancerConfig::resolve_interval`. Endpoints that
+    /// disappear are dropped once their calls finish; new ones join in.
+    pub async fn discover(discovery: Discovery, config: BalancerConfig) -> Result<Self> {
+        let addrs = discovery.resolve().await?;
+        if addrs.is_empty() {
+            bail!("{discovery:?} resolved to no addresses");
+        }
+        Ok(Self::start(addrs, Some(discovery), config))
+    }
+
+    fn start(
+        addrs: BTreeSet<String>,
+        discovery: Option<Discovery>,
+        mut config: BalancerConfig,
+    ) -> Self {
+        let retry = config.pool.client.retry.take();
+        let hedge = config.pool.client.hedge.take().map(Hedger::new);
+        // Each endpoint's breaker sits in front of its pool, so the pool doesn't need its own.
+        config.pool.client.circuit_breaker = None;
+        let inner = Arc::new(Inner {
+            strategy: config.strategy,
+            retry,
+            hedge,
+            pool: config.pool,
+            circuit_breaker: config.circuit_breaker,
+            endpoints: RwLock::default(),
+            next: AtomicUsize::new(0),
+        });
+        inner.set_endpoints(addrs);
+
+        let mut background = Vec::new();
+        if let Some(interval) = config.health_check_interval {
+            background.push(tokio::spawn(check_health(Arc::downgrade(&inner), interval)));
+        }
+        if let Some(discovery) = discovery {
+            let refresh = refresh(Arc::downgrade(&inner), discovery, config.resolve_interval);
+            background.push(tokio::spawn(refresh));
+        }
+        Self {
+            inner,
+            _background: Arc::new(Background(background)),
+        }
+    }
+
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.inner.retry {
+            Some(retry) => {
+                retry
+                    .run(request, true, |request| self.call_hedged(request))
+                    .await
+            }
+            None => self.call_hedged(request).await,
+        }
+    }
+
+    async fn call_hedged(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.inner.hedge {
+            Some(hedge) => hedge.run(request, |request| self.call_once(request)).await,
+            None => self.call_once(request).await,
+        }
+    }
+
+    async fn call_once(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        let endpoint = self.inner.pick();
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _running = InFlight(&endpoint);
+        endpoint
+            .breaker
+            .run(async { endpoint.pool.get().await?.call(request).await })
+            .await
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response(response)
+    }
+
+    pub fn endpoints(&self) -> Vec<EndpointStatus> {
+        self.inner
+            .endpoints()
+            .iter()
+            .map(|endpoint| EndpointStatus {
+                addr: endpoint.addr.clone(),
+                healthy: endpoint.healthy.load(Ordering::Relaxed),
+                circuit: endpoint.breaker.state(),
+                in_flight: endpoint.in_flight.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    fn endpoints(&self) -> Vec<Arc<Endpoint>> {
+        self.endpoints.read().unwrap().clone()
+    }
+
+    fn pick(&self) -> Arc<Endpoint> {
+        let endpoints = self.endpoints.read().unwrap();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = endpoints.len();
+        let in_turn = (0..count).map(|offset| &endpoints[(start + offset) % count]);
+        let available = || in_turn.clone().filter(|endpoint| endpoint.is_available());
+
+        let picked = match self.strategy {
+            Strategy::RoundRobin => available().next(),
+            // `min_by_key` keeps the first of equals, so ties still go round.
+            Strategy::LeastInFlight => {
+                available().min_by_key(|endpoint| endpoint.in_flight.load(Ordering::Relaxed))
+            }
+        };
+        picked.unwrap_or(&endpoints[start % count]).clone()
+    }
+
+    /// Replaces the endpoint set with `addrs`, keeping the pools and health
+    /// of the addresses already known.
+    fn set_endpoints(&self, addrs: BTreeSet<String>) {
+        let mut endpoints = self.endpoints.write().unwrap();
+        let mut updated = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            if let Some(endpoint) = endpoints.iter().find(|endpoint| endpoint.addr == addr) {
+                updated.push(endpoint.clone());
+                continue;
+            }
+            if !endpoints.is_empty() {
+                tracing::info!(%addr, "Endpoint added");
+            }
+            updated.push(Arc::new(Endpoint {
+                pool: Pool::new(addr.clone(), self.pool.clone()),
+                addr,
+                breaker: CircuitBreaker::new(self.circuit_breaker.clone()),
+                healthy: AtomicBool::new(true),
+                in_flight: AtomicUsize::new(0),
+            }));
+        }
+        for endpoint in endpoints.iter() {
+            if !updated.iter().any(|kept| kept.addr == endpoint.addr) {
+                tracing::info!(addr = %endpoint.addr, "Endpoint removed");
+            }
+        }
+        *endpoints = updated;
+    }
+}
+
+impl Endpoint {
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.breaker.is_available()
+    }
+}
+
+struct InFlight<'a>(&'a Endpoint);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn check_health(inner: Weak<Inner>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let endpoints = inner.endpoints();
+        drop(inner);
+        let checks = endpoints.iter().map(|endpoint| async move {
+            let check = endpoint.pool.call_typed(HealthCheck);
+            let healthy = matches!(
+                tokio::time::timeout(interval, check).await,
+                Ok(Ok(response)) if response.status == HealthStatus::Serving
+            );
+            if endpoint.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                if healthy {
+                    tracing::info!(addr = %endpoint.addr, "Endpoint healthy again");
+                } else {
+                    tracing::warn!(addr = %endpoint.addr, "Endpoint failed its health check");
+                }
+            }
+        });
+        futures::future::join_all(checks).await;
+    }
+}
+
+async fn refresh(inner: Weak<Inner>, discovery: Discovery, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick fires straight away, and the endpoints were only just resolved.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let addrs = match discovery.resolve().await {
+            Ok(addrs) if addrs.is_empty() => {
+                tracing::warn!(
+                    ?discovery,
+                    "Resolved to no addresses, keeping the current endpoints"
+                );
+                continue;
+            }
+            Ok(addrs) => addrs,
+            Err(e) => {
+                tracing::warn!(error = %format!("{e:#}"), "Re-resolving endpoints failed, keeping the current ones");
+                continue;
+            }
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        inner.set_endpoints(addrs);
+    }
+}
@@ -0,0 +1,213 @@
+//! Temporary bans for peers that keep breaking the protocol.
+//!
+//! With `ServerConfig::ban` set, every oversized frame, undecodable frame
+//! and failed authentication counts against the peer's IP address. A peer
+//! that reaches the policy's threshold within its window has its sessions
+//! closed and new connections refused until the ban runs out. The admin
+//! socket's `bans` command lists the bans in force and `unban` lifts them.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Peers are pruned once the table grows past this many, at most once per
+/// policy window and never more often than `MIN_PRUNE_INTERVAL`.
+const PRUNE_THRESHOLD: usize = 4096;
+
+const MIN_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When a peer is banned, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    /// Violations within `window` that get a peer banned.
+    pub threshold: u32,
+    pub window: Duration,
+    /// How long a ban lasts.
+    pub duration: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 10,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Something a peer did that counts towards banning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Violation {
+    OversizedFrame,
+    MalformedFrame,
+    AuthFailure,
+}
+
+impl Violation {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Violation::OversizedFrame => "oversized_frame",
+            Violation::MalformedFrame => "malformed_frame",
+            Violation::AuthFailure => "auth_failure",
+        }
+    }
+}
+
+/// A ban in force.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// How long until the peer may connect again.
+    pub remaining: Duration,
+}
+
+#[derive(Default)]
+struct Peer {
+    /// The latest violations, at most the policy's threshold of them.
+    violations: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Peer {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Every peer's recent violations and bans, shared by every session on a server.
+#[derive(Default)]
+pub(crate) struct Bans {
+    peers: Mutex<Peers>,
+}
+
+#[derive(Default)]
+struct Peers {
+    by_ip: HashMap<IpAddr, Peer>,
+    pruned: Option<Instant>,
+}
+
+impl Bans {
+    /// Counts a violation by `ip`, returning whether it got the peer banned.
+    pub(crate) fn record(&self, policy: &BanPolicy, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let interval = policy.window.max(MIN_PRUNE_INTERVAL);
+        if peers.by_ip.len() >= PRUNE_THRESHOLD
+            && peers
+                .pruned
+                .is_none_or(|pruned| now.duration_since(pruned) >= interval)
+        {
+            peers.by_ip.retain(|_, peer| {
+                peer.is_banned(now)
+                    || peer
+                        .violations
+                        .back()
+                        .is_some_and(|&last| now.duration_since(last) < policy.window)
+            });
+            peers.pruned = Some(now);
+        }
+
+        let peer = peers.by_ip.entry(ip.to_canonical()).or_default();
+        if peer.is_banned(now) {
+            return false;
+        }
+        let threshold = policy.threshold.max(1) as usize;
+        peer.violations.push_back(now);
+        while peer.violations.len() > threshold {
+            peer.violations.pop_front();
+        }
+        let banned = peer.violations.len() == threshold
+            && peer
+                .violations
+                .front()
+                .is_some_and(|&first| now.duration_since(first) < policy.window);
+        if banned {
+            peer.violations.clear();
+            peer.banned_until = Some(now + policy.duration);
+        }
+        banned
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .by_ip
+            .get(&ip.to_canonical())
+            .is_some_and(|peer| peer.is_banned(Instant::now()))
+    }
+
+    /// The bans in force, soonest to run out first.
+    pub(crate) fn list(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut bans: Vec<Ban> = self
+            .peers
+            .lock()
+            .unwrap()
+            .by_ip
+            .iter()
+            .filter_map(|(&ip, peer)| {
+                let until = peer.banned_until.filter(|&until| until > now)?;
+                Some(Ban {
+                    ip,
+                    remaining: until - now,
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.remaining);
+        bans
+    }
+
+    /// Lifts `ip`'s ban and forgets its violations, returning whether it was banned.
+    pub(crate) fn unban(&self, ip: IpAddr) -> bool {
+        let peer = self.peers.lock().unwrap().by_ip.remove(&ip.to_canonical());
+        peer.is_some_and(|peer| peer.is_banned(Instant::now()))
+    }
+
+    /// Lifts every ban, returning how many there were.
+    pub(crate) fn clear(&self) -> usize {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let banned = peers
+            .by_ip
+            .values()
+            .filter(|peer| peer.is_banned(now))
+            .count();
+        peers.by_ip.clear();
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn ip(n: usize) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n as u32))
+    }
+
+    #[test]
+    fn prunes_at_most_once_per_window() {
+        let policy = BanPolicy {
+            threshold: 2,
+            window: Duration::ZERO,
+            ..BanPolicy::default()
+        };
+        let bans = Bans::default();
+        bans.peers.lock().unwrap().pruned = Some(Instant::now());
+        for n in 0..=PRUNE_THRESHOLD {
+            bans.record(&policy, ip(n));
+        }
+        // Every violation is outside the window, but the table was just pruned.
+        assert_eq!(bans.peers.lock().unwrap().by_ip.len(), PRUNE_THRESHOLD + 1);
+
+        let mut peers = bans.peers.lock().unwrap();
+        peers.pruned = peers.pruned.map(|pruned| pruned - MIN_PRUNE_INTERVAL);
+        drop(peers);
+        bans.record(&policy, ip(usize::MAX));
+        assert_eq!(bans.peers.lock().unwrap().by_ip.len(), 1);
+    }
+}
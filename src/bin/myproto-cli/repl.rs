@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result};
+use myproto::Client;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::validate::MatchingBracketValidator;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use serde_json::Value;
+
+const HELP: &str = "\
+<Type> [JSON]   call a request, e.g. Echo {\"message\": \"hi\"}
+.types          list the request types the server knows
+.help           show this message
+.quit           leave (or Ctrl-D)";
+
+/// Completes the request type at the start of a line; JSON left open across
+/// a newline keeps reading until its brackets balance.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct Prompt {
+    types: Vec<String>,
+    #[rustyline(Validator)]
+    brackets: MatchingBracketValidator,
+}
+
+impl Completer for Prompt {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let matches = self
+            .types
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect();
+        Ok((0, matches))
+    }
+}
+
+pub(crate) async fn run(client: &Client) -> Result<()> {
+    let types = request_types(client).await?;
+    let mut editor = Editor::new()?;
+    editor.set_helper(Some(Prompt {
+        types,
+        brackets: MatchingBracketValidator::new(),
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing file just means there is no history yet.
+        let _ = editor.load_history(path);
+    }
+    println!("Connected; .help lists commands");
+
+    loop {
+        let line = match tokio::task::block_in_place(|| editor.readline("myproto> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        match line {
+            ".quit" | ".exit" => break,
+            ".help" => println!("{HELP}"),
+            ".types" => {
+                for name in &editor.helper().expect("helper is set").types {
+                    println!("{name}");
+                }
+            }
+            _ => {
+                if let Err(e) = call(client, line).await {
+                    eprintln!("error: {e:#}");
+                }
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+async fn call(client: &Client, line: &str) -> Result<()> {
+    let (type_name, fields) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let fields = match fields.trim() {
+        "" => Value::Null,
+        fields => serde_json::from_str(fields).context("request is not valid JSON")?,
+    };
+    super::call(client, type_name, fields).await
+}
+
+async fn request_types(client: &Client) -> Result<Vec<String>> {
+    let response = client
+        .call_untyped("Introspect", Value::Null)
+        .await
+        .context("failed to list the server's request types")?;
+    let types = response.fields["request_types"]
+        .as_array()
+        .context("Introspect response has no request_types")?
+        .iter()
+        .filter_map(|name| name.as_str().map(String::from))
+        .collect();
+    Ok(types)
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".myproto_history"))
+}
@@ -0,0 +1,138 @@
+//! Runs one request arriving from outside the binary protocol, through the
+//! HTTP gateway or the gRPC bridge, with its fields as JSON.
+//!
+//! Each is handled as its own short-lived session: it is never listed in
+//! `Server::connections` and anything pushed to it is dropped. With an
+//! `Authenticator` installed, callers present a bearer token, which is
+//! checked against an empty challenge. The `IpFilter` and bans apply to the
+//! caller's address as they do to a connection's, and a refused token
+//! counts towards banning it.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::auth::Credential;
+use crate::ban::Violation;
+use crate::codec::Codec;
+use crate::frame::Compression;
+use crate::rate_limit::RateKey;
+use crate::server;
+use crate::{Connection, Context, ErrorCode, Identity, ProtocolError, Request, Server};
+
+/// Runs the request `type_name` with the JSON fields `body`, empty for a
+/// unit request, answering with the response's type name and fields.
+pub(crate) async fn call(
+    server: &Server,
+    peer_addr: SocketAddr,
+    type_name: &str,
+    bearer: Option<&str>,
+    body: &[u8],
+) -> Result<(String, Value), ProtocolError> {
+    if !server.permits(peer_addr) {
+        return Err(ProtocolError::new(
+            ErrorCode::PermissionDenied,
+            "requests from this address are refused",
+        ));
+    }
+    let identity = authenticate(server, peer_addr, bearer).await?;
+    let req = decode(type_name, body)?;
+
+    if let Some(limiter) = server.rate_limiter()
+        && let Err(retry_after) =
+            limiter.acquire(&RateKey::new(identity.as_ref(), peer_addr.ip()), 1)
+    {
+        let mut err = ProtocolError::new(ErrorCode::RateLimited, "Rate limit exceeded");
+        if retry_after != Duration::MAX {
+            err = err.with_details(format!("retry after {retry_after:?}"));
+        }
+        return Err(err);
+    }
+    if let Some(quotas) = &server.quotas {
+        quotas.charge(identity.as_ref(), 1, body.len()).await?;
+    }
+
+    let _running = server
+        .load
+        .try_start(1, server.config().max_in_flight)
+        .ok_or_else(|| ProtocolError::new(ErrorCode::Busy, "Server is busy"))?;
+    let ctx = Context::new(
+        Connection::detached(server, peer_addr, Codec::Json),
+        Compression::None,
+        identity,
+        server,
+    );
+    ctx.connection().count_requests(1);
+
+    let (result, _) = server::dispatch(req, &ctx, None, &server.middleware).await;
+    encode(result?.as_ref())
+}
+
+async fn authenticate(
+    server: &Server,
+    peer_addr: SocketAddr,
+    bearer: Option<&str>,
+) -> Result<Option<Identity>, ProtocolError> {
+    let Some(authenticator) = &server.authenticator else {
+        return server.admit_identity(None);
+    };
+    let unauthenticated =
+        || ProtocolError::new(ErrorCode::Unauthenticated, "authentication failed");
+    let token = bearer.ok_or_else(unauthenticated)?;
+    let credential = Credential::Bearer(token.to_string());
+    match authenticator
+        .authenticate(&credential, &[], peer_addr)
+        .await
+    {
+        Ok(identity) => server.admit_identity(Some(identity)),
+        Err(e) => {
+            tracing::warn!(%peer_addr, error = %e, "Bridged request failed authentication");
+            server.misbehaved(peer_addr, Violation::AuthFailure);
+            Err(unauthenticated())
+        }
+    }
+}
+
+/// Rebuilds the tagged form typetag expects from the path and body.
+fn decode(type_name: &str, body: &[u8]) -> Result<Box<dyn Request>, ProtocolError> {
+    let fields: Value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(body).map_err(|e| {
+            ProtocolError::new(ErrorCode::Malformed, format!("malformed JSON body: {e}"))
+        })?
+    };
+    let tagged = Value::Object([(type_name.to_string(), fields)].into_iter().collect());
+    serde_json::from_value(tagged).map_err(|e| {
+        if e.to_string().starts_with("unknown variant") {
+            ProtocolError::new(
+                ErrorCode::Unsupported,
+                format!("unknown request type {type_name}"),
+            )
+        } else {
+            ProtocolError::new(
+                ErrorCode::Malformed,
+                format!("invalid {type_name} request: {e}"),
+            )
+        }
+    })
+}
+
+fn encode(response: &dyn crate::Response) -> Result<(String, Value), ProtocolError> {
+    let internal = |e: serde_json::Error| {
+        ProtocolError::new(
+            ErrorCode::Internal,
+            format!("failed to encode response: {e}"),
+        )
+    };
+    match serde_json::to_value(response).map_err(internal)? {
+        Value::Object(tagged) if tagged.len() == 1 => {
+            Ok(tagged.into_iter().next().expect("checked length"))
+        }
+        _ => Err(ProtocolError::new(
+            ErrorCode::Internal,
+            "response did not encode as a tagged object",
+        )),
+    }
+}
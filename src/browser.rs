@@ -0,0 +1,211 @@
+//! The client in a browser, built for wasm32 with the `wasm` feature and
+//! without the default `cli` one.
+//!
+//! A browser can't open sockets, so the client reaches the server over a
+//! WebSocket it opens through `web_sys`, to a listener accepting WebSocket
+//! clients with `Binding::websocket`. Frames travel in binary messages just
+//! as they do with the native `websocket::connect`.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use anyhow::{Result, anyhow};
+use bytes::{Buf, Bytes, BytesMut};
+use js_sys::{ArrayBuffer, Uint8Array};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::closure::Closure;
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+use crate::{Client, ClientConfig};
+
+/// Connects to the WebSocket listener at `url`, e.g. `wss://example.com/myproto`.
+pub async fn connect(url: &str, config: ClientConfig) -> Result<Client> {
+    let socket = BrowserSocket::open(url).await?;
+    Client::with_config(socket, config).await
+}
+
+enum Event {
+    Opened,
+    Message(Bytes),
+    /// The browser says only that something went wrong; a `Closed` follows.
+    Failed,
+    Closed {
+        code: u16,
+        reason: String,
+    },
+}
+
+/// A browser WebSocket read and written as the byte stream a session runs over.
+///
+/// The `WebSocket` itself can't leave the browser's thread, so it lives in a
+/// task of its own there, and this holds only the channels to it.
+pub struct BrowserSocket {
+    events: mpsc::UnboundedReceiver<Event>,
+    /// Dropped on shutdown, which closes the WebSocket.
+    outgoing: Option<mpsc::UnboundedSender<Bytes>>,
+    /// What's left of the last message read.
+    read: Bytes,
+    /// Written since the last flush, sent as one message on the next.
+    write: BytesMut,
+    closed: bool,
+}
+
+impl BrowserSocket {
+    /// Opens a WebSocket to `url`, waiting until the browser has connected it.
+    pub async fn open(url: &str) -> Result<Self> {
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        wasm_bindgen_futures::spawn_local(run(url.to_string(), events_tx, outgoing_rx));
+        match events.recv().await {
+            Some(Event::Opened) => {}
+            Some(Event::Closed { code, reason }) => {
+                return Err(anyhow!(
+                    "failed to connect to {url}: closed with {code} {reason}"
+                ));
+            }
+            _ => return Err(anyhow!("failed to connect to {url}")),
+        }
+        Ok(Self {
+            events,
+            outgoing: Some(outgoing),
+            read: Bytes::new(),
+            write: BytesMut::new(),
+            closed: false,
+        })
+    }
+}
+
+/// Owns the WebSocket, passing its events on and sending what's written
+/// until the `BrowserSocket` is dropped or shut down.
+async fn run(
+    url: String,
+    events: mpsc::UnboundedSender<Event>,
+    mut outgoing: mpsc::UnboundedReceiver<Bytes>,
+) {
+    let ws = match WebSocket::new(&url) {
+        Ok(ws) => ws,
+        Err(e) => {
+            tracing::debug!(%url, error = ?e, "Failed to open WebSocket");
+            let _ = events.send(Event::Failed);
+            return;
+        }
+    };
+    ws.set_binary_type(BinaryType::Arraybuffer);
+
+    let on_open = {
+        let events = events.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            let _ = events.send(Event::Opened);
+        })
+    };
+    let on_message = {
+        let events = events.clone();
+        Closure::<dyn FnMut(MessageEvent)>::new(move |message: MessageEvent| {
+            // Text messages aren't part of the protocol, and end the session as the server's would.
+            let event = match message.data().dyn_into::<ArrayBuffer>() {
+                Ok(buffer) => Event::Message(Uint8Array::new(&buffer).to_vec().into()),
+                Err(_) => Event::Failed,
+            };
+            let _ = events.send(event);
+        })
+    };
+    let on_error = {
+        let events = events.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            let _ = events.send(Event::Failed);
+        })
+    };
+    let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |close: CloseEvent| {
+        let _ = events.send(Event::Closed {
+            code: close.code(),
+            reason: close.reason(),
+        });
+    });
+    ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+    while let Some(bytes) = outgoing.recv().await {
+        if ws.send_with_u8_array(&bytes).is_err() {
+            break;
+        }
+    }
+    let _ = ws.close();
+    // The handlers go before the closures they call are dropped.
+    ws.set_onopen(None);
+    ws.set_onmessage(None);
+    ws.set_onerror(None);
+    ws.set_onclose(None);
+}
+
+impl AsyncRead for BrowserSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read.is_empty() {
+                let n = self.read.len().min(buf.remaining());
+                buf.put_slice(&self.read[..n]);
+                self.read.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if self.closed {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.events.poll_recv(cx)) {
+                Some(Event::Message(bytes)) => self.read = bytes,
+                Some(Event::Opened) => {}
+                Some(Event::Failed) => {
+                    self.closed = true;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "WebSocket failed",
+                    )));
+                }
+                Some(Event::Closed { .. }) | None => self.closed = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for BrowserSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.outgoing.is_none() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        self.write.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let message = self.write.split().freeze();
+        let sent = match &self.outgoing {
+            Some(outgoing) => outgoing.send(message).is_ok(),
+            None => false,
+        };
+        if sent {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.outgoing = None;
+        Poll::Ready(Ok(()))
+    }
+}
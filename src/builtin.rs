@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::handshake::PROTOCOL_VERSION;
+use crate::rt::{SystemTime, UNIX_EPOCH};
+use crate::schema::Describe;
+use crate::{
+    Codec, Context, Priority, Request, Response, StreamingRequest, TypedRequest, UploadRequest,
+    registry,
+};
+
+/// A liveness probe that load balancers can send without knowing any application types.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = HealthCheckResponse)]
+pub struct HealthCheck;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Describe)]
+pub enum HealthStatus {
+    Serving,
+    /// The server is shutting down and will close this connection soon.
+    Draining,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct HealthCheckResponse {
+    pub status: HealthStatus,
+}
+
+#[typetag::serde]
+impl Response for HealthCheckResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for HealthCheck {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let status = if ctx.server().is_shutting_down() {
+            HealthStatus::Draining
+        } else {
+            HealthStatus::Serving
+        };
+        Ok(Box::new(HealthCheckResponse { status }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+}
+
+impl TypedRequest for HealthCheck {
+    type Response = HealthCheckResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = ServerInfoResponse)]
+pub struct ServerInfo;
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ServerInfoResponse {
+    /// The version of this library the server was built with.
+    pub version: String,
+    pub protocol_version: u16,
+    pub uptime: Duration,
+    pub active_connections: usize,
+    pub request_types: Vec<String>,
+}
+
+#[typetag::serde]
+impl Response for ServerInfoResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ServerInfo {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let server = ctx.server();
+        Ok(Box::new(ServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            uptime: server.uptime(),
+            active_connections: server.connection_count(),
+            request_types: names::<dyn Request>(),
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for ServerInfo {
+    type Response = ServerInfoResponse;
+}
+
+/// Asks for the server's clocks, for clients that need timestamps roughly
+/// in step with it; `Client::estimate_clock` does the arithmetic.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = ServerTimeResponse)]
+pub struct ServerTime;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+pub struct ServerTimeResponse {
+    /// Wall-clock time, in microseconds since the Unix epoch.
+    pub unix_time_us: u64,
+    /// Monotonic time since the server started, which never jumps when the
+    /// wall clock is set.
+    pub monotonic: Duration,
+}
+
+#[typetag::serde]
+impl Response for ServerTimeResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ServerTime {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        Ok(Box::new(ServerTimeResponse {
+            unix_time_us: unix_time_us(SystemTime::now()),
+            monotonic: ctx.server().uptime(),
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    // Queueing behind other calls would only add to the round trip.
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+}
+
+impl TypedRequest for ServerTime {
+    type Response = ServerTimeResponse;
+}
+
+pub(crate) fn unix_time_us(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// How far the server's wall clock is from this machine's, NTP-style: the
+/// server is assumed to have read its clock halfway through the round trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockEstimate {
+    /// Microseconds to add to local time to get the server's; negative if
+    /// the server is behind.
+    pub offset_us: i64,
+    /// The round trip of the sample the estimate came from. The offset is
+    /// off by at most half of it.
+    pub round_trip: Duration,
+}
+
+impl ClockEstimate {
+    /// What the server's clock reads at local time `local`.
+    pub fn server_time(&self, local: SystemTime) -> SystemTime {
+        let offset = Duration::from_micros(self.offset_us.unsigned_abs());
+        if self.offset_us >= 0 {
+            local + offset
+        } else {
+            local - offset
+        }
+    }
+
+    /// What the server's clock reads now.
+    pub fn server_now(&self) -> SystemTime {
+        self.server_time(SystemTime::now())
+    }
+}
+
+/// Asks the server which message types and codecs it understands.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = IntrospectResponse)]
+pub struct Introspect;
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct IntrospectResponse {
+    pub protocol_version: u16,
+    /// The codec this connection negotiated.
+    pub codec: Codec,
+    /// Every codec the server accepts.
+    pub codecs: Vec<Codec>,
+    pub request_types: Vec<String>,
+    pub streaming_request_types: Vec<String>,
+    pub upload_request_types: Vec<String>,
+    pub response_types: Vec<String>,
+}
+
+#[typetag::serde]
+impl Response for IntrospectResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Introspect {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        Ok(Box::new(IntrospectResponse {
+            protocol_version: PROTOCOL_VERSION,
+            codec: ctx.connection().codec(),
+            codecs: ctx.server().config().codecs.clone(),
+            request_types: names::<dyn Request>(),
+            streaming_request_types: names::<dyn StreamingRequest>(),
+            upload_request_types: names::<dyn UploadRequest>(),
+            response_types: names::<dyn Response>(),
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for Introspect {
+    type Response = IntrospectResponse;
+}
+
+fn names<T: ?Sized>() -> Vec<String>
+where
+    Box<T>: DeserializeOwned,
+{
+    registry::registered::<T>()
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::{Codec, Context, Middleware, Next, Request, Response};
+
+/// Entries held before the oldest are evicted, unless set with `ResponseCache::capacity`.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Answers repeated unary requests from memory, keyed by request type and a
+/// hash of the request's fields. Only requests with a TTL are cached: one
+/// from `Request::cache_ttl` or, overriding it, one given here for their
+/// type. Only successful responses are kept, and every caller shares them,
+/// so cache only requests whose answer doesn't depend on who's asking.
+///
+/// Registered with `ServerBuilder::middleware` like any other middleware,
+/// and sees only what the middleware before it lets through.
+pub struct ResponseCache {
+    ttls: HashMap<String, Option<Duration>>,
+    capacity: usize,
+    entries: Mutex<HashMap<(&'static str, [u8; 32]), Entry>>,
+}
+
+struct Entry {
+    response: Bytes,
+    expires: Instant,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            ttls: HashMap::new(),
+            capacity: DEFAULT_CAPACITY,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Caches responses to `request_type` for `ttl`, whatever its `cache_ttl` says.
+    pub fn ttl(mut self, request_type: impl Into<String>, ttl: Duration) -> Self {
+        self.ttls.insert(request_type.into(), Some(ttl));
+        self
+    }
+
+    /// Never caches `request_type`, even if its `cache_ttl` asks for it.
+    pub fn bypass(mut self, request_type: impl Into<String>) -> Self {
+        self.ttls.insert(request_type.into(), None);
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Drops every cached response.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn lookup(&self, key: &(&'static str, [u8; 32])) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.expires > Instant::now() {
+            return Some(entry.response.clone());
+        }
+        entries.remove(key);
+        None
+    }
+
+    fn insert(&self, key: (&'static str, [u8; 32]), response: Bytes, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires > now);
+            // Still full of live entries: make room by dropping the one closest to expiring.
+            if entries.len() >= self.capacity
+                && let Some(soonest) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| *key)
+            {
+                entries.remove(&soonest);
+            }
+        }
+        if self.capacity > 0 {
+            let expires = now + ttl;
+            entries.insert(key, Entry { response, expires });
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for ResponseCache {
+    async fn call(
+        &self,
+        req: &dyn Request,
+        ctx: &Context,
+        next: Next<'_>,
+    ) -> Result<Box<dyn Response>> {
+        let request_type = req.typetag_name();
+        let ttl = match self.ttls.get(request_type) {
+            Some(ttl) => *ttl,
+            None => req.cache_ttl(),
+        };
+        let Some(ttl) = ttl else {
+            return next.run(req, ctx).await;
+        };
+
+        // Bincode writes fields in a fixed order, so equal requests hash alike.
+        let key = (
+            request_type,
+            Sha256::digest(Codec::Bincode.encode(req)?).into(),
+        );
+        if let Some(cached) = self.lookup(&key) {
+            tracing::trace!(request_type, "Serving cached response");
+            return Codec::Bincode.decode(&cached);
+        }
+        let response = next.run(req, ctx).await?;
+        self.insert(key, Codec::Bincode.encode(&response)?.into(), ttl);
+        Ok(response)
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{ErrorCode, ProtocolError};
+
+/// Admits a client's calls onto its connection at most `max_in_flight` at a
+/// time, the rest waiting in the order they were made.
+pub(crate) struct CallQueue {
+    slots: Semaphore,
+    waiting: AtomicUsize,
+    max_waiting: Option<usize>,
+}
+
+impl CallQueue {
+    pub(crate) fn new(max_in_flight: usize, max_waiting: Option<usize>) -> Self {
+        Self {
+            slots: Semaphore::new(max_in_flight.max(1)),
+            waiting: AtomicUsize::new(0),
+            max_waiting,
+        }
+    }
+
+    /// Waits for a slot, or fails with `Busy` if `max_waiting` calls already are.
+    pub(crate) async fn admit(&self) -> Result<SemaphorePermit<'_>, ProtocolError> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Ok(permit);
+        }
+        let waiting = self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        if self.max_waiting.is_some_and(|max| waiting >= max) {
+            return Err(ProtocolError::new(
+                ErrorCode::Busy,
+                "too many calls are waiting for this connection",
+            ));
+        }
+        Ok(self
+            .slots
+            .acquire()
+            .await
+            .expect("the call queue's semaphore is never closed"))
+    }
+
+    /// Calls waiting for a slot.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts a call as waiting until it gets a slot, fails or is dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,97 @@
+//! Logical channels: independent conversations over one connection, e.g. a
+//! control channel beside a bulk one.
+//!
+//! Each channel has its own bounded queue of outgoing frames, and the task
+//! writing to the socket takes from the queues in turn, so a channel with a
+//! backlog delays another by at most a frame rather than the whole backlog.
+//! Channel 0 is the one every connection starts with.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::SelectAll;
+use futures::{FutureExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Where frames for each channel are queued, with queues made as channels
+/// are first used.
+pub(crate) struct Channels<T> {
+    capacity: usize,
+    senders: Mutex<HashMap<u32, mpsc::Sender<T>>>,
+    opened: mpsc::UnboundedSender<mpsc::Receiver<T>>,
+}
+
+impl<T: Send + 'static> Channels<T> {
+    /// Channels whose queues hold up to `capacity` frames each, and the
+    /// receiving end that takes from all of them.
+    pub(crate) fn new(capacity: usize) -> (Arc<Self>, Queues<T>) {
+        let (opened, added) = mpsc::unbounded_channel();
+        let channels = Arc::new(Self {
+            capacity,
+            senders: Mutex::default(),
+            opened,
+        });
+        let queues = Queues {
+            added,
+            queues: SelectAll::new(),
+        };
+        (channels, queues)
+    }
+
+    /// The queue for `channel`'s frames.
+    pub(crate) fn sender(&self, channel: u32) -> mpsc::Sender<T> {
+        let mut senders = self.senders.lock().unwrap();
+        senders
+            .entry(channel)
+            .or_insert_with(|| {
+                let (tx, rx) = mpsc::channel(self.capacity);
+                let _ = self.opened.send(rx);
+                tx
+            })
+            .clone()
+    }
+}
+
+impl<T> fmt::Debug for Channels<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channels")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The receiving end of every channel's queue.
+pub(crate) struct Queues<T> {
+    added: mpsc::UnboundedReceiver<mpsc::Receiver<T>>,
+    queues: SelectAll<ReceiverStream<T>>,
+}
+
+impl<T: Send + 'static> Queues<T> {
+    /// The next frame from whichever channel's turn it is; `None` once the
+    /// `Channels` and every sender taken from it are gone.
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        loop {
+            tokio::select! {
+                biased;
+                Some(queue) = self.added.recv() => self.queues.push(ReceiverStream::new(queue)),
+                item = self.queues.next(), if !self.queues.is_empty() => return item,
+                else => return None,
+            }
+        }
+    }
+
+    /// A frame if any channel has one queued right now.
+    pub(crate) fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        while let Ok(queue) = self.added.try_recv() {
+            self.queues.push(ReceiverStream::new(queue));
+        }
+        match self.queues.next().now_or_never() {
+            Some(Some(item)) => Ok(item),
+            Some(None) => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::client::Disconnected;
+use crate::rt::Instant;
+use crate::{ErrorCode, ProtocolError};
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed calls that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit fails calls before letting one probe through.
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through.
+    Closed,
+    /// The server is failing; calls fail straight away.
+    Open,
+    /// One probe call is finding out whether the server has recovered.
+    HalfOpen,
+}
+
+/// The error a call fails with, without being sent, while the circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "circuit open after repeated failures, next probe in {:?}",
+            self.retry_in
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Fails calls to a server locally once it keeps failing, instead of letting
+/// every caller wait out its own timeout.
+///
+/// Only failures that say something about the server count: lost connections,
+/// timeouts and `Busy`, `Overloaded` or `Internal` errors. A handler
+/// returning an error is the server working as intended.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match &*self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a call now would be let through, as a call or as a probe.
+    pub fn is_available(&self) -> bool {
+        match &*self.state.lock().unwrap() {
+            State::Closed { .. } => true,
+            State::Open { until } => Instant::now() >= *until,
+            State::HalfOpen => false,
+        }
+    }
+
+    /// Runs `call` unless the circuit is open, and records how it went.
+    pub async fn run<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        let mut probe = self.admit()?;
+        let result = call.await;
+        let failed = result.as_ref().err().is_some_and(is_failure);
+        probe.finish(failed);
+        result
+    }
+
+    fn admit(&self) -> Result<Probe<'_>, CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(Probe::new(self, false)),
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(CircuitOpen {
+                        retry_in: until - now,
+                    });
+                }
+                tracing::info!("Circuit half-open, probing");
+                *state = State::HalfOpen;
+                Ok(Probe::new(self, true))
+            }
+            State::HalfOpen => Err(CircuitOpen {
+                retry_in: Duration::ZERO,
+            }),
+        }
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, failed) {
+            (State::Closed { failures }, true) => {
+                *failures += 1;
+                if *failures >= self.config.failure_threshold {
+                    tracing::warn!(failures = *failures, "Circuit opened");
+                    *state = self.open();
+                }
+            }
+            (State::Closed { failures }, false) => *failures = 0,
+            (State::HalfOpen, true) => {
+                tracing::warn!("Probe failed, circuit opened again");
+                *state = self.open();
+            }
+            (State::HalfOpen, false) => {
+                tracing::info!("Probe succeeded, circuit closed");
+                *state = State::Closed { failures: 0 };
+            }
+            // Calls admitted before the circuit opened finishing late change nothing.
+            (State::Open { .. }, _) => {}
+        }
+    }
+
+    fn open(&self) -> State {
+        State::Open {
+            until: Instant::now() + self.config.open_for,
+        }
+    }
+}
+
+/// An admitted call; a probe dropped before it finishes, e.g. because the
+/// caller gave up on it, lets the next call probe instead.
+struct Probe<'a> {
+    breaker: &'a CircuitBreaker,
+    probing: bool,
+    finished: bool,
+}
+
+impl<'a> Probe<'a> {
+    fn new(breaker: &'a CircuitBreaker, probing: bool) -> Self {
+        Self {
+            breaker,
+            probing,
+            finished: false,
+        }
+    }
+
+    fn finish(&mut self, failed: bool) {
+        self.finished = true;
+        self.breaker.record(failed);
+    }
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if self.probing && !self.finished {
+            *self.breaker.state.lock().unwrap() = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+fn is_failure(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<ProtocolError>() {
+        return matches!(
+            error.code,
+            ErrorCode::Timeout | ErrorCode::Busy | ErrorCode::Overloaded | ErrorCode::Internal
+        );
+    }
+    error
+        .chain()
+        .any(|cause| cause.is::<Disconnected>() || cause.is::<std::io::Error>())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn breaker(open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_for,
+        })
+    }
+
+    async fn fail(breaker: &CircuitBreaker, code: ErrorCode) -> Result<()> {
+        breaker
+            .run(async { Err(ProtocolError::new(code, "failed").into()) })
+            .await
+    }
+
+    async fn succeed(breaker: &CircuitBreaker) -> Result<()> {
+        breaker.run(async { Ok(()) }).await
+    }
+
+    async fn open(breaker: &CircuitBreaker) {
+        for _ in 0..3 {
+            fail(breaker, ErrorCode::Internal).await.unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn consecutive_failures_open_the_circuit() {
+        let breaker = breaker(Duration::from_secs(60));
+        for _ in 0..2 {
+            fail(&breaker, ErrorCode::Timeout).await.unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        fail(&breaker, ErrorCode::Busy).await.unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.is_available());
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_failure_count() {
+        let breaker = breaker(Duration::from_secs(60));
+        for _ in 0..2 {
+            fail(&breaker, ErrorCode::Overloaded).await.unwrap_err();
+        }
+        succeed(&breaker).await.unwrap();
+        for _ in 0..2 {
+            fail(&breaker, ErrorCode::Overloaded).await.unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn handler_errors_and_other_failures_do_not_count() {
+        let breaker = breaker(Duration::from_secs(60));
+        for _ in 0..5 {
+            fail(&breaker, ErrorCode::Handler).await.unwrap_err();
+            fail(&breaker, ErrorCode::InvalidRequest).await.unwrap_err();
+            breaker
+                .run(async { Err::<(), _>(anyhow!("not from the server")) })
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn lost_connections_count() {
+        let breaker = breaker(Duration::from_secs(60));
+        for _ in 0..3 {
+            breaker
+                .run(async {
+                    Err::<(), _>(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+                })
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_calls_without_making_them() {
+        let breaker = breaker(Duration::from_secs(60));
+        open(&breaker).await;
+        let mut called = false;
+        let err = breaker
+            .run(async {
+                called = true;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert!(!called);
+        let err = err.downcast::<CircuitOpen>().unwrap();
+        assert!(err.retry_in > Duration::ZERO && err.retry_in <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn successful_probe_closes_the_circuit() {
+        let breaker = breaker(Duration::ZERO);
+        open(&breaker).await;
+        assert!(breaker.is_available());
+        breaker
+            .run(async {
+                // Only the probe goes through while it is out.
+                assert_eq!(breaker.state(), CircuitState::HalfOpen);
+                assert!(!breaker.is_available());
+                let err = succeed(&breaker).await.unwrap_err();
+                assert_eq!(
+                    err.downcast::<CircuitOpen>().unwrap().retry_in,
+                    Duration::ZERO
+                );
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn failed_probe_opens_the_circuit_again() {
+        let breaker = breaker(Duration::ZERO);
+        open(&breaker).await;
+        fail(&breaker, ErrorCode::Internal).await.unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn abandoned_probe_lets_the_next_call_probe() {
+        let breaker = breaker(Duration::ZERO);
+        open(&breaker).await;
+        {
+            let probe = std::pin::pin!(breaker.run(std::future::pending::<Result<()>>()));
+            assert!(futures::poll!(probe).is_pending());
+            assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.is_available());
+        succeed(&breaker).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
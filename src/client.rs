@@ -0,0 +1,1202 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{SinkExt, Stream, StreamExt};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream, UnboundedReceiverStream};
+use tokio_util::codec::Framed;
+
+use crate::auth::{self, Credentials};
+use crate::builtin::{self, ClockEstimate, ServerTime};
+use crate::call_queue::CallQueue;
+use crate::channels::{Channels, Queues};
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+use crate::codec::Codec;
+use crate::envelope::{
+    self, ClientMessage, GoAway, NotifyFrame, Progress, ProgressFrame, RequestFrame, ServerMessage,
+    StreamFrame, StreamItem, StreamRequestFrame, UploadFrame, UploadItem, UploadRequestFrame,
+    WindowUpdate,
+};
+use crate::frame::{Compression, Encoded, FrameCodec};
+use crate::handshake::{self, Features, Hello, PROTOCOL_VERSION};
+use crate::heartbeat::Heartbeat;
+use crate::hedge::HedgePolicy;
+use crate::interceptor::{CallReply, Interceptor, Next, OutgoingCall};
+use crate::protocol::{ClientProtocol, Inbound};
+use crate::relay::{Register, SendTo};
+use crate::resumption;
+use crate::retry::RetryPolicy;
+use crate::rt;
+use crate::signing::SigningKey;
+use crate::tcp::TcpOptions;
+use crate::trace;
+use crate::untyped::Untyped;
+use crate::{
+    ErrorCode, ProtocolError, Request, Response, ResponseResult, ResponseStream, StreamingRequest,
+    TypedRequest, UploadRequest,
+};
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Compression to offer the server, most preferred first; empty asks for none.
+    pub compression: Vec<Compression>,
+    pub max_frame_length: usize,
+    /// As `ServerConfig::max_message_length`, for messages in either direction.
+    pub max_message_length: usize,
+    /// Ask for a CRC32 on every frame, in both directions, to catch
+    /// corruption on unreliable links.
+    pub checksums: bool,
+    /// Have the server reply to calls in the order they were made, rather
+    /// than each as soon as it's ready.
+    pub ordered_replies: bool,
+    /// Payload codecs to offer the server, most preferred first.
+    pub codecs: Vec<Codec>,
+    /// How often to ping the server; `None` disables heartbeats.
+    pub heartbeat_interval: Option<Duration>,
+    /// The connection is considered dead after this long without any frame from the server.
+    pub heartbeat_timeout: Duration,
+    /// Presented to servers that require authentication.
+    pub credentials: Option<Credentials>,
+    /// A name to register on connecting, which other clients can send this
+    /// one requests under with `relay::SendTo`.
+    pub peer_name: Option<String>,
+    /// A token from an earlier connection's `Client::session`, to resume
+    /// that session on servers that keep them.
+    pub session: Option<String>,
+    /// How many pushed messages each `notifications()` stream may fall behind by.
+    pub notification_buffer: usize,
+    /// Bytes of each server stream the server may send ahead of what has
+    /// been read from it; `None` lets it send as fast as the connection
+    /// takes them, however far behind the reader is.
+    pub stream_window: Option<usize>,
+    /// Calls and batches awaiting the server at once; further calls wait
+    /// for one to be answered, in the order they were made. `None` sends
+    /// every call straight away. Streams and uploads aren't counted.
+    pub max_in_flight: Option<usize>,
+    /// Calls waiting under `max_in_flight` past which more fail at once
+    /// with `ErrorCode::Busy`; `None` lets any number wait.
+    pub max_queued: Option<usize>,
+    /// Decode every response as an [`Untyped`](crate::untyped::Untyped)
+    /// instead of its own type, for tools that don't link the server's types.
+    /// Connecting fails unless a self-describing codec is negotiated.
+    pub untyped: bool,
+    /// Retries idempotent calls that fail with one of the policy's errors.
+    /// `ReconnectingClient` and `Pool` apply it across connections, so they
+    /// retry calls whose connection dropped too.
+    pub retry: Option<RetryPolicy>,
+    /// Fails calls locally while the server keeps failing. `ReconnectingClient`
+    /// and `Pool` keep one breaker for all their connections.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Sends a second copy of a slow idempotent call to another connection
+    /// and takes whichever answers first. Only `Pool` and `Balancer` apply
+    /// it, a balancer sending the copy to another server when it can.
+    pub hedge: Option<HedgePolicy>,
+    /// Applied to the socket by `connect_with`; streams handed to
+    /// `with_config` are used as they are.
+    pub tcp: TcpOptions,
+    /// Have `connect_with` go through this proxy rather than straight to
+    /// the server; see [`proxy`](crate::proxy).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub proxy: Option<crate::Proxy>,
+    /// Encrypt the connection with a Noise handshake before anything else,
+    /// for servers built with `ServerBuilder::noise`.
+    #[cfg(feature = "noise")]
+    pub noise: Option<crate::noise::NoiseConfig>,
+    /// Seals calls of the types it picks keys for, end to end, for servers
+    /// built with `ServerBuilder::key_provider`; see [`sealed`](crate::sealed).
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Arc<dyn crate::sealed::KeyProvider>>,
+    /// Signs every call's requests, for servers running the
+    /// `VerifySignatures` middleware; see [`signing`](crate::signing).
+    /// Requests are signed before they're sealed.
+    pub signing: Option<SigningKey>,
+    /// Log every frame, as `Protocol::dump_frames` does. Defaults to whether
+    /// `MYPROTO_DUMP_FRAMES` is set.
+    pub dump_frames: bool,
+    /// Wrap every call, outermost first; add them with `interceptor`.
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            compression: vec![Compression::Zstd, Compression::Lz4],
+            max_frame_length: crate::frame::DEFAULT_MAX_FRAME_LENGTH,
+            max_message_length: crate::frame::DEFAULT_MAX_MESSAGE_LENGTH,
+            checksums: false,
+            ordered_replies: false,
+            codecs: vec![Codec::Bincode],
+            heartbeat_interval: Some(Duration::from_secs(15)),
+            heartbeat_timeout: Duration::from_secs(45),
+            notification_buffer: 256,
+            stream_window: Some(1024 * 1024),
+            max_in_flight: None,
+            max_queued: None,
+            credentials: None,
+            peer_name: None,
+            session: None,
+            untyped: false,
+            retry: None,
+            circuit_breaker: None,
+            hedge: None,
+            tcp: TcpOptions::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            proxy: None,
+            #[cfg(feature = "noise")]
+            noise: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            signing: None,
+            dump_frames: crate::protocol::dump_frames_from_env(),
+            interceptors: Vec::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Adds `interceptor` to the chain each call goes through, inside the
+    /// ones added before it.
+    pub fn interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+}
+
+pub const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+pub type Notifications = BoxStream<'static, Arc<dyn Response>>;
+
+/// The progress reports of a call made with `Client::call_with_progress`.
+pub type ProgressStream = BoxStream<'static, Progress>;
+
+pub(crate) type ProgressSender = mpsc::Sender<Progress>;
+
+/// Progress reports each `ProgressStream` holds before dropping new ones.
+const PROGRESS_BUFFER: usize = 16;
+
+/// The error a call fails with when the connection goes away before it is answered.
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection closed")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+type PendingReply = oneshot::Sender<Result<CallReply>>;
+
+type PushReceiver = broadcast::Receiver<Arc<dyn Response>>;
+
+/// Each stream item with the length of the frame it came in, which reading
+/// it frees up in the stream's window.
+type StreamSender = mpsc::UnboundedSender<(Result<Box<dyn Response>>, usize)>;
+
+enum Outgoing {
+    Call {
+        id: u64,
+        bytes: Encoded,
+        reply: PendingReply,
+        /// Where to forward the call's `Progress` reports, if anywhere.
+        progress: Option<ProgressSender>,
+    },
+    Stream {
+        id: u64,
+        bytes: Bytes,
+        items: StreamSender,
+    },
+    /// A frame that doesn't expect an answer of its own.
+    Frame(Encoded),
+    /// Stop waiting for `id` and tell the server to abandon it.
+    Cancel(u64),
+}
+
+/// Cancels request `id` on the server if dropped before `disarm`, e.g. when
+/// the caller's future is dropped or a local deadline fires.
+struct CancelGuard {
+    id: u64,
+    outgoing: Option<mpsc::Sender<Outgoing>>,
+}
+
+impl CancelGuard {
+    fn disarm(&mut self) {
+        self.outgoing = None;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(outgoing) = self.outgoing.take() {
+            // Drop can't wait; if the queue is full the reply is simply ignored.
+            let _ = outgoing.try_send(Outgoing::Cancel(self.id));
+        }
+    }
+}
+
+/// A server stream that cancels itself when dropped before it ends.
+struct CallStream {
+    items: UnboundedReceiverStream<(Result<Box<dyn Response>>, usize)>,
+    window: Option<StreamWindow>,
+    guard: CancelGuard,
+}
+
+/// Grants the server more of a stream as its items are read.
+struct StreamWindow {
+    size: u64,
+    /// Bytes read since the last grant.
+    read: u64,
+    credits: mpsc::UnboundedSender<WindowUpdate>,
+}
+
+impl Stream for CallStream {
+    type Item = Result<Box<dyn Response>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (item, len) = match Pin::new(&mut self.items).poll_next(cx) {
+            Poll::Ready(Some(item)) => item,
+            Poll::Ready(None) => {
+                self.guard.disarm();
+                return Poll::Ready(None);
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        let id = self.guard.id;
+        if let Some(window) = &mut self.window {
+            window.read += len as u64;
+            // Half a window at a time, so the server rarely runs dry.
+            if window.read >= window.size / 2 {
+                let bytes = std::mem::take(&mut window.read);
+                let _ = window.credits.send(WindowUpdate { id, bytes });
+            }
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+/// A connection to a server. Calls can be issued concurrently from clones;
+/// a background task owns the socket and answers heartbeats.
+#[derive(Clone)]
+pub struct Client {
+    outgoing: mpsc::Sender<Outgoing>,
+    next_id: Arc<AtomicU64>,
+    notifications: broadcast::Sender<Arc<dyn Response>>,
+    codec: Codec,
+    retry: Option<Arc<RetryPolicy>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    queue: Option<Arc<CallQueue>>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Arc<dyn crate::sealed::KeyProvider>>,
+    signing: Option<SigningKey>,
+    session: Option<Arc<str>>,
+    resumed: bool,
+    /// Subscribed before a resumed session's waiting pushes arrive, for the
+    /// first `notifications()` stream.
+    resumed_pushes: Arc<Mutex<Option<PushReceiver>>>,
+    go_away: Arc<Mutex<Option<GoAway>>>,
+    stream_window: Option<u64>,
+    credits: mpsc::UnboundedSender<WindowUpdate>,
+    interceptors: Arc<[Arc<dyn Interceptor>]>,
+    /// The logical channel this handle's calls go out on, with its own
+    /// queue in `channels`.
+    channel: u32,
+    channels: Arc<Channels<Outgoing>>,
+}
+
+impl Client {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect(addr: impl ToSocketAddrs + fmt::Display) -> Result<Self> {
+        Self::connect_with(addr, ClientConfig::default()).await
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect_with(
+        addr: impl ToSocketAddrs + fmt::Display,
+        config: ClientConfig,
+    ) -> Result<Self> {
+        let stream = match &config.proxy {
+            Some(proxy) => {
+                let addr = addr.to_string();
+                let (host, port) = crate::proxy::host_and_port(&addr)?;
+                proxy.connect(host, port).await?
+            }
+            None => TcpStream::connect(addr).await?,
+        };
+        config.tcp.apply(&stream)?;
+        Self::with_config(stream, config).await
+    }
+
+    /// In a browser, where `addr` is the URL of a WebSocket listener, as
+    /// for `browser::connect`.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn connect(addr: &str) -> Result<Self> {
+        Self::connect_with(addr, ClientConfig::default()).await
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub async fn connect_with(addr: &str, config: ClientConfig) -> Result<Self> {
+        crate::browser::connect(addr, config).await
+    }
+
+    /// Connects to the Windows named pipe `name`, such as `\\.\pipe\myproto`,
+    /// waiting while every instance of it is busy with another client.
+    #[cfg(windows)]
+    pub async fn connect_pipe(name: &str, config: ClientConfig) -> Result<Self> {
+        use tokio::net::windows::named_pipe::ClientOptions;
+        /// `ERROR_PIPE_BUSY`: the server has no instance free right now.
+        const PIPE_BUSY: i32 = 231;
+        let pipe = loop {
+            match ClientOptions::new().open(name) {
+                Ok(pipe) => break pipe,
+                Err(e) if e.raw_os_error() == Some(PIPE_BUSY) => {}
+                Err(e) => {
+                    return Err(
+                        anyhow::Error::new(e).context(format!("failed to connect to {name}"))
+                    );
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        Self::with_config(pipe, config).await
+    }
+
+    pub async fn new<S>(stream: S) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_config(stream, ClientConfig::default()).await
+    }
+
+    pub async fn with_config<S>(stream: S, config: ClientConfig) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        #[cfg(feature = "noise")]
+        if let Some(noise) = &config.noise {
+            let stream = rt::timeout(
+                crate::noise::HANDSHAKE_TIMEOUT,
+                crate::noise::connect(stream, noise),
+            )
+            .await
+            .map_err(|_| anyhow!("Noise handshake timed out"))??;
+            return Self::start(stream, config).await;
+        }
+        Self::start(stream, config).await
+    }
+
+    async fn start<S>(stream: S, config: ClientConfig) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut framed = Framed::new(
+            stream,
+            FrameCodec::with_max_frame_length(config.max_frame_length),
+        );
+        let ack = handshake::initiate(
+            &mut framed,
+            Hello {
+                version: PROTOCOL_VERSION,
+                codecs: config.codecs.clone(),
+                features: Features::empty()
+                    .with(
+                        Features::CHUNKING,
+                        config.max_message_length > config.max_frame_length,
+                    )
+                    .with(Features::CHECKSUMS, config.checksums)
+                    .with(Features::RESUMPTION, true)
+                    .with(Features::ORDERED_REPLIES, config.ordered_replies)
+                    | Features::GO_AWAY
+                    | Features::PROGRESS,
+                compression: config.compression.clone(),
+            },
+        )
+        .await?;
+        if ack.chunking() {
+            framed
+                .codec_mut()
+                .set_max_message_length(config.max_message_length);
+        }
+
+        if config.untyped && !ack.codec.is_self_describing() {
+            bail!(
+                "untyped clients need a self-describing codec, but {:?} was negotiated",
+                ack.codec
+            );
+        }
+
+        let resumed = if ack.resumption() {
+            Some(resumption::offer(&mut framed, config.session.clone()).await?)
+        } else {
+            None
+        };
+        let resumed_session = resumed.as_ref().is_some_and(|resumed| resumed.resumed);
+        if resumed_session {
+            tracing::debug!("Resumed session");
+        }
+
+        if let Some(challenge) = &ack.challenge
+            && !resumed_session
+        {
+            let Some(credentials) = &config.credentials else {
+                bail!("server requires authentication but no credentials are configured");
+            };
+            let identity = auth::present(&mut framed, credentials, challenge).await?;
+            tracing::debug!(subject = %identity.subject, "Authenticated");
+        }
+
+        let retry = config.retry.clone().map(Arc::new);
+        let breaker = config
+            .circuit_breaker
+            .clone()
+            .map(|config| Arc::new(CircuitBreaker::new(config)));
+        let queue = config
+            .max_in_flight
+            .map(|max| Arc::new(CallQueue::new(max, config.max_queued)));
+        let peer_name = config.peer_name.clone();
+        #[cfg(feature = "encryption")]
+        let encryption = config.encryption.clone();
+        let signing = config.signing.clone();
+        let interceptors = config.interceptors.clone().into();
+        let (channels, rx) = Channels::new(64);
+        let outgoing = channels.sender(0);
+        let (notifications, first) = broadcast::channel(config.notification_buffer);
+        let pushes = notifications.clone();
+        let resumed_pushes = Arc::new(Mutex::new(resumed_session.then_some(first)));
+        let framed = framed.map_codec(|frames| {
+            let protocol = ClientProtocol::new(frames, ack.codec).dump_frames(config.dump_frames);
+            if config.untyped {
+                protocol.untyped()
+            } else {
+                protocol
+            }
+        });
+        let go_away = Arc::new(Mutex::new(None));
+        let told = go_away.clone();
+        let (credits, granted) = mpsc::unbounded_channel();
+        let stream_window = config.stream_window.map(|window| window as u64);
+        rt::spawn(async move {
+            if let Err(e) = drive(framed, rx, granted, pushes, told, ack.codec, &config).await {
+                tracing::debug!(error = %e, "Client connection closed");
+            }
+        });
+
+        let client = Self {
+            outgoing,
+            next_id: Arc::new(AtomicU64::new(1)),
+            notifications,
+            codec: ack.codec,
+            retry,
+            breaker,
+            queue,
+            #[cfg(feature = "encryption")]
+            encryption,
+            signing,
+            session: resumed.map(|resumed| resumed.session.into()),
+            resumed: resumed_session,
+            resumed_pushes,
+            go_away,
+            stream_window,
+            credits,
+            interceptors,
+            channel: 0,
+            channels,
+        };
+        if let Some(name) = peer_name {
+            client.call(Box::new(Register { name })).await?;
+        }
+        Ok(client)
+    }
+
+    /// A handle to the same connection whose calls, streams, uploads and
+    /// notifications go out on logical channel `channel`, e.g. 1 for bulk
+    /// transfers beside control calls on 0. Each channel has its own queue
+    /// here and its own slots and reply order on the server, so a backlog
+    /// on one holds up another by no more than a frame at a time. Servers
+    /// refuse calls on channels past their `max_channels`.
+    pub fn channel(&self, channel: u32) -> Self {
+        Self {
+            outgoing: self.channels.sender(channel),
+            channel,
+            ..self.clone()
+        }
+    }
+
+    /// The token to resume this session with after the connection drops, if
+    /// the server keeps sessions for resumption. Pass it in `ClientConfig::session`.
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Whether connecting resumed the session named in `ClientConfig::session`,
+    /// subscriptions and identity included.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Why the server said it was closing the connection, once it has. A
+    /// connection that closes without one was lost rather than closed on purpose.
+    pub fn go_away(&self) -> Option<GoAway> {
+        self.go_away.lock().unwrap().clone()
+    }
+
+    /// Whether the connection has gone away; every call on a closed client fails.
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    /// Resolves once the connection has gone away.
+    pub async fn closed(&self) {
+        self.outgoing.closed().await
+    }
+
+    /// Calls waiting for a slot under `ClientConfig::max_in_flight`.
+    pub fn queued_calls(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.waiting())
+    }
+
+    /// Messages pushed by the server from now on. Each call returns an
+    /// independent stream; a subscriber that falls more than
+    /// `ClientConfig::notification_buffer` messages behind skips ahead. On a
+    /// resumed session, the first stream also has the pushes that were
+    /// waiting for it.
+    pub fn notifications(&self) -> Notifications {
+        let receiver = self.resumed_pushes.lock().unwrap().take();
+        BroadcastStream::new(receiver.unwrap_or_else(|| self.notifications.subscribe()))
+            .filter_map(|item| async move {
+                match item {
+                    Ok(message) => Some(message),
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "Notification subscriber lagged");
+                        None
+                    }
+                }
+            })
+            .boxed()
+    }
+
+    /// Sends several requests in one frame; the results come back in the same order.
+    pub async fn call_batch(&self, requests: Vec<Box<dyn Request>>) -> Result<Vec<ResponseResult>> {
+        self.exchange(requests, None, false, None, None).await
+    }
+
+    /// Like `call_batch`, but the server runs each request only after the
+    /// previous one has finished, for batches whose requests depend on each other.
+    pub async fn call_batch_sequential(
+        &self,
+        requests: Vec<Box<dyn Request>>,
+    ) -> Result<Vec<ResponseResult>> {
+        self.exchange(requests, None, true, None, None).await
+    }
+
+    /// Like `call_batch`, but gives up after `timeout` and tells the server
+    /// not to bother starting work once the deadline has passed.
+    pub async fn call_batch_with_deadline(
+        &self,
+        requests: Vec<Box<dyn Request>>,
+        timeout: Duration,
+    ) -> Result<Vec<ResponseResult>> {
+        let deadline = envelope::deadline_after(timeout);
+        rt::timeout(
+            timeout,
+            self.exchange(requests, Some(deadline), false, None, None),
+        )
+        .await
+        .map_err(|_| ProtocolError::new(ErrorCode::Timeout, "call deadline exceeded"))?
+    }
+
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.retry {
+            // A dropped connection stays dropped, so only the server's errors are retried here.
+            Some(retry) => {
+                retry
+                    .run(request, false, |request| self.call_once(request, None))
+                    .await
+            }
+            None => self.call_once(request, None).await,
+        }
+    }
+
+    /// Like `call`, but sends `key`, which should be unique to the operation
+    /// (a UUID, say), so the server runs it at most once: repeats with the
+    /// same key within its `idempotency_ttl` get the first call's results.
+    /// The `RetryPolicy` retries these calls even if the request isn't `idempotent`.
+    pub async fn call_with_idempotency_key(
+        &self,
+        request: Box<dyn Request>,
+        key: impl Into<String>,
+    ) -> Result<Box<dyn Response>> {
+        let key = key.into();
+        match &self.retry {
+            Some(retry) => {
+                retry
+                    .run_any(request, false, |request| {
+                        self.call_once(request, Some(&key))
+                    })
+                    .await
+            }
+            None => self.call_once(request, Some(&key)).await,
+        }
+    }
+
+    async fn call_once(
+        &self,
+        request: Box<dyn Request>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Box<dyn Response>> {
+        let call = async {
+            let key = idempotency_key.map(str::to_string);
+            single(self.exchange(vec![request], None, false, key, None).await?)
+        };
+        match &self.breaker {
+            Some(breaker) => breaker.run(call).await,
+            None => call.await,
+        }
+    }
+
+    pub async fn call_with_deadline(
+        &self,
+        request: Box<dyn Request>,
+        timeout: Duration,
+    ) -> Result<Box<dyn Response>> {
+        let results = self
+            .call_batch_with_deadline(vec![request], timeout)
+            .await?;
+        single(results)
+    }
+
+    /// Makes `request` as `call` does, without retries, alongside a stream
+    /// of the `Progress` its handler reports until the response arrives.
+    /// Reports the stream isn't keeping up with are dropped, and servers
+    /// that can't send them leave it empty.
+    pub fn call_with_progress(
+        &self,
+        request: Box<dyn Request>,
+    ) -> (
+        ProgressStream,
+        BoxFuture<'static, Result<Box<dyn Response>>>,
+    ) {
+        let (tx, rx) = mpsc::channel(PROGRESS_BUFFER);
+        let client = self.clone();
+        let response = async move {
+            let call = client.exchange(vec![request], None, false, None, Some(tx));
+            let call = async { single(call.await?) };
+            match &client.breaker {
+                Some(breaker) => breaker.run(call).await,
+                None => call.await,
+            }
+        };
+        (ReceiverStream::new(rx).boxed(), Box::pin(response))
+    }
+
+    /// Makes `request` as `call` does, without retries, with `metadata` in
+    /// its envelope for the server to read with `Context::metadata`, and
+    /// returns the metadata its reply came back with alongside the response.
+    pub async fn call_with_metadata(
+        &self,
+        request: Box<dyn Request>,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<(Box<dyn Response>, BTreeMap<String, String>)> {
+        let call = async {
+            let call = OutgoingCall {
+                requests: vec![request],
+                deadline: None,
+                trace: trace::current(),
+                sequential: false,
+                idempotency_key: None,
+                metadata,
+            };
+            let reply = Next::new(&self.interceptors, self, None).run(call).await?;
+            Ok((single(reply.results)?, reply.metadata))
+        };
+        match &self.breaker {
+            Some(breaker) => breaker.run(call).await,
+            None => call.await,
+        }
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response::<R::Response>(response)
+    }
+
+    /// Estimates how far the server's clock is from this machine's from
+    /// `samples` `ServerTime` calls, keeping the one with the shortest
+    /// round trip as the least disturbed.
+    pub async fn estimate_clock(&self, samples: usize) -> Result<ClockEstimate> {
+        let mut best: Option<ClockEstimate> = None;
+        for _ in 0..samples.max(1) {
+            let sent = rt::SystemTime::now();
+            let started = rt::Instant::now();
+            let time = self.call_typed(ServerTime).await?;
+            let round_trip = started.elapsed();
+            let midpoint = builtin::unix_time_us(sent + round_trip / 2);
+            let estimate = ClockEstimate {
+                offset_us: time.unix_time_us as i64 - midpoint as i64,
+                round_trip,
+            };
+            if best.is_none_or(|best| estimate.round_trip < best.round_trip) {
+                best = Some(estimate);
+            }
+        }
+        Ok(best.expect("at least one sample is taken"))
+    }
+
+    /// Sends `request` to the client registered as `peer`, through the server.
+    pub async fn send_to<R: TypedRequest>(
+        &self,
+        peer: impl Into<String>,
+        request: R,
+    ) -> Result<R::Response> {
+        let response = self
+            .call(Box::new(SendTo {
+                peer: peer.into(),
+                request: Box::new(request),
+            }))
+            .await?;
+        downcast_response::<R::Response>(response)
+    }
+
+    /// Calls the request type named `type_name` with `fields`, for clients
+    /// built with `ClientConfig::untyped`.
+    pub async fn call_untyped(&self, type_name: &str, fields: Value) -> Result<Untyped> {
+        let response = self.call(Box::new(Untyped::new(type_name, fields))).await?;
+        downcast_response(response)
+    }
+
+    async fn exchange(
+        &self,
+        requests: Vec<Box<dyn Request>>,
+        deadline: Option<u64>,
+        sequential: bool,
+        idempotency_key: Option<String>,
+        progress: Option<ProgressSender>,
+    ) -> Result<Vec<ResponseResult>> {
+        let call = OutgoingCall {
+            requests,
+            deadline,
+            trace: trace::current(),
+            sequential,
+            idempotency_key,
+            metadata: BTreeMap::new(),
+        };
+        let reply = Next::new(&self.interceptors, self, progress.as_ref())
+            .run(call)
+            .await?;
+        Ok(reply.results)
+    }
+
+    /// Sends `call` as it came out of the interceptors.
+    pub(crate) async fn send_call(
+        &self,
+        call: OutgoingCall,
+        progress: Option<ProgressSender>,
+    ) -> Result<CallReply> {
+        let OutgoingCall {
+            requests,
+            deadline,
+            trace,
+            sequential,
+            idempotency_key,
+            metadata,
+        } = call;
+        let _slot = match &self.queue {
+            Some(queue) => Some(queue.admit().await?),
+            None => None,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let priority = requests.iter().map(|request| request.priority()).max();
+        let requests = self.sign_and_seal(requests)?;
+        #[cfg(feature = "encryption")]
+        let sealed: Vec<_> = requests
+            .iter()
+            .map(|request| crate::sealed::sealed_with(&**request))
+            .collect();
+        let message = ClientMessage::Call(RequestFrame {
+            id,
+            deadline,
+            trace,
+            sequential,
+            priority: priority.unwrap_or_default(),
+            idempotency_key,
+            metadata,
+            channel: self.channel,
+            requests,
+        });
+        let bytes = Encoded {
+            bytes: self.codec.encode_bytes(&message)?,
+            compressible: message.compressible(),
+        };
+
+        let (reply, response) = oneshot::channel();
+        self.outgoing
+            .send(Outgoing::Call {
+                id,
+                bytes,
+                reply,
+                progress,
+            })
+            .await
+            .map_err(|_| Disconnected)?;
+        let mut guard = self.cancel_guard(id);
+        let reply = response.await.map_err(|_| Disconnected)?;
+        guard.disarm();
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &self.encryption {
+            let CallReply { results, metadata } = reply?;
+            return Ok(CallReply {
+                results: results
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, result)| {
+                        let sealed = sealed.get(i).and_then(Option::as_ref);
+                        crate::sealed::open_result(&**keys, sealed, result)
+                    })
+                    .collect(),
+                metadata,
+            });
+        }
+        reply
+    }
+
+    /// Sends `requests` for the server to handle without answering, for
+    /// high-volume messages like telemetry that nobody waits on. Returns
+    /// once they are queued to be written; nothing says whether, or how,
+    /// the server handled them, and the client's interceptors, retries and
+    /// `max_in_flight` don't apply.
+    pub async fn notify_batch(&self, requests: Vec<Box<dyn Request>>) -> Result<()> {
+        let message = ClientMessage::Notify(NotifyFrame {
+            trace: trace::current(),
+            channel: self.channel,
+            requests: self.sign_and_seal(requests)?,
+        });
+        let bytes = Encoded {
+            bytes: self.codec.encode_bytes(&message)?,
+            compressible: message.compressible(),
+        };
+        self.outgoing
+            .send(Outgoing::Frame(bytes))
+            .await
+            .map_err(|_| Disconnected.into())
+    }
+
+    pub async fn notify(&self, request: Box<dyn Request>) -> Result<()> {
+        self.notify_batch(vec![request]).await
+    }
+
+    /// Signs and then seals `requests`, as the client is configured to.
+    fn sign_and_seal(&self, requests: Vec<Box<dyn Request>>) -> Result<Vec<Box<dyn Request>>> {
+        let requests = match &self.signing {
+            Some(key) => requests
+                .into_iter()
+                .map(|request| Ok(Box::new(key.sign(&*request)?) as Box<dyn Request>))
+                .collect::<Result<_>>()?,
+            None => requests,
+        };
+        #[cfg(feature = "encryption")]
+        let requests = match &self.encryption {
+            Some(keys) => requests
+                .into_iter()
+                .map(|request| crate::sealed::seal_request(&**keys, request))
+                .collect::<Result<_>>()?,
+            None => requests,
+        };
+        Ok(requests)
+    }
+
+    fn cancel_guard(&self, id: u64) -> CancelGuard {
+        CancelGuard {
+            id,
+            outgoing: Some(self.outgoing.clone()),
+        }
+    }
+
+    /// Streams `body` to an upload handler and waits for its response. Chunks
+    /// larger than `UPLOAD_CHUNK_SIZE` are split across frames.
+    pub async fn upload<B>(
+        &self,
+        request: Box<dyn UploadRequest>,
+        body: B,
+    ) -> Result<Box<dyn Response>>
+    where
+        B: Stream<Item = Bytes> + Send,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = ClientMessage::OpenUpload(UploadRequestFrame {
+            id,
+            trace: trace::current(),
+            request,
+            channel: self.channel,
+        });
+        let bytes = self.codec.encode_bytes(&message)?.into();
+
+        let (reply, response) = oneshot::channel();
+        self.outgoing
+            .send(Outgoing::Call {
+                id,
+                bytes,
+                reply,
+                progress: None,
+            })
+            .await
+            .map_err(|_| Disconnected)?;
+        let mut guard = self.cancel_guard(id);
+        let response = async {
+            let reply = response.await.map_err(|_| Disconnected)??;
+            Ok::<_, anyhow::Error>(reply.results)
+        };
+        tokio::pin!(response);
+
+        let send_body = async {
+            tokio::pin!(body);
+            while let Some(mut chunk) = body.next().await {
+                while !chunk.is_empty() {
+                    let data = chunk.split_to(chunk.len().min(UPLOAD_CHUNK_SIZE));
+                    self.send_upload_item(id, UploadItem::Data(data)).await?;
+                }
+            }
+            self.send_upload_item(id, UploadItem::End).await
+        };
+
+        // The server may answer before the body is finished, e.g. to reject it.
+        tokio::select! {
+            results = &mut response => {
+                guard.disarm();
+                return single(results?);
+            }
+            sent = send_body => sent?,
+        }
+        let results = response.await;
+        guard.disarm();
+        single(results?)
+    }
+
+    async fn send_upload_item(&self, id: u64, item: UploadItem) -> Result<()> {
+        let message = ClientMessage::UploadChunk(UploadFrame { id, item });
+        let bytes = self.codec.encode_bytes(&message)?;
+        self.outgoing
+            .send(Outgoing::Frame(bytes.into()))
+            .await
+            .map_err(|_| Disconnected.into())
+    }
+
+    /// Opens a server stream; it ends after the server's `End` frame, or
+    /// with an error item if the handler or the connection fails.
+    pub async fn call_stream(&self, request: Box<dyn StreamingRequest>) -> Result<ResponseStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = ClientMessage::OpenStream(StreamRequestFrame {
+            id,
+            trace: trace::current(),
+            request,
+            window: self.stream_window,
+            channel: self.channel,
+        });
+        let bytes = self.codec.encode_bytes(&message)?;
+
+        let (items, rx) = mpsc::unbounded_channel();
+        self.outgoing
+            .send(Outgoing::Stream { id, bytes, items })
+            .await
+            .map_err(|_| Disconnected)?;
+        Ok(CallStream {
+            items: UnboundedReceiverStream::new(rx),
+            window: self.stream_window.map(|size| StreamWindow {
+                size,
+                read: 0,
+                credits: self.credits.clone(),
+            }),
+            guard: self.cancel_guard(id),
+        }
+        .boxed())
+    }
+}
+
+async fn drive<S>(
+    mut framed: Framed<S, ClientProtocol>,
+    mut outgoing: Queues<Outgoing>,
+    mut credits: mpsc::UnboundedReceiver<WindowUpdate>,
+    notifications: broadcast::Sender<Arc<dyn Response>>,
+    go_away: Arc<Mutex<Option<GoAway>>>,
+    codec: Codec,
+    config: &ClientConfig,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pending: BTreeMap<u64, PendingReply> = BTreeMap::new();
+    let mut streams: HashMap<u64, StreamSender> = HashMap::new();
+    let mut progress: HashMap<u64, ProgressSender> = HashMap::new();
+    let mut heartbeat = Heartbeat::new(config.heartbeat_interval, config.heartbeat_timeout);
+
+    let result = async {
+        loop {
+            tokio::select! {
+                call = outgoing.recv() => {
+                    let closing = go_away.lock().unwrap().clone();
+                    let bytes = match call {
+                        // The server doesn't read anything sent after its GoAway.
+                        Some(Outgoing::Call { reply, .. }) if let Some(closing) = &closing => {
+                            let _ = reply.send(Err(anyhow!("server is closing the connection: {closing}")));
+                            continue;
+                        }
+                        Some(Outgoing::Stream { items, .. }) if let Some(closing) = &closing => {
+                            let _ = items.send((Err(anyhow!("server is closing the connection: {closing}")), 0));
+                            continue;
+                        }
+                        Some(Outgoing::Call { id, bytes, reply, progress: watcher }) => {
+                            pending.insert(id, reply);
+                            if let Some(watcher) = watcher {
+                                progress.insert(id, watcher);
+                            }
+                            bytes
+                        }
+                        Some(Outgoing::Stream { id, bytes, items }) => {
+                            streams.insert(id, items);
+                            bytes.into()
+                        }
+                        Some(Outgoing::Frame(bytes)) => bytes,
+                        Some(Outgoing::Cancel(id)) => {
+                            pending.remove(&id);
+                            streams.remove(&id);
+                            progress.remove(&id);
+                            codec.encode_bytes(&ClientMessage::Cancel(id))?.into()
+                        }
+                        None => return Ok(()),
+                    };
+                    framed.send(bytes).await?;
+                }
+
+                Some(update) = credits.recv() => {
+                    // A stream that has ended has no use for more.
+                    if streams.contains_key(&update.id) {
+                        let update = codec.encode_bytes(&ClientMessage::WindowUpdate(update))?;
+                        framed.send(update).await?;
+                    }
+                }
+
+                frame = framed.next() => {
+                    let Some(frame) = frame else {
+                        match &*go_away.lock().unwrap() {
+                            Some(closing) => bail!("server closed the connection: {closing}"),
+                            None => bail!("server closed the connection"),
+                        }
+                    };
+                    heartbeat.saw_frame();
+                    let (message, len) = match frame? {
+                        Inbound::Message(message, payload) => (message, payload.len()),
+                        Inbound::Oversized(len) | Inbound::PayloadTooLarge { len, .. } => {
+                            tracing::warn!(len, "Dropped oversized frame from server");
+                            continue;
+                        }
+                        Inbound::Corrupt(len) => {
+                            tracing::warn!(len, "Dropped frame from server that failed its checksum");
+                            continue;
+                        }
+                        Inbound::Malformed { error, .. } => return Err(error),
+                    };
+                    match message {
+                        ServerMessage::Reply(reply) => {
+                            // Replies without an id answer a frame the server couldn't
+                            // parse; frames are handled in order, so that is the oldest.
+                            let waiter = match reply.id {
+                                Some(id) => pending.remove_entry(&id),
+                                None => pending.pop_first(),
+                            };
+                            let waiter = waiter.map(|(id, waiter)| {
+                                progress.remove(&id);
+                                waiter
+                            });
+                            if let Some(waiter) = waiter {
+                                let _ = waiter.send(Ok(CallReply {
+                                    results: reply.results,
+                                    metadata: reply.metadata,
+                                }));
+                            }
+                        }
+                        ServerMessage::Stream(StreamFrame { id, item }) => match item {
+                            StreamItem::Data(resp) => {
+                                if let Some(items) = streams.get(&id) {
+                                    let _ = items.send((Ok(resp), len));
+                                }
+                            }
+                            StreamItem::End => {
+                                streams.remove(&id);
+                            }
+                            StreamItem::Error(err) => {
+                                if let Some(items) = streams.remove(&id) {
+                                    let _ = items.send((Err(err.into()), 0));
+                                }
+                            }
+                        },
+                        ServerMessage::Push(message) => {
+                            // No subscribers just means nobody is listening yet.
+                            let _ = notifications.send(Arc::from(message));
+                        }
+                        ServerMessage::Ping(seq) => {
+                            let pong = codec.encode_bytes(&ClientMessage::Pong(seq))?;
+                            framed.send(pong).await?;
+                        }
+                        ServerMessage::Pong(_) => {}
+                        ServerMessage::GoAway(closing) => {
+                            tracing::debug!(%closing, "Server is closing the connection");
+                            *go_away.lock().unwrap() = Some(closing);
+                        }
+                        ServerMessage::Progress(ProgressFrame { id, progress: report }) => {
+                            if let Some(watcher) = progress.get(&id) {
+                                let _ = watcher.try_send(report);
+                            }
+                        }
+                    }
+                }
+
+                seq = heartbeat.tick() => {
+                    let Some(seq) = seq else {
+                        bail!("server stopped responding to heartbeats");
+                    };
+                    let ping = codec.encode_bytes(&ClientMessage::Ping(seq))?;
+                    framed.send(ping).await?;
+                }
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = &result {
+        for (_, waiter) in pending {
+            let _ = waiter.send(Err(anyhow!("{e}")));
+        }
+        for (_, items) in streams {
+            let _ = items.send((Err(anyhow!("{e}")), 0));
+        }
+    }
+    result
+}
+
+fn single(mut results: Vec<ResponseResult>) -> Result<Box<dyn Response>> {
+    if results.len() != 1 {
+        bail!("expected 1 response, got {}", results.len());
+    }
+    Ok(results.remove(0)?)
+}
+
+pub(crate) fn downcast_response<T: Response>(response: Box<dyn Response>) -> Result<T> {
+    let description = format!("{response:?}");
+    let any: Box<dyn std::any::Any> = response;
+    any.downcast::<T>()
+        .map(|response| *response)
+        .map_err(|_| anyhow!("unexpected response type: {description}"))
+}
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::client::downcast_response;
+use crate::hedge::Hedger;
+use crate::{Client, ClientConfig, Request, Response, RetryPolicy, TypedRequest};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections the pool opens at most, and so calls it runs at once.
+    pub size: usize,
+    /// Idle connections unused for this long are closed rather than reused.
+    pub max_idle: Option<Duration>,
+    /// Connections older than this are closed rather than reused, so load
+    /// spreads again after servers behind one address come and go.
+    pub max_lifetime: Option<Duration>,
+    pub client: ClientConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 8,
+            max_idle: Some(Duration::from_secs(90)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            client: ClientConfig::default(),
+        }
+    }
+}
+
+/// Up to `PoolConfig::size` connections to one server, each checked out by
+/// one caller at a time.
+///
+/// A single `Client` already multiplexes concurrent calls, but funnels them
+/// all through one socket and one background task; a pool spreads them out.
+/// Connections are opened on demand, and ones that have closed or outlived
+/// the pool's policy are replaced on the next checkout.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    addr: String,
+    config: PoolConfig,
+    /// Taken out of the client config, so a retry or hedge can check out
+    /// another connection and one breaker covers them all.
+    retry: Option<RetryPolicy>,
+    breaker: Option<CircuitBreaker>,
+    hedge: Option<Hedger>,
+    checkouts: Arc<Semaphore>,
+    idle: Mutex<VecDeque<Idle>>,
+}
+
+struct Idle {
+    client: Client,
+    opened: Instant,
+    returned: Instant,
+}
+
+impl Pool {
+    pub fn new(addr: impl Into<String>, mut config: PoolConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                addr: addr.into(),
+                checkouts: Arc::new(Semaphore::new(config.size)),
+                retry: config.client.retry.take(),
+                breaker: config
+                    .client
+                    .circuit_breaker
+                    .take()
+                    .map(CircuitBreaker::new),
+                hedge: config.client.hedge.take().map(Hedger::new),
+                config,
+                idle: Mutex::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    /// Waits for a free slot and hands out an idle connection, or a new one
+    /// if none is fit for reuse.
+    pub async fn get(&self) -> Result<Pooled> {
+        let permit = self
+            .inner
+            .checkouts
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+
+        let reusable = loop {
+            let Some(idle) = self.inner.idle.lock().unwrap().pop_front() else {
+                break None;
+            };
+            if idle.client.is_closed() {
+                tracing::debug!("Discarding closed pooled connection");
+            } else if self.inner.expired(idle.opened, idle.returned) {
+                tracing::debug!("Discarding expired pooled connection");
+            } else {
+                break Some((idle.client, idle.opened));
+            }
+        };
+        let (client, opened) = match reusable {
+            Some(reused) => reused,
+            None => {
+                let client =
+                    Client::connect_with(&self.inner.addr, self.inner.config.client.clone())
+                        .await?;
+                (client, Instant::now())
+            }
+        };
+
+        Ok(Pooled {
+            client: Some(client),
+            opened,
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.inner.retry {
+            Some(retry) => {
+                retry
+                    .run(request, true, |request| self.call_hedged(request))
+                    .await
+            }
+            None => self.call_hedged(request).await,
+        }
+    }
+
+    async fn call_hedged(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.inner.hedge {
+            Some(hedge) => hedge.run(request, |request| self.call_once(request)).await,
+            None => self.call_once(request).await,
+        }
+    }
+
+    async fn call_once(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        let call = async { self.get().await?.call(request).await };
+        match &self.inner.breaker {
+            Some(breaker) => breaker.run(call).await,
+            None => call.await,
+        }
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response(response)
+    }
+
+    /// Connections currently sitting idle in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+impl Inner {
+    fn expired(&self, opened: Instant, returned: Instant) -> bool {
+        let now = Instant::now();
+        self.config
+            .max_lifetime
+            .is_some_and(|max| now.duration_since(opened) >= max)
+            || self
+                .config
+                .max_idle
+                .is_some_and(|max| now.duration_since(returned) >= max)
+    }
+}
+
+/// A connection checked out of a [`Pool`], returned to it when dropped.
+pub struct Pooled {
+    client: Option<Client>,
+    opened: Instant,
+    pool: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pooled {
+    /// Keeps the connection from going back to the pool, e.g. after the
+    /// server misbehaved on it.
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for Pooled {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("only taken on drop or discard")
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        let now = Instant::now();
+        if client.is_closed() || self.pool.expired(self.opened, now) {
+            return;
+        }
+        // Most recently used first, so connections beyond what the load needs go idle and expire.
+        self.pool.idle.lock().unwrap().push_front(Idle {
+            client,
+            opened: self.opened,
+            returned: now,
+        });
+    }
+}
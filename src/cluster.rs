@@ -0,0 +1,597 @@
+//! Several servers acting as one: each node dials every peer in a static
+//! list, and together they share which node every relay peer name is
+//! connected to and pass on what is published.
+//!
+//! Nodes talk over ordinary client connections, introducing themselves
+//! with a [`ClusterJoin`] that carries the cluster's shared secret. A
+//! message published on one node is published on every node it can reach,
+//! and a `SendTo` for a peer connected elsewhere is forwarded to that
+//! node. Links that drop are redialed with backoff. Connection ids are
+//! each node's own, so `Server::push_to` only reaches clients connected to
+//! the node it's called on and fails for any other id; reach a client
+//! elsewhere by its relay name instead.
+//!
+//! Requests with a `Request::shard_key` are handled by the node owning the
+//! key on a consistent-hash ring of this node and the peers it can reach,
+//! so each key moves only when the node owning it joins or leaves. A
+//! request forwarded this way reaches its handler with the context of the
+//! link it came over, not of the client that sent it. With an
+//! `Authenticator`, `ClusterConfig::client` needs credentials the peers
+//! accept, and an `Authorization` policy must let them make the cluster's
+//! requests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::auth::constant_time_eq;
+use crate::middleware;
+use crate::reconnect::Backoff;
+use crate::relay::{relay_local, unavailable};
+use crate::schema::Describe;
+use crate::signing::Signer;
+use crate::{
+    Client, ClientConfig, Context, ErrorCode, ProtocolError, Request, Response, Server,
+    TypedRequest,
+};
+
+/// This node's name, its peers' addresses and the secret they share.
+#[derive(Clone)]
+pub struct ClusterConfig {
+    pub node: String,
+    pub secret: String,
+    pub peers: Vec<String>,
+    /// How this node connects to its peers.
+    pub client: ClientConfig,
+    pub backoff: Backoff,
+}
+
+impl ClusterConfig {
+    pub fn new(node: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            node: node.into(),
+            secret: secret.into(),
+            peers: Vec::new(),
+            client: ClientConfig::default(),
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Adds the node listening at `addr` to the cluster.
+    pub fn peer(mut self, addr: impl Into<String>) -> Self {
+        self.peers.push(addr.into());
+        self
+    }
+
+    pub fn client(mut self, client: ClientConfig) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+/// The secret is left out.
+impl std::fmt::Debug for ClusterConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClusterConfig")
+            .field("node", &self.node)
+            .field("peers", &self.peers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Points each node claims on the ring, so keys spread evenly between them.
+const POINTS_PER_NODE: u32 = 64;
+
+/// A consistent-hash ring of node names.
+struct Ring {
+    /// Each point's hash and the node it belongs to, sorted by hash.
+    points: Vec<(u64, String)>,
+}
+
+impl Ring {
+    fn new<'a>(nodes: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut points: Vec<_> = nodes
+            .into_iter()
+            .flat_map(|node| {
+                (0..POINTS_PER_NODE)
+                    .map(move |i| (hash(format!("{node}#{i}").as_bytes()), node.to_string()))
+            })
+            .collect();
+        points.sort();
+        Self { points }
+    }
+
+    /// The node owning `key`: the first point at or after its hash, wrapping around.
+    fn owner(&self, key: &str) -> Option<&str> {
+        let hash = hash(key.as_bytes());
+        let i = self.points.partition_point(|(point, _)| *point < hash);
+        self.points
+            .get(i)
+            .or_else(|| self.points.first())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+/// 64-bit FNV-1a with MurmurHash3's finalizer, so keys differing only in
+/// their last bytes still land far apart; every node computes the same hash
+/// whatever it was built with.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ hash >> 33
+}
+
+/// What a node knows about the rest of the cluster.
+pub(crate) struct Cluster {
+    config: ClusterConfig,
+    /// Connections to each reachable peer, by node name.
+    links: Mutex<HashMap<String, Client>>,
+    /// This node and the reachable peers, rebuilt as links come and go.
+    ring: Mutex<Ring>,
+    /// Peers' connections to this node, by connection id, with their node names.
+    joined: Mutex<HashMap<u64, String>>,
+    /// Which node each relay peer name elsewhere in the cluster is connected to.
+    remote_names: Mutex<HashMap<String, String>>,
+    /// Peer names registered or dropped here, for peers to hear about in order.
+    announcements: mpsc::UnboundedSender<(String, bool)>,
+    pending_announcements: Mutex<Option<mpsc::UnboundedReceiver<(String, bool)>>>,
+}
+
+impl Cluster {
+    pub(crate) fn new(config: ClusterConfig) -> Self {
+        let (announcements, pending) = mpsc::unbounded_channel();
+        Self {
+            ring: Mutex::new(Ring::new([config.node.as_str()])),
+            config,
+            links: Mutex::default(),
+            joined: Mutex::default(),
+            remote_names: Mutex::default(),
+            announcements,
+            pending_announcements: Mutex::new(Some(pending)),
+        }
+    }
+
+    /// Dials every peer and starts telling them about local peer names.
+    pub(crate) fn start(self: &Arc<Self>, server: &Server) -> Vec<JoinHandle<()>> {
+        let mut tasks: Vec<_> = self
+            .config
+            .peers
+            .iter()
+            .map(|addr| tokio::spawn(self.clone().link(server.clone(), addr.clone())))
+            .collect();
+        if let Some(announcements) = self.pending_announcements.lock().unwrap().take() {
+            tasks.push(tokio::spawn(self.clone().announce_all(announcements)));
+        }
+        tasks
+    }
+
+    async fn link(self: Arc<Self>, server: Server, addr: String) {
+        let mut attempt = 0;
+        loop {
+            match self.join(&server, &addr).await {
+                Ok((node, client)) => {
+                    tracing::info!(%addr, node, "Joined cluster peer");
+                    attempt = 0;
+                    self.links
+                        .lock()
+                        .unwrap()
+                        .insert(node.clone(), client.clone());
+                    self.rebuild_ring();
+                    client.closed().await;
+                    self.links.lock().unwrap().remove(&node);
+                    self.rebuild_ring();
+                    tracing::warn!(%addr, node, "Lost cluster peer, redialing");
+                }
+                Err(e) => {
+                    tracing::debug!(%addr, attempt, error = format!("{e:#}"), "Failed to join cluster peer");
+                }
+            }
+            tokio::time::sleep(self.config.backoff.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    fn rebuild_ring(&self) {
+        let links = self.links.lock().unwrap();
+        let nodes = links.keys().map(String::as_str);
+        *self.ring.lock().unwrap() = Ring::new(nodes.chain([self.config.node.as_str()]));
+    }
+
+    async fn join(&self, server: &Server, addr: &str) -> Result<(String, Client)> {
+        let client = Client::connect_with(addr, self.config.client.clone()).await?;
+        let joined = client
+            .call_typed(ClusterJoin {
+                node: self.config.node.clone(),
+                secret: self.config.secret.clone(),
+                names: server.peers.names(),
+            })
+            .await?;
+        Ok((joined.node, client))
+    }
+
+    async fn announce_all(
+        self: Arc<Self>,
+        mut announcements: mpsc::UnboundedReceiver<(String, bool)>,
+    ) {
+        while let Some((name, present)) = announcements.recv().await {
+            let links: Vec<Client> = self.links.lock().unwrap().values().cloned().collect();
+            join_all(links.iter().map(|link| {
+                link.call_typed(ClusterAnnounce {
+                    name: name.clone(),
+                    present,
+                })
+            }))
+            .await;
+        }
+    }
+
+    /// Lets the other nodes know the relay peer `name` connected here, or left.
+    pub(crate) fn announce(&self, name: &str, present: bool) {
+        let _ = self.announcements.send((name.to_string(), present));
+    }
+
+    /// Forgets a peer node's connection to this one, and the names it brought.
+    pub(crate) fn remove_connection(&self, connection_id: u64) {
+        let Some(node) = self.joined.lock().unwrap().remove(&connection_id) else {
+            return;
+        };
+        self.remote_names
+            .lock()
+            .unwrap()
+            .retain(|_, holder| *holder != node);
+    }
+
+    /// The node that sent a request over `ctx`, failing unless it joined.
+    fn node_of(&self, ctx: &Context) -> Result<String, ProtocolError> {
+        self.joined
+            .lock()
+            .unwrap()
+            .get(&ctx.connection_id)
+            .cloned()
+            .ok_or_else(|| {
+                ProtocolError::new(ErrorCode::PermissionDenied, "not a joined cluster node")
+            })
+    }
+
+    /// Publishes `message`, in bincode, on every reachable peer, returning
+    /// how many connections it was queued for there.
+    pub(crate) async fn publish(&self, topic: &str, message: &[u8]) -> Result<usize> {
+        let links: Vec<(String, Client)> = self
+            .links
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(node, link)| (node.clone(), link.clone()))
+            .collect();
+        let mut calls = Vec::with_capacity(links.len());
+        for (node, link) in &links {
+            let publish = ClusterPublish {
+                topic: topic.to_string(),
+                message: bincode::deserialize(message)?,
+            };
+            calls.push(async move { (node, link.call_typed(publish).await) });
+        }
+
+        let mut delivered = 0;
+        for (node, result) in join_all(calls).await {
+            match result {
+                Ok(response) => delivered += response.delivered,
+                Err(e) => {
+                    tracing::debug!(node, error = format!("{e:#}"), "Failed to forward publish")
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// The peer owning `key`, or `None` if this node does.
+    pub(crate) fn owner(&self, key: &str) -> Option<String> {
+        let ring = self.ring.lock().unwrap();
+        ring.owner(key)
+            .filter(|node| *node != self.config.node)
+            .map(str::to_string)
+    }
+
+    /// Has the peer `node` handle `request`.
+    pub(crate) async fn forward(
+        &self,
+        node: &str,
+        request: &dyn Request,
+        signer: Option<&Signer>,
+    ) -> Result<Box<dyn Response>> {
+        let Some(link) = self.links.lock().unwrap().get(node).cloned() else {
+            // The link dropped since the ring was read; the next try goes to the new owner.
+            return Err(ProtocolError::new(
+                ErrorCode::Busy,
+                format!("cluster node {node} is unreachable"),
+            )
+            .into());
+        };
+        // Trait objects can't be cloned, so the request is passed on through its wire form.
+        let request: Box<dyn Request> = bincode::deserialize(&bincode::serialize(request)?)?;
+        link.call(Box::new(ClusterForward {
+            request,
+            signer: signer.cloned(),
+        }))
+        .await
+    }
+
+    /// Forwards a relayed request to the node `peer` is connected to.
+    pub(crate) async fn relay(
+        &self,
+        peer: &str,
+        from: Option<String>,
+        identity: Option<String>,
+        request: Box<dyn Request>,
+    ) -> Result<Box<dyn Response>> {
+        let Some(node) = self.remote_names.lock().unwrap().get(peer).cloned() else {
+            return Err(unavailable(peer, "is not connected"));
+        };
+        let Some(link) = self.links.lock().unwrap().get(&node).cloned() else {
+            return Err(unavailable(peer, &format!("is on unreachable node {node}")));
+        };
+        link.call(Box::new(ClusterRelay {
+            peer: peer.to_string(),
+            from,
+            identity,
+            request,
+        }))
+        .await
+    }
+}
+
+fn cluster(ctx: &Context) -> Result<&Arc<Cluster>, ProtocolError> {
+    ctx.server()
+        .cluster
+        .as_ref()
+        .ok_or_else(|| ProtocolError::new(ErrorCode::Unsupported, "this server is not clustered"))
+}
+
+/// Introduces a node to a peer, with the relay names connected to it.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = ClusterJoinResponse)]
+pub struct ClusterJoin {
+    pub node: String,
+    pub secret: String,
+    pub names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ClusterJoinResponse {
+    pub node: String,
+}
+
+#[typetag::serde]
+impl Response for ClusterJoinResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ClusterJoin {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let cluster = cluster(ctx)?;
+        if !constant_time_eq(self.secret.as_bytes(), cluster.config.secret.as_bytes()) {
+            tracing::warn!(node = self.node, peer_addr = %ctx.peer_addr, "Cluster join with the wrong secret");
+            return Err(
+                ProtocolError::new(ErrorCode::PermissionDenied, "wrong cluster secret").into(),
+            );
+        }
+        cluster
+            .joined
+            .lock()
+            .unwrap()
+            .insert(ctx.connection_id, self.node.clone());
+        let mut remote_names = cluster.remote_names.lock().unwrap();
+        remote_names.retain(|_, holder| *holder != self.node);
+        for name in &self.names {
+            remote_names.insert(name.clone(), self.node.clone());
+        }
+        Ok(Box::new(ClusterJoinResponse {
+            node: cluster.config.node.clone(),
+        }))
+    }
+}
+
+impl TypedRequest for ClusterJoin {
+    type Response = ClusterJoinResponse;
+}
+
+/// Tells a peer the relay name `name` connected to the sending node, or left it.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = ClusterAnnounceResponse)]
+pub struct ClusterAnnounce {
+    pub name: String,
+    pub present: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ClusterAnnounceResponse;
+
+#[typetag::serde]
+impl Response for ClusterAnnounceResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ClusterAnnounce {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let cluster = cluster(ctx)?;
+        let node = cluster.node_of(ctx)?;
+        let mut remote_names = cluster.remote_names.lock().unwrap();
+        if self.present {
+            remote_names.insert(self.name.clone(), node);
+        } else if remote_names.get(&self.name) == Some(&node) {
+            remote_names.remove(&self.name);
+        }
+        Ok(Box::new(ClusterAnnounceResponse))
+    }
+}
+
+impl TypedRequest for ClusterAnnounce {
+    type Response = ClusterAnnounceResponse;
+}
+
+/// A message published on another node, to publish to this one's subscribers.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ClusterPublish {
+    pub topic: String,
+    pub message: Box<dyn Response>,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ClusterPublish {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        cluster(ctx)?.node_of(ctx)?;
+        let message: Box<dyn Response> = bincode::deserialize(&bincode::serialize(&self.message)?)?;
+        let delivered = ctx.topics().publish_local(&self.topic, message).await?;
+        Ok(Box::new(crate::pubsub::PublishResponse { delivered }))
+    }
+}
+
+impl TypedRequest for ClusterPublish {
+    type Response = crate::pubsub::PublishResponse;
+}
+
+/// A `SendTo` for a peer connected to this node, forwarded by another.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ClusterRelay {
+    pub peer: String,
+    pub from: Option<String>,
+    pub identity: Option<String>,
+    pub request: Box<dyn Request>,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ClusterRelay {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        cluster(ctx)?.node_of(ctx)?;
+        let request: Box<dyn Request> = bincode::deserialize(&bincode::serialize(&self.request)?)?;
+        relay_local(
+            ctx,
+            &self.peer,
+            self.from.clone(),
+            self.identity.clone(),
+            request,
+        )
+        .await
+    }
+}
+
+/// A sharded request sent on by the node it arrived at, for this one to
+/// handle as the owner of its key.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ClusterForward {
+    pub request: Box<dyn Request>,
+    /// Who signed the request, as the forwarding node verified it.
+    #[serde(default)]
+    pub signer: Option<Signer>,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ClusterForward {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        cluster(ctx)?.node_of(ctx)?;
+        // Handled here whoever this node thinks owns the key, so nodes whose
+        // rings disagree don't pass a request back and forth.
+        match &self.signer {
+            Some(signer) => {
+                let ctx = ctx.with_signer(Arc::new(signer.clone()));
+                middleware::dispatch(&*self.request, &ctx).await
+            }
+            None => middleware::dispatch(&*self.request, ctx).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..1000).map(|i| format!("key-{i}"))
+    }
+
+    #[test]
+    fn hash_is_the_same_on_every_build() {
+        // Nodes must agree on owners, so the hash may never change.
+        assert_eq!(hash(b""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(hash(b"node-a#0"), 0xac32_1eca_c879_4ee8);
+    }
+
+    #[test]
+    fn empty_ring_has_no_owner() {
+        assert_eq!(Ring::new([]).owner("key"), None);
+    }
+
+    #[test]
+    fn owner_is_stable_whatever_order_nodes_are_given_in() {
+        let ring = Ring::new(["a", "b", "c"]);
+        let reordered = Ring::new(["c", "a", "b"]);
+        for key in keys() {
+            assert_eq!(ring.owner(&key), reordered.owner(&key), "{key}");
+        }
+    }
+
+    #[test]
+    fn every_node_owns_some_keys() {
+        let ring = Ring::new(["a", "b", "c"]);
+        for node in ["a", "b", "c"] {
+            assert!(keys().any(|key| ring.owner(&key) == Some(node)), "{node}");
+        }
+    }
+
+    #[test]
+    fn key_past_the_last_point_wraps_around_to_the_first() {
+        let ring = Ring::new(["a", "b", "c"]);
+        let (last, _) = ring.points.last().unwrap();
+        let key = (0..)
+            .map(|i| format!("key-{i}"))
+            .find(|key| hash(key.as_bytes()) > *last)
+            .expect("some key hashes past the last point");
+        assert_eq!(ring.owner(&key), Some(ring.points[0].1.as_str()));
+    }
+
+    #[test]
+    fn key_on_a_point_belongs_to_that_point() {
+        let ring = Ring::new(["a", "b", "c"]);
+        let key = "b#7";
+        assert_eq!(ring.owner(key), Some("b"));
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = Ring::new(["a", "b", "c"]);
+        let after = Ring::new(["a", "b", "c", "d"]);
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.owner(&key), after.owner(&key));
+            if old != new {
+                assert_eq!(new, Some("d"), "{key} moved from {old:?}");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = Ring::new(["a", "b", "c"]);
+        let after = Ring::new(["a", "c"]);
+        for key in keys() {
+            if before.owner(&key) != Some("b") {
+                assert_eq!(before.owner(&key), after.owner(&key), "{key}");
+            }
+        }
+    }
+}
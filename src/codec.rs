@@ -0,0 +1,111 @@
+use anyhow::Result;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::Describe;
+use crate::{pool, type_ids};
+
+/// How request and response payloads are serialized, negotiated per connection.
+///
+/// The handshake itself is always bincode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Describe)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Codec {
+    Bincode,
+    Json,
+    MessagePack,
+    /// Bincode, with types registered through `type_ids!` tagged by number
+    /// rather than by name.
+    CompactBincode,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [
+        Codec::Bincode,
+        Codec::Json,
+        Codec::MessagePack,
+        Codec::CompactBincode,
+    ];
+
+    /// Whether payloads carry their own field names and types, so they can be
+    /// read without the Rust types that wrote them.
+    pub fn is_self_describing(self) -> bool {
+        matches!(self, Codec::Json | Codec::MessagePack)
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Codec::Bincode => bincode::serialize(value)?,
+            Codec::Json => serde_json::to_vec(value)?,
+            // Named fields keep the payload readable from dynamically typed clients.
+            Codec::MessagePack => rmp_serde::to_vec_named(value)?,
+            Codec::CompactBincode => type_ids::compact(|| bincode::serialize(value))?,
+        })
+    }
+
+    /// Like [`Codec::encode`], but writes into a pooled buffer instead of a
+    /// fresh `Vec` per message.
+    pub fn encode_bytes<T: Serialize + ?Sized>(self, value: &T) -> Result<Bytes> {
+        Ok(match self {
+            Codec::Bincode => pool::encode(|buf| bincode::serialize_into(buf, value))?,
+            Codec::Json => pool::encode(|buf| serde_json::to_writer(buf, value))?,
+            Codec::MessagePack => pool::encode(|buf| rmp_serde::encode::write_named(buf, value))?,
+            Codec::CompactBincode => {
+                type_ids::compact(|| pool::encode(|buf| bincode::serialize_into(buf, value)))?
+            }
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            Codec::Bincode => bincode::deserialize(bytes)?,
+            Codec::Json => serde_json::from_slice(bytes)?,
+            Codec::MessagePack => rmp_serde::from_slice(bytes)?,
+            Codec::CompactBincode => type_ids::compact(|| bincode::deserialize(bytes))?,
+        })
+    }
+
+    /// About how far into `bytes` decoding a `T` gets before it fails, for
+    /// error reports; `None` if it doesn't fail. Decodes again, so only worth
+    /// calling once decoding has already failed.
+    pub(crate) fn error_offset<T: DeserializeOwned>(self, bytes: &[u8]) -> Option<usize> {
+        let mut reader = Counting { bytes, read: 0 };
+        let failed = match self {
+            Codec::Bincode => bincode::deserialize_from::<_, T>(&mut reader).is_err(),
+            Codec::Json => {
+                let error = serde_json::from_slice::<T>(bytes).err()?;
+                return Some(json_offset(bytes, error.line(), error.column()));
+            }
+            Codec::MessagePack => rmp_serde::from_read::<_, T>(&mut reader).is_err(),
+            Codec::CompactBincode => {
+                type_ids::compact(|| bincode::deserialize_from::<_, T>(&mut reader)).is_err()
+            }
+        };
+        failed.then_some(reader.read)
+    }
+}
+
+/// Reads from a slice, keeping count of how much has been read.
+struct Counting<'a> {
+    bytes: &'a [u8],
+    read: usize,
+}
+
+impl std::io::Read for Counting<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.bytes.read(buf)?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// The byte offset of serde_json's one-based `line` and `column`.
+fn json_offset(bytes: &[u8], line: usize, column: usize) -> usize {
+    let line_start = bytes
+        .split_inclusive(|&byte| byte == b'\n')
+        .take(line.saturating_sub(1))
+        .map(<[u8]>::len)
+        .sum::<usize>();
+    (line_start + column.saturating_sub(1)).min(bytes.len())
+}
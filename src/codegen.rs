@@ -0,0 +1,758 @@
+//! Python and TypeScript clients generated from a [`Schema`], so services
+//! in other languages can call a server without keeping their own copies of
+//! its message layouts.
+//!
+//! The generated clients speak JSON to the server's HTTP gateway (the
+//! `gateway` feature) rather than the binary protocol, one method per unary
+//! request; streaming and upload requests aren't reachable that way and are
+//! left out. Each message becomes a Python dataclass or a TypeScript type
+//! with the fields serde writes as JSON. Types the schema names without
+//! describing come through as untyped JSON, as do fields holding any request
+//! or response, which keep typetag's `{"Type": {..fields}}` form.
+//!
+//! `myproto-cli codegen <python|typescript> <SCHEMA>` generates a client from a
+//! schema dumped with `myproto --schema`, and a build script can call
+//! [`generate`] on one it builds with `Schema::registered`.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::schema::{Field, Schema, Shape, TypeRef, TypeSchema, Variant};
+use crate::versioning;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Python,
+    TypeScript,
+}
+
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "python" | "py" => Ok(Language::Python),
+            "typescript" | "ts" => Ok(Language::TypeScript),
+            _ => bail!("unknown language {s:?}, expected python or typescript"),
+        }
+    }
+}
+
+/// The source of a client for `schema` in `language`.
+pub fn generate(schema: &Schema, language: Language) -> String {
+    let types = Types::new(schema);
+    match language {
+        Language::Python => python::generate(&types),
+        Language::TypeScript => typescript::generate(&types),
+    }
+}
+
+/// The schema's described types, in the order they are generated.
+struct Types<'a> {
+    schema: &'a Schema,
+    described: Vec<&'a TypeSchema>,
+    names: BTreeSet<&'a str>,
+}
+
+impl<'a> Types<'a> {
+    fn new(schema: &'a Schema) -> Self {
+        let described: Vec<&TypeSchema> = [
+            &schema.types,
+            &schema.requests,
+            &schema.responses,
+            &schema.streaming_requests,
+            &schema.upload_requests,
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|ty| ty.shape.is_some())
+        .collect();
+        let names = described
+            .iter()
+            .map(|ty| versioning::base_name(&ty.name))
+            .collect();
+        Self {
+            schema,
+            described,
+            names,
+        }
+    }
+
+    fn is_described(&self, name: &str) -> bool {
+        self.names.contains(versioning::base_name(name))
+    }
+
+    /// The response `request` declares, if it is described.
+    fn response_of<'b>(&self, request: &'b TypeSchema) -> Option<&'b str> {
+        request
+            .response
+            .as_deref()
+            .filter(|name| self.is_described(name))
+    }
+}
+
+fn header(comment: &str, schema: &Schema) -> String {
+    format!(
+        "{comment} Generated by myproto from a schema of protocol version {}; do not edit.\n",
+        schema.protocol_version
+    )
+}
+
+fn is_unit_enum(variants: &[Variant]) -> bool {
+    variants
+        .iter()
+        .all(|variant| matches!(variant.shape, Shape::Unit))
+}
+
+/// The identifier a request or message tag is generated as:
+/// `billing.CreateInvoice@2` as `BillingCreateInvoice`.
+fn type_name(tag: &str) -> String {
+    versioning::base_name(tag)
+        .split('.')
+        .map(|segment| {
+            let mut chars = segment.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// `HealthCheck` as `health_check`.
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.char_indices() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// `HealthCheck` as `healthCheck`.
+fn camel_case(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+mod python {
+    use super::*;
+
+    const KEYWORDS: &[&str] = &[
+        "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+        "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+        "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return",
+        "try", "while", "with", "yield",
+    ];
+
+    const PRELUDE: &str = r#"from __future__ import annotations
+
+import enum
+import json
+import urllib.error
+import urllib.request
+from dataclasses import dataclass
+from datetime import timedelta
+from typing import Any, Dict, List, Optional, Tuple
+
+
+class MyprotoError(Exception):
+    """A request the server answered with an error."""
+
+    def __init__(self, code: str, message: str, details: Optional[str] = None):
+        super().__init__(f"{code}: {message}")
+        self.code = code
+        self.message = message
+        self.details = details
+
+
+def _same(value: Any) -> Any:
+    return value
+
+
+def _to_json(value: Any) -> Any:
+    return value.to_json()
+
+
+def _opt(f):
+    return lambda value: None if value is None else f(value)
+
+
+def _list(f):
+    return lambda value: [f(item) for item in value]
+
+
+def _map(f):
+    return lambda value: {key: f(item) for key, item in value.items()}
+
+
+def _tuple_to(*fs):
+    return lambda value: [f(item) for f, item in zip(fs, value)]
+
+
+def _tuple_from(*fs):
+    return lambda value: tuple(f(item) for f, item in zip(fs, value))
+
+
+def _result(ok, err):
+    return lambda value: {key: (ok if key == "Ok" else err)(item) for key, item in value.items()}
+
+
+def _drop_unset(value: Dict[str, Any], optional: Tuple[str, ...]) -> Dict[str, Any]:
+    return {key: item for key, item in value.items() if item is not None or key not in optional}
+
+
+def _bytes_to(value: bytes) -> Any:
+    return list(value)
+
+
+def _bytes_from(value: Any) -> bytes:
+    return bytes(value)
+
+
+def _duration_to(value: timedelta) -> Any:
+    return {"secs": value.days * 86400 + value.seconds, "nanos": value.microseconds * 1000}
+
+
+def _duration_from(value: Any) -> timedelta:
+    return timedelta(seconds=value["secs"], microseconds=value["nanos"] // 1000)
+"#;
+
+    const CLIENT: &str = r#"
+
+class Client:
+    """Calls a server through its HTTP gateway, e.g. `Client("http://127.0.0.1:8080")`."""
+
+    def __init__(self, base_url: str, token: Optional[str] = None, timeout: float = 30.0):
+        self.base_url = base_url.rstrip("/")
+        self.token = token
+        self.timeout = timeout
+
+    def _call(self, type_name: str, body: Any) -> Any:
+        headers = {"Content-Type": "application/json"}
+        if self.token is not None:
+            headers["Authorization"] = f"Bearer {self.token}"
+        request = urllib.request.Request(
+            f"{self.base_url}/rpc/{type_name}",
+            data=json.dumps(body).encode(),
+            headers=headers,
+            method="POST",
+        )
+        try:
+            with urllib.request.urlopen(request, timeout=self.timeout) as response:
+                return json.load(response)
+        except urllib.error.HTTPError as e:
+            error = json.load(e)
+            raise MyprotoError(error["code"], error["message"], error.get("details")) from None
+"#;
+
+    pub(super) fn generate(types: &Types) -> String {
+        let mut out = header("#", types.schema);
+        out.push('\n');
+        out.push_str(PRELUDE);
+        for ty in &types.described {
+            let shape = ty
+                .shape
+                .as_ref()
+                .expect("only described types are generated");
+            type_def(&mut out, types, ty, shape);
+        }
+        out.push_str(CLIENT);
+        for request in &types.schema.requests {
+            method(&mut out, types, request);
+        }
+        out
+    }
+
+    fn type_def(out: &mut String, types: &Types, ty: &TypeSchema, shape: &Shape) {
+        let name = type_name(&ty.name);
+        match shape {
+            Shape::Enum(variants) if is_unit_enum(variants) => {
+                let _ = writeln!(out, "\n\nclass {name}(str, enum.Enum):");
+                docstring(out, "    ", ty.docs.as_deref());
+                for variant in variants {
+                    let _ = writeln!(out, "    {} = {:?}", variant.name, variant.name);
+                }
+                let _ = write!(
+                    out,
+                    "\n    def to_json(self) -> Any:\n        return self.value\n\n    \
+                     @classmethod\n    def from_json(cls, data: Any) -> {name}:\n        return cls(data)\n"
+                );
+            }
+            Shape::Enum(variants) => {
+                let _ = writeln!(out, "\n\nclass {name}:");
+                docstring(out, "    ", ty.docs.as_deref());
+                let _ = write!(
+                    out,
+                    "    _variants: Dict[str, Any] = {{}}\n\n    \
+                     def to_json(self) -> Any:\n        raise NotImplementedError\n\n    \
+                     @staticmethod\n    def from_json(data: Any) -> {name}:\n        \
+                     tag, body = (data, None) if isinstance(data, str) else next(iter(data.items()))\n        \
+                     return {name}._variants[tag]._from_body(body)\n"
+                );
+                for variant in variants {
+                    let class = format!("{name}{}", variant.name);
+                    let _ = writeln!(out, "\n\n@dataclass\nclass {class}({name}):");
+                    docstring(out, "    ", variant.docs.as_deref());
+                    let (fields, to_body, from_body) = body(types, &variant.shape);
+                    out.push_str(&fields);
+                    let to_json = match &variant.shape {
+                        Shape::Unit => format!("{:?}", variant.name),
+                        _ => format!("{{{:?}: {to_body}}}", variant.name),
+                    };
+                    let _ = write!(
+                        out,
+                        "\n    def to_json(self) -> Any:\n        return {to_json}\n\n    \
+                         @classmethod\n    def _from_body(cls, body: Any) -> {class}:\n        return {from_body}\n"
+                    );
+                }
+                let variants: Vec<String> = variants
+                    .iter()
+                    .map(|variant| format!("{:?}: {name}{}", variant.name, variant.name))
+                    .collect();
+                let _ = writeln!(out, "\n\n{name}._variants = {{{}}}", variants.join(", "));
+            }
+            shape => {
+                let _ = writeln!(out, "\n\n@dataclass\nclass {name}:");
+                docstring(out, "    ", ty.docs.as_deref());
+                let (fields, to_json, from_json) = body(types, shape);
+                out.push_str(&fields);
+                let from_json = from_json.replace("body", "data");
+                let _ = write!(
+                    out,
+                    "\n    def to_json(self) -> Any:\n        return {to_json}\n\n    \
+                     @classmethod\n    def from_json(cls, data: Any) -> {name}:\n        return {from_json}\n"
+                );
+            }
+        }
+    }
+
+    /// A struct's or variant's field declarations, with expressions writing
+    /// them as JSON and reading them back from `body`.
+    fn body(types: &Types, shape: &Shape) -> (String, String, String) {
+        let mut fields = String::new();
+        match shape {
+            Shape::Unit | Shape::Enum(_) => ("    pass\n".into(), "None".into(), "cls()".into()),
+            Shape::Struct(struct_fields) => {
+                let mut to = Vec::new();
+                let mut from = Vec::new();
+                // Fields the server has a default for are left out when `None`.
+                let mut unset = Vec::new();
+                for Field {
+                    name,
+                    docs,
+                    ty,
+                    optional,
+                } in struct_fields
+                {
+                    let attr = attribute(name);
+                    let hint = match ty {
+                        TypeRef::Option(_) => hint(types, ty),
+                        _ if *optional => format!("Optional[{}]", hint(types, ty)),
+                        _ => hint(types, ty),
+                    };
+                    let _ = writeln!(fields, "    {attr}: {hint}");
+                    docstring(&mut fields, "    ", docs.as_deref());
+                    let (encoder, decoder) = if *optional {
+                        unset.push(format!("{name:?}, "));
+                        let inner = match ty {
+                            TypeRef::Option(inner) => inner,
+                            _ => ty,
+                        };
+                        (
+                            format!("_opt({})", encoder(types, inner)),
+                            format!("_opt({})", decoder(types, inner)),
+                        )
+                    } else {
+                        (encoder(types, ty), decoder(types, ty))
+                    };
+                    to.push(format!("{name:?}: {encoder}(self.{attr})"));
+                    let value = match ty {
+                        _ if *optional => format!("body.get({name:?})"),
+                        TypeRef::Option(_) => format!("body.get({name:?})"),
+                        _ => format!("body[{name:?}]"),
+                    };
+                    from.push(format!("{attr}={decoder}({value})"));
+                }
+                if struct_fields.is_empty() {
+                    fields.push_str("    pass\n");
+                }
+                let to = format!("{{{}}}", to.join(", "));
+                let to = if unset.is_empty() {
+                    to
+                } else {
+                    format!("_drop_unset({to}, ({}))", unset.concat().trim_end())
+                };
+                (fields, to, format!("cls({})", from.join(", ")))
+            }
+            Shape::Tuple(elems) if elems.len() == 1 => {
+                let _ = writeln!(fields, "    value: {}", hint(types, &elems[0]));
+                (
+                    fields,
+                    format!("{}(self.value)", encoder(types, &elems[0])),
+                    format!("cls({}(body))", decoder(types, &elems[0])),
+                )
+            }
+            Shape::Tuple(elems) => {
+                let mut to = Vec::new();
+                let mut from = Vec::new();
+                for (i, ty) in elems.iter().enumerate() {
+                    let _ = writeln!(fields, "    _{i}: {}", hint(types, ty));
+                    to.push(format!("{}(self._{i})", encoder(types, ty)));
+                    from.push(format!("{}(body[{i}])", decoder(types, ty)));
+                }
+                (
+                    fields,
+                    format!("[{}]", to.join(", ")),
+                    format!("cls({})", from.join(", ")),
+                )
+            }
+        }
+    }
+
+    fn method(out: &mut String, types: &Types, request: &TypeSchema) {
+        let wire = &request.name;
+        let name = type_name(wire);
+        let method = snake_case(&name);
+        let (returns, read) = match types.response_of(request) {
+            Some(response) => (response.to_string(), format!("{response}.from_json")),
+            None => ("Any".to_string(), "_same".to_string()),
+        };
+        if matches!(request.shape, Some(Shape::Unit)) {
+            let _ = writeln!(out, "\n    def {method}(self) -> {returns}:");
+            docstring(out, "        ", request.docs.as_deref());
+            let _ = writeln!(out, "        return {read}(self._call({wire:?}, None))");
+        } else if types.is_described(wire) {
+            let _ = writeln!(
+                out,
+                "\n    def {method}(self, request: {name}) -> {returns}:"
+            );
+            docstring(out, "        ", request.docs.as_deref());
+            let _ = writeln!(
+                out,
+                "        return {read}(self._call({wire:?}, request.to_json()))"
+            );
+        } else {
+            let _ = writeln!(out, "\n    def {method}(self, request: Any) -> {returns}:");
+            let _ = writeln!(out, "        return {read}(self._call({wire:?}, request))");
+        }
+    }
+
+    fn attribute(name: &str) -> String {
+        if KEYWORDS.contains(&name) {
+            format!("{name}_")
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn docstring(out: &mut String, indent: &str, docs: Option<&str>) {
+        let Some(docs) = docs else { return };
+        let docs = docs.replace('\\', "\\\\").replace("\"\"\"", "\\\"\\\"\\\"");
+        let mut lines = docs.lines();
+        let _ = write!(out, "{indent}\"\"\"{}", lines.next().unwrap_or_default());
+        for line in lines {
+            if line.is_empty() {
+                out.push('\n');
+            } else {
+                let _ = write!(out, "\n{indent}{line}");
+            }
+        }
+        out.push_str("\"\"\"\n");
+    }
+
+    fn hint(types: &Types, ty: &TypeRef) -> String {
+        match ty {
+            TypeRef::Bool => "bool".into(),
+            TypeRef::I8
+            | TypeRef::I16
+            | TypeRef::I32
+            | TypeRef::I64
+            | TypeRef::I128
+            | TypeRef::U8
+            | TypeRef::U16
+            | TypeRef::U32
+            | TypeRef::U64
+            | TypeRef::U128 => "int".into(),
+            TypeRef::F32 | TypeRef::F64 => "float".into(),
+            TypeRef::Char | TypeRef::String => "str".into(),
+            TypeRef::Bytes => "bytes".into(),
+            TypeRef::Duration => "timedelta".into(),
+            TypeRef::Json => "Any".into(),
+            TypeRef::Request | TypeRef::Response => "Dict[str, Any]".into(),
+            TypeRef::Unit => "None".into(),
+            TypeRef::Option(inner) => format!("Optional[{}]", hint(types, inner)),
+            TypeRef::List(inner) => format!("List[{}]", hint(types, inner)),
+            TypeRef::Map(key, value) => {
+                format!("Dict[{}, {}]", hint(types, key), hint(types, value))
+            }
+            TypeRef::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|ty| hint(types, ty)).collect();
+                format!("Tuple[{}]", elems.join(", "))
+            }
+            TypeRef::Result(..) => "Dict[str, Any]".into(),
+            TypeRef::Named(name) if types.is_described(name) => name.clone(),
+            TypeRef::Named(_) => "Any".into(),
+        }
+    }
+
+    fn encoder(types: &Types, ty: &TypeRef) -> String {
+        match ty {
+            TypeRef::Bytes => "_bytes_to".into(),
+            TypeRef::Duration => "_duration_to".into(),
+            TypeRef::Option(inner) => format!("_opt({})", encoder(types, inner)),
+            TypeRef::List(inner) => format!("_list({})", encoder(types, inner)),
+            TypeRef::Map(_, value) => format!("_map({})", encoder(types, value)),
+            TypeRef::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|ty| encoder(types, ty)).collect();
+                format!("_tuple_to({})", elems.join(", "))
+            }
+            TypeRef::Result(ok, err) => {
+                format!("_result({}, {})", encoder(types, ok), encoder(types, err))
+            }
+            TypeRef::Named(name) if types.is_described(name) => "_to_json".into(),
+            _ => "_same".into(),
+        }
+    }
+
+    fn decoder(types: &Types, ty: &TypeRef) -> String {
+        match ty {
+            TypeRef::Bytes => "_bytes_from".into(),
+            TypeRef::Duration => "_duration_from".into(),
+            TypeRef::Option(inner) => format!("_opt({})", decoder(types, inner)),
+            TypeRef::List(inner) => format!("_list({})", decoder(types, inner)),
+            TypeRef::Map(_, value) => format!("_map({})", decoder(types, value)),
+            TypeRef::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|ty| decoder(types, ty)).collect();
+                format!("_tuple_from({})", elems.join(", "))
+            }
+            TypeRef::Result(ok, err) => {
+                format!("_result({}, {})", decoder(types, ok), decoder(types, err))
+            }
+            TypeRef::Named(name) if types.is_described(name) => format!("{name}.from_json"),
+            _ => "_same".into(),
+        }
+    }
+}
+
+mod typescript {
+    use super::*;
+
+    const PRELUDE: &str = r#"
+/** `std::time::Duration`, as serde writes it. */
+export interface Duration {
+  secs: number;
+  nanos: number;
+}
+
+/** Any request or response, tagged with its type name: `{"Type": {...fields}}`. */
+export type AnyMessage = { [type: string]: unknown };
+
+/** A request the server answered with an error. */
+export class MyprotoError extends Error {
+  constructor(
+    readonly code: string,
+    message: string,
+    readonly details: string | null,
+  ) {
+    super(`${code}: ${message}`);
+    this.name = "MyprotoError";
+  }
+}
+"#;
+
+    const CLIENT: &str = r#"
+/** Calls a server through its HTTP gateway, e.g. `new Client("http://127.0.0.1:8080")`. */
+export class Client {
+  constructor(
+    private readonly baseUrl: string,
+    private readonly token?: string,
+  ) {}
+
+  private async call<T>(typeName: string, request: unknown): Promise<T> {
+    const headers: Record<string, string> = { "Content-Type": "application/json" };
+    if (this.token !== undefined) {
+      headers["Authorization"] = `Bearer ${this.token}`;
+    }
+    const response = await fetch(`${this.baseUrl.replace(/\/$/, "")}/rpc/${typeName}`, {
+      method: "POST",
+      headers,
+      body: JSON.stringify(request),
+    });
+    const body = await response.json();
+    if (!response.ok) {
+      throw new MyprotoError(body.code, body.message, body.details ?? null);
+    }
+    return body as T;
+  }
+"#;
+
+    pub(super) fn generate(types: &Types) -> String {
+        let mut out = header("//", types.schema);
+        out.push_str(PRELUDE);
+        for ty in &types.described {
+            let shape = ty
+                .shape
+                .as_ref()
+                .expect("only described types are generated");
+            out.push('\n');
+            doc(&mut out, "", ty.docs.as_deref());
+            match shape {
+                Shape::Struct(fields) => {
+                    let _ = writeln!(out, "export interface {} {{", type_name(&ty.name));
+                    for field in fields {
+                        doc(&mut out, "  ", field.docs.as_deref());
+                        let optional = if field.optional { "?" } else { "" };
+                        let _ = writeln!(
+                            out,
+                            "  {}{optional}: {};",
+                            field.name,
+                            ts_type(types, &field.ty)
+                        );
+                    }
+                    out.push_str("}\n");
+                }
+                shape => {
+                    let _ = writeln!(
+                        out,
+                        "export type {} = {};",
+                        type_name(&ty.name),
+                        shape_type(types, shape)
+                    );
+                }
+            }
+        }
+        out.push_str(CLIENT);
+        for request in &types.schema.requests {
+            method(&mut out, types, request);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn method(out: &mut String, types: &Types, request: &TypeSchema) {
+        let wire = &request.name;
+        let name = type_name(wire);
+        let returns = types.response_of(request).unwrap_or("unknown");
+        out.push('\n');
+        doc(out, "  ", request.docs.as_deref());
+        let unit = matches!(request.shape, Some(Shape::Unit));
+        if unit {
+            let _ = writeln!(
+                out,
+                "  {}(): Promise<{returns}> {{\n    return this.call({wire:?}, null);\n  }}",
+                camel_case(&name)
+            );
+        } else {
+            let param = if types.is_described(wire) {
+                name.as_str()
+            } else {
+                "unknown"
+            };
+            let _ = writeln!(
+                out,
+                "  {}(request: {param}): Promise<{returns}> {{\n    return this.call({wire:?}, request);\n  }}",
+                camel_case(&name)
+            );
+        }
+    }
+
+    fn shape_type(types: &Types, shape: &Shape) -> String {
+        match shape {
+            Shape::Unit => "null".into(),
+            Shape::Struct(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| {
+                        let optional = if field.optional { "?" } else { "" };
+                        format!("{}{optional}: {}", field.name, ts_type(types, &field.ty))
+                    })
+                    .collect();
+                format!("{{ {} }}", fields.join("; "))
+            }
+            Shape::Tuple(elems) if elems.len() == 1 => ts_type(types, &elems[0]),
+            Shape::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|ty| ts_type(types, ty)).collect();
+                format!("[{}]", elems.join(", "))
+            }
+            Shape::Enum(variants) => {
+                let variants: Vec<String> = variants
+                    .iter()
+                    .map(|variant| match &variant.shape {
+                        Shape::Unit => format!("{:?}", variant.name),
+                        shape => format!("{{ {}: {} }}", variant.name, shape_type(types, shape)),
+                    })
+                    .collect();
+                variants.join(" | ")
+            }
+        }
+    }
+
+    fn ts_type(types: &Types, ty: &TypeRef) -> String {
+        match ty {
+            TypeRef::Bool => "boolean".into(),
+            TypeRef::I8
+            | TypeRef::I16
+            | TypeRef::I32
+            | TypeRef::I64
+            | TypeRef::I128
+            | TypeRef::U8
+            | TypeRef::U16
+            | TypeRef::U32
+            | TypeRef::U64
+            | TypeRef::U128
+            | TypeRef::F32
+            | TypeRef::F64 => "number".into(),
+            TypeRef::Char | TypeRef::String => "string".into(),
+            TypeRef::Bytes => "number[]".into(),
+            TypeRef::Duration => "Duration".into(),
+            TypeRef::Json => "unknown".into(),
+            TypeRef::Request | TypeRef::Response => "AnyMessage".into(),
+            TypeRef::Unit => "null".into(),
+            TypeRef::Option(inner) => format!("{} | null", ts_type(types, inner)),
+            TypeRef::List(inner) => format!("Array<{}>", ts_type(types, inner)),
+            TypeRef::Map(_, value) => format!("Record<string, {}>", ts_type(types, value)),
+            TypeRef::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|ty| ts_type(types, ty)).collect();
+                format!("[{}]", elems.join(", "))
+            }
+            TypeRef::Result(ok, err) => format!(
+                "{{ Ok: {} }} | {{ Err: {} }}",
+                ts_type(types, ok),
+                ts_type(types, err)
+            ),
+            TypeRef::Named(name) if types.is_described(name) => name.clone(),
+            TypeRef::Named(_) => "unknown".into(),
+        }
+    }
+
+    fn doc(out: &mut String, indent: &str, docs: Option<&str>) {
+        let Some(docs) = docs else { return };
+        let docs = docs.replace("*/", "*\\/");
+        if !docs.contains('\n') {
+            let _ = writeln!(out, "{indent}/** {docs} */");
+            return;
+        }
+        let _ = writeln!(out, "{indent}/**");
+        for line in docs.lines() {
+            if line.is_empty() {
+                let _ = writeln!(out, "{indent} *");
+            } else {
+                let _ = writeln!(out, "{indent} * {line}");
+            }
+        }
+        let _ = writeln!(out, "{indent} */");
+    }
+}
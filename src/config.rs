@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::RateLimit;
+use crate::ban::BanPolicy;
+use crate::codec::Codec;
+use crate::frame::{Compression, DEFAULT_MAX_FRAME_LENGTH, DEFAULT_MAX_MESSAGE_LENGTH};
+use crate::tcp::TcpOptions;
+
+/// What `Server::serve` does with a connection once `max_connections` are open.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverLimit {
+    /// Stop accepting until a slot frees up, leaving new clients in the listen backlog.
+    Wait,
+    /// Accept and immediately close the connection.
+    Refuse,
+}
+
+/// What a session does when a client reads slower than replies and pushes
+/// are produced and its outbound queue fills up.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClient {
+    /// Make handlers and pushes wait for room in the queue. Published
+    /// messages wait in the subscriber's queue instead, under `subscriber_overflow`.
+    Block,
+    /// Drop pushes that don't fit, still waiting for room for replies.
+    DropPushes,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// What a subscriber's queue does with a published message once it holds
+/// `subscriber_queue` that the client hasn't taken yet.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Drop the oldest queued message to make room, keeping the latest.
+    DropOldest,
+    /// Drop the new message, keeping the ones already queued.
+    DropNew,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+impl Overflow {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Overflow::DropOldest => "drop_oldest",
+            Overflow::DropNew => "drop_new",
+            Overflow::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// When a session's writer hands the frames it has queued to the socket.
+/// Frames written in between wait in its buffer, so a burst of small
+/// replies or published messages goes out in one write instead of one each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Frames past which the buffer is flushed even while more are queued.
+    pub max_frames: usize,
+    /// How long to wait for more frames once the queue runs dry before
+    /// flushing; zero flushes as soon as it does.
+    pub max_delay: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_frames: 32,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub max_frame_length: usize,
+    /// Messages longer than a frame are split into chunks of up to
+    /// `max_frame_length` and reassembled, up to this length, for clients
+    /// that support it. Chunking is off when this isn't above `max_frame_length`.
+    pub max_message_length: usize,
+    /// Lower limits for frames carrying particular request types, by type
+    /// name, e.g. 64 KiB for `Echo`. They are checked once a request's type
+    /// is read, before the rest of it is decoded, and frames over them are
+    /// answered with `PayloadTooLarge`.
+    pub max_payload_lengths: HashMap<String, usize>,
+    /// Compression clients may pick from during the handshake; empty turns
+    /// it off.
+    pub compression: Vec<Compression>,
+    /// Agree to a CRC32 on every frame with clients that ask for one.
+    pub checksums: bool,
+    /// Payload codecs clients may pick from during the handshake.
+    pub codecs: Vec<Codec>,
+    /// Upper bound on a single `Request::handle` call; the handler is dropped when it expires.
+    pub request_timeout: Option<Duration>,
+    /// Timeouts for particular request types, by type name, in place of
+    /// `request_timeout`; `None` lets that type run as long as it takes.
+    pub request_timeouts: HashMap<String, Option<Duration>>,
+    /// Handlers taking longer than this are logged as slow; `None` disables the warning.
+    pub slow_request_threshold: Option<Duration>,
+    /// Connections that haven't finished the handshake this long after
+    /// they're accepted are closed.
+    pub handshake_timeout: Duration,
+    /// How long `Server::serve` waits for open sessions after shutdown is requested.
+    pub drain_timeout: Duration,
+    /// How long `Server::upgrade` waits for the new process to be ready before giving up on it.
+    pub upgrade_timeout: Duration,
+    pub max_connections: Option<usize>,
+    /// How often to ping the client; `None` disables heartbeats.
+    pub heartbeat_interval: Option<Duration>,
+    /// Sessions that haven't sent any frame for this long are closed.
+    pub heartbeat_timeout: Duration,
+    pub over_limit: OverLimit,
+    /// Sessions that haven't made a call for this long are closed, heartbeats notwithstanding.
+    pub idle_timeout: Option<Duration>,
+    /// How long a session whose client has shut down its write half goes on
+    /// answering the calls it already sent before cancelling the rest.
+    pub half_close_timeout: Duration,
+    /// Frames a session buffers for a client before `slow_client` applies.
+    pub outbound_queue: usize,
+    pub slow_client: SlowClient,
+    /// Taken when each session starts.
+    pub flush: FlushPolicy,
+    /// Undecodable frames after which a session is closed; `None` answers
+    /// each with a `Malformed` error however many there are.
+    pub max_malformed_frames: Option<u64>,
+    /// Published messages queued for each subscribing connection, ahead of
+    /// its outbound queue, before `subscriber_overflow` applies; a slow
+    /// subscriber holds up neither publishers nor other subscribers. Taken
+    /// when the server is built.
+    pub subscriber_queue: usize,
+    pub subscriber_overflow: Overflow,
+    /// Calls, streams and uploads a session runs at once on each of its channels.
+    pub max_concurrent_calls: usize,
+    /// Calls, streams and uploads a session holds on each channel while
+    /// `max_concurrent_calls` are running there, starting them highest
+    /// `Priority` first as slots free up; past this it stops reading frames.
+    pub max_queued_calls: usize,
+    /// Logical channels a client may spread its calls over with
+    /// `Client::channel`, numbered from 0; calls on any past these are refused.
+    pub max_channels: u32,
+    /// Requests running across all sessions past which new ones are refused
+    /// with `ErrorCode::Busy` instead of queued.
+    pub max_in_flight: Option<usize>,
+    /// Jobs run at once, across all sessions. Taken when the server is built.
+    pub max_running_jobs: usize,
+    /// Jobs waiting for one of the `max_running_jobs` to finish past which
+    /// `SubmitJob` is refused with `ErrorCode::Busy`. Taken when the server is built.
+    pub max_queued_jobs: usize,
+    /// How long a finished job's outcome is kept for `JobResult`.
+    pub job_retention: Duration,
+    /// How long the results of a call made with an idempotency key are kept
+    /// to answer repeats of it.
+    pub idempotency_ttl: Duration,
+    /// Messages each `SubscribeAcked` subscriber keeps until acked; past
+    /// this, the oldest are dropped.
+    pub unacked_push_limit: usize,
+    /// Times an acked subscriber is sent a message it doesn't ack before the
+    /// message goes to its dead letters; `None` keeps sending it.
+    pub max_delivery_attempts: Option<u32>,
+    /// Dead letters kept across all subscribers, past which the oldest are
+    /// dropped. Taken when the server is built.
+    pub dead_letter_limit: usize,
+    /// Messages `PublishAt` holds for later at once, past which it is
+    /// refused with `ErrorCode::Overloaded`. Taken when the server is built.
+    pub max_scheduled_messages: usize,
+    /// How far ahead `PublishAt` may schedule a message; later times are
+    /// refused with `ErrorCode::InvalidRequest`. Taken when the server is built.
+    pub max_publish_delay: Duration,
+    /// How long a disconnected session's subscriptions, identity and pushes
+    /// are kept for a client to resume it; `None` disables resumption.
+    pub session_resume_timeout: Option<Duration>,
+    /// Per-client request budget, keyed by authenticated identity or else peer IP.
+    pub rate_limit: Option<RateLimit>,
+    /// Bans peer IPs that keep sending oversized or undecodable frames or
+    /// failing authentication; `None` never bans.
+    pub ban: Option<BanPolicy>,
+    /// Unix socket path for admin commands (`connections`, `disconnect`, `bans`, `unban`, `log`, `reload`, `drain`).
+    pub admin_socket: Option<PathBuf>,
+    /// Applied to each connection as it is accepted.
+    pub tcp: TcpOptions,
+    /// Log every frame of sessions that start from now on, as
+    /// `Protocol::dump_frames` does. Defaults to whether `MYPROTO_DUMP_FRAMES` is set.
+    pub dump_frames: bool,
+    /// Share of calls, streams, uploads and datagrams, from 0 to 1, that get
+    /// a span of their own; ones carrying the client's trace context always do.
+    pub trace_sample_rate: f64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            max_payload_lengths: HashMap::new(),
+            compression: vec![Compression::Zstd, Compression::Lz4],
+            checksums: true,
+            codecs: Codec::ALL.to_vec(),
+            request_timeout: Some(Duration::from_secs(30)),
+            request_timeouts: HashMap::new(),
+            slow_request_threshold: Some(Duration::from_secs(1)),
+            handshake_timeout: Duration::from_secs(10),
+            drain_timeout: Duration::from_secs(30),
+            upgrade_timeout: Duration::from_secs(30),
+            max_connections: None,
+            over_limit: OverLimit::Wait,
+            heartbeat_interval: Some(Duration::from_secs(15)),
+            heartbeat_timeout: Duration::from_secs(45),
+            idle_timeout: None,
+            half_close_timeout: Duration::from_secs(30),
+            outbound_queue: 64,
+            slow_client: SlowClient::Block,
+            flush: FlushPolicy::default(),
+            max_malformed_frames: None,
+            subscriber_queue: 1024,
+            subscriber_overflow: Overflow::DropOldest,
+            max_concurrent_calls: 128,
+            max_queued_calls: 128,
+            max_channels: 16,
+            max_in_flight: None,
+            max_running_jobs: 16,
+            max_queued_jobs: 1024,
+            job_retention: Duration::from_secs(10 * 60),
+            idempotency_ttl: Duration::from_secs(5 * 60),
+            unacked_push_limit: 1024,
+            max_delivery_attempts: None,
+            dead_letter_limit: 10_000,
+            max_scheduled_messages: 10_000,
+            max_publish_delay: Duration::from_secs(7 * 24 * 60 * 60),
+            session_resume_timeout: None,
+            rate_limit: None,
+            ban: None,
+            admin_socket: None,
+            tcp: TcpOptions::default(),
+            dump_frames: crate::protocol::dump_frames_from_env(),
+            trace_sample_rate: 1.0,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// How long a request of type `request_type` may run.
+    pub fn request_timeout_for(&self, request_type: &str) -> Option<Duration> {
+        self.request_timeouts
+            .get(request_type)
+            .copied()
+            .unwrap_or(self.request_timeout)
+    }
+}
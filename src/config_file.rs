@@ -0,0 +1,762 @@
+//! The server binary's TOML configuration.
+//!
+//! Every key is optional and falls back to [`ServerConfig::default`]:
+//!
+//! ```toml
+//! listen = "127.0.0.1:8443"       # or a list, e.g. ["0.0.0.0:8443", "unix:/run/myproto.sock"]
+//! proxy_protocol = false        # expect a PROXY header on every connection
+//! accept_shards = 4              # SO_REUSEPORT listeners per TCP address, each on its own task
+//! admin_socket = "/run/myproto/admin.sock"
+//! access_log = "-"               # stdout, or a file to append to
+//! record_dir = "/var/lib/myproto/sessions"  # record every session for replay
+//! file_root = "/srv/myproto"     # serve GetFile and PutFile from here
+//! file_mode = 0o644              # most permission bits PutFile may give a file
+//! max_file_size = 104857600      # longest file PutFile writes, in bytes
+//! journal = "/var/lib/myproto/journal"  # keep every published message
+//! request_journal = "/var/lib/myproto/requests"  # replay mutating requests on startup
+//! kv_store = "memory"            # serve KvGet and co; a directory needs the `sled` feature
+//! gateway = "127.0.0.1:8080"     # needs the `gateway` feature
+//! grpc = "127.0.0.1:50051"       # needs the `grpc` feature
+//! websocket = "127.0.0.1:8444"   # WebSocket clients, needs the `websocket` feature
+//! datagram = "127.0.0.1:8443"    # UDP, for fire-and-forget requests
+//! codecs = ["Bincode", "Json"]
+//! compression = ["Zstd", "Lz4"]  # most preferred first; [] turns it off
+//! checksums = true               # CRC32 every frame for clients that ask
+//!
+//! [log]
+//! filter = "info,myproto=debug"  # defaults to RUST_LOG
+//! format = "json"                # or "text"
+//! dump_frames = false            # log every frame; also set by MYPROTO_DUMP_FRAMES
+//! trace_sample_rate = 0.1        # share of calls given a span; traced calls always are
+//!
+//! [limits]
+//! max_frame_length = 16777216
+//! max_message_length = 67108864  # larger messages are chunked across frames
+//! max_payload_lengths = { Echo = 65536 }  # by request type, below the above
+//! max_connections = 1000
+//! over_limit = "refuse"          # or "wait"
+//! max_concurrent_calls = 128
+//! max_queued_calls = 128
+//! max_channels = 16              # logical channels per connection
+//! max_in_flight = 10000
+//! max_running_jobs = 16          # background jobs run at once
+//! max_queued_jobs = 1024
+//! outbound_queue = 64
+//! unacked_push_limit = 1024      # messages kept per acked subscriber
+//! max_delivery_attempts = 5      # before an unacked message is dead-lettered
+//! dead_letter_limit = 10000
+//! max_scheduled_messages = 10000 # held by PublishAt at once
+//! slow_client = "drop_pushes"    # or "block", "disconnect"
+//! max_malformed_frames = 16      # undecodable frames before a session is closed
+//! subscriber_queue = 1024        # published messages queued per subscriber
+//! subscriber_overflow = "drop_oldest"  # or "drop_new", "disconnect"
+//! rate_limit = { per_second = 50.0, burst = 100 }
+//!
+//! [timeouts]
+//! request = "30s"                # "off" disables any optional timeout
+//! requests = { Ping = "100ms", RunReport = "5m" }  # by request type, in place of `request`
+//! slow_request = "1s"
+//! handshake = "10s"              # for a new connection to finish the handshake
+//! drain = "30s"
+//! upgrade = "30s"               # for a restarted process to be ready
+//! heartbeat_interval = "15s"
+//! heartbeat = "45s"
+//! idle = "10m"
+//! half_close = "30s"             # to answer a client that has stopped sending
+//! idempotency = "5m"             # how long idempotency keys are remembered
+//! session_resume = "30s"         # keep dropped sessions this long for clients to resume
+//! job_retention = "10m"          # how long finished jobs' outcomes are kept
+//! publish_delay = "168h"         # how far ahead PublishAt may schedule
+//!
+//! [audit]
+//! log = "/var/log/myproto/audit.log"  # "-" for stdout, "syslog" for the local daemon
+//! requests = ["PutFile", "Login"]     # every request when left out
+//! redact = { Login = ["password"] }
+//! arguments = true               # false leaves every request's arguments out
+//!
+//! [ban]
+//! threshold = 10                 # oversized or undecodable frames and failed logins; bans are off without it
+//! window = "1m"                  # within which the threshold is reached
+//! duration = "10m"               # how long a banned IP is refused
+//!
+//! [flush]
+//! max_frames = 32                # queued frames written to the socket at once
+//! max_delay = "200us"            # to wait for more before writing; "0ms" writes right away
+//!
+//! [ip_filter]
+//! allow = ["10.0.0.0/8", "192.168.0.0/16"]  # empty lets everyone in
+//! deny = ["10.6.6.0/24"]                    # wins over allow
+//!
+//! [cluster]
+//! node = "a"                     # this server's name among its peers
+//! secret = "change-me"           # the same on every node
+//! peers = ["10.0.0.2:8443", "10.0.0.3:8443"]
+//!
+//! [tcp]
+//! nodelay = true
+//! keepalive = "60s"
+//! keepalive_interval = "10s"
+//! send_buffer_size = 262144
+//! recv_buffer_size = 262144
+//! ```
+//!
+//! Any key can also be given on the command line as `--key value`, with
+//! tables joined by dots and dashes standing in for underscores, e.g.
+//! `--limits.max-connections 1000`; flags win over the file.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+use toml::{Table, Value};
+
+use crate::access_log::JsonLines;
+#[cfg(unix)]
+use crate::audit::Syslog;
+use crate::config::{FlushPolicy, OverLimit, Overflow, SlowClient};
+use crate::kv::{MemoryStore, Store};
+use crate::{
+    Audit, BanPolicy, ClusterConfig, Codec, Compression, IpFilter, RateLimit, ServerConfig,
+    TcpOptions,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Addresses to accept clients on: `host:port`, `unix:` and a socket
+    /// path, or a Windows named pipe such as `\\.\pipe\myproto`. Ignored
+    /// when systemd socket activation passes the sockets instead.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<String>,
+    /// Whether connections on the `listen` addresses start with a PROXY protocol header.
+    pub proxy_protocol: bool,
+    /// Listeners bound to each TCP `listen` address, on Unix; see `Listener::bind_shards`.
+    pub accept_shards: Option<usize>,
+    pub admin_socket: Option<PathBuf>,
+    /// `-` for stdout, otherwise a file appended to.
+    pub access_log: Option<PathBuf>,
+    /// Directory every session is recorded to, one file each.
+    pub record_dir: Option<PathBuf>,
+    /// Directory `GetFile` and `PutFile` work in; they're refused without one.
+    pub file_root: Option<PathBuf>,
+    /// Permission bits `PutFile` may give the files it writes.
+    pub file_mode: Option<u32>,
+    /// Longest file `PutFile` writes, in bytes.
+    pub max_file_size: Option<u64>,
+    /// File every published message is appended to, for `SubscribeFrom`.
+    pub journal: Option<PathBuf>,
+    /// File every mutating request is appended to, and replayed from at startup.
+    pub request_journal: Option<PathBuf>,
+    /// `memory`, or a directory for a sled database, to serve the key-value requests from.
+    pub kv_store: Option<PathBuf>,
+    /// Address for the HTTP gateway, if it should run.
+    pub gateway: Option<String>,
+    /// Address for the gRPC bridge, if it should run.
+    pub grpc: Option<String>,
+    /// Addresses to accept WebSocket clients on, in the same forms as `listen`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub websocket: Vec<String>,
+    /// UDP address to take fire-and-forget datagrams on, if any.
+    pub datagram: Option<String>,
+    pub codecs: Option<Vec<Codec>>,
+    pub compression: Option<Vec<Compression>>,
+    pub checksums: Option<bool>,
+    pub log: LogConfig,
+    pub audit: AuditConfig,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub ban: BanConfig,
+    pub flush: FlushConfig,
+    pub ip_filter: IpRanges,
+    pub cluster: Cluster,
+    pub tcp: Tcp,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:8443".to_string()],
+            proxy_protocol: false,
+            accept_shards: None,
+            admin_socket: None,
+            access_log: None,
+            record_dir: None,
+            file_root: None,
+            file_mode: None,
+            max_file_size: None,
+            journal: None,
+            request_journal: None,
+            kv_store: None,
+            gateway: None,
+            grpc: None,
+            websocket: Vec::new(),
+            datagram: None,
+            codecs: None,
+            compression: None,
+            checksums: None,
+            log: LogConfig::default(),
+            audit: AuditConfig::default(),
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            ban: BanConfig::default(),
+            flush: FlushConfig::default(),
+            ip_filter: IpRanges::default(),
+            cluster: Cluster::default(),
+            tcp: Tcp::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `tracing` filter directives; `RUST_LOG` is used when unset.
+    pub filter: Option<String>,
+    pub format: LogFormat,
+    pub dump_frames: bool,
+    pub trace_sample_rate: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Requests are audited when `log` is set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// `-` for stdout, `syslog` for the local syslog daemon, otherwise a
+    /// file appended to.
+    pub log: Option<PathBuf>,
+    /// The request types to audit; every request when unset.
+    pub requests: Option<Vec<String>>,
+    /// Fields masked in each request type's arguments.
+    pub redact: HashMap<String, Vec<String>>,
+    pub arguments: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            log: None,
+            requests: None,
+            redact: Default::default(),
+            arguments: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_frame_length: Option<usize>,
+    pub max_message_length: Option<usize>,
+    pub max_payload_lengths: HashMap<String, usize>,
+    pub max_connections: Option<usize>,
+    pub over_limit: Option<OverLimit>,
+    pub max_concurrent_calls: Option<usize>,
+    pub max_queued_calls: Option<usize>,
+    pub max_channels: Option<u32>,
+    pub max_in_flight: Option<usize>,
+    pub max_running_jobs: Option<usize>,
+    pub max_queued_jobs: Option<usize>,
+    pub outbound_queue: Option<usize>,
+    pub unacked_push_limit: Option<usize>,
+    pub max_delivery_attempts: Option<u32>,
+    pub dead_letter_limit: Option<usize>,
+    pub max_scheduled_messages: Option<usize>,
+    pub slow_client: Option<SlowClient>,
+    pub max_malformed_frames: Option<u64>,
+    pub subscriber_queue: Option<usize>,
+    pub subscriber_overflow: Option<Overflow>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Durations are written like `"500ms"`, `"30s"`, `"5m"` or `"1h"`. The outer
+/// `Option` is whether the key was given; the inner one is `"off"`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    #[serde(deserialize_with = "optional_duration")]
+    pub request: Option<Option<Duration>>,
+    #[serde(deserialize_with = "optional_durations")]
+    pub requests: HashMap<String, Option<Duration>>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub slow_request: Option<Option<Duration>>,
+    #[serde(deserialize_with = "required_duration")]
+    pub handshake: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub drain: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub upgrade: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub heartbeat_interval: Option<Option<Duration>>,
+    #[serde(deserialize_with = "required_duration")]
+    pub heartbeat: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub idle: Option<Option<Duration>>,
+    #[serde(deserialize_with = "required_duration")]
+    pub half_close: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub idempotency: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub session_resume: Option<Option<Duration>>,
+    #[serde(deserialize_with = "required_duration")]
+    pub job_retention: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub publish_delay: Option<Duration>,
+}
+
+/// Banning is on once `threshold` is set; the durations fall back to
+/// [`BanPolicy::default`].
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanConfig {
+    pub threshold: Option<u32>,
+    #[serde(deserialize_with = "required_duration")]
+    pub window: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub duration: Option<Duration>,
+}
+
+/// Either key falls back to [`FlushPolicy::default`].
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushConfig {
+    pub max_frames: Option<usize>,
+    #[serde(deserialize_with = "required_duration")]
+    pub max_delay: Option<Duration>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpRanges {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+/// The server is clustered when `node` is set.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cluster {
+    pub node: Option<String>,
+    pub secret: Option<String>,
+    pub peers: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tcp {
+    pub nodelay: bool,
+    #[serde(deserialize_with = "required_duration")]
+    pub keepalive: Option<Duration>,
+    #[serde(deserialize_with = "required_duration")]
+    pub keepalive_interval: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl ConfigFile {
+    /// Reads `path`, if given, and applies `overrides` as `(key, value)`
+    /// pairs from the command line, e.g. `("limits.max-connections", "1000")`.
+    pub fn load(path: Option<&Path>, overrides: &[(String, String)]) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                // Parsed straight from the text first so mistakes are reported with their line.
+                toml::from_str::<ConfigFile>(&text)
+                    .map_err(|e| anyhow!("invalid config file {}:\n{e}", path.display()))?;
+                toml::from_str::<Table>(&text)?
+            }
+            None => Table::new(),
+        };
+        for (key, value) in overrides {
+            set(&mut table, key, value)?;
+        }
+
+        let config = ConfigFile::deserialize(table).map_err(|e| anyhow!("invalid config: {e}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every setting that would make the server misbehave, rather than just the first.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let limits = &self.limits;
+        if limits.max_frame_length == Some(0) {
+            problems.push("limits.max_frame_length must be positive");
+        }
+        if limits.max_payload_lengths.values().any(|&max| max == 0) {
+            problems.push("limits.max_payload_lengths must be positive");
+        }
+        if limits.max_connections == Some(0) {
+            problems.push("limits.max_connections must be positive");
+        }
+        if limits.max_concurrent_calls == Some(0) {
+            problems.push("limits.max_concurrent_calls must be positive");
+        }
+        if limits.max_channels == Some(0) {
+            problems.push("limits.max_channels must be positive");
+        }
+        if limits.max_running_jobs == Some(0) {
+            problems.push("limits.max_running_jobs must be positive");
+        }
+        if limits.outbound_queue == Some(0) {
+            problems.push("limits.outbound_queue must be positive");
+        }
+        if limits.unacked_push_limit == Some(0) {
+            problems.push("limits.unacked_push_limit must be positive");
+        }
+        if limits.max_delivery_attempts == Some(0) {
+            problems.push("limits.max_delivery_attempts must be positive");
+        }
+        if limits.subscriber_queue == Some(0) {
+            problems.push("limits.subscriber_queue must be positive");
+        }
+        if let Some(limit) = &limits.rate_limit {
+            if !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+                problems.push("limits.rate_limit.per_second must be a positive number");
+            }
+            if limit.burst == 0 {
+                problems.push("limits.rate_limit.burst must be positive");
+            }
+        }
+        if self.ban.threshold == Some(0) {
+            problems.push("ban.threshold must be positive");
+        }
+        if self.ban.threshold.is_none()
+            && (self.ban.window.is_some() || self.ban.duration.is_some())
+        {
+            problems.push("ban.window and ban.duration need ban.threshold to be set");
+        }
+        if self.flush.max_frames == Some(0) {
+            problems.push("flush.max_frames must be positive");
+        }
+        if self.tcp.keepalive_interval.is_some() && self.tcp.keepalive.is_none() {
+            problems.push("tcp.keepalive_interval needs tcp.keepalive to be set");
+        }
+        if self.accept_shards == Some(0) {
+            problems.push("accept_shards must be positive");
+        }
+        if self.listen.is_empty() {
+            problems.push("listen must list at least one address");
+        }
+        let cluster = &self.cluster;
+        if cluster.node.is_some() != cluster.secret.is_some() {
+            problems.push("cluster.node and cluster.secret must be set together");
+        }
+        if !cluster.peers.is_empty() && cluster.node.is_none() {
+            problems.push("cluster.peers needs cluster.node and cluster.secret to be set");
+        }
+        if self.codecs.as_ref().is_some_and(Vec::is_empty) {
+            problems.push("codecs must list at least one codec");
+        }
+
+        let config = self.server_config();
+        if let Some(interval) = config.heartbeat_interval
+            && interval >= config.heartbeat_timeout
+        {
+            problems.push("timeouts.heartbeat must be longer than timeouts.heartbeat_interval");
+        }
+        if self.gateway.is_some() && !cfg!(feature = "gateway") {
+            problems.push("gateway needs the server built with the `gateway` feature");
+        }
+        if self
+            .log
+            .trace_sample_rate
+            .is_some_and(|rate| !(0.0..=1.0).contains(&rate))
+        {
+            problems.push("log.trace_sample_rate must be between 0 and 1");
+        }
+        let audit = &self.audit;
+        if audit.log.is_none() && (audit.requests.is_some() || !audit.redact.is_empty()) {
+            problems.push("audit.requests and audit.redact need audit.log");
+        }
+        if self
+            .kv_store
+            .as_ref()
+            .is_some_and(|store| store.as_os_str() != "memory")
+            && !cfg!(feature = "sled")
+        {
+            problems.push(
+                "kv_store other than \"memory\" needs the server built with the `sled` feature",
+            );
+        }
+        if self.grpc.is_some() && !cfg!(feature = "grpc") {
+            problems.push("grpc needs the server built with the `grpc` feature");
+        }
+        if !self.websocket.is_empty() && !cfg!(feature = "websocket") {
+            problems.push("websocket needs the server built with the `websocket` feature");
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let mut message = String::from("invalid config:");
+        for problem in problems {
+            let _ = write!(message, "\n  - {problem}");
+        }
+        bail!(message)
+    }
+
+    /// The filter for `ServerBuilder::ip_filter`, if any ranges are configured.
+    pub fn ip_filter(&self) -> Option<IpFilter> {
+        let ranges = &self.ip_filter;
+        if ranges.allow.is_empty() && ranges.deny.is_empty() {
+            return None;
+        }
+        let filter = ranges
+            .allow
+            .iter()
+            .fold(IpFilter::new(), |f, r| f.allow(*r));
+        Some(ranges.deny.iter().fold(filter, |f, r| f.deny(*r)))
+    }
+
+    /// The audit for `ServerBuilder::audit`, opening its log, if requests are audited.
+    pub fn audit(&self) -> Result<Option<Audit>> {
+        let config = &self.audit;
+        let Some(path) = &config.log else {
+            return Ok(None);
+        };
+        let mut audit = match path.to_str() {
+            Some("-") => Audit::new(JsonLines::stdout()),
+            #[cfg(unix)]
+            Some("syslog") => Audit::new(Syslog::new().context("connecting to syslog")?),
+            _ => Audit::new(
+                JsonLines::file(path).with_context(|| format!("opening {}", path.display()))?,
+            ),
+        };
+        audit = match &config.requests {
+            Some(requests) => requests.iter().fold(audit, |a, ty| a.request(ty)),
+            None => audit.every_request(),
+        };
+        for (ty, fields) in &config.redact {
+            audit = fields.iter().fold(audit, |a, field| a.redact(ty, field));
+        }
+        if !config.arguments {
+            audit = audit.without_arguments();
+        }
+        Ok(Some(audit))
+    }
+
+    /// The store for `ServerBuilder::kv_store`, if the server has one.
+    pub fn kv_store(&self) -> Result<Option<Box<dyn Store>>> {
+        let Some(path) = &self.kv_store else {
+            return Ok(None);
+        };
+        if path.as_os_str() == "memory" {
+            return Ok(Some(Box::new(MemoryStore::default())));
+        }
+        #[cfg(feature = "sled")]
+        return Ok(Some(Box::new(
+            crate::kv::SledStore::open(path)
+                .with_context(|| format!("opening {}", path.display()))?,
+        )));
+        #[cfg(not(feature = "sled"))]
+        bail!("kv_store {} needs the `sled` feature", path.display());
+    }
+
+    /// The configuration for `ServerBuilder::cluster`, if the server is clustered.
+    pub fn cluster(&self) -> Option<ClusterConfig> {
+        let cluster = &self.cluster;
+        let config = ClusterConfig::new(cluster.node.clone()?, cluster.secret.clone()?);
+        Some(cluster.peers.iter().fold(config, |c, peer| c.peer(peer)))
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        let defaults = ServerConfig::default();
+        let limits = &self.limits;
+        let timeouts = &self.timeouts;
+        ServerConfig {
+            max_frame_length: limits.max_frame_length.unwrap_or(defaults.max_frame_length),
+            max_message_length: limits
+                .max_message_length
+                .unwrap_or(defaults.max_message_length),
+            max_payload_lengths: limits.max_payload_lengths.clone(),
+            compression: self.compression.clone().unwrap_or(defaults.compression),
+            checksums: self.checksums.unwrap_or(defaults.checksums),
+            codecs: self.codecs.clone().unwrap_or(defaults.codecs),
+            request_timeout: timeouts.request.unwrap_or(defaults.request_timeout),
+            request_timeouts: timeouts.requests.clone(),
+            slow_request_threshold: timeouts
+                .slow_request
+                .unwrap_or(defaults.slow_request_threshold),
+            handshake_timeout: timeouts.handshake.unwrap_or(defaults.handshake_timeout),
+            drain_timeout: timeouts.drain.unwrap_or(defaults.drain_timeout),
+            upgrade_timeout: timeouts.upgrade.unwrap_or(defaults.upgrade_timeout),
+            max_connections: limits.max_connections.or(defaults.max_connections),
+            over_limit: limits.over_limit.unwrap_or(defaults.over_limit),
+            heartbeat_interval: timeouts
+                .heartbeat_interval
+                .unwrap_or(defaults.heartbeat_interval),
+            heartbeat_timeout: timeouts.heartbeat.unwrap_or(defaults.heartbeat_timeout),
+            idle_timeout: timeouts.idle.unwrap_or(defaults.idle_timeout),
+            half_close_timeout: timeouts.half_close.unwrap_or(defaults.half_close_timeout),
+            outbound_queue: limits.outbound_queue.unwrap_or(defaults.outbound_queue),
+            slow_client: limits.slow_client.unwrap_or(defaults.slow_client),
+            flush: FlushPolicy {
+                max_frames: self.flush.max_frames.unwrap_or(defaults.flush.max_frames),
+                max_delay: self.flush.max_delay.unwrap_or(defaults.flush.max_delay),
+            },
+            max_malformed_frames: limits
+                .max_malformed_frames
+                .or(defaults.max_malformed_frames),
+            subscriber_queue: limits.subscriber_queue.unwrap_or(defaults.subscriber_queue),
+            subscriber_overflow: limits
+                .subscriber_overflow
+                .unwrap_or(defaults.subscriber_overflow),
+            max_concurrent_calls: limits
+                .max_concurrent_calls
+                .unwrap_or(defaults.max_concurrent_calls),
+            max_queued_calls: limits.max_queued_calls.unwrap_or(defaults.max_queued_calls),
+            max_channels: limits.max_channels.unwrap_or(defaults.max_channels),
+            max_in_flight: limits.max_in_flight.or(defaults.max_in_flight),
+            max_running_jobs: limits.max_running_jobs.unwrap_or(defaults.max_running_jobs),
+            max_queued_jobs: limits.max_queued_jobs.unwrap_or(defaults.max_queued_jobs),
+            job_retention: timeouts.job_retention.unwrap_or(defaults.job_retention),
+            idempotency_ttl: timeouts.idempotency.unwrap_or(defaults.idempotency_ttl),
+            session_resume_timeout: timeouts
+                .session_resume
+                .unwrap_or(defaults.session_resume_timeout),
+            unacked_push_limit: limits
+                .unacked_push_limit
+                .unwrap_or(defaults.unacked_push_limit),
+            max_delivery_attempts: limits
+                .max_delivery_attempts
+                .or(defaults.max_delivery_attempts),
+            dead_letter_limit: limits
+                .dead_letter_limit
+                .unwrap_or(defaults.dead_letter_limit),
+            max_scheduled_messages: limits
+                .max_scheduled_messages
+                .unwrap_or(defaults.max_scheduled_messages),
+            max_publish_delay: timeouts.publish_delay.unwrap_or(defaults.max_publish_delay),
+            rate_limit: limits.rate_limit.or(defaults.rate_limit),
+            ban: self.ban.threshold.map(|threshold| {
+                let policy = BanPolicy::default();
+                BanPolicy {
+                    threshold,
+                    window: self.ban.window.unwrap_or(policy.window),
+                    duration: self.ban.duration.unwrap_or(policy.duration),
+                }
+            }),
+            admin_socket: self.admin_socket.clone().or(defaults.admin_socket),
+            dump_frames: self.log.dump_frames || defaults.dump_frames,
+            trace_sample_rate: self
+                .log
+                .trace_sample_rate
+                .unwrap_or(defaults.trace_sample_rate),
+            tcp: TcpOptions {
+                nodelay: self.tcp.nodelay,
+                keepalive: self.tcp.keepalive,
+                keepalive_interval: self.tcp.keepalive_interval,
+                send_buffer_size: self.tcp.send_buffer_size,
+                recv_buffer_size: self.tcp.recv_buffer_size,
+            },
+        }
+    }
+}
+
+/// Sets the dotted `key` in `table`, reading `value` as TOML where it parses
+/// and as a bare string otherwise.
+fn set(table: &mut Table, key: &str, value: &str) -> Result<()> {
+    let value = toml::from_str::<Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+
+    let key = key.replace('-', "_");
+    let mut path = key.split('.').peekable();
+    let mut table = table;
+    while let Some(part) = path.next() {
+        if part.is_empty() {
+            bail!("invalid option --{key}");
+        }
+        if path.peek().is_none() {
+            table.insert(part.to_string(), value);
+            return Ok(());
+        }
+        let entry = table
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = entry
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("--{key}: {part} is not a table"))?;
+    }
+    bail!("invalid option --{key}")
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<Duration>>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    if text == "off" {
+        return Ok(Some(None));
+    }
+    parse_duration(&text)
+        .map(|duration| Some(Some(duration)))
+        .map_err(de::Error::custom)
+}
+
+fn optional_durations<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Option<Duration>>, D::Error> {
+    let texts = HashMap::<String, String>::deserialize(deserializer)?;
+    texts
+        .into_iter()
+        .map(|(key, text)| match text.as_str() {
+            "off" => Ok((key, None)),
+            _ => parse_duration(&text).map(|duration| (key, Some(duration))),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(de::Error::custom)
+}
+
+fn required_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text).map(Some).map_err(de::Error::custom)
+}
+
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid =
+        || format!("invalid duration {text:?}, expected e.g. \"500ms\", \"30s\" or \"5m\"");
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(invalid)?;
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit.trim() {
+        "us" => number / 1_000_000.0,
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
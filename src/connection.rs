@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::sync::CancellationToken;
+
+use crate::channels::Channels;
+use crate::codec::Codec;
+use crate::config::SlowClient;
+use crate::envelope::{self, ServerMessage};
+use crate::frame::Encoded;
+use crate::rt::Instant;
+use crate::{Response, Server};
+
+/// A handle to one client session that can outlive the handler it was taken
+/// from, used to send the client messages it didn't ask for.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    id: u64,
+    peer_addr: SocketAddr,
+    outbound: Outbound,
+    max_message_length: usize,
+    codec: Codec,
+    counters: Arc<Counters>,
+}
+
+/// What a session has done so far, as [`Connection::stats`] reports it.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// Requests, streams and uploads the client has started.
+    pub requests: u64,
+    /// Requests that failed and frames rejected before dispatch.
+    pub errors: u64,
+    /// Frames that couldn't be decoded, counted among `errors` too.
+    pub malformed: u64,
+    /// Payload bytes received, after decompression.
+    pub bytes_received: u64,
+    /// Payload bytes sent, before compression.
+    pub bytes_sent: u64,
+    pub connected_for: Duration,
+    /// Time since the client last sent a frame.
+    pub idle_for: Duration,
+    pub codec: Codec,
+}
+
+/// A session's running totals, shared by every handle to it and its writer.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    malformed: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    connected: Instant,
+    /// When the client last sent a frame, in milliseconds after `connected`.
+    last_active: AtomicU64,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            connected: Instant::now(),
+            last_active: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        let since = self.connected.elapsed().as_millis() as u64;
+        self.last_active.store(since, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+impl Connection {
+    /// A connection for requests that don't arrive over a session, with
+    /// nowhere for pushes to go.
+    pub(crate) fn detached(server: &Server, peer_addr: SocketAddr, codec: Codec) -> Self {
+        let (tx, _) = mpsc::channel(1);
+        let config = server.config();
+        Self::new(
+            server.next_connection_id.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            Outbound::new(tx, config.slow_client),
+            config.max_message_length,
+            codec,
+        )
+    }
+
+    pub(crate) fn new(
+        id: u64,
+        peer_addr: SocketAddr,
+        outbound: Outbound,
+        max_message_length: usize,
+        codec: Codec,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            outbound,
+            max_message_length,
+            codec,
+            counters: Arc::new(Counters::new()),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The longest message that can be sent to this client: the frame limit,
+    /// or the message limit if the client negotiated chunking.
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// The payload codec negotiated with this client.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Requests, streams and uploads the client has started so far.
+    pub fn request_count(&self) -> u64 {
+        self.counters.requests.load(Ordering::Relaxed)
+    }
+
+    /// The session's totals so far, for finding the clients that are
+    /// keeping the server busy.
+    pub fn stats(&self) -> ConnectionStats {
+        let counters = &self.counters;
+        let connected_for = counters.connected.elapsed();
+        let last_active = Duration::from_millis(counters.last_active.load(Ordering::Relaxed));
+        ConnectionStats {
+            requests: counters.requests.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            malformed: counters.malformed.load(Ordering::Relaxed),
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            connected_for,
+            idle_for: connected_for.saturating_sub(last_active),
+            codec: self.codec,
+        }
+    }
+
+    pub(crate) fn count_requests(&self, n: usize) {
+        self.counters
+            .requests
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_error(&self) {
+        self.counters.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a frame that couldn't be decoded, returning how many there
+    /// have been.
+    pub(crate) fn count_malformed(&self) -> u64 {
+        self.count_error();
+        self.counters.malformed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Adds the totals of the session this connection resumed to its own.
+    pub(crate) fn carry_over(&self, requests: u64, errors: u64, malformed: u64) {
+        let counters = &self.counters;
+        counters.requests.fetch_add(requests, Ordering::Relaxed);
+        counters.errors.fetch_add(errors, Ordering::Relaxed);
+        counters.malformed.fetch_add(malformed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.outbound.close.is_cancelled() || self.outbound.tx.is_closed()
+    }
+
+    /// Asks the session to disconnect the client once its in-flight requests are cancelled.
+    pub fn close(&self) {
+        self.outbound.close.cancel();
+    }
+
+    pub(crate) async fn closed(&self) {
+        self.outbound.close.cancelled().await
+    }
+
+    /// Queues `message` for delivery on the client's `notifications()` stream.
+    pub async fn push(&self, message: Box<dyn Response>) -> Result<()> {
+        self.send_message(&ServerMessage::Push(message)).await
+    }
+
+    /// Queues a message the client didn't ask for.
+    pub(crate) async fn send_message(&self, message: &ServerMessage) -> Result<()> {
+        self.push_encoded(self.encode(message)?).await
+    }
+
+    /// `message` as this client's codec writes it.
+    pub(crate) fn encode(&self, message: &ServerMessage) -> Result<Encoded> {
+        Ok(envelope::try_encode(
+            self.codec,
+            message,
+            self.max_message_length,
+        )?)
+    }
+
+    /// Queues a message the client can do without if there is room for it right now.
+    pub(crate) fn try_send_message(&self, message: &ServerMessage) -> Result<()> {
+        self.outbound.try_send(self.encode(message)?)
+    }
+
+    /// Queues a message the client didn't ask for, already encoded for it.
+    pub(crate) async fn push_encoded(&self, bytes: Encoded) -> Result<()> {
+        self.outbound.push(bytes).await
+    }
+}
+
+/// The sending half of a session's outbound queue, applying the server's
+/// `SlowClient` policy when it is full.
+#[derive(Clone, Debug)]
+pub(crate) struct Outbound {
+    tx: mpsc::Sender<Encoded>,
+    policy: SlowClient,
+    close: CancellationToken,
+    /// The session's other logical channels, for sessions that have them.
+    channels: Option<Arc<Channels<Encoded>>>,
+}
+
+impl Outbound {
+    pub(crate) fn new(tx: mpsc::Sender<Encoded>, policy: SlowClient) -> Self {
+        Self {
+            tx,
+            policy,
+            close: CancellationToken::new(),
+            channels: None,
+        }
+    }
+
+    /// The queue for channel 0 of `channels`, from which the others can be had
+    /// with `channel`.
+    pub(crate) fn with_channels(channels: Arc<Channels<Encoded>>, policy: SlowClient) -> Self {
+        Self {
+            tx: channels.sender(0),
+            policy,
+            close: CancellationToken::new(),
+            channels: Some(channels),
+        }
+    }
+
+    /// The queue for `channel`'s frames, which share the session's `SlowClient`
+    /// policy and closing.
+    pub(crate) fn channel(&self, channel: u32) -> Self {
+        match &self.channels {
+            Some(channels) if channel != 0 => Self {
+                tx: channels.sender(channel),
+                ..self.clone()
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// Queues a reply or stream item, which is only ever dropped along with the connection.
+    pub(crate) async fn send(&self, bytes: impl Into<Encoded>) -> Result<()> {
+        let bytes = bytes.into();
+        match self.policy {
+            SlowClient::Block | SlowClient::DropPushes => self
+                .tx
+                .send(bytes)
+                .await
+                .map_err(|_| anyhow!("connection closed")),
+            SlowClient::Disconnect => self.send_or_disconnect(bytes),
+        }
+    }
+
+    /// Resolves once frames can no longer reach the client, its writer
+    /// having stopped.
+    pub(crate) async fn disconnected(&self) {
+        self.tx.closed().await
+    }
+
+    /// Queues a frame if there is room for it right now.
+    pub(crate) fn try_send(&self, bytes: impl Into<Encoded>) -> Result<()> {
+        self.tx
+            .try_send(bytes.into())
+            .map_err(|_| anyhow!("outbound queue full or closed"))
+    }
+
+    /// Queues a frame the client didn't ask for.
+    pub(crate) async fn push(&self, bytes: impl Into<Encoded>) -> Result<()> {
+        let bytes = bytes.into();
+        match self.policy {
+            SlowClient::Block => self.send(bytes).await,
+            SlowClient::DropPushes => self.tx.try_send(bytes).map_err(|e| match e {
+                TrySendError::Full(_) => {
+                    tracing::debug!("Dropping push: outbound queue full");
+                    anyhow!("outbound queue full")
+                }
+                TrySendError::Closed(_) => anyhow!("connection closed"),
+            }),
+            SlowClient::Disconnect => self.send_or_disconnect(bytes),
+        }
+    }
+
+    fn send_or_disconnect(&self, bytes: Encoded) -> Result<()> {
+        self.tx.try_send(bytes).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                tracing::warn!("Disconnecting slow client: outbound queue full");
+                self.close.cancel();
+                anyhow!("outbound queue full")
+            }
+            TrySendError::Closed(_) => anyhow!("connection closed"),
+        })
+    }
+}
+
+/// Sends `message` to each of `connections`, returning how many it was
+/// queued for. The pushes wait for room side by side, so under
+/// `SlowClient::Block` a client slow to read holds up only its own copy.
+pub(crate) async fn fan_out(
+    connections: Vec<Connection>,
+    message: &ServerMessage,
+) -> Result<usize> {
+    let pushes = encode_each(connections, message)?
+        .into_iter()
+        .map(|(connection, bytes)| async move { connection.outbound.push(bytes).await.is_ok() });
+    let delivered = futures::future::join_all(pushes).await;
+    Ok(delivered.into_iter().filter(|&queued| queued).count())
+}
+
+/// `message` as each of `connections` takes it, encoded once per codec in
+/// use against the smallest message limit any of them accepts.
+pub(crate) fn encode_each(
+    connections: Vec<Connection>,
+    message: &ServerMessage,
+) -> Result<Vec<(Connection, Encoded)>> {
+    let max_message_length = connections
+        .iter()
+        .map(Connection::max_message_length)
+        .min()
+        .unwrap_or(usize::MAX);
+
+    let mut encoded: HashMap<Codec, Encoded> = HashMap::new();
+    connections
+        .into_iter()
+        .map(|connection| {
+            let bytes = match encoded.get(&connection.codec) {
+                Some(bytes) => bytes.clone(),
+                None => {
+                    let bytes =
+                        envelope::try_encode(connection.codec, message, max_message_length)?;
+                    encoded.insert(connection.codec, bytes.clone());
+                    bytes
+                }
+            };
+            Ok((connection, bytes))
+        })
+        .collect()
+}
+
+/// Every live session on a server, keyed by connection id.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionRegistry {
+    connections: Arc<Mutex<HashMap<u64, Connection>>>,
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn insert(&self, connection: Connection) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.insert(connection.id(), connection);
+    }
+
+    pub(crate) fn remove(&self, connection_id: u64) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+
+    pub(crate) fn get(&self, connection_id: u64) -> Option<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&connection_id)
+            .cloned()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<Connection> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+}
@@ -0,0 +1,268 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::envelope::{Progress, ProgressFrame, ServerMessage};
+use crate::frame::Compression;
+use crate::pubsub::TopicRegistry;
+use crate::signing::Signer;
+use crate::tenant;
+use crate::{Connection, Extensions, Identity, ProtocolError, Server};
+
+/// Per-connection information handed to every `Request::handle` call.
+#[derive(Clone)]
+pub struct Context {
+    pub peer_addr: SocketAddr,
+    pub connection_id: u64,
+    pub compression: Compression,
+    connection: Connection,
+    cancellation: CancellationToken,
+    identity: Option<Arc<Identity>>,
+    server: Server,
+    replaying: bool,
+    deadline: Option<Instant>,
+    /// The call to report `progress` under, when the client can take it.
+    progress: Option<u64>,
+    signer: Option<Arc<Signer>>,
+    metadata: Arc<Metadata>,
+}
+
+/// The metadata of one call, shared by the contexts its requests run with.
+#[derive(Default)]
+struct Metadata {
+    /// What the client sent, and what middleware added to it.
+    request: Mutex<BTreeMap<String, String>>,
+    /// What goes back with the reply.
+    response: Mutex<BTreeMap<String, String>>,
+}
+
+impl Context {
+    pub(crate) fn new(
+        connection: Connection,
+        compression: Compression,
+        identity: Option<Identity>,
+        server: &Server,
+    ) -> Self {
+        Self {
+            peer_addr: connection.peer_addr(),
+            connection_id: connection.id(),
+            compression,
+            connection,
+            cancellation: CancellationToken::new(),
+            identity: identity.map(Arc::new),
+            server: server.clone(),
+            replaying: false,
+            deadline: None,
+            progress: None,
+            signer: None,
+            metadata: Arc::default(),
+        }
+    }
+
+    /// Marks this context as replaying a journaled request.
+    pub(crate) fn replaying(mut self) -> Self {
+        self.replaying = true;
+        self
+    }
+
+    /// A copy of this context for a single request that must finish by `deadline`.
+    pub(crate) fn with_deadline(&self, deadline: Option<Instant>) -> Self {
+        Self {
+            deadline,
+            ..self.clone()
+        }
+    }
+
+    /// A copy of this context for call `id`, whose client takes progress reports.
+    pub(crate) fn with_progress(&self, id: u64) -> Self {
+        Self {
+            progress: Some(id),
+            ..self.clone()
+        }
+    }
+
+    /// A copy of this context for the request inside a `Signed` one, made by `signer`.
+    pub(crate) fn with_signer(&self, signer: Arc<Signer>) -> Self {
+        Self {
+            signer: Some(signer),
+            ..self.clone()
+        }
+    }
+
+    /// A copy of this context for a call that came with `metadata`, with
+    /// none of its own to reply with yet.
+    pub(crate) fn with_metadata(&self, metadata: BTreeMap<String, String>) -> Self {
+        Self {
+            metadata: Arc::new(Metadata {
+                request: Mutex::new(metadata),
+                response: Mutex::default(),
+            }),
+            ..self.clone()
+        }
+    }
+
+    /// A copy of this context for a job, which runs past the call that
+    /// submitted it until `cancellation` stops it.
+    pub(crate) fn for_job(&self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation,
+            deadline: None,
+            progress: None,
+            ..self.clone()
+        }
+    }
+
+    /// A copy of this context for a single request that can be cancelled on its own.
+    pub(crate) fn with_cancellation(&self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation,
+            ..self.clone()
+        }
+    }
+
+    /// The session this request arrived on, e.g. to keep for later pushes.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Who the client authenticated as; `None` when the server has no `Authenticator`.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_deref()
+    }
+
+    /// The tenant the client belongs to, on a server in multi-tenant mode.
+    pub fn tenant(&self) -> Option<&str> {
+        match &self.identity {
+            Some(identity) if self.server.multi_tenant => identity.tenant.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// `name` under the client's tenant's prefix in multi-tenant mode, and
+    /// as it is otherwise: what to key anything the client names by so
+    /// tenants can't reach each other's. Fails with `PermissionDenied` for
+    /// a client without a tenant on a multi-tenant server.
+    pub fn scoped<'a>(&self, name: &'a str) -> Result<Cow<'a, str>, ProtocolError> {
+        match self.tenant() {
+            Some(tenant) => Ok(Cow::Owned(tenant::scoped(tenant, name))),
+            None if self.server.multi_tenant => Err(tenant::missing()),
+            None => Ok(Cow::Borrowed(name)),
+        }
+    }
+
+    /// Whose signature the request came under, once `VerifySignatures` has
+    /// checked it; `None` for requests that came unsigned.
+    pub fn signer(&self) -> Option<&Signer> {
+        self.signer.as_deref()
+    }
+
+    /// The value of `key` in the call's metadata: set by the client's
+    /// interceptors, or by middleware with `set_metadata`.
+    pub fn metadata(&self, key: &str) -> Option<String> {
+        self.metadata.request.lock().unwrap().get(key).cloned()
+    }
+
+    /// Sets `key` in the call's metadata, for the middleware and handler
+    /// after this one, e.g. a tenant worked out from the identity.
+    pub fn set_metadata(&self, key: impl Into<String>, value: impl Into<String>) {
+        let mut request = self.metadata.request.lock().unwrap();
+        request.insert(key.into(), value.into());
+    }
+
+    /// Sets `key` in the metadata the call's reply goes back with, where
+    /// the client's interceptors can read it. Every request in a batch
+    /// shares the one reply.
+    pub fn set_response_metadata(&self, key: impl Into<String>, value: impl Into<String>) {
+        let mut response = self.metadata.response.lock().unwrap();
+        response.insert(key.into(), value.into());
+    }
+
+    pub(crate) fn take_response_metadata(&self) -> BTreeMap<String, String> {
+        std::mem::take(&mut self.metadata.response.lock().unwrap())
+    }
+
+    /// Whether the request is being replayed from the server's
+    /// `RequestJournal` at startup rather than sent by a client.
+    pub fn is_replay(&self) -> bool {
+        self.replaying
+    }
+
+    /// When the request will be given up on: the sooner of its type's
+    /// timeout and the client's deadline. `None` if it may run indefinitely.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// How long the request has left before `deadline`, e.g. to pass on
+    /// to calls the handler makes in turn.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Tells the client how far the request has got, ahead of its response,
+    /// for callers of `Client::call_with_progress`. Reports are dropped
+    /// rather than waited on when the connection is backed up, and go
+    /// nowhere for clients that can't take them or requests outside a call.
+    pub fn progress(&self, progress: Progress) {
+        let Some(id) = self.progress else { return };
+        let message = ServerMessage::Progress(ProgressFrame { id, progress });
+        if let Err(e) = self.connection.try_send_message(&message) {
+            tracing::trace!(id, error = %e, "Dropped progress report");
+        }
+    }
+
+    /// The server this connection belongs to, e.g. to look up other sessions.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn topics(&self) -> &TopicRegistry {
+        &self.server.topics
+    }
+
+    /// Whether the client has cancelled this request or gone away.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Resolves once the request is cancelled, for handlers that want to
+    /// stop cleanly instead of being dropped mid-await.
+    pub async fn cancelled(&self) {
+        self.cancellation.cancelled().await
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// The application state registered with `ServerBuilder::state`, if it is a `T`.
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.server.state.downcast_ref()
+    }
+
+    /// The extension of type `T` registered with `ServerBuilder::extension`.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.server.extensions.get()
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.server.extensions
+    }
+}
+
+impl std::fmt::Debug for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context")
+            .field("peer_addr", &self.peer_addr)
+            .field("connection_id", &self.connection_id)
+            .field("compression", &self.compression)
+            .field("identity", &self.identity)
+            .field("signer", &self.signer)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,218 @@
+//! Fire-and-forget requests over UDP, for small messages that can afford to
+//! be lost, such as telemetry and discovery beacons.
+//!
+//! Each datagram is one codec byte, the index of its codec in `Codec::ALL`,
+//! followed by a [`Datagram`] in that codec. There is no handshake, no
+//! session and no reply: the server runs the requests through the usual
+//! middleware and handlers and drops whatever they answer. Datagrams pass
+//! the IP filter, rate limit and `max_in_flight` like calls do, and are
+//! dropped rather than refused when they don't. Nothing on UDP can
+//! authenticate, so a server with an `Authenticator` drops them all.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::net::{ToSocketAddrs, UdpSocket, lookup_host};
+use tracing::Instrument;
+
+use crate::codec::Codec;
+use crate::envelope::TraceContext;
+use crate::frame::Compression;
+use crate::rate_limit::RateKey;
+use crate::supervisor::Connections;
+use crate::{Connection, Context, Request, Server, metrics, server, trace, type_ids};
+
+/// The largest payload a UDP datagram can carry over IPv4.
+pub const MAX_DATAGRAM_LENGTH: usize = 65_507;
+
+/// What a datagram carries: the part of a `RequestFrame` that means
+/// something without a session to answer on.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Datagram {
+    pub trace: Option<TraceContext>,
+    #[serde(with = "type_ids::with::vec")]
+    pub requests: Vec<Box<dyn Request>>,
+}
+
+impl Datagram {
+    /// The datagram as sent on the wire, in `codec`.
+    pub fn encode(&self, codec: Codec) -> Result<Vec<u8>> {
+        let tag = Codec::ALL.iter().position(|c| *c == codec).unwrap_or(0) as u8;
+        let mut bytes = vec![tag];
+        bytes.extend(codec.encode(self)?);
+        if bytes.len() > MAX_DATAGRAM_LENGTH {
+            bail!(
+                "datagram is {} bytes, over the limit of {MAX_DATAGRAM_LENGTH}",
+                bytes.len()
+            );
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a datagram as it arrived, returning the codec it was sent in.
+    pub fn decode(bytes: &[u8]) -> Result<(Codec, Self)> {
+        let (&tag, payload) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("empty datagram"))?;
+        let codec = *Codec::ALL
+            .get(usize::from(tag))
+            .ok_or_else(|| anyhow!("unknown codec {tag}"))?;
+        Ok((codec, codec.decode(payload)?))
+    }
+}
+
+/// Handles the datagrams arriving at `socket` until `shutdown` resolves or
+/// `server` starts shutting down, then waits up to `drain_timeout` for the
+/// requests still running.
+pub async fn serve(
+    server: Server,
+    socket: UdpSocket,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let name = format!("udp:{}", socket.local_addr()?);
+    let server = server.child();
+    let mut running = Connections::new(&name);
+    let mut buf = vec![0; MAX_DATAGRAM_LENGTH + 1];
+    tokio::pin!(shutdown);
+    tracing::info!(listener = %name, "Listening");
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, addr) = match received {
+                    Ok(received) => received,
+                    // An ICMP error for an earlier send, on some platforms; not fatal.
+                    Err(e) => {
+                        tracing::debug!(listener = %name, error = %e, "Failed to receive datagram");
+                        continue;
+                    }
+                };
+                if let Some(handle) = admit(&server, &buf[..len], addr) {
+                    running.spawn(addr, handle);
+                }
+            }
+            Some(_) = running.join_next(), if !running.is_empty() => {}
+            _ = &mut shutdown => break,
+            _ = server.shutdown.cancelled() => break,
+        }
+    }
+
+    server.shutdown.cancel();
+    tracing::info!(listener = %name, active = running.len(), "Draining datagrams");
+    running.drain(server.config().drain_timeout).await;
+    Ok(())
+}
+
+/// Reads a datagram and returns the task handling it, or `None` if it is dropped.
+fn admit(
+    server: &Server,
+    bytes: &[u8],
+    addr: SocketAddr,
+) -> Option<impl Future<Output = ()> + Send + 'static> {
+    let dropped = |reason: &'static str| {
+        tracing::debug!(%addr, reason, "Dropping datagram");
+        metrics::datagram_dropped(reason);
+        None
+    };
+    if server.authenticator.is_some() {
+        return dropped("unauthenticated");
+    }
+    if let Some(filter) = &server.ip_filter
+        && !filter.permits(addr.ip())
+    {
+        return dropped("ip_filter");
+    }
+    if bytes.len() > MAX_DATAGRAM_LENGTH {
+        return dropped("too_long");
+    }
+    let (codec, datagram) = match Datagram::decode(bytes) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            tracing::debug!(%addr, error = %format!("{e:#}"), "Undecodable datagram");
+            return dropped("malformed");
+        }
+    };
+    let cost = datagram.requests.len();
+    if let Some(limiter) = server.rate_limiter()
+        && limiter
+            .acquire(&RateKey::new(None, addr.ip()), cost as u32)
+            .is_err()
+    {
+        return dropped("rate_limited");
+    }
+    let Some(slot) = server.load.try_start(cost, server.config().max_in_flight) else {
+        return dropped("busy");
+    };
+    metrics::bytes_received(bytes.len());
+    let len = bytes.len();
+
+    let server = server.clone();
+    let span = trace::sampled(
+        server.config().trace_sample_rate,
+        &datagram.trace,
+        || tracing::info_span!("datagram", %addr),
+    );
+    Some(
+        async move {
+            let ctx = Context::new(
+                Connection::detached(&server, addr, codec),
+                Compression::None,
+                None,
+                &server,
+            );
+            ctx.connection().count_requests(cost);
+            ctx.connection().counters().received(len);
+            for request in datagram.requests {
+                let (result, (request_type, _)) =
+                    server::dispatch(request, &ctx, None, &server.middleware).await;
+                if let Err(err) = result {
+                    tracing::debug!(request_type, error = %err, "Datagram request failed");
+                }
+            }
+            drop(slot);
+        }
+        .instrument(span),
+    )
+}
+
+/// Sends requests to a server's datagram socket, without waiting to hear
+/// whether they arrived.
+pub struct DatagramSender {
+    socket: UdpSocket,
+    codec: Codec,
+}
+
+impl DatagramSender {
+    /// Sends to the server at `addr`, encoding requests with `codec`.
+    pub async fn connect(addr: impl ToSocketAddrs, codec: Codec) -> Result<Self> {
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("address resolved to nothing"))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("failed to connect to {addr}"))?;
+        Ok(Self { socket, codec })
+    }
+
+    pub async fn send(&self, request: Box<dyn Request>) -> Result<()> {
+        self.send_batch(vec![request]).await
+    }
+
+    /// Sends several requests in one datagram; the server runs them in order.
+    pub async fn send_batch(&self, requests: Vec<Box<dyn Request>>) -> Result<()> {
+        let datagram = Datagram {
+            trace: trace::current(),
+            requests,
+        };
+        self.socket.send(&datagram.encode(self.codec)?).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Where acked deliveries go when they can't be delivered: messages an
+//! acked subscriber was sent `ServerConfig::max_delivery_attempts` times
+//! without acking, and ones pushed out of its buffer by newer messages.
+//!
+//! Each dead letter keeps the message with its subscriber, topic, how many
+//! times it was sent and why it was given up on. [`ListDeadLetters`] shows
+//! a client its own, and [`RedriveDeadLetters`] hands them back to their
+//! subscribers to be delivered afresh. The server keeps up to
+//! `dead_letter_limit` of them, dropping the oldest past that.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::builtin::unix_time_us;
+use crate::metrics;
+use crate::pubsub::{self, SubscriberKey};
+use crate::rt::SystemTime;
+use crate::schema::Describe;
+use crate::{Context, Identity, Request, Response, TypedRequest};
+
+/// The most dead letters one `ListDeadLetters` returns.
+pub const MAX_LIST: usize = 1000;
+
+/// A message given up on, as the server keeps it.
+pub(crate) struct Letter {
+    pub(crate) id: u64,
+    pub(crate) key: SubscriberKey,
+    pub(crate) topic: String,
+    /// The message in bincode, as `AckedMessage` carries it.
+    pub(crate) message: Bytes,
+    pub(crate) attempts: u32,
+    pub(crate) reason: String,
+    pub(crate) dead_at_us: u64,
+}
+
+/// Every dead letter on a server, oldest first.
+pub(crate) struct DeadLetters {
+    inner: Mutex<Inner>,
+    limit: usize,
+}
+
+struct Inner {
+    letters: VecDeque<Letter>,
+    next_id: u64,
+}
+
+impl DeadLetters {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                letters: VecDeque::new(),
+                next_id: 1,
+            }),
+            limit,
+        }
+    }
+
+    /// Keeps a message `key` was sent `attempts` times, for `reason`.
+    pub(crate) fn insert(
+        &self,
+        key: SubscriberKey,
+        topic: String,
+        message: Bytes,
+        attempts: u32,
+        reason: String,
+    ) {
+        tracing::warn!(
+            subscriber = key.1,
+            topic,
+            attempts,
+            reason,
+            "Moving message to dead letters"
+        );
+        metrics::message_dead_lettered();
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.letters.len() >= self.limit
+            && let Some(dropped) = inner.letters.pop_front()
+        {
+            tracing::warn!(id = dropped.id, "Dropping dead letter: limit reached");
+        }
+        inner.letters.push_back(Letter {
+            id,
+            key,
+            topic,
+            message,
+            attempts,
+            reason,
+            dead_at_us: unix_time_us(SystemTime::now()),
+        });
+    }
+
+    /// Up to `limit` of the dead letters with ids after `after` that pass
+    /// `owned`, oldest first.
+    fn list(
+        &self,
+        after: u64,
+        limit: usize,
+        owned: impl Fn(&SubscriberKey) -> bool,
+    ) -> Result<Vec<DeadLetter>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .letters
+            .iter()
+            .filter(|letter| letter.id > after && owned(&letter.key))
+            .take(limit)
+            .map(|letter| {
+                Ok(DeadLetter {
+                    id: letter.id,
+                    subscriber: letter.key.1.clone(),
+                    topic: letter.topic.clone(),
+                    message: bincode::deserialize(&letter.message)?,
+                    attempts: letter.attempts,
+                    reason: letter.reason.clone(),
+                    dead_at_us: letter.dead_at_us,
+                })
+            })
+            .collect()
+    }
+
+    /// Takes out the dead letters `ids` that pass `owned`.
+    fn take(&self, ids: &[u64], owned: impl Fn(&SubscriberKey) -> bool) -> Vec<Letter> {
+        let mut inner = self.inner.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut inner.letters)
+            .into_iter()
+            .partition(|letter| ids.contains(&letter.id) && owned(&letter.key));
+        inner.letters = kept;
+        taken.into()
+    }
+
+    /// Puts back letters that couldn't be redriven, in id order.
+    fn restore(&self, letters: Vec<Letter>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.letters.extend(letters);
+        inner
+            .letters
+            .make_contiguous()
+            .sort_by_key(|letter| letter.id);
+    }
+}
+
+/// Whether a dead letter kept under `key` is the caller's to see.
+fn owned_by(ctx: &Context) -> impl Fn(&SubscriberKey) -> bool + '_ {
+    let subject = ctx.identity().map(Identity::key);
+    move |key| key.0.as_deref() == subject.as_deref()
+}
+
+/// A message an acked subscriber never acked, as [`ListDeadLetters`] shows it.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub subscriber: String,
+    pub topic: String,
+    pub message: Box<dyn Response>,
+    /// How many times the message was sent to the subscriber.
+    pub attempts: u32,
+    /// Why it was given up on: the reason of the last `Nack`, or else what
+    /// the server ran out of.
+    pub reason: String,
+    /// When it was given up on, in microseconds since the Unix epoch.
+    pub dead_at_us: u64,
+}
+
+/// Lists the caller's dead letters, optionally only those of one acked
+/// subscriber, oldest first. Page through them by passing the last id seen
+/// as `after`.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = ListDeadLettersResponse)]
+pub struct ListDeadLetters {
+    pub subscriber: Option<String>,
+    pub after: u64,
+    /// Capped at [`MAX_LIST`].
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ListDeadLettersResponse {
+    pub letters: Vec<DeadLetter>,
+}
+
+#[typetag::serde]
+impl Response for ListDeadLettersResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for ListDeadLetters {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let owned = owned_by(ctx);
+        let mut letters =
+            ctx.topics()
+                .dead_letters()
+                .list(self.after, self.limit.min(MAX_LIST), |key| {
+                    owned(key) && self.subscriber.as_ref().is_none_or(|name| *name == key.1)
+                })?;
+        for letter in &mut letters {
+            letter.topic = pubsub::shown(&letter.topic, ctx.server().is_multi_tenant()).to_string();
+        }
+        Ok(Box::new(ListDeadLettersResponse { letters }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for ListDeadLetters {
+    type Response = ListDeadLettersResponse;
+}
+
+/// Hands the caller's dead letters `ids` back to their subscribers, which
+/// get them as new `AckedMessage`s with a fresh count of attempts. Letters
+/// whose subscriber has since gone stay where they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = RedriveDeadLettersResponse)]
+pub struct RedriveDeadLetters {
+    pub ids: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct RedriveDeadLettersResponse {
+    pub redriven: usize,
+}
+
+#[typetag::serde]
+impl Response for RedriveDeadLettersResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for RedriveDeadLetters {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let topics = ctx.topics();
+        let dead_letters = topics.dead_letters();
+        let letters = dead_letters.take(&self.ids, owned_by(ctx));
+        let taken = letters.len();
+        let (stranded, pushes) = topics.redrive(letters);
+        let redriven = taken - stranded.len();
+        if !stranded.is_empty() {
+            dead_letters.restore(stranded);
+        }
+        for (connection, message) in pushes {
+            // A subscriber that has gone away gets it again when it's back.
+            let _ = connection.send_message(&message).await;
+        }
+        Ok(Box::new(RedriveDeadLettersResponse { redriven }))
+    }
+}
+
+impl TypedRequest for RedriveDeadLetters {
+    type Response = RedriveDeadLettersResponse;
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context as _, Result};
+
+/// Where a [`Balancer`](crate::Balancer) finds its endpoints when they come
+/// from DNS rather than a fixed list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discovery {
+    /// A `host:port` whose A and AAAA records are the endpoints.
+    Host(String),
+    /// An SRV record name such as `_myproto._tcp.example.com`. The targets of
+    /// the lowest priority present are the endpoints; weights are ignored, as
+    /// the balancer spreads calls itself.
+    #[cfg(feature = "srv")]
+    Srv(String),
+}
+
+impl Discovery {
+    /// Looks up the current endpoint addresses.
+    pub async fn resolve(&self) -> Result<BTreeSet<String>> {
+        match self {
+            Discovery::Host(target) => {
+                let addrs = tokio::net::lookup_host(target.as_str())
+                    .await
+                    .with_context(|| format!("failed to resolve {target}"))?;
+                Ok(addrs.map(|addr| addr.to_string()).collect())
+            }
+            #[cfg(feature = "srv")]
+            Discovery::Srv(name) => resolve_srv(name).await,
+        }
+    }
+}
+
+#[cfg(feature = "srv")]
+async fn resolve_srv(name: &str) -> Result<BTreeSet<String>> {
+    use hickory_resolver::TokioResolver;
+    use hickory_resolver::proto::rr::RData;
+
+    let resolver = TokioResolver::builder_tokio()?.build()?;
+    let lookup = resolver
+        .srv_lookup(name)
+        .await
+        .with_context(|| format!("failed to look up SRV records for {name}"))?;
+    let records: Vec<_> = lookup
+        .answers()
+        .iter()
+        .filter_map(|record| match &record.data {
+            RData::SRV(srv) => Some(srv),
+            _ => None,
+        })
+        .collect();
+    let Some(priority) = records.iter().map(|srv| srv.priority).min() else {
+        return Ok(BTreeSet::new());
+    };
+    Ok(records
+        .iter()
+        .filter(|srv| srv.priority == priority)
+        .map(|srv| {
+            let host = srv.target.to_ascii();
+            format!("{}:{}", host.trim_end_matches('.'), srv.port)
+        })
+        .collect())
+}
@@ -0,0 +1,103 @@
+//! Running a server inside an application or an integration test, on a
+//! task of its own, without setting up listeners and shutdown signals.
+//!
+//! ```ignore
+//! let handle = Server::builder().router(router).build().spawn().await?;
+//! let client = handle.connect().await?;
+//! println!("also reachable at {}", handle.local_addr());
+//! handle.shutdown().await?;
+//! ```
+//!
+//! [`ServerHandle::connect`] opens a session over an in-memory stream, with
+//! no socket involved; the port `Server::spawn` binds is for clients in
+//! other processes. The server drains and stops when the handle is shut
+//! down or dropped.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::{Context as _, Result, anyhow};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::{Client, ClientConfig, Server};
+
+/// Bytes either direction of an in-memory session buffers before writes wait.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A server serving on a task of its own, from `Server::spawn`.
+pub struct ServerHandle {
+    server: Server,
+    local_addr: SocketAddr,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl Server {
+    /// Serves on a port the OS picks on `127.0.0.1`, in the background.
+    pub async fn spawn(&self) -> Result<ServerHandle> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .context("failed to bind a port for the embedded server")?;
+        self.spawn_on(listener)
+    }
+
+    /// Serves on `listener` in the background.
+    pub fn spawn_on(&self, listener: TcpListener) -> Result<ServerHandle> {
+        let local_addr = listener.local_addr()?;
+        let server = self.clone();
+        let task =
+            tokio::spawn(async move { server.serve(listener, std::future::pending::<()>()).await });
+        Ok(ServerHandle {
+            server: self.clone(),
+            local_addr,
+            task: Some(task),
+        })
+    }
+}
+
+impl ServerHandle {
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// The address clients outside the process connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Opens a session over an in-memory stream and a client connected to
+    /// it, which appears to the server as `127.0.0.1:0`.
+    pub async fn connect(&self) -> Result<Client> {
+        self.connect_with(ClientConfig::default()).await
+    }
+
+    pub async fn connect_with(&self, config: ClientConfig) -> Result<Client> {
+        let (client, session) = tokio::io::duplex(BUFFER_SIZE);
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            let peer_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            if let Err(e) = server.handle_client(session, peer_addr).await {
+                tracing::debug!(error = %e, "In-memory session ended with an error");
+            }
+        });
+        Client::with_config(client, config).await
+    }
+
+    /// Stops accepting, drains the sessions as `Server::drain` does, and
+    /// waits for the server to stop, returning the error it stopped with.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.server.begin_drain();
+        let Some(task) = self.task.take() else {
+            return Ok(());
+        };
+        task.await
+            .map_err(|e| anyhow!("embedded server task failed: {e}"))?
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if self.task.is_some() {
+            self.server.begin_drain();
+        }
+    }
+}
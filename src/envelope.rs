@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::frame::Encoded;
+use crate::rt::{SystemTime, UNIX_EPOCH};
+use crate::type_ids::{self, Tagged};
+use crate::{
+    ErrorCode, ProtocolError, Request, Response, ResponseResult, StreamingRequest, UploadRequest,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ClientMessage {
+    Call(RequestFrame),
+    OpenStream(StreamRequestFrame),
+    OpenUpload(UploadRequestFrame),
+    UploadChunk(UploadFrame),
+    Ping(u64),
+    Pong(u64),
+    /// Abandons the call, stream or upload with this id.
+    Cancel(u64),
+    /// Lets the server send more of a stream opened with a `window`.
+    WindowUpdate(WindowUpdate),
+    /// Requests the server handles without answering.
+    Notify(NotifyFrame),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ServerMessage {
+    Reply(ResponseFrame),
+    Stream(StreamFrame),
+    /// A message the server sent on its own initiative.
+    Push(#[serde(with = "type_ids::with::boxed")] Box<dyn Response>),
+    Ping(u64),
+    Pong(u64),
+    GoAway(GoAway),
+    /// How far along the call with the same `id` is, sent to clients that
+    /// negotiated `Features::PROGRESS` before its reply.
+    Progress(ProgressFrame),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProgressFrame {
+    pub id: u64,
+    pub progress: Progress,
+}
+
+/// A long-running handler's report of how far it has got, from `Context::progress`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Percent done, from 0 to 100.
+    pub pct: f32,
+    pub note: Option<String>,
+}
+
+impl Progress {
+    pub fn new(pct: f32) -> Self {
+        Self { pct, note: None }
+    }
+
+    /// Adds a description of the current step, e.g. `"imported 40000 rows"`.
+    pub fn note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+/// Sent by the server, to clients that negotiated `Features::GO_AWAY`, when
+/// it stops reading from a connection it is about to close. Replies to
+/// calls already made may still follow; nothing sent after it is answered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GoAway {
+    pub reason: GoAwayReason,
+    pub message: String,
+    /// How long the server expects to be gone, for a client deciding when
+    /// to reconnect; set when it is drained by `Server::drain`.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoAwayReason {
+    /// The server is shutting down or draining; another server, or this one
+    /// later, can take the calls.
+    Shutdown,
+    /// The server dropped this client on purpose: an admin disconnected it,
+    /// or it read too slowly.
+    Disconnected,
+    IdleTimeout,
+    /// The server stopped hearing from the client.
+    HeartbeatTimeout,
+    /// The client sent something the server couldn't read.
+    ProtocolError,
+}
+
+impl GoAway {
+    pub fn new(reason: GoAwayReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
+impl std::fmt::Display for GoAway {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.reason, self.message)?;
+        if let Some(retry_after) = self.retry_after {
+            write!(f, " (retry after {retry_after:?})")?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientMessage {
+    /// The message's variant and the types it carries, e.g. `Call(Echo, Add)`.
+    pub fn kind(&self) -> String {
+        match self {
+            ClientMessage::Call(call) => {
+                let names: Vec<&str> = call.requests.iter().map(|r| type_name(&**r)).collect();
+                format!("Call({})", names.join(", "))
+            }
+            ClientMessage::OpenStream(open) => format!("OpenStream({})", type_name(&*open.request)),
+            ClientMessage::OpenUpload(open) => format!("OpenUpload({})", type_name(&*open.request)),
+            ClientMessage::UploadChunk(chunk) => match &chunk.item {
+                UploadItem::Data(data) => format!("UploadChunk(Data, {} bytes)", data.len()),
+                UploadItem::End => "UploadChunk(End)".to_string(),
+                UploadItem::Abort(_) => "UploadChunk(Abort)".to_string(),
+            },
+            ClientMessage::Ping(_) => "Ping".to_string(),
+            ClientMessage::Pong(_) => "Pong".to_string(),
+            ClientMessage::Cancel(_) => "Cancel".to_string(),
+            ClientMessage::WindowUpdate(_) => "WindowUpdate".to_string(),
+            ClientMessage::Notify(notify) => {
+                let names: Vec<&str> = notify.requests.iter().map(|r| type_name(&**r)).collect();
+                format!("Notify({})", names.join(", "))
+            }
+        }
+    }
+
+    /// Whether the message may be compressed: false when a request in it
+    /// opted out with `Request::compressible`.
+    pub fn compressible(&self) -> bool {
+        match self {
+            ClientMessage::Call(call) => call.requests.iter().all(|r| r.compressible()),
+            ClientMessage::Notify(notify) => notify.requests.iter().all(|r| r.compressible()),
+            _ => true,
+        }
+    }
+
+    /// The logical channel a call, stream, upload or notification was sent
+    /// on; the rest go with the call they're about, or on channel 0.
+    pub fn channel(&self) -> u32 {
+        match self {
+            ClientMessage::Call(call) => call.channel,
+            ClientMessage::OpenStream(open) => open.channel,
+            ClientMessage::OpenUpload(open) => open.channel,
+            ClientMessage::Notify(notify) => notify.channel,
+            _ => 0,
+        }
+    }
+}
+
+impl ServerMessage {
+    /// The message's variant and the types it carries, e.g. `Reply(EchoResponse, Err(Timeout))`.
+    pub fn kind(&self) -> String {
+        match self {
+            ServerMessage::Reply(reply) => {
+                let results: Vec<String> = reply
+                    .results
+                    .iter()
+                    .map(|result| match result {
+                        Ok(resp) => type_name(&**resp).to_string(),
+                        Err(err) => format!("Err({})", err.code),
+                    })
+                    .collect();
+                format!("Reply({})", results.join(", "))
+            }
+            ServerMessage::Stream(frame) => match &frame.item {
+                StreamItem::Data(resp) => format!("Stream(Data, {})", type_name(&**resp)),
+                StreamItem::End => "Stream(End)".to_string(),
+                StreamItem::Error(err) => format!("Stream(Err({}))", err.code),
+            },
+            ServerMessage::Push(resp) => format!("Push({})", type_name(&**resp)),
+            ServerMessage::Ping(_) => "Ping".to_string(),
+            ServerMessage::Pong(_) => "Pong".to_string(),
+            ServerMessage::GoAway(go_away) => format!("GoAway({:?})", go_away.reason),
+            ServerMessage::Progress(frame) => format!("Progress({}%)", frame.progress.pct),
+        }
+    }
+
+    /// Whether the message may be compressed: false when a response in it
+    /// opted out with `Response::compressible`.
+    pub fn compressible(&self) -> bool {
+        match self {
+            ServerMessage::Reply(reply) => reply.results.iter().all(|result| match result {
+                Ok(resp) => resp.compressible(),
+                Err(_) => true,
+            }),
+            ServerMessage::Stream(StreamFrame {
+                item: StreamItem::Data(resp),
+                ..
+            })
+            | ServerMessage::Push(resp) => resp.compressible(),
+            _ => true,
+        }
+    }
+}
+
+/// The name `value` is tagged with on the wire, looking through `Untyped`.
+fn type_name<T: ?Sized + Tagged>(value: &T) -> &str {
+    value
+        .untyped()
+        .map_or_else(|| value.tag(), |untyped| untyped.type_name.as_str())
+}
+
+/// A client-to-server call carrying one or more requests.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestFrame {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch after which the client stops waiting.
+    pub deadline: Option<u64>,
+    pub trace: Option<TraceContext>,
+    /// Run the requests one at a time, in order, instead of concurrently.
+    #[serde(default)]
+    pub sequential: bool,
+    /// The highest `Request::priority` among the requests.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Set by the client to have the server run the call at most once, and
+    /// answer repeats of it with the first call's results.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Set by the client's interceptors, for handlers to read with `Context::metadata`.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// The logical channel it was sent on, set with `Client::channel`.
+    #[serde(default)]
+    pub channel: u32,
+    #[serde(with = "type_ids::with::vec")]
+    pub requests: Vec<Box<dyn Request>>,
+}
+
+/// Requests sent with `Client::notify`, which have no id since nothing is
+/// sent back: not their results, nor whether they were handled at all.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotifyFrame {
+    pub trace: Option<TraceContext>,
+    /// The logical channel it was sent on, set with `Client::channel`.
+    #[serde(default)]
+    pub channel: u32,
+    #[serde(with = "type_ids::with::vec")]
+    pub requests: Vec<Box<dyn Request>>,
+}
+
+/// How soon a call starts when it arrives at a session already running as
+/// many as `max_concurrent_calls` allows: waiting calls start highest
+/// priority first, and in the order they arrived within a priority.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Priority {
+    /// Bulk work that can wait behind everything else.
+    Low,
+    #[default]
+    Normal,
+    /// Health checks and control messages, which shouldn't queue behind bulk traffic.
+    High,
+}
+
+/// W3C trace context of the client span that issued a request, so the
+/// server's handler span can join the same trace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+}
+
+/// The server's answer to the `RequestFrame` with the same `id`.
+///
+/// `id` is `None` when the server couldn't read the request frame at all.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseFrame {
+    pub id: Option<u64>,
+    #[serde(with = "type_ids::with::results")]
+    pub results: Vec<ResponseResult>,
+    /// Set by the server's middleware and handlers with
+    /// `Context::set_response_metadata`.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamRequestFrame {
+    pub id: u64,
+    pub trace: Option<TraceContext>,
+    #[serde(with = "type_ids::with::boxed")]
+    pub request: Box<dyn StreamingRequest>,
+    /// Bytes of stream frames the server may send before waiting for a
+    /// `WindowUpdate`; `None` sends them as fast as the connection takes them.
+    pub window: Option<u64>,
+    /// The logical channel it was sent on, set with `Client::channel`.
+    #[serde(default)]
+    pub channel: u32,
+}
+
+/// Credit for `bytes` more of stream `id`, granted as the client consumes
+/// what it was sent. The server sends an item whenever it has any credit
+/// left, so one larger than the window can't stall the stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowUpdate {
+    pub id: u64,
+    pub bytes: u64,
+}
+
+/// One item of the stream opened by the `StreamRequestFrame` with the same `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamFrame {
+    pub id: u64,
+    pub item: StreamItem,
+}
+
+/// `End` and `Error` are both terminal; nothing follows them for that `id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StreamItem {
+    Data(#[serde(with = "type_ids::with::boxed")] Box<dyn Response>),
+    End,
+    Error(ProtocolError),
+}
+
+/// Opens an upload; its body follows as `UploadFrame`s with the same `id`,
+/// and the handler's result comes back as an ordinary `ResponseFrame`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadRequestFrame {
+    pub id: u64,
+    pub trace: Option<TraceContext>,
+    #[serde(with = "type_ids::with::boxed")]
+    pub request: Box<dyn UploadRequest>,
+    /// The logical channel it was sent on, set with `Client::channel`.
+    #[serde(default)]
+    pub channel: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadFrame {
+    pub id: u64,
+    pub item: UploadItem,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum UploadItem {
+    Data(#[serde(with = "crate::shared_bytes")] Bytes),
+    End,
+    /// The client gave up on the upload; the handler sees an error item.
+    Abort(String),
+}
+
+pub fn deadline_after(timeout: Duration) -> u64 {
+    let deadline = SystemTime::now() + timeout;
+    deadline
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Time left until `deadline`, or `None` if it has already passed.
+pub fn time_remaining(deadline: u64) -> Option<Duration> {
+    let deadline = UNIX_EPOCH + Duration::from_millis(deadline);
+    deadline
+        .duration_since(SystemTime::now())
+        .ok()
+        .filter(|remaining| !remaining.is_zero())
+}
+
+pub(crate) fn encode_message(codec: Codec, message: &ServerMessage) -> Bytes {
+    codec
+        .encode_bytes(message)
+        .expect("control messages are always serializable")
+}
+
+pub(crate) fn error_frame(codec: Codec, id: Option<u64>, err: ProtocolError) -> Bytes {
+    encode_message(
+        codec,
+        &ServerMessage::Reply(ResponseFrame {
+            id,
+            results: vec![Err(err)],
+            metadata: BTreeMap::new(),
+        }),
+    )
+}
+
+/// Serializes `message`, failing when it can't be encoded or is longer than
+/// the peer accepts.
+pub(crate) fn try_encode(
+    codec: Codec,
+    message: &ServerMessage,
+    max_length: usize,
+) -> Result<Encoded, ProtocolError> {
+    match codec.encode_bytes(message) {
+        Ok(bytes) if bytes.len() < max_length => Ok(Encoded {
+            bytes,
+            compressible: message.compressible(),
+        }),
+        Ok(bytes) => {
+            tracing::error!(len = bytes.len(), "Response exceeds frame limit");
+            Err(ProtocolError::new(
+                ErrorCode::FrameTooLarge,
+                format!(
+                    "Response of {} bytes exceeds the {max_length} byte limit",
+                    bytes.len()
+                ),
+            ))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to serialize response");
+            Err(ProtocolError::new(
+                ErrorCode::Internal,
+                format!("Failed to serialize response: {e}"),
+            ))
+        }
+    }
+}
+
+/// Serializes a reply, substituting a single error slot when the responses
+/// can't be encoded, so a misbehaving handler never tears down the connection.
+pub(crate) fn encode_reply(codec: Codec, resp: ResponseFrame, max_length: usize) -> Encoded {
+    let id = resp.id;
+    try_encode(codec, &ServerMessage::Reply(resp), max_length)
+        .unwrap_or_else(|err| error_frame(codec, id, err).into())
+}
+
+/// Serializes a stream's error item, substituting the error it couldn't be
+/// sent with when it doesn't fit in a frame, so the stream still ends.
+pub(crate) fn encode_stream_error(
+    codec: Codec,
+    id: u64,
+    err: ProtocolError,
+    max_length: usize,
+) -> Encoded {
+    let frame = |err| {
+        ServerMessage::Stream(StreamFrame {
+            id,
+            item: StreamItem::Error(err),
+        })
+    };
+    try_encode(codec, &frame(err), max_length)
+        .unwrap_or_else(|err| encode_message(codec, &frame(err)).into())
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::schema::Describe;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Describe)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ErrorCode {
+    /// The frame could not be decoded into requests.
+    Malformed,
+    FrameTooLarge,
+    /// The handler ran and returned an error.
+    Handler,
+    Timeout,
+    Overloaded,
+    Internal,
+    /// The peer asked for a protocol version or codec this side doesn't speak.
+    Unsupported,
+    /// The connection was refused because the client failed to authenticate.
+    Unauthenticated,
+    /// The client exceeded its request rate; `details` says when to retry.
+    RateLimited,
+    /// The client cancelled the request before it completed.
+    Cancelled,
+    /// The server is running as many requests as it allows; retry later.
+    Busy,
+    /// The request failed its `validate` check; `details` names the field, if one was at fault.
+    InvalidRequest,
+    /// A frame arrived with a checksum that doesn't match its contents, so it was dropped.
+    ChecksumMismatch,
+    /// The client's identity isn't allowed to make this request.
+    PermissionDenied,
+    /// The client's identity has used up a quota; `details` says when it resets.
+    QuotaExceeded,
+    /// The client a request was relayed to isn't connected, or disconnected before answering.
+    PeerUnavailable,
+    /// The frame was longer than the server allows for the type of a request in it.
+    PayloadTooLarge,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The error half of every response slot on the wire.
+///
+/// Handlers can return a `ProtocolError` through `anyhow` to pick the code
+/// the client sees; any other error is reported as [`ErrorCode::Handler`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Describe)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    pub fn from_handler(err: anyhow::Error) -> Self {
+        match err.downcast::<ProtocolError>() {
+            Ok(err) => err,
+            Err(err) => Self::new(ErrorCode::Handler, format!("{err:#}")),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)?;
+        if let Some(details) = &self.details {
+            write!(f, " ({details})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Why a request was refused by its `validate` check before being handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            field: None,
+            message: message.into(),
+        }
+    }
+
+    /// An error about one field of the request, e.g. `("limit", "must be at most 100")`.
+    pub fn field(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: Some(field.into()),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{field}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for ProtocolError {
+    fn from(err: ValidationError) -> Self {
+        let error = ProtocolError::new(ErrorCode::InvalidRequest, err.message);
+        match err.field {
+            Some(field) => error.with_details(field),
+            None => error,
+        }
+    }
+}
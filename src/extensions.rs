@@ -0,0 +1,80 @@
+//! A map of values keyed by their type, for handlers to pull the
+//! dependencies they need out of without one state struct that every
+//! handler crate has to agree on:
+//!
+//! ```ignore
+//! let server = Server::builder()
+//!     .extension(db_pool)
+//!     .extension(Mailer::new(smtp))
+//!     .build();
+//!
+//! // In a handler:
+//! let pool = ctx.get::<DbPool>().context("no database")?;
+//! ```
+
+use std::any::{Any, TypeId, type_name};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Values registered with `ServerBuilder::extension`, at most one of each type.
+#[derive(Default, Clone)]
+pub struct Extensions {
+    values: HashMap<TypeId, (&'static str, Arc<dyn Any + Send + Sync>)>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value`, returning the one of its type it replaces, if any
+    /// and no one else holds it.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.insert_arc(Arc::new(value))
+            .and_then(|old| Arc::try_unwrap(old).ok())
+    }
+
+    /// Adds a value that is already shared, returning the one of its type it replaces.
+    pub fn insert_arc<T: Send + Sync + 'static>(&mut self, value: Arc<T>) -> Option<Arc<T>> {
+        let old = self
+            .values
+            .insert(TypeId::of::<T>(), (type_name::<T>(), value))?;
+        old.1.downcast().ok()
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.1.downcast_ref()
+    }
+
+    /// The value of type `T` as a handle that can outlive the request.
+    pub fn get_arc<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.values.get(&TypeId::of::<T>())?.1.clone();
+        value.downcast().ok()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<Arc<T>> {
+        let (_, value) = self.values.remove(&TypeId::of::<T>())?;
+        value.downcast().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.values.values().map(|(name, _)| *name).collect();
+        names.sort_unstable();
+        f.debug_set().entries(names).finish()
+    }
+}
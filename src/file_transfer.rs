@@ -0,0 +1,373 @@
+//! Moving files to and from a directory on the server in chunks, so large
+//! blobs never need to fit in one frame.
+//!
+//! Both request types are refused unless the server was built with
+//! `ServerBuilder::file_root`, and only reach files beneath that directory.
+//! [`GetFile`] streams a [`FilePart::Metadata`], then the contents as
+//! [`FilePart::Data`], then a [`FilePart::Checksum`] of what was sent.
+//! [`PutFile`] writes its upload to a temporary file, checks its size and
+//! checksum, and only then moves it into place; uploads longer than
+//! `ServerBuilder::max_file_size` are cut off and refused.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context as _, Result, bail};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::client::{UPLOAD_CHUNK_SIZE, downcast_response};
+use crate::schema::Describe;
+use crate::{
+    ByteStream, Client, Context, ErrorCode, ProtocolError, Response, ResponseStream,
+    StreamingRequest, UploadRequest, ValidationError,
+};
+
+/// Downloads the file at `path`, relative to the server's file root.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct GetFile {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Describe)]
+pub struct FileMetadata {
+    pub size: u64,
+    /// Unix permission bits, where the server has them.
+    pub mode: Option<u32>,
+}
+
+/// One item of a `GetFile` stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub enum FilePart {
+    Metadata(FileMetadata),
+    Data(#[serde(with = "crate::shared_bytes")] Bytes),
+    /// Hex SHA-256 of every `Data` item before it.
+    Checksum(String),
+}
+
+#[typetag::serde]
+impl Response for FilePart {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl StreamingRequest for GetFile {
+    async fn handle(&self, ctx: &Context) -> Result<ResponseStream> {
+        let path = resolve_existing(ctx, &self.path).await?;
+        let file = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("failed to open {}", self.path))?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            bail!("{} is not a file", self.path);
+        }
+        let head = FilePart::Metadata(FileMetadata {
+            size: metadata.len(),
+            mode: mode(&metadata),
+        });
+
+        let body = futures::stream::try_unfold(Some((file, Sha256::new())), |state| async move {
+            let Some((mut file, mut hasher)) = state else {
+                return Ok(None);
+            };
+            let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                let checksum = format!("{:x}", hasher.finalize());
+                return Ok(Some((FilePart::Checksum(checksum), None)));
+            }
+            buf.truncate(n);
+            hasher.update(&buf);
+            anyhow::Ok(Some((FilePart::Data(buf.into()), Some((file, hasher)))))
+        });
+        let items = futures::stream::once(async { Ok(head) })
+            .chain(body)
+            .map_ok(|part| Box::new(part) as Box<dyn Response>);
+        Ok(items.boxed())
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        check_path(&self.path)
+    }
+}
+
+/// Uploads a file to `path`, relative to the server's file root, creating
+/// any directories on the way.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct PutFile {
+    pub path: String,
+    /// The upload is rejected unless it is exactly this long.
+    pub size: Option<u64>,
+    /// Hex SHA-256 the upload is rejected unless it matches.
+    pub sha256: Option<String>,
+    /// Unix permission bits to give the file, within the server's
+    /// `ServerBuilder::file_mode`.
+    pub mode: Option<u32>,
+    /// Replace a file already at `path` instead of failing.
+    pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Describe)]
+pub struct PutFileResponse {
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[typetag::serde]
+impl Response for PutFileResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl UploadRequest for PutFile {
+    async fn handle(&self, ctx: &Context, mut body: ByteStream) -> Result<Box<dyn Response>> {
+        let max_size = ctx.server().max_file_size();
+        if self.size.is_some_and(|size| size > max_size) {
+            return Err(too_large(max_size).into());
+        }
+        let path = resolve_new(ctx, &self.path).await?;
+        if !self.overwrite && tokio::fs::try_exists(&path).await? {
+            bail!("{} already exists", self.path);
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let partial = path.with_file_name(format!(".{name}.{}.part", ctx.connection().id()));
+        let written = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                size += chunk.len() as u64;
+                if size > max_size {
+                    return Err(too_large(max_size).into());
+                }
+                if self.size.is_some_and(|expected| size > expected) {
+                    break;
+                }
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            anyhow::Ok((file, size, format!("{:x}", hasher.finalize())))
+        };
+        let (file, size, sha256) = match written.await {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e);
+            }
+        };
+
+        let mismatch = match (self.size, &self.sha256) {
+            (Some(expected), _) if size != expected => Some((
+                "size",
+                format!("uploaded {size} bytes, expected {expected}"),
+            )),
+            (_, Some(expected)) if !expected.eq_ignore_ascii_case(&sha256) => {
+                Some(("sha256", format!("upload has checksum {sha256}")))
+            }
+            _ => None,
+        };
+        if let Some((field, message)) = mismatch {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(ProtocolError::from(ValidationError::field(field, message)).into());
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = mode & ctx.server().file_mode();
+            file.set_permissions(std::fs::Permissions::from_mode(mode))
+                .await?;
+        }
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(Box::new(PutFileResponse { size, sha256 }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        check_path(&self.path)?;
+        if let Some(sha256) = &self.sha256
+            && (sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            return Err(ValidationError::field("sha256", "must be 64 hex digits"));
+        }
+        Ok(())
+    }
+}
+
+fn too_large(max_size: u64) -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::PayloadTooLarge,
+        format!("files may be at most {max_size} bytes"),
+    )
+}
+
+impl Client {
+    /// Downloads `remote` from the server's file root to `local`, checking
+    /// it against the server's size and checksum.
+    pub async fn get_file(&self, remote: &str, local: impl AsRef<Path>) -> Result<FileMetadata> {
+        let local = local.as_ref();
+        let mut parts = self
+            .call_stream(Box::new(GetFile {
+                path: remote.to_string(),
+            }))
+            .await?;
+        let mut download = None;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(part) = parts.next().await {
+            match downcast_response::<FilePart>(part?)? {
+                FilePart::Metadata(metadata) => {
+                    let file = tokio::fs::File::create(local)
+                        .await
+                        .with_context(|| format!("failed to create {}", local.display()))?;
+                    download = Some((metadata, file));
+                }
+                FilePart::Data(data) => {
+                    let (_, file) = download.as_mut().context("server sent no file metadata")?;
+                    size += data.len() as u64;
+                    hasher.update(&data);
+                    file.write_all(&data).await?;
+                }
+                FilePart::Checksum(expected) => {
+                    let (metadata, mut file) = download.context("server sent no file metadata")?;
+                    file.flush().await?;
+                    if size != metadata.size {
+                        bail!(
+                            "received {size} bytes of {remote}, expected {}",
+                            metadata.size
+                        );
+                    }
+                    if format!("{:x}", hasher.finalize()) != expected {
+                        bail!("checksum of {remote} doesn't match the server's");
+                    }
+                    return Ok(metadata);
+                }
+            }
+        }
+        bail!("download of {remote} ended early")
+    }
+
+    /// Uploads `local` to `remote` in the server's file root, replacing any
+    /// file already there and keeping `local`'s permissions.
+    pub async fn put_file(&self, local: impl AsRef<Path>, remote: &str) -> Result<PutFileResponse> {
+        let local = local.as_ref();
+        let metadata = tokio::fs::metadata(local)
+            .await
+            .with_context(|| format!("failed to read {}", local.display()))?;
+        let request = PutFile {
+            path: remote.to_string(),
+            size: Some(metadata.len()),
+            sha256: Some(sha256_file(local).await?),
+            mode: mode(&metadata),
+            overwrite: true,
+        };
+        let file = tokio::fs::File::open(local).await?;
+        let response = self.upload(Box::new(request), chunks(file)).await?;
+        downcast_response(response)
+    }
+}
+
+fn chunks(file: tokio::fs::File) -> impl Stream<Item = Bytes> + Send {
+    futures::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) | Err(_) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Bytes::from(buf), Some(file)))
+            }
+        }
+    })
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(format!("{:x}", hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Accepts only plain relative paths, so a request can't name anything
+/// outside the file root before symlinks are considered.
+fn check_path(path: &str) -> Result<(), ValidationError> {
+    let mut components = Path::new(path).components().peekable();
+    if components.peek().is_none() {
+        return Err(ValidationError::field("path", "must not be empty"));
+    }
+    if components.all(|component| matches!(component, Component::Normal(_))) {
+        Ok(())
+    } else {
+        Err(ValidationError::field(
+            "path",
+            "must be relative, without `.` or `..` components",
+        ))
+    }
+}
+
+fn file_root(ctx: &Context) -> Result<&Path> {
+    ctx.server().file_root().ok_or_else(|| {
+        ProtocolError::new(
+            ErrorCode::Unsupported,
+            "file transfer is not enabled on this server",
+        )
+        .into()
+    })
+}
+
+/// The real location of `path` under the root, which must exist and not be
+/// reached through a symlink pointing out of the root.
+async fn resolve_existing(ctx: &Context, path: &str) -> Result<PathBuf> {
+    let root = tokio::fs::canonicalize(file_root(ctx)?).await?;
+    let resolved = tokio::fs::canonicalize(root.join(path))
+        .await
+        .with_context(|| format!("{path} not found"))?;
+    if !resolved.starts_with(&root) {
+        bail!("{path} not found");
+    }
+    Ok(resolved)
+}
+
+/// Where a file uploaded to `path` goes, creating its directory one level
+/// at a time so a symlink can't lead the way out of the root.
+async fn resolve_new(ctx: &Context, path: &str) -> Result<PathBuf> {
+    let root = tokio::fs::canonicalize(file_root(ctx)?).await?;
+    let path = Path::new(path);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("{} is not a file path", path.display());
+    };
+    let mut dir = root.clone();
+    for component in parent.components() {
+        dir.push(component);
+        match tokio::fs::create_dir(&dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+        dir = tokio::fs::canonicalize(&dir).await?;
+        if !dir.starts_with(&root) {
+            bail!("{} is outside the file root", path.display());
+        }
+    }
+    Ok(dir.join(name))
+}
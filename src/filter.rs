@@ -0,0 +1,475 @@
+//! Predicates over published messages, which a subscriber attaches with
+//! `SubscribeFiltered` so the server only pushes it the messages it wants.
+//!
+//! A filter looks at the message's fields as they serialize, with dotted
+//! paths reaching into nested ones, and can be built directly or parsed:
+//!
+//! ```ignore
+//! let filter: Filter = r#"symbol == "AAPL" && (price > 100 || !halted)"#.parse()?;
+//! ```
+//!
+//! A comparison is `path op literal`, with `==`, `!=`, `<`, `<=`, `>` or
+//! `>=` and a number, a quoted string, `true`, `false` or `null`; a bare
+//! path is true when the field is there and neither `false` nor `null`.
+//! `!`, `&&`, `||` and parentheses combine them.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Response;
+use crate::schema::Describe;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Describe)]
+pub enum Filter {
+    /// The field at `path` compared against `value`; false if it is missing.
+    Compare {
+        path: String,
+        op: Op,
+        value: Scalar,
+    },
+    /// The field at the path is there and neither `false` nor `null`.
+    Truthy(String),
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Describe)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A literal a field is compared with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Describe)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Filter {
+    pub fn compare(path: impl Into<String>, op: Op, value: impl Into<Scalar>) -> Self {
+        Self::Compare {
+            path: path.into(),
+            op,
+            value: value.into(),
+        }
+    }
+
+    /// Whether `message` passes, judged by its serialized fields.
+    pub fn matches(&self, message: &dyn Response) -> bool {
+        match serde_json::to_value(message) {
+            Ok(value) => self.matches_fields(fields(&value)),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the fields of a message, as JSON, pass.
+    pub fn matches_fields(&self, fields: &Value) -> bool {
+        match self {
+            Filter::Compare { path, op, value } => {
+                lookup(fields, path).is_some_and(|field| compare(field, *op, value))
+            }
+            Filter::Truthy(path) => lookup(fields, path)
+                .is_some_and(|field| !matches!(field, Value::Null | Value::Bool(false))),
+            Filter::All(filters) => filters.iter().all(|filter| filter.matches_fields(fields)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.matches_fields(fields)),
+            Filter::Not(filter) => !filter.matches_fields(fields),
+        }
+    }
+}
+
+/// A response serializes tagged with its type, `{"Quote": {...}}`; its
+/// fields are inside.
+pub(crate) fn fields(value: &Value) -> &Value {
+    match value {
+        Value::Object(tagged) if tagged.len() == 1 => tagged.values().next().unwrap_or(value),
+        _ => value,
+    }
+}
+
+fn lookup<'a>(fields: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(fields, |value, key| match value {
+        Value::Object(object) => object.get(key),
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+fn compare(field: &Value, op: Op, value: &Scalar) -> bool {
+    use std::cmp::Ordering;
+
+    let ordering = match (field, value) {
+        (Value::Null, Scalar::Null) => Some(Ordering::Equal),
+        (Value::Bool(a), Scalar::Bool(b)) => Some(a.cmp(b)),
+        (Value::String(a), Scalar::String(b)) => Some(a.as_str().cmp(b)),
+        (Value::Number(a), Scalar::Int(b)) => match a.as_i64() {
+            Some(a) => Some(a.cmp(b)),
+            None => a.as_f64().and_then(|a| a.partial_cmp(&(*b as f64))),
+        },
+        (Value::Number(a), Scalar::Float(b)) => a.as_f64().and_then(|a| a.partial_cmp(b)),
+        _ => None,
+    };
+    match ordering {
+        Some(ordering) => match op {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+        },
+        // Values of different kinds are never equal, and never ordered.
+        None => op == Op::Ne,
+    }
+}
+
+impl From<bool> for Scalar {
+    fn from(value: bool) -> Self {
+        Scalar::Bool(value)
+    }
+}
+
+impl From<i64> for Scalar {
+    fn from(value: i64) -> Self {
+        Scalar::Int(value)
+    }
+}
+
+impl From<f64> for Scalar {
+    fn from(value: f64) -> Self {
+        Scalar::Float(value)
+    }
+}
+
+impl From<&str> for Scalar {
+    fn from(value: &str) -> Self {
+        Scalar::String(value.to_string())
+    }
+}
+
+impl From<String> for Scalar {
+    fn from(value: String) -> Self {
+        Scalar::String(value)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser { rest: s };
+        let filter = parser.or()?;
+        parser.skip_space();
+        if !parser.rest.is_empty() {
+            bail!("unexpected {:?} in filter", parser.rest);
+        }
+        Ok(filter)
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn or(&mut self) -> Result<Filter> {
+        let mut filters = vec![self.and()?];
+        while self.eat("||") {
+            filters.push(self.and()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::Any(filters)
+        })
+    }
+
+    fn and(&mut self) -> Result<Filter> {
+        let mut filters = vec![self.unary()?];
+        while self.eat("&&") {
+            filters.push(self.unary()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::All(filters)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Filter> {
+        if self.eat("!") {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let filter = self.or()?;
+            if !self.eat(")") {
+                bail!("missing ) in filter");
+            }
+            return Ok(filter);
+        }
+        let path = self.path()?;
+        let op = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| self.eat(token).then_some(op));
+        match op {
+            Some(op) => Ok(Filter::Compare {
+                path,
+                op,
+                value: self.literal()?,
+            }),
+            None => Ok(Filter::Truthy(path)),
+        }
+    }
+
+    fn path(&mut self) -> Result<String> {
+        self.skip_space();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            bail!("expected a field name in filter at {:?}", self.rest);
+        }
+        let (path, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(path.to_string())
+    }
+
+    fn literal(&mut self) -> Result<Scalar> {
+        self.skip_space();
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        self.rest = &quoted[i + 1..];
+                        return Ok(Scalar::String(value));
+                    }
+                    '\\' => match chars.next() {
+                        Some((_, c)) => value.push(c),
+                        None => break,
+                    },
+                    c => value.push(c),
+                }
+            }
+            bail!("unterminated string in filter");
+        }
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, ')' | '&' | '|'))
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        let value = match word {
+            "null" => Scalar::Null,
+            "true" => Scalar::Bool(true),
+            "false" => Scalar::Bool(false),
+            _ => match word.parse::<i64>() {
+                Ok(int) => Scalar::Int(int),
+                Err(_) => Scalar::Float(
+                    word.parse()
+                        .map_err(|_| anyhow!("expected a value in filter, not {word:?}"))?,
+                ),
+            },
+        };
+        self.rest = rest;
+        Ok(value)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn skip_space(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn quote() -> Value {
+        json!({
+            "symbol": "AAPL",
+            "price": 150,
+            "change": -1.5,
+            "halted": false,
+            "note": null,
+            "venue": { "name": "NASDAQ", "open": true },
+            "bids": [149, 148],
+        })
+    }
+
+    fn passes(filter: &str) -> bool {
+        filter.parse::<Filter>().unwrap().matches_fields(&quote())
+    }
+
+    #[test]
+    fn each_operator_compares_numbers() {
+        for (filter, expected) in [
+            ("price == 150", true),
+            ("price == 151", false),
+            ("price != 151", true),
+            ("price != 150", false),
+            ("price < 151", true),
+            ("price < 150", false),
+            ("price <= 150", true),
+            ("price <= 149", false),
+            ("price > 149", true),
+            ("price > 150", false),
+            ("price >= 150", true),
+            ("price >= 151", false),
+            ("change < -1", true),
+            ("change >= -1.5", true),
+            ("price == 150.0", true),
+        ] {
+            assert_eq!(passes(filter), expected, "{filter}");
+        }
+    }
+
+    #[test]
+    fn each_operator_compares_strings_and_bools() {
+        for (filter, expected) in [
+            (r#"symbol == "AAPL""#, true),
+            (r#"symbol != "AAPL""#, false),
+            (r#"symbol < "MSFT""#, true),
+            (r#"symbol <= "AAPL""#, true),
+            (r#"symbol > "AAPL""#, false),
+            (r#"symbol >= "AAPK""#, true),
+            ("halted == false", true),
+            ("halted != true", true),
+            ("halted < true", true),
+            ("note == null", true),
+            ("note != null", false),
+        ] {
+            assert_eq!(passes(filter), expected, "{filter}");
+        }
+    }
+
+    #[test]
+    fn values_of_different_kinds_are_only_unequal() {
+        for (filter, expected) in [
+            (r#"price == "150""#, false),
+            (r#"price != "150""#, true),
+            (r#"price < "200""#, false),
+            (r#"price >= "100""#, false),
+            ("symbol == 1", false),
+            ("halted == null", false),
+        ] {
+            assert_eq!(passes(filter), expected, "{filter}");
+        }
+    }
+
+    #[test]
+    fn missing_fields_fail_every_comparison() {
+        for op in ["==", "!=", "<", "<=", ">", ">="] {
+            let filter = format!("volume {op} 100");
+            assert!(!passes(&filter), "{filter}");
+        }
+        assert!(!passes("venue.city == null"));
+        assert!(!passes("bids.5 > 0"));
+        assert!(!passes("symbol.length > 0"));
+        assert!(passes("!volume"));
+    }
+
+    #[test]
+    fn truthy_needs_a_field_that_is_neither_false_nor_null() {
+        assert!(passes("symbol"));
+        assert!(passes("venue.open"));
+        assert!(!passes("halted"));
+        assert!(!passes("note"));
+        assert!(!passes("volume"));
+    }
+
+    #[test]
+    fn paths_reach_into_objects_and_arrays() {
+        assert!(passes(r#"venue.name == "NASDAQ""#));
+        assert!(passes("bids.0 == 149"));
+        assert!(passes("bids.1 < 149"));
+    }
+
+    #[test]
+    fn combinators_follow_precedence_and_parentheses() {
+        assert!(passes(r#"symbol == "AAPL" && (price > 100 || !halted)"#));
+        assert!(passes("price > 200 || halted || venue.open"));
+        assert!(!passes("price > 200 || halted && venue.open"));
+        assert!(passes("!(price > 200 || halted)"));
+        assert!(!passes("!!halted"));
+        assert_eq!(
+            "a && b || c".parse::<Filter>().unwrap(),
+            Filter::Any(vec![
+                Filter::All(vec![Filter::Truthy("a".into()), Filter::Truthy("b".into())]),
+                Filter::Truthy("c".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn strings_may_escape_quotes() {
+        let filter: Filter = r#"name == "say \"hi\"""#.parse().unwrap();
+        assert_eq!(filter, Filter::compare("name", Op::Eq, r#"say "hi""#));
+    }
+
+    #[test]
+    fn malformed_filters_are_refused() {
+        for filter in [
+            "",
+            "price >",
+            "price > abc",
+            "(price > 1",
+            "price > 1)",
+            r#"symbol == "AAPL"#,
+            "&& price",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn tagged_response_fields_are_unwrapped() {
+        let tagged = json!({ "Quote": quote() });
+        assert!(Filter::compare("price", Op::Eq, 150).matches_fields(fields(&tagged)));
+    }
+}
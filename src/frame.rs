@@ -0,0 +1,595 @@
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The frame is compressed with the connection's `Compression`.
+pub const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// More frames of the same message follow this one. Every chunk of a message
+/// carries the same `FLAG_COMPRESSED` bit, which applies to the whole message.
+pub const FLAG_CONTINUED: u8 = 0b0000_0010;
+
+/// A CRC32 of the rest of the frame follows the flags byte.
+pub const FLAG_CHECKSUM: u8 = 0b0000_0100;
+
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+const HEADER_LEN: usize = 4;
+
+const CHECKSUM_LEN: usize = 4;
+
+/// How frames are compressed, negotiated per connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    /// Faster than zstd on both ends, for a larger result.
+    Lz4,
+}
+
+impl Compression {
+    /// `bytes` compressed, or `None` when this is `Compression::None` or
+    /// compressing wouldn't make them any shorter.
+    fn compress(self, bytes: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Compression::None => return Ok(None),
+            Compression::Zstd => zstd::bulk::compress(bytes, ZSTD_LEVEL)?,
+            Compression::Lz4 => lz4_flex::block::compress_prepend_size(bytes),
+        };
+        Ok(Some(compressed).filter(|compressed| compressed.len() < bytes.len()))
+    }
+
+    /// Undoes `compress`, refusing anything that would come out longer than `max_length`.
+    fn decompress(self, bytes: &[u8], max_length: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Err(invalid_data(
+                "compressed frame on a connection without compression",
+            )),
+            Compression::Zstd => zstd::bulk::decompress(bytes, max_length),
+            Compression::Lz4 => {
+                let (len, block) =
+                    lz4_flex::block::uncompressed_size(bytes).map_err(invalid_data)?;
+                if len > max_length {
+                    return Err(invalid_data(format!(
+                        "compressed frame of {len} bytes exceeds the {max_length} byte limit"
+                    )));
+                }
+                lz4_flex::block::decompress(block, len).map_err(invalid_data)
+            }
+        }
+    }
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// A message on its way to `FrameCodec`, which compresses it only if it's
+/// `compressible`.
+#[derive(Debug, Clone)]
+pub struct Encoded {
+    pub bytes: Bytes,
+    /// False when a request or response in the message opted out with
+    /// `Request::compressible` or `Response::compressible`.
+    pub compressible: bool,
+}
+
+impl Encoded {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl From<Bytes> for Encoded {
+    fn from(bytes: Bytes) -> Self {
+        Self {
+            bytes,
+            compressible: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    Payload(Bytes),
+    /// A frame whose declared length exceeded the limit, or a chunked message
+    /// that grew past the message limit; its bytes were skipped.
+    Oversized {
+        len: usize,
+    },
+    /// A frame whose contents don't match its checksum; it was skipped, along
+    /// with the rest of the message it was a chunk of.
+    Corrupt {
+        len: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecodeState {
+    Head,
+    Data(usize),
+    Discard { remaining: usize, len: usize },
+}
+
+/// A chunked message being reassembled.
+#[derive(Debug)]
+enum Partial {
+    Collecting(BytesMut),
+    /// Past the message limit: the rest of its chunks are skipped.
+    Discarding {
+        len: usize,
+    },
+    /// A chunk failed its checksum: the rest of the message's chunks are
+    /// skipped, up to and including its last.
+    Skipping,
+}
+
+/// Length-delimited frames whose payload is prefixed with a flags byte.
+///
+/// Frames longer than `max_frame_length` are skipped rather than treated as a
+/// fatal error, so the connection survives a single bad frame. Messages
+/// arriving in several frames, marked with `FLAG_CONTINUED`, are reassembled
+/// up to `max_message_length`; they are only sent that way with chunking on.
+/// Frames carrying a checksum are checked whether or not checksums were
+/// negotiated, so either side may start sending them.
+#[derive(Debug)]
+pub struct FrameCodec {
+    max_frame_length: usize,
+    max_message_length: usize,
+    compression: Compression,
+    chunking: bool,
+    checksums: bool,
+    state: DecodeState,
+    partial: Option<Partial>,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self {
+            max_frame_length,
+            max_message_length: max_frame_length,
+            compression: Compression::None,
+            chunking: false,
+            checksums: false,
+            state: DecodeState::Head,
+            partial: None,
+        }
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    /// The largest message reassembled from chunks; it starts out as the frame limit.
+    pub fn set_max_message_length(&mut self, max_message_length: usize) {
+        self.max_message_length = max_message_length.max(self.max_frame_length);
+    }
+
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// Splits messages too long for one frame into chunks instead of failing
+    /// to send them. Only for peers that negotiated `Features::CHUNKING`.
+    pub fn set_chunking(&mut self, enabled: bool) {
+        self.chunking = enabled;
+    }
+
+    pub fn chunking(&self) -> bool {
+        self.chunking
+    }
+
+    /// Adds a CRC32 to every frame sent. Only for peers that negotiated
+    /// `Features::CHECKSUMS`.
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
+    }
+
+    pub fn checksums(&self) -> bool {
+        self.checksums
+    }
+
+    /// Compresses frames past `COMPRESSION_THRESHOLD` with `compression`, and
+    /// reads compressed frames with it. Only for what the handshake settled on.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Decodes one frame's body, or returns `None` while a chunked message is
+    /// still incomplete.
+    fn decode_payload(&mut self, mut frame: BytesMut) -> io::Result<Option<Frame>> {
+        if frame.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame is missing its flags byte",
+            ));
+        }
+        let flags = frame.get_u8();
+        let continued = flags & FLAG_CONTINUED != 0;
+        if let Some(Partial::Skipping) = self.partial {
+            // Already reported as corrupt; without this, its remaining chunks
+            // would be reassembled as a message of their own.
+            if !continued {
+                self.partial = None;
+            }
+            return Ok(None);
+        }
+
+        if flags & FLAG_CHECKSUM != 0 {
+            if frame.len() < CHECKSUM_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame is too short for its checksum",
+                ));
+            }
+            let expected = frame.get_u32();
+            if crc32fast::hash(&frame) != expected {
+                self.partial = continued.then_some(Partial::Skipping);
+                return Ok(Some(Frame::Corrupt {
+                    len: frame.len() + CHECKSUM_LEN + 1,
+                }));
+            }
+        }
+
+        let message = match self.partial.take() {
+            None if !continued => frame,
+            None => {
+                self.collect(BytesMut::new(), frame, continued);
+                return Ok(None);
+            }
+            Some(Partial::Collecting(collected)) => {
+                match self.collect(collected, frame, continued) {
+                    Some(message) => message,
+                    None => return Ok(None),
+                }
+            }
+            Some(Partial::Skipping) => unreachable!("skipped chunks return early"),
+            Some(Partial::Discarding { len }) => {
+                let len = len + frame.len();
+                if continued {
+                    self.partial = Some(Partial::Discarding { len });
+                    return Ok(None);
+                }
+                return Ok(Some(Frame::Oversized { len }));
+            }
+        };
+
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(Some(Frame::Payload(message.freeze())));
+        }
+
+        let payload = self
+            .compression
+            .decompress(&message, self.max_message_length)?;
+        Ok(Some(Frame::Payload(payload.into())))
+    }
+
+    /// Adds a chunk to the message collected so far, returning the message
+    /// once its last chunk is in.
+    fn collect(
+        &mut self,
+        mut collected: BytesMut,
+        chunk: BytesMut,
+        continued: bool,
+    ) -> Option<BytesMut> {
+        let len = collected.len() + chunk.len();
+        if len > self.max_message_length {
+            self.partial = Some(Partial::Discarding { len });
+            return None;
+        }
+        collected.unsplit(chunk);
+        if continued {
+            self.partial = Some(Partial::Collecting(collected));
+            None
+        } else {
+            Some(collected)
+        }
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        loop {
+            match self.state {
+                DecodeState::Head => {
+                    if src.len() < HEADER_LEN {
+                        return Ok(None);
+                    }
+                    let len = src.get_u32() as usize;
+                    if len > self.max_frame_length {
+                        self.state = DecodeState::Discard {
+                            remaining: len,
+                            len,
+                        };
+                    } else {
+                        src.reserve(len);
+                        self.state = DecodeState::Data(len);
+                    }
+                }
+                DecodeState::Data(len) => {
+                    if src.len() < len {
+                        return Ok(None);
+                    }
+                    let frame = src.split_to(len);
+                    self.state = DecodeState::Head;
+                    match self.decode_payload(frame)? {
+                        Some(frame) => return Ok(Some(frame)),
+                        None => continue,
+                    }
+                }
+                DecodeState::Discard { remaining, len } => {
+                    let skip = remaining.min(src.len());
+                    src.advance(skip);
+                    if remaining > skip {
+                        self.state = DecodeState::Discard {
+                            remaining: remaining - skip,
+                            len,
+                        };
+                        return Ok(None);
+                    }
+                    self.state = DecodeState::Head;
+                    // A bad chunk spoils the message it belongs to.
+                    self.partial = None;
+                    return Ok(Some(Frame::Oversized { len }));
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode_message(item.into(), dst)
+    }
+}
+
+impl FrameCodec {
+    /// As `Encoder::encode`, but leaves messages that aren't `compressible`
+    /// uncompressed.
+    pub fn encode_message(&mut self, item: Encoded, dst: &mut BytesMut) -> io::Result<()> {
+        let Encoded {
+            bytes: item,
+            compressible,
+        } = item;
+        let compressed = if compressible && item.len() >= COMPRESSION_THRESHOLD {
+            self.compression.compress(&item)?
+        } else {
+            None
+        };
+
+        let (flags, payload) = match &compressed {
+            Some(compressed) => (FLAG_COMPRESSED, &compressed[..]),
+            None => (0, &item[..]),
+        };
+
+        let overhead = if self.checksums { CHECKSUM_LEN + 1 } else { 1 };
+        let len = payload.len() + overhead;
+        if len <= self.max_frame_length {
+            self.put_frame(dst, flags, payload);
+            return Ok(());
+        }
+        if !self.chunking || payload.len() > self.max_message_length {
+            let limit = if self.chunking {
+                self.max_message_length
+            } else {
+                self.max_frame_length
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {len} bytes exceeds the {limit} byte limit"),
+            ));
+        }
+
+        let chunk_len = self.max_frame_length.saturating_sub(overhead).max(1);
+        let mut chunks = payload.chunks(chunk_len).peekable();
+        while let Some(chunk) = chunks.next() {
+            let continued = if chunks.peek().is_some() {
+                FLAG_CONTINUED
+            } else {
+                0
+            };
+            self.put_frame(dst, flags | continued, chunk);
+        }
+        Ok(())
+    }
+
+    fn put_frame(&self, dst: &mut BytesMut, flags: u8, payload: &[u8]) {
+        let checksum = self.checksums.then(|| crc32fast::hash(payload));
+        let len = payload.len() + 1 + checksum.map_or(0, |_| CHECKSUM_LEN);
+        dst.reserve(HEADER_LEN + len);
+        dst.put_u32(len as u32);
+        match checksum {
+            Some(checksum) => {
+                dst.put_u8(flags | FLAG_CHECKSUM);
+                dst.put_u32(checksum);
+            }
+            None => dst.put_u8(flags),
+        }
+        dst.put_slice(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A codec splitting messages into chunks of 11 bytes, with checksums.
+    fn chunking() -> FrameCodec {
+        let mut codec = FrameCodec::with_max_frame_length(16);
+        codec.set_max_message_length(256);
+        codec.set_chunking(true);
+        codec.set_checksums(true);
+        codec
+    }
+
+    fn encode(codec: &mut FrameCodec, message: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Bytes::copy_from_slice(message), &mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn decode_all(codec: &mut FrameCodec, buf: &mut BytesMut) -> Vec<Frame> {
+        std::iter::from_fn(|| codec.decode(buf).unwrap()).collect()
+    }
+
+    fn payload(frame: &Frame) -> &[u8] {
+        match frame {
+            Frame::Payload(payload) => payload,
+            other => panic!("expected a payload, got {other:?}"),
+        }
+    }
+
+    /// Flips a bit in the last byte of the `n`th frame in `buf`, past its checksum.
+    fn corrupt_frame(buf: &mut BytesMut, n: usize) {
+        let mut start = 0;
+        for _ in 0..n {
+            let len = u32::from_be_bytes(buf[start..start + HEADER_LEN].try_into().unwrap());
+            start += HEADER_LEN + len as usize;
+        }
+        let len = u32::from_be_bytes(buf[start..start + HEADER_LEN].try_into().unwrap());
+        buf[start + HEADER_LEN + len as usize - 1] ^= 1;
+    }
+
+    #[test]
+    fn chunked_message_is_reassembled() {
+        let mut codec = chunking();
+        let message: Vec<u8> = (0..100).collect();
+        let mut buf = encode(&mut codec, &message);
+        assert!(
+            buf.len() > HEADER_LEN + 16,
+            "message should span several frames"
+        );
+
+        // Fed a byte at a time, as it might arrive.
+        let mut input = BytesMut::new();
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            input.put_u8(buf.get_u8());
+            frames.extend(codec.decode(&mut input).unwrap());
+        }
+        assert_eq!(frames.len(), 1);
+        assert_eq!(payload(&frames[0]), &message[..]);
+    }
+
+    #[test]
+    fn checksummed_frame_round_trips() {
+        let mut codec = FrameCodec::new();
+        codec.set_checksums(true);
+        let mut buf = encode(&mut codec, b"hello");
+        assert_eq!(buf[HEADER_LEN] & FLAG_CHECKSUM, FLAG_CHECKSUM);
+        let frames = decode_all(&mut FrameCodec::new(), &mut buf);
+        assert_eq!(payload(&frames[0]), b"hello");
+    }
+
+    #[test]
+    fn corrupt_frame_is_reported() {
+        let mut codec = FrameCodec::new();
+        codec.set_checksums(true);
+        let mut buf = encode(&mut codec, b"hello");
+        buf.unsplit(encode(&mut codec, b"world"));
+        corrupt_frame(&mut buf, 0);
+        let frames = decode_all(&mut codec, &mut buf);
+        assert!(matches!(frames[0], Frame::Corrupt { .. }));
+        assert_eq!(payload(&frames[1]), b"world");
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn corrupt_chunk_skips_the_rest_of_its_message() {
+        let mut codec = chunking();
+        let message: Vec<u8> = (0..100).collect();
+        let mut buf = encode(&mut codec, &message);
+        corrupt_frame(&mut buf, 2);
+        buf.unsplit(encode(&mut codec, b"next"));
+
+        let frames = decode_all(&mut codec, &mut buf);
+        assert_eq!(frames.len(), 2, "{frames:?}");
+        assert!(matches!(frames[0], Frame::Corrupt { .. }));
+        assert_eq!(payload(&frames[1]), b"next");
+    }
+
+    #[test]
+    fn corrupt_last_chunk_leaves_the_next_message_whole() {
+        let mut codec = chunking();
+        let message: Vec<u8> = (0..100).collect();
+        let mut buf = encode(&mut codec, &message);
+        let chunks = message.len().div_ceil(16 - CHECKSUM_LEN - 1);
+        corrupt_frame(&mut buf, chunks - 1);
+        buf.unsplit(encode(&mut codec, &message));
+
+        let frames = decode_all(&mut codec, &mut buf);
+        assert_eq!(frames.len(), 2, "{frames:?}");
+        assert!(matches!(frames[0], Frame::Corrupt { .. }));
+        assert_eq!(payload(&frames[1]), &message[..]);
+    }
+
+    #[test]
+    fn message_past_the_limit_is_skipped() {
+        let mut sender = chunking();
+        sender.set_max_message_length(1024);
+        let mut buf = encode(&mut sender, &[7; 300]);
+        buf.unsplit(encode(&mut sender, b"next"));
+
+        let frames = decode_all(&mut chunking(), &mut buf);
+        assert!(matches!(frames[0], Frame::Oversized { len: 300 }));
+        assert_eq!(payload(&frames[1]), b"next");
+    }
+
+    #[test]
+    fn oversized_frame_is_skipped() {
+        let mut buf = encode(&mut FrameCodec::new(), &[0; 64]);
+        buf.unsplit(encode(&mut FrameCodec::new(), b"next"));
+        let frames = decode_all(&mut FrameCodec::with_max_frame_length(32), &mut buf);
+        assert!(matches!(frames[0], Frame::Oversized { len: 65 }));
+        assert_eq!(payload(&frames[1]), b"next");
+    }
+
+    #[test]
+    fn compressed_message_round_trips() {
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let mut codec = FrameCodec::new();
+            codec.set_compression(compression);
+            let message = vec![b'a'; 4 * COMPRESSION_THRESHOLD];
+            let mut buf = encode(&mut codec, &message);
+            assert!(buf.len() < message.len());
+            assert_eq!(buf[HEADER_LEN] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+            let frames = decode_all(&mut codec, &mut buf);
+            assert_eq!(payload(&frames[0]), &message[..]);
+        }
+    }
+}
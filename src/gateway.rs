@@ -0,0 +1,91 @@
+//! An HTTP front door onto a server's unary requests.
+//!
+//! `POST /rpc/{TypeName}` with the request's fields as a JSON body runs it
+//! through the same middleware, timeouts and handlers as a request on the
+//! binary protocol, and answers with the response's fields as JSON and its
+//! type name in the `myproto-type` header. Failures come back as a
+//! [`ProtocolError`] body with a matching HTTP status.
+//!
+//! Each HTTP request is handled as its own short-lived session: it is never
+//! listed in `Server::connections` and anything pushed to it is dropped. With
+//! an `Authenticator` installed, callers present an `Authorization: Bearer`
+//! token.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::post;
+use tokio::net::TcpListener;
+
+use crate::bridge;
+use crate::{ErrorCode, Server};
+
+/// The response header carrying the response's type name.
+pub const TYPE_HEADER: &str = "myproto-type";
+
+/// Serves the gateway on `listener` until `shutdown` resolves or `server`
+/// starts shutting down.
+pub async fn serve(
+    server: Server,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let stopping = server.shutdown.clone();
+    let app = Router::new()
+        .route("/rpc/{type_name}", post(rpc))
+        .layer(DefaultBodyLimit::max(server.config().max_frame_length))
+        .with_state(server);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        tokio::select! {
+            _ = shutdown => {}
+            _ = stopping.cancelled() => {}
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+async fn rpc(
+    State(server): State<Server>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    Path(type_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HttpResponse {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bridge::call(&server, peer_addr, &type_name, bearer, &body).await {
+        Ok((type_name, fields)) => ([(TYPE_HEADER, type_name)], axum::Json(fields)).into_response(),
+        Err(err) => {
+            tracing::debug!(%peer_addr, request_type = type_name, error = %err, "Gateway request failed");
+            (status(err.code), axum::Json(err)).into_response()
+        }
+    }
+}
+
+fn status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::Malformed | ErrorCode::ChecksumMismatch => StatusCode::BAD_REQUEST,
+        ErrorCode::InvalidRequest => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::FrameTooLarge | ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorCode::Handler | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::Overloaded | ErrorCode::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Unsupported | ErrorCode::PeerUnavailable => StatusCode::NOT_FOUND,
+        ErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+        ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorCode::RateLimited | ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Cancelled => StatusCode::REQUEST_TIMEOUT,
+    }
+}
@@ -0,0 +1,256 @@
+//! A gRPC front door onto a server's unary requests, for callers that only
+//! speak gRPC.
+//!
+//! There is no `.proto` per message type; the bridge serves two services
+//! whatever requests are registered:
+//!
+//! - `myproto.Bridge/Call` takes a `google.protobuf.Any` whose `type_url`
+//!   ends in the request's type name, `myproto/Echo` say, and whose `value`
+//!   is the request's fields as JSON. It answers with an `Any` carrying the
+//!   response the same way.
+//! - `myproto.Requests/{TypeName}` takes the request's fields as the JSON
+//!   message itself, for clients with a JSON codec (`application/grpc+json`),
+//!   and answers with the response's fields, its type name in the
+//!   `myproto-type` metadata.
+//!
+//! Requests run as they do through the HTTP gateway: through the same
+//! middleware, timeouts and handlers, each as its own short-lived session,
+//! with an `authorization: Bearer` token where the server authenticates.
+//! Failures come back as a status with a matching code, the
+//! [`ProtocolError`] as JSON in its details.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes};
+use futures::future::BoxFuture;
+use prost::Message;
+use prost_types::Any;
+use tokio::net::TcpListener;
+use tonic::body::Body;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::{Service, http};
+use tonic::metadata::MetadataValue;
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Status};
+
+use crate::bridge;
+use crate::{ErrorCode, ProtocolError, Server};
+
+/// What the `type_url` of a response `Any` starts with, before its type name.
+pub const TYPE_URL_PREFIX: &str = "myproto/";
+
+/// The metadata key carrying a `myproto.Requests` response's type name.
+pub const TYPE_METADATA: &str = "myproto-type";
+
+/// Serves the bridge on `listener` until `shutdown` resolves or `server`
+/// starts shutting down.
+pub async fn serve(
+    server: Server,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let stopping = server.shutdown.clone();
+    tonic::transport::Server::builder()
+        .add_service(Bridge(server.clone()))
+        .add_service(Requests(server))
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), async move {
+            tokio::select! {
+                _ = shutdown => {}
+                _ = stopping.cancelled() => {}
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// `myproto.Bridge`, taking requests wrapped in `Any`.
+#[derive(Clone)]
+struct Bridge(Server);
+
+impl NamedService for Bridge {
+    const NAME: &'static str = "myproto.Bridge";
+}
+
+/// `myproto.Requests`, a method per request type, fields as JSON.
+#[derive(Clone)]
+struct Requests(Server);
+
+impl NamedService for Requests {
+    const NAME: &'static str = "myproto.Requests";
+}
+
+impl Service<http::Request<Body>> for Bridge {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let server = self.0.clone();
+        Box::pin(async move {
+            if req.uri().path() != "/myproto.Bridge/Call" {
+                return Ok(Status::unimplemented("myproto.Bridge has only Call").into_http());
+            }
+            Ok(Grpc::new(Raw).unary(CallAny(server), req).await)
+        })
+    }
+}
+
+impl Service<http::Request<Body>> for Requests {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let server = self.0.clone();
+        Box::pin(async move {
+            let type_name = req
+                .uri()
+                .path()
+                .strip_prefix("/myproto.Requests/")
+                .unwrap_or_default()
+                .to_string();
+            Ok(Grpc::new(Raw).unary(CallJson(server, type_name), req).await)
+        })
+    }
+}
+
+struct CallAny(Server);
+
+impl UnaryService<Bytes> for CallAny {
+    type Response = Bytes;
+    type Future = BoxFuture<'static, Result<tonic::Response<Bytes>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<Bytes>) -> Self::Future {
+        let server = self.0.clone();
+        Box::pin(async move {
+            let (peer_addr, bearer) = caller(&request);
+            let any = Any::decode(request.into_inner())
+                .map_err(|e| Status::invalid_argument(format!("not a google.protobuf.Any: {e}")))?;
+            let type_name = any.type_url.rsplit('/').next().unwrap_or_default();
+            let (response_type, fields) =
+                run(
+                    bridge::call(&server, peer_addr, type_name, bearer.as_deref(), &any.value)
+                        .await,
+                )?;
+            let any = Any {
+                type_url: format!("{TYPE_URL_PREFIX}{response_type}"),
+                value: serde_json::to_vec(&fields).map_err(|e| Status::internal(e.to_string()))?,
+            };
+            Ok(tonic::Response::new(any.encode_to_vec().into()))
+        })
+    }
+}
+
+struct CallJson(Server, String);
+
+impl UnaryService<Bytes> for CallJson {
+    type Response = Bytes;
+    type Future = BoxFuture<'static, Result<tonic::Response<Bytes>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<Bytes>) -> Self::Future {
+        let server = self.0.clone();
+        let type_name = self.1.clone();
+        Box::pin(async move {
+            let (peer_addr, bearer) = caller(&request);
+            let body = request.into_inner();
+            let (response_type, fields) =
+                run(bridge::call(&server, peer_addr, &type_name, bearer.as_deref(), &body).await)?;
+            let body = serde_json::to_vec(&fields).map_err(|e| Status::internal(e.to_string()))?;
+            let mut response = tonic::Response::new(Bytes::from(body));
+            if let Ok(value) = MetadataValue::try_from(response_type.as_str()) {
+                response.metadata_mut().insert(TYPE_METADATA, value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// The caller's address and bearer token, if it sent one.
+fn caller(request: &tonic::Request<Bytes>) -> (SocketAddr, Option<String>) {
+    let peer_addr = request
+        .remote_addr()
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let bearer = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    (peer_addr, bearer)
+}
+
+fn run<T>(result: Result<T, ProtocolError>) -> Result<T, Status> {
+    result.map_err(|err| {
+        tracing::debug!(error = %err, "gRPC request failed");
+        let details = serde_json::to_vec(&err).unwrap_or_default();
+        Status::with_details(code(err.code), err.message, details.into())
+    })
+}
+
+fn code(code: ErrorCode) -> Code {
+    match code {
+        ErrorCode::Malformed | ErrorCode::ChecksumMismatch | ErrorCode::InvalidRequest => {
+            Code::InvalidArgument
+        }
+        ErrorCode::FrameTooLarge | ErrorCode::PayloadTooLarge => Code::OutOfRange,
+        ErrorCode::Handler => Code::Unknown,
+        ErrorCode::Internal => Code::Internal,
+        ErrorCode::Timeout => Code::DeadlineExceeded,
+        ErrorCode::Overloaded | ErrorCode::Busy | ErrorCode::PeerUnavailable => Code::Unavailable,
+        ErrorCode::Unsupported => Code::Unimplemented,
+        ErrorCode::Unauthenticated => Code::Unauthenticated,
+        ErrorCode::PermissionDenied => Code::PermissionDenied,
+        ErrorCode::RateLimited | ErrorCode::QuotaExceeded => Code::ResourceExhausted,
+        ErrorCode::Cancelled => Code::Cancelled,
+    }
+}
+
+/// Passes messages through as bytes, leaving them to the service to read.
+#[derive(Default)]
+struct Raw;
+
+impl Codec for Raw {
+    type Encode = Bytes;
+    type Decode = Bytes;
+    type Encoder = Raw;
+    type Decoder = Raw;
+
+    fn encoder(&mut self) -> Raw {
+        Raw
+    }
+
+    fn decoder(&mut self) -> Raw {
+        Raw
+    }
+}
+
+impl Encoder for Raw {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn encode(&mut self, item: Bytes, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for Raw {
+    type Item = Bytes;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Bytes>, Status> {
+        Ok(Some(src.copy_to_bytes(src.remaining())))
+    }
+}
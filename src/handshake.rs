@@ -0,0 +1,246 @@
+use std::ops::BitOr;
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::codec::Codec;
+use crate::frame::{Compression, Frame, FrameCodec};
+use crate::{ErrorCode, ProtocolError};
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest protocol version this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional protocol features, negotiated as the intersection of what both sides offer.
+///
+/// Unknown bits are ignored, so newer peers can offer features older ones don't know about.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Features(u32);
+
+impl Features {
+    // Bit 0 once switched zstd on; compression is now negotiated like codecs.
+    /// Messages too long for one frame may be split across several; see `FrameCodec`.
+    pub const CHUNKING: Features = Features(1 << 1);
+    /// Every frame carries a CRC32 of its contents.
+    pub const CHECKSUMS: Features = Features(1 << 2);
+    /// The handshake is followed by a `resumption::Resume` exchange.
+    pub const RESUMPTION: Features = Features(1 << 3);
+    /// The server replies to calls in the order it received them.
+    pub const ORDERED_REPLIES: Features = Features(1 << 4);
+    /// The server says why with an `envelope::GoAway` before closing a connection.
+    pub const GO_AWAY: Features = Features(1 << 5);
+    /// The server may send `envelope::Progress` for a call ahead of its reply.
+    pub const PROGRESS: Features = Features(1 << 6);
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Self {
+        Features(self.0 & other.0)
+    }
+
+    /// `self` with `flag` added when `enabled` is true.
+    pub fn with(self, flag: Features, enabled: bool) -> Self {
+        if enabled { self | flag } else { self }
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// First frame sent by the client, before any requests.
+///
+/// `version` must stay the first field so any future server can read it
+/// before deciding how to parse the rest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Hello {
+    pub version: u16,
+    /// Codecs the client can use, most preferred first.
+    pub codecs: Vec<Codec>,
+    pub features: Features,
+    /// Compression the client can use, most preferred first.
+    pub compression: Vec<Compression>,
+}
+
+/// The server's answer to [`Hello`], carrying the settings both sides use from then on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloAck {
+    pub version: u16,
+    pub codec: Codec,
+    pub features: Features,
+    /// A nonce to authenticate against, present when the server requires authentication.
+    pub challenge: Option<Vec<u8>>,
+    pub compression: Compression,
+}
+
+impl HelloAck {
+    pub fn chunking(&self) -> bool {
+        self.features.contains(Features::CHUNKING)
+    }
+
+    pub fn checksums(&self) -> bool {
+        self.features.contains(Features::CHECKSUMS)
+    }
+
+    pub fn resumption(&self) -> bool {
+        self.features.contains(Features::RESUMPTION)
+    }
+
+    pub fn ordered_replies(&self) -> bool {
+        self.features.contains(Features::ORDERED_REPLIES)
+    }
+
+    pub fn go_away(&self) -> bool {
+        self.features.contains(Features::GO_AWAY)
+    }
+
+    pub fn progress(&self) -> bool {
+        self.features.contains(Features::PROGRESS)
+    }
+}
+
+/// Answers the client's [`Hello`], or rejects it and returns the reason as an error.
+///
+/// The codec is the client's most preferred one that appears in `codecs`, and
+/// likewise the compression, which is `Compression::None` if there's none in
+/// common.
+pub async fn accept<S>(
+    framed: &mut Framed<S, FrameCodec>,
+    features: Features,
+    codecs: &[Codec],
+    compression: &[Compression],
+    challenge: Option<Vec<u8>>,
+) -> Result<HelloAck>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let bytes = recv(framed)
+        .await
+        .context("connection closed before handshake")?;
+
+    let reply = negotiate(&bytes, features, codecs, compression, challenge);
+    framed.send(bincode::serialize(&reply)?.into()).await?;
+    let ack = reply?;
+    framed.codec_mut().set_compression(ack.compression);
+    framed.codec_mut().set_chunking(ack.chunking());
+    framed.codec_mut().set_checksums(ack.checksums());
+
+    Ok(ack)
+}
+
+fn negotiate(
+    bytes: &[u8],
+    features: Features,
+    codecs: &[Codec],
+    compression: &[Compression],
+    challenge: Option<Vec<u8>>,
+) -> Result<HelloAck, ProtocolError> {
+    let version: u16 = bincode::deserialize(bytes).map_err(|e| {
+        ProtocolError::new(ErrorCode::Malformed, format!("malformed handshake: {e}"))
+    })?;
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(ProtocolError::new(
+            ErrorCode::Unsupported,
+            format!(
+                "protocol version {version} is not supported; \
+                 this server speaks {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+            ),
+        ));
+    }
+
+    let hello: Hello = bincode::deserialize(bytes).map_err(|e| {
+        ProtocolError::new(ErrorCode::Malformed, format!("malformed handshake: {e}"))
+    })?;
+    let codec = hello
+        .codecs
+        .iter()
+        .copied()
+        .find(|codec| codecs.contains(codec))
+        .ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::Unsupported,
+                format!(
+                    "none of the offered codecs {:?} are supported; this server speaks {codecs:?}",
+                    hello.codecs
+                ),
+            )
+        })?;
+    let compression = hello
+        .compression
+        .iter()
+        .copied()
+        .find(|algorithm| compression.contains(algorithm))
+        .unwrap_or_default();
+
+    Ok(HelloAck {
+        version: version.min(PROTOCOL_VERSION),
+        codec,
+        features: features.intersection(hello.features),
+        challenge,
+        compression,
+    })
+}
+
+pub async fn initiate<S>(framed: &mut Framed<S, FrameCodec>, hello: Hello) -> Result<HelloAck>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed.send(bincode::serialize(&hello)?.into()).await?;
+
+    let bytes = recv(framed)
+        .await
+        .context("connection closed during handshake")?;
+    let reply: Result<HelloAck, ProtocolError> =
+        bincode::deserialize(&bytes).context("malformed handshake ack")?;
+    let ack = reply.context("server rejected the handshake")?;
+
+    if !(MIN_PROTOCOL_VERSION..=hello.version).contains(&ack.version) {
+        bail!("server chose unsupported protocol version {}", ack.version);
+    }
+    if !hello.codecs.contains(&ack.codec) {
+        bail!("server chose codec {:?} that was not offered", ack.codec);
+    }
+    if !hello.features.contains(ack.features) {
+        bail!("server enabled features that were not offered");
+    }
+    if ack.compression != Compression::None && !hello.compression.contains(&ack.compression) {
+        bail!(
+            "server chose compression {:?} that was not offered",
+            ack.compression
+        );
+    }
+    framed.codec_mut().set_compression(ack.compression);
+    framed.codec_mut().set_chunking(ack.chunking());
+    framed.codec_mut().set_checksums(ack.checksums());
+
+    Ok(ack)
+}
+
+pub(crate) async fn recv<S>(framed: &mut Framed<S, FrameCodec>) -> Result<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match framed.next().await.context("stream ended")?? {
+        Frame::Payload(bytes) => Ok(bytes),
+        Frame::Oversized { len } => bail!("handshake frame of {len} bytes exceeds the limit"),
+        Frame::Corrupt { .. } => bail!("handshake frame failed its checksum"),
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use crate::rt::{self, Instant};
+
+/// Liveness tracking shared by both ends of a connection.
+///
+/// Any received frame counts as a sign of life; pings are only there to
+/// provoke traffic on connections that are otherwise quiet.
+pub(crate) struct Heartbeat {
+    interval: Option<Duration>,
+    next_ping: Instant,
+    timeout: Duration,
+    last_seen: Instant,
+    next_seq: u64,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(interval: Option<Duration>, timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            next_ping: now + interval.unwrap_or_default(),
+            timeout,
+            last_seen: now,
+            next_seq: 1,
+        }
+    }
+
+    pub(crate) fn saw_frame(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Waits for the next ping slot and returns its sequence number, or
+    /// `None` once the peer has been silent for longer than the timeout.
+    /// Never resolves when heartbeats are disabled.
+    pub(crate) async fn tick(&mut self) -> Option<u64> {
+        let Some(interval) = self.interval else {
+            return std::future::pending().await;
+        };
+        rt::sleep(self.next_ping.saturating_duration_since(Instant::now())).await;
+        // A late tick pushes the ones after it back rather than bunching them up.
+        self.next_ping = Instant::now() + interval;
+
+        if self.last_seen.elapsed() > self.timeout {
+            return None;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Some(seq)
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::latency::Latencies;
+use crate::retry::duplicate;
+use crate::{Request, Response, rt};
+
+/// When to send a second copy of a slow call, for latency-sensitive reads.
+///
+/// Only requests whose `Request::idempotent` says so are ever hedged, since
+/// both copies may run on the server. The hedge goes out once the call has
+/// taken longer than `quantile` of earlier calls of its type, kept within
+/// `min_delay..=max_delay`; whichever copy answers first wins, and the other
+/// is cancelled.
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    /// The share of earlier calls that must have been answered sooner.
+    pub quantile: f64,
+    pub min_delay: Duration,
+    /// Also the delay until `min_samples` calls of a type have been answered.
+    pub max_delay: Duration,
+    pub min_samples: u64,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            quantile: 0.95,
+            min_delay: Duration::from_millis(5),
+            max_delay: Duration::from_secs(1),
+            min_samples: 20,
+        }
+    }
+}
+
+/// A `HedgePolicy` and the latencies it picks its delays from.
+pub(crate) struct Hedger {
+    policy: HedgePolicy,
+    latencies: Latencies,
+}
+
+impl Hedger {
+    pub(crate) fn new(policy: HedgePolicy) -> Self {
+        Self {
+            policy,
+            latencies: Latencies::default(),
+        }
+    }
+
+    /// Runs `attempt`, and again with a copy of the request if the first
+    /// hasn't been answered in time, returning the first to succeed.
+    pub(crate) async fn run<F, Fut>(
+        &self,
+        request: Box<dyn Request>,
+        mut attempt: F,
+    ) -> Result<Box<dyn Response>>
+    where
+        F: FnMut(Box<dyn Request>) -> Fut,
+        Fut: Future<Output = Result<Box<dyn Response>>>,
+    {
+        if !request.idempotent() {
+            return attempt(request).await;
+        }
+        let request_type = request.typetag_name();
+        let delay = self.delay(request_type);
+        let hedge = duplicate(&*request)?;
+        let started = Instant::now();
+
+        let primary = attempt(request);
+        tokio::pin!(primary);
+        let result = tokio::select! {
+            result = &mut primary => result,
+            _ = rt::sleep(delay) => {
+                tracing::debug!(request_type, ?delay, "Hedging call");
+                let hedged = attempt(hedge);
+                tokio::pin!(hedged);
+                // A copy that fails leaves the other to answer; dropping the
+                // loser cancels it on the server.
+                tokio::select! {
+                    result = &mut primary => match result {
+                        Ok(response) => Ok(response),
+                        Err(_) => hedged.await,
+                    },
+                    result = &mut hedged => match result {
+                        Ok(response) => Ok(response),
+                        Err(_) => primary.await,
+                    },
+                }
+            }
+        };
+        if result.is_ok() {
+            self.latencies.record(request_type, started.elapsed());
+        }
+        result
+    }
+
+    fn delay(&self, request_type: &str) -> Duration {
+        let policy = &self.policy;
+        self.latencies
+            .quantile(request_type, policy.quantile, policy.min_samples)
+            .map_or(policy.max_delay, |delay| {
+                delay.clamp(policy.min_delay, policy.max_delay)
+            })
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::sync::watch;
+
+/// Remembered calls are pruned once the table grows past this many keys.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The identity a key was sent under, if any, and the key itself, so clients
+/// can't replay each other's responses.
+pub(crate) type Key = (Option<String>, String);
+
+enum Slot {
+    /// The first call with the key is still running; it sends the results when done.
+    Running(watch::Receiver<Option<Bytes>>),
+    Done {
+        results: Bytes,
+        expires: Instant,
+    },
+}
+
+/// The results of recent calls that carried an idempotency key, shared by
+/// every session on a server, so a retry on a new connection finds them too.
+#[derive(Clone, Default)]
+pub(crate) struct IdempotencyKeys {
+    slots: Arc<Mutex<HashMap<Key, Slot>>>,
+}
+
+/// What a call with an idempotency key should do.
+pub(crate) enum Claim {
+    /// The key is new: run the call and hand its results to the `Completion`.
+    Run(Completion),
+    /// A call with the key already ran; these are its encoded results.
+    Replay(Bytes),
+}
+
+/// The right to run the call for a key. Dropping it without `finish`
+/// forgets the key, so a later call with it runs afresh.
+pub(crate) struct Completion {
+    keys: IdempotencyKeys,
+    key: Key,
+    done: watch::Sender<Option<Bytes>>,
+}
+
+impl IdempotencyKeys {
+    /// Claims `key`, waiting for a call already running with it to finish.
+    pub(crate) async fn claim(&self, key: Key) -> Claim {
+        loop {
+            let mut running = {
+                let mut slots = self.slots.lock().unwrap();
+                let now = Instant::now();
+                if slots.len() >= PRUNE_THRESHOLD {
+                    slots.retain(|_, slot| match slot {
+                        Slot::Running(done) => done.has_changed().is_ok(),
+                        Slot::Done { expires, .. } => *expires > now,
+                    });
+                }
+                match slots.get(&key) {
+                    Some(Slot::Done { results, expires }) if *expires > now => {
+                        return Claim::Replay(results.clone());
+                    }
+                    // A closed channel means the call that claimed the key was abandoned.
+                    Some(Slot::Running(done)) if done.has_changed().is_ok() => done.clone(),
+                    _ => {
+                        let (done, running) = watch::channel(None);
+                        slots.insert(key.clone(), Slot::Running(running));
+                        return Claim::Run(Completion {
+                            keys: self.clone(),
+                            key,
+                            done,
+                        });
+                    }
+                }
+            };
+            if let Ok(results) = running.wait_for(Option::is_some).await {
+                return Claim::Replay(results.clone().expect("waited for results"));
+            }
+        }
+    }
+}
+
+impl Completion {
+    /// Remembers `results` for `ttl` and passes them to calls waiting on the key.
+    pub(crate) fn finish(self, results: Bytes, ttl: Duration) {
+        let expires = Instant::now() + ttl;
+        let mut slots = self.keys.slots.lock().unwrap();
+        slots.insert(
+            self.key.clone(),
+            Slot::Done {
+                results: results.clone(),
+                expires,
+            },
+        );
+        let _ = self.done.send(Some(results));
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if self.done.borrow().is_none() {
+            let mut slots = self.keys.slots.lock().unwrap();
+            if matches!(slots.get(&self.key), Some(Slot::Running(_))) {
+                slots.remove(&self.key);
+            }
+        }
+    }
+}
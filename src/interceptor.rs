@@ -0,0 +1,124 @@
+//! The client's counterpart to [`Middleware`](crate::Middleware): code that
+//! wraps every call a `Client` sends, to change what goes out and see what
+//! comes back.
+//!
+//! ```ignore
+//! #[derive(Debug)]
+//! struct BearerToken(Arc<TokenSource>);
+//!
+//! #[async_trait]
+//! impl Interceptor for BearerToken {
+//!     async fn call(&self, mut call: OutgoingCall, next: Next<'_>) -> Result<CallReply> {
+//!         let retry = call.duplicate()?;
+//!         call.metadata.insert("authorization".into(), self.0.token().await?);
+//!         let reply = next.run(call).await?;
+//!         if !reply.results.iter().any(|r| r.as_ref().is_err_and(|e| e.code == ErrorCode::Unauthenticated)) {
+//!             return Ok(reply);
+//!         }
+//!         let mut retry = retry;
+//!         retry.metadata.insert("authorization".into(), self.0.refresh().await?);
+//!         next.run(retry).await
+//!     }
+//! }
+//!
+//! let client = Client::connect_with(addr, ClientConfig::default().interceptor(BearerToken(tokens))).await?;
+//! ```
+//!
+//! Interceptors wrap calls and batches, inside any retries and before the
+//! requests are signed or sealed; streams and uploads go out as they are.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::client::ProgressSender;
+use crate::envelope::TraceContext;
+use crate::{Client, Request, ResponseResult, retry};
+
+/// Wraps every call a client sends; registered in order with
+/// `ClientConfig::interceptor`, the first one registered runs outermost.
+///
+/// A call that never got an answer, e.g. because the connection dropped,
+/// fails as a whole. Like everything in `ClientConfig`, an interceptor
+/// must be `Debug`.
+#[async_trait]
+pub trait Interceptor: Send + Sync + fmt::Debug + 'static {
+    async fn call(&self, call: OutgoingCall, next: Next<'_>) -> Result<CallReply>;
+}
+
+/// What a call will put in its envelope, for interceptors to change.
+#[derive(Debug)]
+pub struct OutgoingCall {
+    pub requests: Vec<Box<dyn Request>>,
+    /// Milliseconds since the Unix epoch after which the server needn't start.
+    pub deadline: Option<u64>,
+    pub trace: Option<TraceContext>,
+    pub sequential: bool,
+    pub idempotency_key: Option<String>,
+    /// Sent alongside the requests, for handlers to read with `Context::metadata`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl OutgoingCall {
+    /// A copy of the call to send again, since sending one consumes it.
+    pub fn duplicate(&self) -> Result<Self> {
+        Ok(Self {
+            requests: self
+                .requests
+                .iter()
+                .map(|request| retry::duplicate(&**request))
+                .collect::<Result<_>>()?,
+            deadline: self.deadline,
+            trace: self.trace.clone(),
+            sequential: self.sequential,
+            idempotency_key: self.idempotency_key.clone(),
+            metadata: self.metadata.clone(),
+        })
+    }
+}
+
+/// What the server answered a call with.
+#[derive(Debug)]
+pub struct CallReply {
+    /// One per request, in the order they were sent.
+    pub results: Vec<ResponseResult>,
+    /// Set by the server's middleware and handlers with
+    /// `Context::set_response_metadata`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// The rest of the chain after the current interceptor, ending in the
+/// call being sent. It can be run more than once, to send a call again.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Interceptor>],
+    client: &'a Client,
+    progress: Option<&'a ProgressSender>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        chain: &'a [Arc<dyn Interceptor>],
+        client: &'a Client,
+        progress: Option<&'a ProgressSender>,
+    ) -> Self {
+        Self {
+            chain,
+            client,
+            progress,
+        }
+    }
+
+    pub async fn run(self, call: OutgoingCall) -> Result<CallReply> {
+        match self.chain.split_first() {
+            Some((interceptor, rest)) => {
+                let next = Next::new(rest, self.client, self.progress);
+                interceptor.call(call, next).await
+            }
+            None => self.client.send_call(call, self.progress.cloned()).await,
+        }
+    }
+}
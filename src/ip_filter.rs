@@ -0,0 +1,130 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Which peer addresses may connect, by CIDR range. A peer in a denied range
+/// is always refused; otherwise it is let in if no allowed ranges are given
+/// or it falls in one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets peers in `range` connect, and once any range is allowed, only them.
+    pub fn allow(mut self, range: IpNet) -> Self {
+        self.allow.push(range);
+        self
+    }
+
+    pub fn deny(mut self, range: IpNet) -> Self {
+        self.deny.push(range);
+        self
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // An IPv4 client on a dual-stack socket shows up as `::ffff:a.b.c.d`.
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|range| range.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(cidr: &str) -> IpNet {
+        cidr.parse().unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn empty_filter_permits_everyone() {
+        let filter = IpFilter::new();
+        assert!(filter.permits(ip("192.0.2.1")));
+        assert!(filter.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn allowed_ipv4_prefix_admits_only_its_addresses() {
+        let filter = IpFilter::new().allow(range("10.1.0.0/16"));
+        assert!(filter.permits(ip("10.1.0.0")));
+        assert!(filter.permits(ip("10.1.255.255")));
+        assert!(!filter.permits(ip("10.2.0.1")));
+        assert!(!filter.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn allowed_ipv6_prefix_admits_only_its_addresses() {
+        let filter = IpFilter::new().allow(range("2001:db8::/32"));
+        assert!(filter.permits(ip("2001:db8:ffff::1")));
+        assert!(!filter.permits(ip("2001:db9::1")));
+        assert!(!filter.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn host_prefixes_match_one_address() {
+        let filter = IpFilter::new()
+            .allow(range("192.0.2.7/32"))
+            .allow(range("2001:db8::7/128"));
+        assert!(filter.permits(ip("192.0.2.7")));
+        assert!(!filter.permits(ip("192.0.2.8")));
+        assert!(filter.permits(ip("2001:db8::7")));
+        assert!(!filter.permits(ip("2001:db8::8")));
+    }
+
+    #[test]
+    fn zero_prefix_covers_its_whole_family() {
+        let filter = IpFilter::new().deny(range("0.0.0.0/0"));
+        assert!(!filter.permits(ip("192.0.2.1")));
+        assert!(!filter.permits(ip("255.255.255.255")));
+        assert!(filter.permits(ip("2001:db8::1")));
+
+        let filter = IpFilter::new().allow(range("::/0"));
+        assert!(filter.permits(ip("2001:db8::1")));
+        assert!(!filter.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let filter = IpFilter::new()
+            .allow(range("10.0.0.0/8"))
+            .deny(range("10.9.0.0/16"));
+        assert!(filter.permits(ip("10.1.2.3")));
+        assert!(!filter.permits(ip("10.9.2.3")));
+
+        // Even for the very range that is also allowed.
+        let filter = IpFilter::new()
+            .deny(range("192.0.2.1/32"))
+            .allow(range("192.0.2.1/32"));
+        assert!(!filter.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn deny_alone_admits_everyone_else() {
+        let filter = IpFilter::new().deny(range("192.0.2.0/24"));
+        assert!(!filter.permits(ip("192.0.2.1")));
+        assert!(filter.permits(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_is_matched_as_ipv4() {
+        let filter = IpFilter::new().allow(range("192.0.2.0/24"));
+        assert!(filter.permits(ip("::ffff:192.0.2.1")));
+        assert!(!filter.permits(ip("::ffff:198.51.100.1")));
+
+        let filter = IpFilter::new().deny(range("192.0.2.0/24"));
+        assert!(!filter.permits(ip("::ffff:192.0.2.1")));
+    }
+}
@@ -0,0 +1,389 @@
+//! Requests run in the background as jobs, for work too long to hold a call
+//! open for.
+//!
+//! [`SubmitJob`] wraps any request and answers straight away with the id of
+//! a job that runs it through the server's authorization and middleware
+//! like a call, but with no timeout. Clients then poll it with
+//! [`JobStatus`], wait for its response or error with [`JobResult`], or
+//! stop it with [`CancelJob`]. Up to `ServerConfig::max_running_jobs` run at
+//! once and up to `max_queued_jobs` more wait their turn; a finished job is
+//! kept for `job_retention`. Jobs belong to the identity that submitted
+//! them, or on a server that doesn't authenticate clients to the connection
+//! that did, and are cancelled when the server shuts down. Their ids are
+//! random, so one client can't guess another's.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::schema::Describe;
+use crate::server::pipeline;
+use crate::{
+    Context, ErrorCode, ProtocolError, Request, Response, ResponseResult, TypedRequest,
+    ValidationError, panic, rt,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Describe)]
+pub enum JobState {
+    /// Waiting for one of the running jobs to finish.
+    Queued,
+    Running,
+    /// Finished with a response.
+    Succeeded,
+    /// Finished with an error.
+    Failed,
+    /// Cancelled by `CancelJob` or the server shutting down.
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+/// Who may see and cancel a job.
+#[derive(PartialEq, Eq)]
+enum Owner {
+    /// The identity that submitted it, as `Identity::key` names it.
+    Identity(String),
+    /// The connection that submitted it, on a server that doesn't
+    /// authenticate clients.
+    Connection(u64),
+}
+
+struct Job {
+    owner: Owner,
+    request_type: &'static str,
+    state: watch::Sender<JobState>,
+    /// The encoded `ResponseResult`, once the job has finished.
+    result: Option<Bytes>,
+    cancellation: CancellationToken,
+    /// When a finished job is forgotten.
+    expires: Option<Instant>,
+}
+
+/// Every job a server knows of, shared by all its sessions.
+pub(crate) struct Jobs {
+    table: Mutex<HashMap<u64, Job>>,
+    running: Arc<Semaphore>,
+    max_queued: usize,
+}
+
+impl Jobs {
+    pub(crate) fn new(max_running: usize, max_queued: usize) -> Self {
+        Self {
+            table: Mutex::default(),
+            running: Arc::new(Semaphore::new(max_running)),
+            max_queued,
+        }
+    }
+
+    /// Starts running `request` in the background, returning the job's id.
+    fn submit(&self, ctx: &Context, request: Box<dyn Request>) -> Result<u64, ProtocolError> {
+        let request_type = request.typetag_name();
+        let mut table = self.table.lock().unwrap();
+        let now = Instant::now();
+        table.retain(|_, job| job.expires.is_none_or(|expires| expires > now));
+        let queued = table
+            .values()
+            .filter(|job| *job.state.borrow() == JobState::Queued)
+            .count();
+        if queued >= self.max_queued {
+            return Err(ProtocolError::new(
+                ErrorCode::Busy,
+                "too many jobs are waiting to run",
+            ));
+        }
+
+        let id = loop {
+            let id = getrandom::u64().expect("the OS random number generator is available");
+            if !table.contains_key(&id) {
+                break id;
+            }
+        };
+        let cancellation = ctx.server().shutdown.child_token();
+        let (state, _) = watch::channel(JobState::Queued);
+        table.insert(
+            id,
+            Job {
+                owner: owner(ctx),
+                request_type,
+                state,
+                result: None,
+                cancellation: cancellation.clone(),
+                expires: None,
+            },
+        );
+        drop(table);
+
+        let ctx = ctx.for_job(cancellation);
+        let running = self.running.clone();
+        rt::spawn(async move {
+            let result = tokio::select! {
+                permit = running.acquire_owned() => {
+                    let Ok(_permit) = permit else { return };
+                    ctx.server().jobs.set_state(id, JobState::Running);
+                    tracing::debug!(job = id, request_type, "Job started");
+                    run(request, &ctx).await
+                }
+                _ = ctx.cancelled() => Err(cancelled()),
+            };
+            ctx.server().jobs.finish(id, result, &ctx);
+        });
+        Ok(id)
+    }
+
+    fn set_state(&self, id: u64, state: JobState) {
+        if let Some(job) = self.table.lock().unwrap().get(&id) {
+            job.state.send_replace(state);
+        }
+    }
+
+    fn finish(&self, id: u64, result: ResponseResult, ctx: &Context) {
+        let state = match &result {
+            Ok(_) => JobState::Succeeded,
+            Err(err) if err.code == ErrorCode::Cancelled => JobState::Cancelled,
+            Err(_) => JobState::Failed,
+        };
+        let encoded = match bincode::serialize(&result) {
+            Ok(encoded) => Bytes::from(encoded),
+            Err(e) => {
+                tracing::error!(job = id, error = %e, "Could not encode job result");
+                let err = ProtocolError::new(ErrorCode::Internal, "could not encode job result");
+                Bytes::from(bincode::serialize(&ResponseResult::Err(err)).unwrap_or_default())
+            }
+        };
+        let mut table = self.table.lock().unwrap();
+        if let Some(job) = table.get_mut(&id) {
+            tracing::debug!(
+                job = id,
+                request_type = job.request_type,
+                ?state,
+                "Job finished"
+            );
+            job.result = Some(encoded);
+            job.expires = Some(Instant::now() + ctx.server().config().job_retention);
+            job.state.send_replace(state);
+        }
+    }
+
+    /// The state of job `id` and a receiver for its changes, if the caller may see it.
+    fn watch(
+        &self,
+        ctx: &Context,
+        id: u64,
+    ) -> Result<(&'static str, watch::Receiver<JobState>), ProtocolError> {
+        let table = self.table.lock().unwrap();
+        match table.get(&id) {
+            Some(job) if job.owner == owner(ctx) => Ok((job.request_type, job.state.subscribe())),
+            _ => Err(unknown(id)),
+        }
+    }
+
+    fn result(&self, ctx: &Context, id: u64) -> Result<ResponseResult, ProtocolError> {
+        let table = self.table.lock().unwrap();
+        let encoded = match table.get(&id) {
+            Some(job) if job.owner == owner(ctx) => job.result.clone(),
+            _ => return Err(unknown(id)),
+        };
+        drop(table);
+        let encoded = encoded.ok_or_else(|| unknown(id))?;
+        bincode::deserialize(&encoded)
+            .map_err(|e| ProtocolError::new(ErrorCode::Internal, format!("{e}")))
+    }
+
+    /// Cancels job `id`, returning whether it hadn't finished yet.
+    fn cancel(&self, ctx: &Context, id: u64) -> Result<bool, ProtocolError> {
+        let table = self.table.lock().unwrap();
+        match table.get(&id) {
+            Some(job) if job.owner == owner(ctx) => {
+                let running = !job.state.borrow().is_finished();
+                job.cancellation.cancel();
+                Ok(running)
+            }
+            _ => Err(unknown(id)),
+        }
+    }
+}
+
+/// Runs a job's request as a call would be, short of the call's timeout.
+async fn run(request: Box<dyn Request>, ctx: &Context) -> ResponseResult {
+    let request_type = request.typetag_name();
+    let middleware = ctx.server().middleware.clone();
+    tokio::select! {
+        result = panic::isolate(request_type, pipeline(request, ctx, &middleware)) => {
+            result.and_then(|result| result.map_err(ProtocolError::from_handler))
+        }
+        _ = ctx.cancelled() => Err(cancelled()),
+    }
+}
+
+fn owner(ctx: &Context) -> Owner {
+    match ctx.identity() {
+        Some(identity) => Owner::Identity(identity.key().into_owned()),
+        None => Owner::Connection(ctx.connection_id),
+    }
+}
+
+fn unknown(id: u64) -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::InvalidRequest,
+        format!("there is no job {id}; it may have expired"),
+    )
+}
+
+fn cancelled() -> ProtocolError {
+    ProtocolError::new(ErrorCode::Cancelled, "the job was cancelled")
+}
+
+/// Runs `request` as a job, answering with its id as soon as it is queued.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SubmitJobResponse)]
+pub struct SubmitJob {
+    pub request: Box<dyn Request>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+pub struct SubmitJobResponse {
+    pub id: u64,
+}
+
+#[typetag::serde]
+impl Response for SubmitJobResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for SubmitJob {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        // Refused now rather than failing the job once it runs.
+        ctx.server().authorize(ctx, self.request.typetag_name())?;
+        // Trait objects can't be cloned, so the job gets the request through its wire form.
+        let request: Box<dyn Request> = bincode::deserialize(&bincode::serialize(&self.request)?)?;
+        let id = ctx.server().jobs.submit(ctx, request)?;
+        Ok(Box::new(SubmitJobResponse { id }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        self.request.validate()
+    }
+}
+
+impl TypedRequest for SubmitJob {
+    type Response = SubmitJobResponse;
+}
+
+/// Asks where job `id` has got to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+#[schema(response = JobStatusResponse)]
+pub struct JobStatus {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct JobStatusResponse {
+    pub state: JobState,
+    pub request_type: String,
+}
+
+#[typetag::serde]
+impl Response for JobStatusResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for JobStatus {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let (request_type, state) = ctx.server().jobs.watch(ctx, self.id)?;
+        let state = *state.borrow();
+        Ok(Box::new(JobStatusResponse {
+            state,
+            request_type: request_type.to_string(),
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for JobStatus {
+    type Response = JobStatusResponse;
+}
+
+/// Waits for job `id` to finish and answers with its outcome. The wait is
+/// bounded by the request timeout, so a long job may need asking again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+#[schema(response = JobResultResponse)]
+pub struct JobResult {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct JobResultResponse {
+    /// The job's response, or the error it failed with.
+    pub result: ResponseResult,
+}
+
+#[typetag::serde]
+impl Response for JobResultResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for JobResult {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let jobs = &ctx.server().jobs;
+        let (_, mut state) = jobs.watch(ctx, self.id)?;
+        // The sender lives as long as the job, which outlives its result.
+        let _ = state.wait_for(|state| state.is_finished()).await;
+        let result = jobs.result(ctx, self.id)?;
+        Ok(Box::new(JobResultResponse { result }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for JobResult {
+    type Response = JobResultResponse;
+}
+
+/// Cancels job `id`, whether it is queued or running. A running handler is
+/// dropped at its next await, as when a call is cancelled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+#[schema(response = CancelJobResponse)]
+pub struct CancelJob {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Describe)]
+pub struct CancelJobResponse {
+    /// Whether the job hadn't finished yet.
+    pub cancelled: bool,
+}
+
+#[typetag::serde]
+impl Response for CancelJobResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for CancelJob {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let cancelled = ctx.server().jobs.cancel(ctx, self.id)?;
+        Ok(Box::new(CancelJobResponse { cancelled }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for CancelJob {
+    type Response = CancelJobResponse;
+}
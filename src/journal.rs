@@ -0,0 +1,353 @@
+//! An append-only file of every message published on a server, so
+//! subscribers that were away can catch up with `SubscribeFrom`.
+//!
+//! Each message gets an offset, counting up from 1 across all topics and
+//! carrying on across restarts, and goes out to live subscribers as a
+//! `TopicMessage` with that offset. The file keeps growing: nothing is
+//! compacted or expired, so rotate it between runs if it matters.
+//!
+//! Messages published for later with `PublishAt` are kept beside it, in a
+//! file of the same name ending in `.scheduled`, until they go out; those
+//! still waiting when the server stopped go out once it serves again.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Each record's length, before the record itself.
+const LEN_PREFIX: u64 = 4;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    offset: u64,
+    topic: String,
+    /// The message in bincode, as `TopicMessage` carries it.
+    message: Bytes,
+}
+
+/// A message waiting in the `.scheduled` file to be published.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ScheduledEntry {
+    pub(crate) id: u64,
+    pub(crate) topic: String,
+    /// When to publish it, in microseconds since the Unix epoch.
+    pub(crate) deliver_at_us: u64,
+    pub(crate) message: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ScheduleRecord {
+    Added(ScheduledEntry),
+    Published(u64),
+}
+
+/// A message journal backed by one file.
+pub struct Journal {
+    path: PathBuf,
+    sync: bool,
+    inner: Arc<Mutex<Inner>>,
+    /// `Inner::next_offset`, for reading without waiting on a write.
+    next_offset: Arc<AtomicU64>,
+    schedule: Arc<Mutex<Schedule>>,
+}
+
+struct Schedule {
+    file: File,
+    /// What was still waiting when the journal was opened, until taken.
+    pending: Vec<ScheduledEntry>,
+}
+
+struct Inner {
+    file: File,
+    next_offset: u64,
+    end: u64,
+    /// Where each topic's records start in the file, by offset.
+    index: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it doesn't exist. A record
+    /// cut short by a crash is dropped from the end; one that doesn't decode
+    /// anywhere else fails it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        let mut next_offset = 1;
+        let mut index: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        let records = read_records(&file, &path, |entry: Entry, position| {
+            index
+                .entry(entry.topic)
+                .or_default()
+                .push((entry.offset, position));
+            next_offset = entry.offset + 1;
+        })?;
+        if records.torn {
+            tracing::warn!(
+                path = %path.display(),
+                end = records.end,
+                "Dropping a torn record from the end of the journal"
+            );
+            file.set_len(records.end)?;
+        }
+        let schedule = open_schedule(&path)?;
+        Ok(Self {
+            path,
+            sync: false,
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                next_offset,
+                end: records.end,
+                index,
+            })),
+            next_offset: Arc::new(AtomicU64::new(next_offset)),
+            schedule: Arc::new(Mutex::new(schedule)),
+        })
+    }
+
+    /// Flushes every append to disk before the message is delivered, so
+    /// messages survive the machine going down and not just the process.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The offset the next message will get.
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset.load(Ordering::Acquire)
+    }
+
+    /// Appends `message`, already in bincode, returning its offset.
+    pub(crate) async fn append(&self, topic: &str, message: Bytes) -> Result<u64> {
+        let (inner, next_offset) = (self.inner.clone(), self.next_offset.clone());
+        let (path, sync, topic) = (self.path.clone(), self.sync, topic.to_string());
+        blocking(move || {
+            let mut inner = inner.lock().unwrap();
+            let offset = inner.next_offset;
+            let record = record(&Entry {
+                offset,
+                topic: topic.clone(),
+                message,
+            })?;
+            inner
+                .file
+                .write_all(&record)
+                .with_context(|| format!("failed to append to {}", path.display()))?;
+            if sync {
+                inner.file.sync_data()?;
+            }
+
+            let position = inner.end;
+            inner
+                .index
+                .entry(topic)
+                .or_default()
+                .push((offset, position));
+            inner.next_offset += 1;
+            inner.end += record.len() as u64;
+            next_offset.store(inner.next_offset, Ordering::Release);
+            Ok(offset)
+        })
+        .await
+    }
+
+    /// Keeps `entry` until `published` is called with its id.
+    pub(crate) async fn schedule(&self, entry: ScheduledEntry) -> Result<()> {
+        self.append_schedule(ScheduleRecord::Added(entry)).await
+    }
+
+    /// Forgets the scheduled message `id`, now that it has gone out.
+    pub(crate) async fn published(&self, id: u64) -> Result<()> {
+        self.append_schedule(ScheduleRecord::Published(id)).await
+    }
+
+    /// The scheduled messages that were still waiting when the journal was
+    /// opened; empty after the first call.
+    pub(crate) fn take_scheduled(&self) -> Vec<ScheduledEntry> {
+        std::mem::take(&mut self.schedule.lock().unwrap().pending)
+    }
+
+    async fn append_schedule(&self, record: ScheduleRecord) -> Result<()> {
+        let (schedule, sync) = (self.schedule.clone(), self.sync);
+        blocking(move || {
+            let record = self::record(&record)?;
+            let mut schedule = schedule.lock().unwrap();
+            schedule
+                .file
+                .write_all(&record)
+                .context("failed to append to the journal's schedule")?;
+            if sync {
+                schedule.file.sync_data()?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Up to `limit` messages on `topic` with offsets after `after` and
+    /// before `until`, oldest first, with their offsets.
+    pub(crate) async fn read(
+        &self,
+        topic: &str,
+        after: u64,
+        until: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Bytes)>> {
+        let (inner, path, topic) = (self.inner.clone(), self.path.clone(), topic.to_string());
+        blocking(move || {
+            let mut inner = inner.lock().unwrap();
+            let positions: Vec<u64> = match inner.index.get(&topic) {
+                Some(records) => {
+                    let first = records.partition_point(|(offset, _)| *offset <= after);
+                    records[first..]
+                        .iter()
+                        .take_while(|(offset, _)| *offset < until)
+                        .take(limit)
+                        .map(|(_, position)| *position)
+                        .collect()
+                }
+                None => return Ok(Vec::new()),
+            };
+
+            let mut messages = Vec::with_capacity(positions.len());
+            for position in positions {
+                inner.file.seek(SeekFrom::Start(position))?;
+                let mut len = [0; LEN_PREFIX as usize];
+                inner.file.read_exact(&mut len)?;
+                let mut entry = vec![0; u32::from_be_bytes(len) as usize];
+                inner.file.read_exact(&mut entry)?;
+                let entry: Entry = bincode::deserialize(&entry)
+                    .with_context(|| format!("corrupt record in {}", path.display()))?;
+                messages.push((entry.offset, entry.message));
+            }
+            Ok(messages)
+        })
+        .await
+    }
+}
+
+/// Runs `f` on the blocking pool, so file I/O and syncs don't hold up the
+/// runtime's workers.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("journal task failed")?
+}
+
+/// Reads the `.scheduled` file beside the journal at `path`, then rewrites
+/// it with only the messages still waiting.
+fn open_schedule(path: &Path) -> Result<Schedule> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".scheduled");
+    let schedule_path = PathBuf::from(name);
+    let mut pending = Vec::new();
+    match File::open(&schedule_path) {
+        Ok(file) => {
+            // Rewritten below from what was read, so a torn record just goes.
+            read_records(&file, &schedule_path, |record, _| match record {
+                ScheduleRecord::Added(entry) => pending.push(entry),
+                ScheduleRecord::Published(id) => {
+                    pending.retain(|entry: &ScheduledEntry| entry.id != id)
+                }
+            })?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", schedule_path.display()));
+        }
+    }
+
+    let mut compacted = Vec::new();
+    for entry in &pending {
+        compacted.extend(record(&ScheduleRecord::Added(entry.clone()))?);
+    }
+    let mut tmp = schedule_path.clone().into_os_string();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, &compacted)
+        .and_then(|()| std::fs::rename(&tmp, &schedule_path))
+        .with_context(|| format!("failed to rewrite {}", schedule_path.display()))?;
+    let file = OpenOptions::new()
+        .append(true)
+        .open(&schedule_path)
+        .with_context(|| format!("failed to open {}", schedule_path.display()))?;
+    Ok(Schedule { file, pending })
+}
+
+/// `value` in bincode, after its length.
+fn record(value: &impl Serialize) -> Result<Vec<u8>> {
+    let encoded = bincode::serialize(value)?;
+    let mut record = Vec::with_capacity(LEN_PREFIX as usize + encoded.len());
+    record.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    record.extend_from_slice(&encoded);
+    Ok(record)
+}
+
+/// Where the whole records read from a file end, and whether a record cut
+/// short follows them.
+struct Records {
+    end: u64,
+    torn: bool,
+}
+
+/// Reads `file` from the start a record at a time, handing each to `each`
+/// with where it starts. Only a record cut short by the end of the file is
+/// taken for one a crash interrupted; a whole one that doesn't decode is
+/// corruption, and fails the read rather than losing the records after it.
+fn read_records<T: DeserializeOwned>(
+    file: &File,
+    path: &Path,
+    mut each: impl FnMut(T, u64),
+) -> Result<Records> {
+    let mut reader = BufReader::new(file);
+    let mut end = 0;
+    let mut buf = Vec::new();
+    loop {
+        let mut len = [0; LEN_PREFIX as usize];
+        match fill(&mut reader, &mut len)? {
+            0 => return Ok(Records { end, torn: false }),
+            n if n < len.len() => return Ok(Records { end, torn: true }),
+            _ => {}
+        }
+        let len = u32::from_be_bytes(len) as u64;
+        buf.clear();
+        if (&mut reader).take(len).read_to_end(&mut buf)? < len as usize {
+            return Ok(Records { end, torn: true });
+        }
+        let value = bincode::deserialize(&buf)
+            .with_context(|| format!("corrupt record at byte {end} of {}", path.display()))?;
+        each(value, end);
+        end += LEN_PREFIX + len;
+    }
+}
+
+/// Reads into `buf` until it's full or the reader runs out, returning how
+/// much was read.
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
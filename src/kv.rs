@@ -0,0 +1,384 @@
+//! A key-value store behind the built-in `KvGet`, `KvSet`, `KvDelete` and
+//! `KvScan` requests, for clients that need somewhere to keep small bits
+//! of state and handlers that want an example of keeping it.
+//!
+//! The requests are refused with `Unsupported` unless the server was built
+//! with a [`Store`]:
+//!
+//! ```ignore
+//! let server = Server::builder().kv_store(MemoryStore::default()).build();
+//! ```
+//!
+//! [`MemoryStore`] forgets everything when the server stops; with the
+//! `sled` feature, [`SledStore`] keeps it on disk. Handlers reach the same
+//! store through `Server::kv_store`; on a multi-tenant server the requests
+//! keep each tenant's keys under its prefix, which `Context::scoped` adds.
+//! `KvSet` and `KvDelete` are mutating, so a `RequestJournal` can rebuild a
+//! `MemoryStore` after a restart.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::Describe;
+use crate::tenant;
+use crate::{Context, ErrorCode, ProtocolError, Request, Response, TypedRequest, ValidationError};
+
+/// The most entries one `KvScan` returns.
+pub const MAX_SCAN: usize = 1000;
+
+/// Where the key-value requests keep their data. Calls come from every
+/// session at once and run on the session's task, so they should be quick.
+pub trait Store: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    /// Returns the value that was there before.
+    fn set(&self, key: &str, value: Bytes) -> Result<Option<Bytes>>;
+
+    /// Returns whether the key was there.
+    fn delete(&self, key: &str) -> Result<bool>;
+
+    /// Up to `limit` entries whose keys start with `prefix` and sort after
+    /// `after`, in key order.
+    fn scan(&self, prefix: &str, after: Option<&str>, limit: usize)
+    -> Result<Vec<(String, Bytes)>>;
+}
+
+impl Store for Box<dyn Store> {
+    fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: Bytes) -> Result<Option<Bytes>> {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        (**self).delete(key)
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Bytes)>> {
+        (**self).scan(prefix, after, limit)
+    }
+}
+
+/// Keeps everything in memory, sorted by key.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: RwLock<BTreeMap<String, Bytes>>,
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self.entries.read().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: Bytes) -> Result<Option<Bytes>> {
+        Ok(self.entries.write().unwrap().insert(key.to_string(), value))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.entries.write().unwrap().remove(key).is_some())
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Bytes)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let entries = self.entries.read().unwrap();
+        Ok(entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+/// Keeps everything in a sled tree on disk.
+#[cfg(feature = "sled")]
+pub struct SledStore {
+    tree: sled::Tree,
+}
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    /// Opens, or creates, the sled database in the directory `path`.
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            tree: (*db).clone(),
+        })
+    }
+
+    /// Keeps the entries in `tree`, e.g. one of several in a database the
+    /// application opened itself.
+    pub fn new(tree: sled::Tree) -> Self {
+        Self { tree }
+    }
+}
+
+#[cfg(feature = "sled")]
+impl Store for SledStore {
+    fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self
+            .tree
+            .get(key)?
+            .map(|value| Bytes::copy_from_slice(&value)))
+    }
+
+    fn set(&self, key: &str, value: Bytes) -> Result<Option<Bytes>> {
+        let previous = self.tree.insert(key, value.as_ref())?;
+        Ok(previous.map(|value| Bytes::copy_from_slice(&value)))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.tree.remove(key)?.is_some())
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Bytes)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut entries = Vec::new();
+        for entry in self.tree.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix.as_bytes()) || entries.len() == limit {
+                break;
+            }
+            let key = String::from_utf8(key.to_vec())?;
+            entries.push((key, Bytes::copy_from_slice(&value)));
+        }
+        Ok(entries)
+    }
+}
+
+fn store(ctx: &Context) -> Result<&dyn Store> {
+    ctx.server().kv_store().ok_or_else(|| {
+        ProtocolError::new(
+            ErrorCode::Unsupported,
+            "the key-value store is not enabled on this server",
+        )
+        .into()
+    })
+}
+
+fn check_key(key: &str) -> Result<(), ValidationError> {
+    if key.is_empty() {
+        return Err(ValidationError::field("key", "must not be empty"));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = KvGetResponse)]
+pub struct KvGet {
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct KvGetResponse {
+    /// `None` if the key isn't set.
+    pub value: Option<Bytes>,
+}
+
+#[typetag::serde]
+impl Response for KvGetResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for KvGet {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let value = store(ctx)?.get(&ctx.scoped(&self.key)?)?;
+        Ok(Box::new(KvGetResponse { value }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        check_key(&self.key)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for KvGet {
+    type Response = KvGetResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = KvSetResponse)]
+pub struct KvSet {
+    pub key: String,
+    pub value: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct KvSetResponse {
+    /// What the key was set to before, if anything.
+    pub previous: Option<Bytes>,
+}
+
+#[typetag::serde]
+impl Response for KvSetResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for KvSet {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let previous = store(ctx)?.set(&ctx.scoped(&self.key)?, self.value.clone())?;
+        Ok(Box::new(KvSetResponse { previous }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        check_key(&self.key)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    fn mutating(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for KvSet {
+    type Response = KvSetResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = KvDeleteResponse)]
+pub struct KvDelete {
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct KvDeleteResponse {
+    pub existed: bool,
+}
+
+#[typetag::serde]
+impl Response for KvDeleteResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for KvDelete {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let existed = store(ctx)?.delete(&ctx.scoped(&self.key)?)?;
+        Ok(Box::new(KvDeleteResponse { existed }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        check_key(&self.key)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    fn mutating(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for KvDelete {
+    type Response = KvDeleteResponse;
+}
+
+/// Lists the entries whose keys start with `prefix`, in key order, a page
+/// at a time: pass the last response's `next` as `after` for the next page.
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+#[schema(response = KvScanResponse)]
+pub struct KvScan {
+    pub prefix: String,
+    pub after: Option<String>,
+    /// At most [`MAX_SCAN`].
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct KvEntry {
+    pub key: String,
+    pub value: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Describe)]
+pub struct KvScanResponse {
+    pub entries: Vec<KvEntry>,
+    /// Where the next page starts; `None` once there are no more entries.
+    pub next: Option<String>,
+}
+
+#[typetag::serde]
+impl Response for KvScanResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for KvScan {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let prefix = ctx.scoped(&self.prefix)?;
+        let after = self
+            .after
+            .as_deref()
+            .map(|after| ctx.scoped(after))
+            .transpose()?;
+        // One more than asked for tells whether there is another page.
+        let mut entries = store(ctx)?.scan(&prefix, after.as_deref(), self.limit + 1)?;
+        if ctx.tenant().is_some() {
+            for (key, _) in &mut entries {
+                *key = tenant::unscoped(key).to_string();
+            }
+        }
+        let next = if entries.len() > self.limit {
+            entries.truncate(self.limit);
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| KvEntry { key, value })
+            .collect();
+        Ok(Box::new(KvScanResponse { entries, next }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.limit == 0 || self.limit > MAX_SCAN {
+            return Err(ValidationError::field(
+                "limit",
+                format!("must be between 1 and {MAX_SCAN}"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for KvScan {
+    type Response = KvScanResponse;
+}
@@ -0,0 +1,136 @@
+//! How long each request type takes to handle, kept in the process so the
+//! admin socket can show it without a metrics recorder installed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Buckets per doubling of latency, so a bucket is within about 6% of
+/// what fell in it.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// Latencies recorded per request type since the server started.
+#[derive(Clone, Default)]
+pub(crate) struct Latencies {
+    types: Arc<Mutex<HashMap<&'static str, Histogram>>>,
+}
+
+impl Latencies {
+    pub(crate) fn record(&self, request_type: &'static str, elapsed: Duration) {
+        self.types
+            .lock()
+            .unwrap()
+            .entry(request_type)
+            .or_default()
+            .record(elapsed);
+    }
+
+    /// The latency at or under which `quantile` of `request_type`'s fell,
+    /// once at least `min_count` have been recorded.
+    pub(crate) fn quantile(
+        &self,
+        request_type: &str,
+        quantile: f64,
+        min_count: u64,
+    ) -> Option<Duration> {
+        let types = self.types.lock().unwrap();
+        let histogram = types.get(request_type)?;
+        (histogram.count >= min_count).then(|| histogram.quantile(quantile))
+    }
+
+    /// Every request type handled so far, by name.
+    pub(crate) fn summaries(&self) -> Vec<LatencySummary> {
+        let types = self.types.lock().unwrap();
+        let mut summaries: Vec<LatencySummary> = types
+            .iter()
+            .map(|(request_type, histogram)| histogram.summary(request_type))
+            .collect();
+        summaries.sort_by(|a, b| a.request_type.cmp(&b.request_type));
+        summaries
+    }
+}
+
+/// The spread of one request type's handling times.
+#[derive(Clone, Debug)]
+pub struct LatencySummary {
+    pub request_type: String,
+    pub count: u64,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Counts of latencies in microseconds, in log-linear buckets.
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total_micros: u128,
+    max_micros: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = bucket(micros);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_micros += u128::from(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    /// The latency at or under which `quantile` of the recorded ones fell.
+    fn quantile(&self, quantile: f64) -> Duration {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(upper_bound(bucket).min(self.max_micros));
+            }
+        }
+        Duration::from_micros(self.max_micros)
+    }
+
+    fn summary(&self, request_type: &str) -> LatencySummary {
+        let mean = self.total_micros / u128::from(self.count.max(1));
+        LatencySummary {
+            request_type: request_type.to_string(),
+            count: self.count,
+            mean: Duration::from_micros(mean as u64),
+            p50: self.quantile(0.5),
+            p90: self.quantile(0.9),
+            p99: self.quantile(0.99),
+            max: Duration::from_micros(self.max_micros),
+        }
+    }
+}
+
+/// Values under `SUB_BUCKETS` get a bucket each; above that, every
+/// doubling is split into `SUB_BUCKETS` equal buckets.
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = u64::BITS - 1 - micros.leading_zeros();
+    let sub = (micros >> (exponent - SUB_BITS)) & (SUB_BUCKETS - 1);
+    ((exponent - SUB_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// The largest value that falls in `bucket`.
+fn upper_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    let low = (SUB_BUCKETS + sub) << shift;
+    low + (1 << shift) - 1
+}
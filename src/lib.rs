@@ -1,70 +1,366 @@
+// A browser accepts no connections, so what only the accept loop uses goes
+// unused on wasm32.
+#![cfg_attr(target_arch = "wasm32", allow(dead_code))]
+
+pub mod access_log;
+#[cfg(unix)]
+mod admin;
+pub mod audit;
+pub mod auth;
+pub mod authorization;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod balancer;
+pub mod ban;
+#[cfg(any(feature = "gateway", feature = "grpc"))]
+mod bridge;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub mod browser;
+pub mod builtin;
+pub mod cache;
+mod call_queue;
+mod channels;
+pub mod circuit_breaker;
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client_pool;
+pub mod cluster;
+pub mod codec;
+pub mod codegen;
+pub mod config;
+pub mod config_file;
+pub mod connection;
+pub mod context;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod datagram;
+pub mod dead_letter;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod embedded;
+pub mod envelope;
+pub mod error;
+pub mod extensions;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod file_transfer;
+pub mod filter;
+pub mod frame;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handshake;
+mod heartbeat;
+pub mod hedge;
+mod idempotency;
+pub mod interceptor;
+pub mod ip_filter;
+pub mod jobs;
+pub mod journal;
+pub mod kv;
+pub mod latency;
+pub mod lifecycle;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod listener;
+mod load;
+mod metrics;
+pub mod middleware;
+pub mod module;
+pub mod namespace;
+#[cfg(feature = "noise")]
+pub mod noise;
+mod panic;
+mod payload_limit;
+mod pool;
+pub mod preflight;
+pub mod protocol;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod proxy;
+mod proxy_protocol;
+pub mod pubsub;
+mod push_queue;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod quota;
+mod rate_limit;
+pub mod reconnect;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod recording;
+pub mod redact;
+pub mod registry;
+pub mod relay;
+mod reply_order;
+pub mod request_journal;
+pub mod resumption;
+pub mod retry;
+#[cfg(any(test, feature = "testing"))]
+pub mod round_trip;
+pub mod router;
+mod rt;
+pub mod schema;
+#[cfg(feature = "encryption")]
+pub mod sealed;
+pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+mod session;
+pub mod session_store;
+pub mod shared_bytes;
+pub mod signing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod snapshot;
+mod supervisor;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tcp;
+pub mod tenant;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod timer_wheel;
+pub mod topic;
+mod trace;
+pub mod type_ids;
+pub mod untyped;
+#[cfg(unix)]
+pub mod upgrade;
+pub mod versioning;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
 use anyhow::Result;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::de::DeserializeOwned;
 
-use futures::{SinkExt, StreamExt, future::join_all};
-use tokio::io::{AsyncRead, AsyncWrite};
+pub use access_log::{AccessLog, AccessRecord};
+pub use audit::{Audit, AuditRecord, AuditSink};
+pub use auth::{Authenticator, Credentials, Identity};
+pub use authorization::Authorization;
+#[cfg(not(target_arch = "wasm32"))]
+pub use balancer::{Balancer, BalancerConfig};
+pub use ban::{Ban, BanPolicy};
+pub use cache::ResponseCache;
+pub use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+pub use client::{Client, ClientConfig};
+#[cfg(not(target_arch = "wasm32"))]
+pub use client_pool::{Pool, PoolConfig};
+pub use cluster::ClusterConfig;
+pub use codec::Codec;
+pub use config::{FlushPolicy, OverLimit, Overflow, ServerConfig, SlowClient};
+pub use connection::{Connection, ConnectionStats};
+pub use context::Context;
+#[cfg(not(target_arch = "wasm32"))]
+pub use discovery::Discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub use embedded::ServerHandle;
+pub use envelope::{Priority, Progress};
+pub use error::{ErrorCode, ProtocolError, ValidationError};
+pub use extensions::Extensions;
+pub use frame::Compression;
+pub use hedge::HedgePolicy;
+pub use interceptor::{CallReply, Interceptor, OutgoingCall};
+pub use ip_filter::IpFilter;
+pub use journal::Journal;
+pub use lifecycle::ConnectionHandler;
+#[cfg(not(target_arch = "wasm32"))]
+pub use listener::{Binding, Listener};
+pub use middleware::{Middleware, Next};
+pub use module::{Module, Registrar};
+pub use myproto_macros::{request, response};
+pub use namespace::Namespace;
+#[cfg(not(target_arch = "wasm32"))]
+pub use proxy::Proxy;
+pub use quota::{Quota, QuotaStore, Quotas};
+pub use rate_limit::RateLimit;
+pub use reconnect::{ReconnectConfig, ReconnectingClient};
+pub use request_journal::RequestJournal;
+pub use retry::RetryPolicy;
+pub use router::Router;
+pub use server::{LogFilter, Server, ServerBuilder};
+pub use signing::{SigningKey, VerifySignatures};
+pub use tcp::TcpOptions;
 
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+/// One slot of a response frame, in the same position as its request.
+pub type ResponseResult = std::result::Result<Box<dyn Response>, ProtocolError>;
 
-pub async fn handle_client<S>(stream: S, peer_addr: std::net::SocketAddr) -> Result<()>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    let span = tracing::info_span!("client_session", %peer_addr);
-    let _enter = span.enter();
+#[typetag::serde]
+#[async_trait::async_trait]
+pub trait Request: Send + Sync + std::fmt::Debug + std::any::Any {
+    /// Defaults to failing with `Unsupported`, for types only ever answered by a `Router`.
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let _ = ctx;
+        Err(ProtocolError::new(
+            ErrorCode::Unsupported,
+            format!("no handler for {}", self.typetag_name()),
+        )
+        .into())
+    }
 
-    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    /// Checks the request's fields once middleware has let it through and
+    /// before it is handled; a failure reaches the client as `InvalidRequest`.
+    fn validate(&self) -> std::result::Result<(), ValidationError> {
+        Ok(())
+    }
 
-    while let Some(line) = framed.next().await {
-        let bytes = line?;
-        let line = String::from_utf8_lossy(&bytes);
+    /// Whether running this request twice has the same effect as running it
+    /// once, which lets a `RetryPolicy` send it again after a failure.
+    fn idempotent(&self) -> bool {
+        false
+    }
 
-        let msg_span = tracing::info_span!("handle_message", message = %line);
-        let _enter_msg = msg_span.enter();
+    /// How long a `ResponseCache` may keep answering this request with the
+    /// same response; `None`, the default, never caches it.
+    fn cache_ttl(&self) -> Option<std::time::Duration> {
+        None
+    }
 
-        tracing::debug!("Processing message");
+    /// The key a clustered server hashes to pick the node that handles this
+    /// request; `None`, the default, handles it wherever it arrives. Usually
+    /// set by implementing [`ShardedRequest`].
+    fn shard_key(&self) -> Option<String> {
+        None
+    }
 
-        let resp = handle_msg(&bytes).await;
-        let resp_bytes = bincode::serialize(&resp)?;
+    /// Whether the handler does CPU-heavy or blocking work, and so runs on
+    /// tokio's blocking thread pool rather than the threads driving every
+    /// connection. It runs there to completion even if the call is
+    /// cancelled or times out, unless it watches `Context::cancelled`.
+    fn blocking(&self) -> bool {
+        false
+    }
 
-        framed.send(resp_bytes.into()).await?;
+    /// Whether the request changes state a server built with a
+    /// `RequestJournal` should rebuild after a restart, and so is journaled
+    /// before it runs and replayed by `Server::replay_requests`.
+    fn mutating(&self) -> bool {
+        false
     }
 
-    tracing::info!("Client disconnected");
+    /// Where calls carrying this request go in the queue of a session that
+    /// is running as many calls as it allows.
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
 
-    Ok(())
-}
+    /// The fields masked wherever the server shows the request, as
+    /// serialized; see [`redact`].
+    fn redacted_fields(&self) -> &'static [&'static str] {
+        &[]
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ErrorResponse(String);
+    /// Whether a call carrying this request may be compressed on a
+    /// connection that negotiated compression. Worth turning off for
+    /// requests carrying data that's compressed already.
+    fn compressible(&self) -> bool {
+        true
+    }
+}
 
 #[typetag::serde]
-impl Response for ErrorResponse {}
-
-async fn handle_msg(input: &[u8]) -> Vec<Box<dyn Response>> {
-    let requests: Vec<Box<dyn Request>> = match bincode::deserialize(input) {
-        Ok(r) => r,
-        Err(e) => {
-            return vec![Box::new(ErrorResponse(format!(
-                "Failed to parse request: {e}"
-            )))];
-        }
-    };
-
-    let futures = requests.into_iter().map(|req| async move {
-        req.handle()
-            .await
-            .unwrap_or_else(|e| Box::new(ErrorResponse(format!("Failed to handle request: {e}"))))
-    });
-    join_all(futures).await
+pub trait Response: Send + Sync + std::fmt::Debug + std::any::Any {
+    /// As `Request::compressible`, for the reply, stream item or push
+    /// carrying this response.
+    fn compressible(&self) -> bool {
+        true
+    }
 }
 
-use serde::{Deserialize, Serialize};
+pub type ResponseStream = BoxStream<'static, Result<Box<dyn Response>>>;
 
+/// A request answered with a sequence of responses instead of just one.
 #[typetag::serde]
 #[async_trait::async_trait]
-pub trait Request: Send + Sync + std::fmt::Debug {
-    async fn handle(&self) -> Result<Box<dyn Response>>;
+pub trait StreamingRequest: Send + Sync + std::fmt::Debug {
+    async fn handle(&self, ctx: &Context) -> Result<ResponseStream>;
+
+    /// As `Request::validate`, checked before `handle` opens the stream.
+    fn validate(&self) -> std::result::Result<(), ValidationError> {
+        Ok(())
+    }
+
+    /// As `Request::redacted_fields`.
+    fn redacted_fields(&self) -> &'static [&'static str] {
+        &[]
+    }
 }
 
+pub type ByteStream = BoxStream<'static, Result<Bytes>>;
+
+/// A request whose handler consumes a stream of bytes uploaded by the client
+/// before producing its single response.
 #[typetag::serde]
-pub trait Response: Send + Sync + std::fmt::Debug {}
+#[async_trait::async_trait]
+pub trait UploadRequest: Send + Sync + std::fmt::Debug {
+    async fn handle(&self, ctx: &Context, body: ByteStream) -> Result<Box<dyn Response>>;
+
+    /// As `Request::validate`, checked before any of the body is read.
+    fn validate(&self) -> std::result::Result<(), ValidationError> {
+        Ok(())
+    }
+
+    /// As `Request::redacted_fields`.
+    fn redacted_fields(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+/// A request with a statically known response type, for `Client::call_typed`.
+pub trait TypedRequest: Request + Sized + 'static {
+    type Response: Response + DeserializeOwned;
+}
+
+/// A request that must be handled by the cluster node owning its key, so
+/// handlers keeping state per key see every request for it. Declared with
+/// `#[request(response = Type, sharded)]`, or by returning this key from a
+/// hand-written `Request::shard_key`.
+pub trait ShardedRequest: Request {
+    fn shard_key(&self) -> String;
+}
+
+/// The handler of a request declared with [`macro@request`], returning its
+/// concrete response type.
+pub trait Handler: TypedRequest {
+    fn handle(&self, ctx: &Context) -> impl Future<Output = Result<Self::Response>> + Send;
+
+    /// Becomes the request's `Request::validate`.
+    fn validate(&self) -> std::result::Result<(), ValidationError> {
+        Ok(())
+    }
+
+    /// Becomes the request's `Request::cache_ttl`.
+    fn cache_ttl(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Becomes the request's `Request::priority`.
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+}
+
+/// A [`Handler`] that answers without awaiting anything, for requests
+/// declared with [`macro@request`] whose handling is a quick computation.
+/// Implementing it implements `Handler`; one that needs `Handler`'s other
+/// methods implements `Handler` itself instead.
+pub trait SyncRequest: TypedRequest {
+    fn handle(&self, ctx: &Context) -> Result<Self::Response>;
+}
+
+impl<T: SyncRequest> Handler for T {
+    async fn handle(&self, ctx: &Context) -> Result<Self::Response> {
+        SyncRequest::handle(self, ctx)
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("building for wasm32 needs the `wasm` feature, for the browser runtime");
+
+// Lets the derives, which name the crate `::myproto`, be used inside it.
+extern crate self as myproto;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+    pub use async_trait;
+    pub use inventory;
+    pub use serde;
+}
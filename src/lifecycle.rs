@@ -0,0 +1,25 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::Context;
+
+/// Callbacks for the start and end of every session, installed with
+/// `ServerBuilder::connection_handler`.
+///
+/// Per-connection state can be kept in the application's own map keyed by
+/// `Context::connection_id` and dropped in `on_disconnect`.
+#[async_trait]
+pub trait ConnectionHandler: Send + Sync + 'static {
+    /// Runs after the handshake, before any request is read. Returning an
+    /// error closes the connection.
+    async fn on_connect(&self, ctx: &Context) -> Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Runs once the session has ended and its in-flight requests have
+    /// finished; only called if `on_connect` succeeded.
+    async fn on_disconnect(&self, ctx: &Context) {
+        let _ = ctx;
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt;
+use std::io;
+#[cfg(any(unix, windows))]
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+#[cfg(unix)]
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use futures::FutureExt;
+use futures::future::BoxFuture;
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::tcp::TcpOptions;
+
+/// A socket the server accepts clients on.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Clients on a Unix socket have no address of their own; they appear as
+    /// `127.0.0.1:0`, and so share one rate limit bucket unless authenticated.
+    #[cfg(unix)]
+    Unix(UnixListener),
+    /// Like Unix socket clients, named pipe clients appear as `127.0.0.1:0`.
+    #[cfg(windows)]
+    Pipe(PipeListener),
+}
+
+pub(crate) enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    Pipe(NamedPipeServer),
+}
+
+impl Listener {
+    /// Binds `addr`: a `host:port`, `unix:` followed by a socket path, or on
+    /// Windows a named pipe such as `\\.\pipe\myproto`. A stale socket file
+    /// left at the path is replaced.
+    pub async fn bind(addr: &str) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Ok(Listener::Unix(bind_unix(Path::new(path)).await?));
+        }
+        #[cfg(windows)]
+        if addr.starts_with(PIPE_PREFIX) {
+            return Ok(Listener::Pipe(PipeListener::bind(addr)?));
+        }
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen on {addr}"))?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Binds `shards` TCP sockets to `addr` with `SO_REUSEPORT`, for the
+    /// kernel to spread new connections over. Served as separate bindings,
+    /// each accepts on its own task, so accepting scales past one core. Any
+    /// other address, or a single shard, binds one listener as `bind` does.
+    #[cfg(unix)]
+    pub async fn bind_shards(addr: &str, shards: usize) -> Result<Vec<Self>> {
+        if shards <= 1 || addr.starts_with("unix:") {
+            return Ok(vec![Self::bind(addr).await?]);
+        }
+        let context = || format!("failed to listen on {addr}");
+        let resolved = tokio::net::lookup_host(addr)
+            .await
+            .with_context(context)?
+            .next()
+            .with_context(|| format!("{addr} resolves to no address"))?;
+        let first = bind_reuseport(resolved).with_context(context)?;
+        // Shards of port 0 share the port the first one got.
+        let resolved = first.local_addr()?;
+        let mut listeners = vec![Listener::Tcp(first)];
+        for _ in 1..shards {
+            listeners.push(Listener::Tcp(
+                bind_reuseport(resolved).with_context(context)?,
+            ));
+        }
+        Ok(listeners)
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<(Accepted, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Accepted::Tcp(stream), addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((
+                    Accepted::Unix(stream),
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                ))
+            }
+            #[cfg(windows)]
+            Listener::Pipe(listener) => {
+                let pipe = listener.accept().await?;
+                Ok((
+                    Accepted::Pipe(pipe),
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                ))
+            }
+        }
+    }
+
+    /// Takes over a listening socket opened by another process, such as
+    /// systemd or the server being upgraded.
+    #[cfg(unix)]
+    pub(crate) fn inherit(fd: OwnedFd) -> Result<Self> {
+        let socket = Socket::from(fd);
+        if socket.r#type()? != Type::STREAM {
+            anyhow::bail!("not a stream socket");
+        }
+        // Keep the socket from leaking into processes the server starts.
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        if socket.local_addr()?.is_unix() {
+            let listener = std::os::unix::net::UnixListener::from(OwnedFd::from(socket));
+            Ok(Listener::Unix(UnixListener::from_std(listener)?))
+        } else {
+            let listener = std::net::TcpListener::from(socket);
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        }
+    }
+
+    /// Removes the socket file of a Unix listener once it is no longer served.
+    pub(crate) fn cleanup(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener) = self
+            && let Ok(addr) = listener.local_addr()
+            && let Some(path) = addr.as_pathname()
+        {
+            drop(listener);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => f.write_str("tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                match listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                {
+                    Some(path) => write!(f, "unix:{path}"),
+                    None => f.write_str("unix"),
+                }
+            }
+            #[cfg(windows)]
+            Listener::Pipe(listener) => f.write_str(&listener.name),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<PipeListener> for Listener {
+    fn from(listener: PipeListener) -> Self {
+        Listener::Pipe(listener)
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+/// A listener together with the settings for the connections accepted on it,
+/// for `Server::serve_all`.
+pub struct Binding {
+    pub(crate) listener: Listener,
+    pub(crate) name: String,
+    pub(crate) tcp: Option<TcpOptions>,
+    pub(crate) proxy_protocol: bool,
+    pub(crate) websocket: bool,
+    /// Whether the listener was handed over rather than bound here, so its
+    /// socket file isn't ours to remove.
+    pub(crate) inherited: bool,
+    pub(crate) shutdown: BoxFuture<'static, ()>,
+}
+
+impl Binding {
+    pub fn new(listener: impl Into<Listener>) -> Self {
+        let listener = listener.into();
+        Self {
+            name: listener.to_string(),
+            listener,
+            tcp: None,
+            proxy_protocol: false,
+            websocket: false,
+            inherited: false,
+            shutdown: std::future::pending().boxed(),
+        }
+    }
+
+    /// How the listener appears in logs; defaults to its address.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Socket options for this listener's connections instead of `ServerConfig::tcp`.
+    pub fn tcp(mut self, options: TcpOptions) -> Self {
+        self.tcp = Some(options);
+        self
+    }
+
+    /// Expects every connection to start with a PROXY protocol header, as
+    /// sent by HAProxy and most cloud TCP load balancers, and reports the
+    /// client it names as the peer instead of the balancer. Only enable this
+    /// behind such a balancer: anyone who can connect directly can claim any
+    /// address.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Expects clients to speak WebSocket, after any PROXY header, and
+    /// carries the protocol in its binary messages.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(mut self, enabled: bool) -> Self {
+        self.websocket = enabled;
+        self
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn inherited(mut self) -> Self {
+        self.inherited = true;
+        self
+    }
+
+    /// Stops this listener alone once `shutdown` resolves, draining its
+    /// sessions while the server's other listeners carry on.
+    pub fn shutdown(mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = shutdown.boxed();
+        self
+    }
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket file left by a
+/// previous run but refusing to take over one that is still being served.
+#[cfg(unix)]
+pub(crate) async fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("socket {} is already in use", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path).with_context(|| format!("failed to listen on {}", path.display()))
+}
+
+#[cfg(unix)]
+fn bind_reuseport(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// How every Windows named pipe's name starts.
+#[cfg(windows)]
+pub const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// A Windows named pipe the server accepts clients on. Each client gets an
+/// instance of the pipe to itself, and a fresh one is created for the next
+/// as each connects.
+#[cfg(windows)]
+pub struct PipeListener {
+    name: String,
+    /// The instance waiting for the next client.
+    next: tokio::sync::Mutex<NamedPipeServer>,
+}
+
+#[cfg(windows)]
+impl PipeListener {
+    /// Creates the pipe `name`, failing if another process already has it.
+    pub fn bind(name: &str) -> Result<Self> {
+        let first = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(name)
+            .with_context(|| format!("failed to listen on {name}"))?;
+        Ok(Self {
+            name: name.to_string(),
+            next: tokio::sync::Mutex::new(first),
+        })
+    }
+
+    async fn accept(&self) -> io::Result<NamedPipeServer> {
+        let mut next = self.next.lock().await;
+        next.connect().await?;
+        let fresh = ServerOptions::new().create(&self.name)?;
+        Ok(std::mem::replace(&mut *next, fresh))
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+
+/// A count of running work checked against a cap that may change between
+/// checks: requests across every session, for shedding load past
+/// `ServerConfig::max_in_flight`, or open connections against `max_connections`.
+#[derive(Clone, Default)]
+pub(crate) struct Load {
+    running: Arc<AtomicUsize>,
+    freed: Arc<Notify>,
+}
+
+impl Load {
+    pub(crate) fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Counts `n` more as running until the returned guard is dropped, or
+    /// returns `None` if that would take the total past `max`.
+    pub(crate) fn try_start(&self, n: usize, max: Option<usize>) -> Option<Running> {
+        let max = max.unwrap_or(usize::MAX);
+        self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                running.checked_add(n).filter(|&total| total <= max)
+            })
+            .ok()?;
+        Some(Running {
+            running: self.running.clone(),
+            freed: self.freed.clone(),
+            n,
+        })
+    }
+
+    /// Like `try_start` for a single unit, but waits for room instead of
+    /// failing, re-reading `max` each time something finishes or `recheck` is called.
+    pub(crate) async fn start(&self, max: impl Fn() -> Option<usize>) -> Running {
+        loop {
+            let freed = self.freed.notified();
+            if let Some(running) = self.try_start(1, max()) {
+                return running;
+            }
+            freed.await;
+        }
+    }
+
+    /// Wakes a `start` waiting for room, e.g. after its cap was raised.
+    pub(crate) fn recheck(&self) {
+        self.freed.notify_one();
+    }
+}
+
+pub(crate) struct Running {
+    running: Arc<AtomicUsize>,
+    freed: Arc<Notify>,
+    n: usize,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.running.fetch_sub(self.n, Ordering::AcqRel);
+        self.freed.notify_one();
+    }
+}
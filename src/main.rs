@@ -1,50 +1,283 @@
-use anyhow::Result;
-use tokio::net::TcpListener;
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
 
 use tokio::signal;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, reload};
 
+use futures::StreamExt;
+use myproto::access_log::JsonLines;
+use myproto::config_file::{ConfigFile, LogFormat};
+use myproto::schema::Describe;
 use myproto::*;
 use serde::{Deserialize, Serialize};
 
+const USAGE: &str = "usage: myproto [--config PATH] [--<key> <value>]...\n       myproto --schema";
+
+struct Args {
+    config: Option<PathBuf>,
+    /// `--key value` pairs laid over the config file.
+    overrides: Vec<(String, String)>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut config = None;
+    let mut overrides = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        if arg == "--schema" {
+            let schema = myproto::schema::Schema::registered();
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            std::process::exit(0);
+        }
+        let Some(key) = arg.strip_prefix("--") else {
+            bail!("unexpected argument {arg:?}\n{USAGE}");
+        };
+        let Some(value) = args.next() else {
+            bail!("--{key} needs a value\n{USAGE}");
+        };
+        if key == "config" {
+            config = Some(PathBuf::from(value));
+        } else {
+            overrides.push((key.to_string(), value));
+        }
+    }
+    Ok(Args { config, overrides })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+    let args = parse_args()?;
+    let file = ConfigFile::load(args.config.as_deref(), &args.overrides)?;
+
+    let filter = match &file.log.filter {
+        Some(directives) => EnvFilter::try_new(directives)?,
+        None => EnvFilter::from_default_env(),
+    };
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let json = file.log.format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .with(json.then(|| tracing_subscriber::fmt::layer().json()))
         .init();
 
-    let server_addr = "127.0.0.1:8443";
+    myproto::type_ids! {
+        Request { Ping = 1, Echo = 2, Add = 3 }
+        StreamingRequest { Count = 1 }
+        UploadRequest { ByteCount = 1 }
+        Response { PingResponse = 1, EchoResponse = 2, AddResponse = 3, CountResponse = 4, ByteCountResponse = 5 }
+    }
 
-    let listener = TcpListener::bind(server_addr).await?;
-    tracing::info!("Listening on {}", server_addr);
+    let set_filter = move |directives: &str| {
+        filter_handle.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    };
+    let reload_filter = set_filter.clone();
+    let mut builder = Server::builder()
+        .config(file.server_config())
+        .log_filter(set_filter)
+        .reload(move |server| {
+            let file = ConfigFile::load(args.config.as_deref(), &args.overrides)?;
+            if let Some(directives) = &file.log.filter {
+                reload_filter(directives)?;
+            }
+            server.reconfigure(file.server_config());
+            Ok(())
+        });
+    match &file.access_log {
+        Some(path) if path.as_os_str() == "-" => builder = builder.access_log(JsonLines::stdout()),
+        Some(path) => builder = builder.access_log(JsonLines::file(path)?),
+        None => {}
+    }
+    if let Some(audit) = file.audit()? {
+        builder = builder.audit(audit);
+    }
+    if let Some(dir) = &file.record_dir {
+        builder = builder.record_sessions(dir);
+    }
+    if let Some(dir) = &file.file_root {
+        builder = builder.file_root(dir);
+    }
+    if let Some(mode) = file.file_mode {
+        builder = builder.file_mode(mode);
+    }
+    if let Some(bytes) = file.max_file_size {
+        builder = builder.max_file_size(bytes);
+    }
+    if let Some(path) = &file.journal {
+        builder = builder.journal(Journal::open(path)?);
+    }
+    if let Some(store) = file.kv_store()? {
+        builder = builder.kv_store(store);
+    }
+    if let Some(path) = &file.request_journal {
+        builder = builder.request_journal(RequestJournal::open(path)?);
+    }
+    if let Some(filter) = file.ip_filter() {
+        builder = builder.ip_filter(filter);
+    }
+    if let Some(cluster) = file.cluster() {
+        builder = builder.cluster(cluster);
+    }
+    let server = builder.build();
+    server.preflight()?;
+    server.replay_requests().await?;
 
-    loop {
-        tokio::select! {
-            Ok((stream, addr)) = listener.accept() => {
-                tracing::info!(%addr, "Client connected");
+    #[cfg(unix)]
+    {
+        let mut hangups = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(e) = server.reload() {
+                    tracing::error!(error = %e, "Failed to reload configuration");
+                }
+            }
+        });
+    }
 
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, addr).await {
-                        tracing::error!(%addr, error = %e, "Error handling client");
-                    }
-                });
+    #[cfg(unix)]
+    {
+        // SIGUSR2 restarts the server in place, e.g. after installing a new binary.
+        let mut upgrades = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            while upgrades.recv().await.is_some() {
+                if let Err(e) = server.upgrade().await {
+                    tracing::error!(error = format!("{e:#}"), "Failed to upgrade");
+                }
             }
+        });
+    }
 
-            _ = signal::ctrl_c() => {
-                tracing::info!("Shutting down");
-                break;
+    #[cfg(feature = "gateway")]
+    if let Some(addr) = &file.gateway {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tracing::info!(addr = ?listener.local_addr()?, "HTTP gateway listening");
+        tokio::spawn(gateway::serve(
+            server.clone(),
+            listener,
+            std::future::pending(),
+        ));
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = &file.grpc {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tracing::info!(addr = ?listener.local_addr()?, "gRPC bridge listening");
+        tokio::spawn(grpc::serve(
+            server.clone(),
+            listener,
+            std::future::pending(),
+        ));
+    }
+
+    if let Some(addr) = &file.datagram {
+        let socket = tokio::net::UdpSocket::bind(addr).await?;
+        tokio::spawn(myproto::datagram::serve(
+            server.clone(),
+            socket,
+            std::future::pending(),
+        ));
+    }
+
+    // Sockets handed over by the process being upgraded are served as it
+    // served them; ones passed by systemd socket activation replace the
+    // `listen` addresses.
+    #[cfg(unix)]
+    let mut bindings = myproto::upgrade::listeners()?;
+    #[cfg(not(unix))]
+    let mut bindings = Vec::new();
+    if bindings.is_empty() {
+        #[cfg(unix)]
+        let mut listeners = myproto::systemd::listeners()?;
+        #[cfg(not(unix))]
+        let mut listeners = Vec::new();
+        if listeners.is_empty() {
+            for addr in &file.listen {
+                #[cfg(unix)]
+                {
+                    let shards = Listener::bind_shards(addr, file.accept_shards.unwrap_or(1));
+                    let shards = shards.await?;
+                    let sharded = shards.len() > 1;
+                    for (shard, listener) in shards.into_iter().enumerate() {
+                        let binding = Binding::new(listener);
+                        listeners.push(if sharded {
+                            binding.name(format!("{addr}#{shard}"))
+                        } else {
+                            binding
+                        });
+                    }
+                }
+                #[cfg(not(unix))]
+                listeners.push(Binding::new(Listener::bind(addr).await?));
             }
         }
+        for binding in listeners {
+            bindings.push(binding.proxy_protocol(file.proxy_protocol));
+        }
+        #[cfg(feature = "websocket")]
+        for addr in &file.websocket {
+            bindings.push(Binding::new(Listener::bind(addr).await?).websocket(true));
+        }
     }
 
+    #[cfg(unix)]
+    if let Err(e) = myproto::upgrade::ready() {
+        tracing::warn!(
+            error = format!("{e:#}"),
+            "Failed to tell the old process we're ready"
+        );
+    }
+    notify("READY=1");
+    server
+        .serve_all(bindings, async {
+            shutdown_signal().await;
+            tracing::info!("Shutting down");
+            notify("STOPPING=1");
+        })
+        .await?;
+
     tracing::info!("Shut down successfully");
 
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Waits for Ctrl-C or, on Unix, SIGTERM, which is how service managers stop the server.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        return;
+    }
+    let _ = signal::ctrl_c().await;
+}
+
+/// Tells systemd, when running under it, how the server is doing.
+fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Err(e) = myproto::systemd::notify(state) {
+        tracing::warn!(error = format!("{e:#}"), state, "Failed to notify systemd");
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = PingResponse)]
 pub struct Ping;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Describe)]
 pub struct PingResponse(String);
 
 #[typetag::serde]
@@ -53,19 +286,28 @@ impl Response for PingResponse {}
 #[typetag::serde]
 #[async_trait::async_trait]
 impl Request for Ping {
-    async fn handle(&self) -> Result<Box<dyn Response>> {
+    async fn handle(&self, _ctx: &Context) -> Result<Box<dyn Response>> {
         Ok(Box::new(PingResponse(
             "Thou shalt not to use HTTP;\nThou shalt write thoust own protocol".to_string(),
         )))
     }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl TypedRequest for Ping {
+    type Response = PingResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = EchoResponse)]
 pub struct Echo {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Describe)]
 pub struct EchoResponse(String);
 
 #[typetag::serde]
@@ -74,31 +316,78 @@ impl Response for EchoResponse {}
 #[typetag::serde]
 #[async_trait::async_trait]
 impl Request for Echo {
-    async fn handle(&self) -> Result<Box<dyn Response>> {
+    async fn handle(&self, _ctx: &Context) -> Result<Box<dyn Response>> {
         Ok(Box::new(EchoResponse(self.message.clone())))
     }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for Echo {
+    type Response = EchoResponse;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[myproto::request(response = AddResponse, idempotent)]
 pub struct Add {
     pub a: i32,
     pub b: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[myproto::response]
 pub struct AddResponse {
     sum: i32,
 }
 
+impl SyncRequest for Add {
+    fn handle(&self, _ctx: &Context) -> Result<AddResponse> {
+        Ok(AddResponse {
+            sum: self.a + self.b,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct Count {
+    pub up_to: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct CountResponse(u32);
+
 #[typetag::serde]
-impl Response for AddResponse {}
+impl Response for CountResponse {}
 
 #[typetag::serde]
 #[async_trait::async_trait]
-impl Request for Add {
-    async fn handle(&self) -> Result<Box<dyn Response>> {
-        Ok(Box::new(AddResponse {
-            sum: self.a + self.b,
-        }))
+impl StreamingRequest for Count {
+    async fn handle(&self, _ctx: &Context) -> Result<ResponseStream> {
+        let items = futures::stream::iter(1..=self.up_to)
+            .map(|n| Ok(Box::new(CountResponse(n)) as Box<dyn Response>));
+        Ok(items.boxed())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ByteCount;
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct ByteCountResponse {
+    bytes: u64,
+}
+
+#[typetag::serde]
+impl Response for ByteCountResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl UploadRequest for ByteCount {
+    async fn handle(&self, _ctx: &Context, mut body: ByteStream) -> Result<Box<dyn Response>> {
+        let mut bytes = 0;
+        while let Some(chunk) = body.next().await {
+            bytes += chunk?.len() as u64;
+        }
+        Ok(Box::new(ByteCountResponse { bytes }))
     }
 }
@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use crate::ErrorCode;
+
+/// Reports through the `metrics` facade; install any recorder, e.g.
+/// `metrics-exporter-prometheus`, to scrape them.
+#[cfg(feature = "metrics")]
+mod recorder {
+    use ::metrics::{
+        Unit, counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+    };
+
+    use super::*;
+
+    pub(crate) fn describe() {
+        describe_counter!("myproto_connections_total", "Sessions accepted");
+        describe_counter!(
+            "myproto_accepts_total",
+            "Connections accepted, by listener or accept shard"
+        );
+        describe_gauge!("myproto_connections_active", "Sessions currently open");
+        describe_gauge!(
+            "myproto_connection_tasks_active",
+            "Accepted connections still running, handshakes included"
+        );
+        describe_counter!(
+            "myproto_connection_panics_total",
+            "Connection tasks that ended in a panic"
+        );
+        describe_counter!(
+            "myproto_connections_rejected_total",
+            "Connections closed straight after accept, by reason"
+        );
+        describe_counter!(
+            "myproto_peers_banned_total",
+            "Peer IPs banned, by the violation that tipped them over"
+        );
+        describe_counter!(
+            "myproto_datagrams_dropped_total",
+            "Datagrams dropped unhandled, by reason"
+        );
+        describe_counter!(
+            "myproto_requests_total",
+            "Requests handled, by request type and outcome"
+        );
+        describe_histogram!(
+            "myproto_request_duration_seconds",
+            Unit::Seconds,
+            "Time spent in request handlers, by request type"
+        );
+        describe_counter!(
+            "myproto_frame_errors_total",
+            "Frames rejected before dispatch, by error code"
+        );
+        describe_counter!(
+            "myproto_quota_exceeded_total",
+            "Requests refused for exceeding an identity's quota, by quota"
+        );
+        describe_counter!(
+            "myproto_pushes_dropped_total",
+            "Published messages a subscriber's full queue dropped, by overflow policy"
+        );
+        describe_counter!(
+            "myproto_dead_letters_total",
+            "Acked deliveries given up on and kept as dead letters"
+        );
+        describe_counter!(
+            "myproto_received_bytes_total",
+            Unit::Bytes,
+            "Payload bytes received, after decompression"
+        );
+        describe_counter!(
+            "myproto_sent_bytes_total",
+            Unit::Bytes,
+            "Payload bytes sent, before compression"
+        );
+    }
+
+    pub(crate) fn connection_opened() {
+        counter!("myproto_connections_total").increment(1);
+        gauge!("myproto_connections_active").increment(1.0);
+    }
+
+    pub(crate) fn connection_accepted(listener: &str) {
+        counter!("myproto_accepts_total", "listener" => listener.to_string()).increment(1);
+    }
+
+    pub(crate) fn connection_closed() {
+        gauge!("myproto_connections_active").decrement(1.0);
+    }
+
+    pub(crate) fn connection_task_started() {
+        gauge!("myproto_connection_tasks_active").increment(1.0);
+    }
+
+    pub(crate) fn connection_task_ended() {
+        gauge!("myproto_connection_tasks_active").decrement(1.0);
+    }
+
+    pub(crate) fn connection_panicked() {
+        counter!("myproto_connection_panics_total").increment(1);
+    }
+
+    pub(crate) fn connection_rejected(reason: &'static str) {
+        counter!("myproto_connections_rejected_total", "reason" => reason).increment(1);
+    }
+
+    pub(crate) fn peer_banned(violation: &'static str) {
+        counter!("myproto_peers_banned_total", "violation" => violation).increment(1);
+    }
+
+    /// `error` is the code the request failed with, if it did; `tenant`
+    /// labels it on a multi-tenant server.
+    pub(crate) fn request_handled(
+        request_type: &'static str,
+        tenant: Option<&str>,
+        elapsed: Duration,
+        error: Option<ErrorCode>,
+    ) {
+        let outcome = error.map_or_else(|| "ok".to_string(), |code| code.to_string());
+        match tenant {
+            Some(tenant) => {
+                let tenant = tenant.to_string();
+                counter!("myproto_requests_total", "type" => request_type, "outcome" => outcome, "tenant" => tenant.clone())
+                    .increment(1);
+                histogram!("myproto_request_duration_seconds", "type" => request_type, "tenant" => tenant)
+                    .record(elapsed.as_secs_f64());
+            }
+            None => {
+                counter!("myproto_requests_total", "type" => request_type, "outcome" => outcome)
+                    .increment(1);
+                histogram!("myproto_request_duration_seconds", "type" => request_type)
+                    .record(elapsed.as_secs_f64());
+            }
+        }
+    }
+
+    pub(crate) fn datagram_dropped(reason: &'static str) {
+        counter!("myproto_datagrams_dropped_total", "reason" => reason).increment(1);
+    }
+
+    pub(crate) fn frame_rejected(code: ErrorCode) {
+        counter!("myproto_frame_errors_total", "code" => code.to_string()).increment(1);
+    }
+
+    pub(crate) fn quota_exceeded(quota: &'static str, tenant: Option<&str>) {
+        match tenant {
+            Some(tenant) => counter!("myproto_quota_exceeded_total", "quota" => quota, "tenant" => tenant.to_string())
+                .increment(1),
+            None => counter!("myproto_quota_exceeded_total", "quota" => quota).increment(1),
+        }
+    }
+
+    pub(crate) fn push_dropped(overflow: &'static str) {
+        counter!("myproto_pushes_dropped_total", "overflow" => overflow).increment(1);
+    }
+
+    pub(crate) fn message_dead_lettered() {
+        counter!("myproto_dead_letters_total").increment(1);
+    }
+
+    pub(crate) fn bytes_received(len: usize) {
+        counter!("myproto_received_bytes_total").increment(len as u64);
+    }
+
+    pub(crate) fn bytes_sent(len: usize) {
+        counter!("myproto_sent_bytes_total").increment(len as u64);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod recorder {
+    use super::*;
+
+    pub(crate) fn describe() {}
+
+    pub(crate) fn connection_opened() {}
+
+    pub(crate) fn connection_accepted(_: &str) {}
+
+    pub(crate) fn connection_closed() {}
+
+    pub(crate) fn connection_task_started() {}
+
+    pub(crate) fn connection_task_ended() {}
+
+    pub(crate) fn connection_panicked() {}
+
+    pub(crate) fn connection_rejected(_: &'static str) {}
+
+    pub(crate) fn peer_banned(_: &'static str) {}
+
+    pub(crate) fn request_handled(
+        _: &'static str,
+        _: Option<&str>,
+        _: Duration,
+        _: Option<ErrorCode>,
+    ) {
+    }
+
+    pub(crate) fn datagram_dropped(_: &'static str) {}
+
+    pub(crate) fn frame_rejected(_: ErrorCode) {}
+
+    pub(crate) fn quota_exceeded(_: &'static str, _: Option<&str>) {}
+
+    pub(crate) fn push_dropped(_: &'static str) {}
+
+    pub(crate) fn message_dead_lettered() {}
+
+    pub(crate) fn bytes_received(_: usize) {}
+
+    pub(crate) fn bytes_sent(_: usize) {}
+}
+
+pub(crate) use recorder::*;
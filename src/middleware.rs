@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{Context, ProtocolError, Request, Response};
+
+/// Wraps the handling of every unary request; registered in order with
+/// `ServerBuilder::middleware`, the first one registered runs outermost.
+///
+/// Returning a `ProtocolError` through `anyhow` picks the code the client sees.
+#[async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    async fn call(
+        &self,
+        req: &dyn Request,
+        ctx: &Context,
+        next: Next<'_>,
+    ) -> Result<Box<dyn Response>>;
+}
+
+/// The rest of the chain after the current middleware, ending in the
+/// request's `validate` check and then, unless a cluster sends it to the
+/// node owning its `shard_key`, the server's `Router` or else the request's
+/// own handler.
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(chain: &'a [Arc<dyn Middleware>]) -> Self {
+        Self { chain }
+    }
+
+    pub async fn run(self, req: &dyn Request, ctx: &Context) -> Result<Box<dyn Response>> {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.call(req, ctx, Next::new(rest)).await,
+            None => {
+                req.validate().map_err(ProtocolError::from)?;
+                if let Some(cluster) = &ctx.server().cluster
+                    && let Some(key) = req.shard_key()
+                    && let Some(owner) = cluster.owner(&key)
+                {
+                    return cluster.forward(&owner, req, ctx.signer()).await;
+                }
+                if req.mutating()
+                    && let Some(journal) = &ctx.server().request_journal
+                {
+                    journal.append(req, ctx.identity())?;
+                }
+                dispatch(req, ctx).await
+            }
+        }
+    }
+}
+
+/// Answers `req` with the server's `Router` or else its own handler.
+pub(crate) async fn dispatch(req: &dyn Request, ctx: &Context) -> Result<Box<dyn Response>> {
+    match ctx.server().router.dispatch(req, ctx) {
+        Some(routed) => routed.await,
+        None if req.blocking() => handle_blocking(req, ctx).await,
+        None => req.handle(ctx).await,
+    }
+}
+
+/// Runs `req`'s handler on the blocking thread pool.
+async fn handle_blocking(req: &dyn Request, ctx: &Context) -> Result<Box<dyn Response>> {
+    // The handler may outlive this call on its thread, so it gets its own copy of the request.
+    let req: Box<dyn Request> = bincode::deserialize(&bincode::serialize(req)?)?;
+    let ctx = ctx.clone();
+    let runtime = tokio::runtime::Handle::current();
+    match tokio::task::spawn_blocking(move || runtime.block_on(req.handle(&ctx))).await {
+        Ok(result) => result,
+        // Raised again here for the session to report like any other handler panic.
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(e.into()),
+    }
+}
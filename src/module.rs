@@ -0,0 +1,120 @@
+//! Self-contained parts of an application, each bringing its own routes,
+//! middleware, dependencies and startup and shutdown hooks, so a large
+//! server can be assembled from handler crates that don't know about each
+//! other:
+//!
+//! ```ignore
+//! pub struct Billing { pub db: DbPool }
+//!
+//! #[async_trait]
+//! impl Module for Billing {
+//!     fn register(&self, module: &mut Registrar<'_>) {
+//!         module
+//!             .extension(self.db.clone())
+//!             .route(create_invoice)
+//!             .middleware(AuditInvoices);
+//!     }
+//!
+//!     async fn on_start(&self, _: &Server) -> anyhow::Result<()> {
+//!         self.db.migrate().await
+//!     }
+//! }
+//!
+//! let server = Server::builder().module(Billing { db }).module(Accounts).build();
+//! ```
+
+use std::any::{TypeId, type_name};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{Context, Extensions, Middleware, Namespace, Router, Server, TypedRequest};
+
+/// A part of an application installed with `ServerBuilder::module`.
+#[async_trait]
+pub trait Module: Send + Sync + 'static {
+    /// How the module appears in logs; defaults to its type's name.
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
+    /// Adds the module's routes, middleware and extensions to the server
+    /// being built. Called once, by `ServerBuilder::module`.
+    fn register(&self, module: &mut Registrar<'_>);
+
+    /// Runs when `serve` starts, before any connection is accepted, in the
+    /// order modules were added. An error stops the server from serving.
+    async fn on_start(&self, server: &Server) -> Result<()> {
+        let _ = server;
+        Ok(())
+    }
+
+    /// Runs once `serve` has drained every session, in the reverse of the
+    /// order modules were added; only called if `on_start` succeeded.
+    async fn on_shutdown(&self, server: &Server) {
+        let _ = server;
+    }
+}
+
+/// What a `Module` can add to the server being built.
+pub struct Registrar<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) router: &'a mut Router,
+    pub(crate) middleware: &'a mut Vec<Arc<dyn Middleware>>,
+    pub(crate) namespaces: &'a mut HashMap<String, Namespace>,
+    pub(crate) extensions: &'a mut Extensions,
+}
+
+impl Registrar<'_> {
+    /// Answers every `T` with `handler`, as `Router::route` does. A type
+    /// another module already routes is taken over, with a warning.
+    pub fn route<T, Fut>(
+        &mut self,
+        handler: impl Fn(T, Context) -> Fut + Send + Sync + 'static,
+    ) -> &mut Self
+    where
+        T: TypedRequest + Clone,
+        Fut: Future<Output = Result<T::Response>> + Send + 'static,
+    {
+        if self.router.routes(TypeId::of::<T>()) {
+            tracing::warn!(
+                module = self.name,
+                request = type_name::<T>(),
+                "Module replaces an earlier route"
+            );
+        }
+        *self.router = std::mem::take(self.router).route(handler);
+        self
+    }
+
+    /// Appends `middleware` to the server's chain. It wraps every unary
+    /// request, not only the module's own, after the middleware of the
+    /// builder and of modules added before this one.
+    pub fn middleware(&mut self, middleware: impl Middleware) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Applies `namespace` to the module's request types tagged `name.Type`,
+    /// as `ServerBuilder::namespace` does.
+    pub fn namespace(&mut self, name: impl Into<String>, namespace: Namespace) -> &mut Self {
+        let name = name.into();
+        if self.namespaces.contains_key(&name) {
+            tracing::warn!(
+                module = self.name,
+                namespace = name,
+                "Module replaces an earlier namespace"
+            );
+        }
+        self.namespaces.insert(name, namespace);
+        self
+    }
+
+    /// Adds `value` to the extensions every handler can `Context::get`.
+    pub fn extension<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.extensions.insert(value);
+        self
+    }
+}
@@ -0,0 +1,94 @@
+//! Request types grouped under a dotted prefix, such as
+//! `billing.CreateInvoice` from `#[request(response = Invoice, namespace =
+//! "billing")]`, so independent modules can each name a request `Create`
+//! without their tags colliding.
+//!
+//! A `Namespace` registered under the prefix with `ServerBuilder::namespace`
+//! adds middleware that only its unary requests pass through, after the
+//! server's own, and an authorization policy every kind of request in it
+//! must pass on top of the server's.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{Authorization, Middleware, versioning};
+
+/// The middleware and authorization for the request types in one namespace.
+#[derive(Default, Clone)]
+pub struct Namespace {
+    middleware: Vec<Arc<dyn Middleware>>,
+    authorization: Option<Authorization>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `middleware` to the chain the namespace's unary requests
+    /// pass through once the server's middleware has run.
+    pub fn middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Requires the namespace's requests to pass `policy` as well as the
+    /// server's own `Authorization`. Rules name requests by their full tag,
+    /// e.g. `billing.CreateInvoice`.
+    pub fn authorization(mut self, policy: Authorization) -> Self {
+        self.authorization = Some(policy);
+        self
+    }
+}
+
+/// The namespace a request tag is in, e.g. `billing` for
+/// `billing.CreateInvoice@2`; `None` for a tag without one.
+pub fn namespace_of(tag: &str) -> Option<&str> {
+    let (namespace, _) = versioning::base_name(tag).rsplit_once('.')?;
+    Some(namespace)
+}
+
+/// A namespace as the server runs it.
+pub(crate) struct Scope {
+    /// The server's middleware followed by the namespace's.
+    pub(crate) chain: Arc<[Arc<dyn Middleware>]>,
+    pub(crate) authorization: Option<Authorization>,
+}
+
+/// Every registered namespace, by name.
+#[derive(Default)]
+pub(crate) struct Namespaces {
+    scopes: HashMap<String, Scope>,
+}
+
+impl Namespaces {
+    pub(crate) fn new(
+        namespaces: HashMap<String, Namespace>,
+        middleware: &[Arc<dyn Middleware>],
+    ) -> Self {
+        let scopes = namespaces
+            .into_iter()
+            .map(|(name, namespace)| {
+                let chain = middleware
+                    .iter()
+                    .chain(&namespace.middleware)
+                    .cloned()
+                    .collect();
+                let scope = Scope {
+                    chain,
+                    authorization: namespace.authorization,
+                };
+                (name, scope)
+            })
+            .collect();
+        Self { scopes }
+    }
+
+    /// The scope of the namespace `request_type` is in, if it has one.
+    pub(crate) fn get(&self, request_type: &str) -> Option<&Scope> {
+        if self.scopes.is_empty() {
+            return None;
+        }
+        self.scopes.get(namespace_of(request_type)?)
+    }
+}
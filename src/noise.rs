@@ -0,0 +1,314 @@
+//! Authenticated encryption for plain TCP without TLS certificates: a Noise
+//! XX handshake between static Curve25519 keys that each side knows in
+//! advance, beneath the protocol's own handshake.
+//!
+//! Each side has a [`Keypair`] and a list of peer public keys it trusts; a
+//! handshake with anyone else fails before any frame is exchanged. Servers
+//! take a [`NoiseConfig`] through `ServerBuilder::noise`, clients through
+//! `ClientConfig::noise`.
+//!
+//! `Server::set_noise` rotates a running server's keys: connections accepted
+//! afterwards handshake with the new ones, without a restart.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, bail};
+use bytes::{Buf, BufMut, BytesMut};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The Noise protocol both sides run.
+pub const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// How long a connection may take to complete the Noise handshake.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const KEY_LEN: usize = 32;
+
+/// The longest Noise message, length prefix aside.
+const MAX_MESSAGE: usize = 65535;
+
+const TAG_LEN: usize = 16;
+
+const MAX_PLAINTEXT: usize = MAX_MESSAGE - TAG_LEN;
+
+/// A static Curve25519 key pair identifying one end of a connection.
+#[derive(Clone)]
+pub struct Keypair {
+    public: [u8; KEY_LEN],
+    private: [u8; KEY_LEN],
+}
+
+impl Keypair {
+    pub fn generate() -> Result<Self> {
+        let keypair = snow::Builder::new(PATTERN.parse()?).generate_keypair()?;
+        let private = keypair.private.as_slice().try_into()?;
+        Ok(Self::from_private(private))
+    }
+
+    pub fn from_private(private: [u8; KEY_LEN]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("the default resolver supports Curve25519");
+        dh.set(&private);
+        let public = dh
+            .pubkey()
+            .try_into()
+            .expect("Curve25519 public keys are 32 bytes");
+        Self { public, private }
+    }
+
+    /// The key peers list to trust this side.
+    pub fn public(&self) -> [u8; KEY_LEN] {
+        self.public
+    }
+
+    pub fn private(&self) -> [u8; KEY_LEN] {
+        self.private
+    }
+}
+
+/// The private key is left out.
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &hex(&self.public))
+            .finish_non_exhaustive()
+    }
+}
+
+/// This side's key pair and the peers it will complete a handshake with.
+#[derive(Debug, Clone)]
+pub struct NoiseConfig {
+    keypair: Keypair,
+    trusted: Vec<[u8; KEY_LEN]>,
+    any_peer: bool,
+}
+
+impl NoiseConfig {
+    /// Trusts no peer until `trust` or `trust_any_peer` is called.
+    pub fn new(keypair: Keypair) -> Self {
+        Self {
+            keypair,
+            trusted: Vec::new(),
+            any_peer: false,
+        }
+    }
+
+    pub fn trust(mut self, public_key: [u8; KEY_LEN]) -> Self {
+        self.trusted.push(public_key);
+        self
+    }
+
+    /// Encrypts connections with any peer, leaving authentication to the
+    /// protocol's own `Authenticator`.
+    pub fn trust_any_peer(mut self) -> Self {
+        self.any_peer = true;
+        self
+    }
+
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
+    }
+
+    fn trusts(&self, public_key: &[u8]) -> bool {
+        self.any_peer || self.trusted.iter().any(|key| key == public_key)
+    }
+}
+
+/// Runs the handshake as the side that opened the connection.
+pub async fn connect<S>(stream: S, config: &NoiseConfig) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake(stream, config, true).await
+}
+
+/// Runs the handshake as the side that accepted the connection.
+pub async fn accept<S>(stream: S, config: &NoiseConfig) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake(stream, config, false).await
+}
+
+async fn handshake<S>(
+    mut stream: S,
+    config: &NoiseConfig,
+    initiator: bool,
+) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let builder =
+        snow::Builder::new(PATTERN.parse()?).local_private_key(&config.keypair.private)?;
+    let mut state: HandshakeState = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+
+    let mut buf = vec![0; MAX_MESSAGE];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf)?;
+            stream.write_u16(len as u16).await?;
+            stream.write_all(&buf[..len]).await?;
+            stream.flush().await?;
+        } else {
+            let len = usize::from(
+                stream
+                    .read_u16()
+                    .await
+                    .context("connection closed during the Noise handshake")?,
+            );
+            let mut message = vec![0; len];
+            stream.read_exact(&mut message).await?;
+            state
+                .read_message(&message, &mut buf)
+                .context("Noise handshake failed")?;
+        }
+    }
+
+    let remote: [u8; KEY_LEN] = state
+        .get_remote_static()
+        .context("peer sent no static key")?
+        .try_into()?;
+    if !config.trusts(&remote) {
+        bail!("peer's Noise key {} is not trusted", hex(&remote));
+    }
+    Ok(NoiseStream {
+        inner: stream,
+        transport: state.into_transport_mode()?,
+        remote,
+        received: BytesMut::new(),
+        plaintext: BytesMut::new(),
+        pending: BytesMut::new(),
+    })
+}
+
+/// A stream encrypted with the keys a Noise handshake agreed on. Every write
+/// goes out as one or more length-prefixed Noise messages.
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+    remote: [u8; KEY_LEN],
+    /// Ciphertext read but not yet a whole message.
+    received: BytesMut,
+    /// Decrypted bytes not yet handed to the reader.
+    plaintext: BytesMut,
+    /// Ciphertext accepted from the writer but not yet written out.
+    pending: BytesMut,
+}
+
+impl<S> NoiseStream<S> {
+    /// The peer's static public key, which the handshake checked was trusted.
+    pub fn remote_public_key(&self) -> [u8; KEY_LEN] {
+        self.remote
+    }
+
+    /// Decrypts the first whole message in `received`, if there is one.
+    fn decrypt_next(&mut self) -> io::Result<bool> {
+        if self.received.len() < 2 {
+            return Ok(false);
+        }
+        let len = usize::from(u16::from_be_bytes([self.received[0], self.received[1]]));
+        if self.received.len() < 2 + len {
+            return Ok(false);
+        }
+        self.received.advance(2);
+        let message = self.received.split_to(len);
+        let mut plaintext = vec![0; len];
+        let n = self
+            .transport
+            .read_message(&message, &mut plaintext)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plaintext.extend_from_slice(&plaintext[..n]);
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.plaintext.is_empty() {
+            if this.decrypt_next()? {
+                continue;
+            }
+            let mut chunk = [0; 8 * 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                if !this.received.is_empty() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            this.received.extend_from_slice(read.filled());
+        }
+        let n = this.plaintext.len().min(buf.remaining());
+        buf.put_slice(&this.plaintext.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // Holding more than a message back would let a fast writer buffer without bound.
+        if this.pending.len() >= MAX_MESSAGE {
+            ready!(this.poll_write_pending(cx))?;
+        }
+        let n = buf.len().min(MAX_PLAINTEXT);
+        let mut message = vec![0; n + TAG_LEN];
+        let len = this
+            .transport
+            .write_message(&buf[..n], &mut message)
+            .map_err(io::Error::other)?;
+        this.pending.reserve(2 + len);
+        this.pending.put_u16(len as u16);
+        this.pending.put_slice(&message[..len]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,32 @@
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+
+use futures::FutureExt;
+
+use crate::{ErrorCode, ProtocolError};
+
+/// Runs a request's handler, turning a panic into an `Internal` error for the
+/// client so the rest of the session carries on.
+pub(crate) async fn isolate<F: Future>(
+    request_type: &'static str,
+    handler: F,
+) -> Result<F::Output, ProtocolError> {
+    AssertUnwindSafe(handler)
+        .catch_unwind()
+        .await
+        .map_err(|panic| {
+            let message = panic_message(&*panic);
+            tracing::error!(request_type, panic = message, "Request handler panicked");
+            ProtocolError::new(ErrorCode::Internal, "Request handler panicked")
+        })
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
@@ -0,0 +1,84 @@
+//! Caps on the payload length of frames carrying particular request types,
+//! from `ServerConfig::max_payload_lengths`.
+//!
+//! A request's type name comes before its fields in every codec, so the cap
+//! is checked as soon as the name is read, and a frame over it is rejected
+//! without decoding the request. What is measured is the whole payload,
+//! envelope included, since a request's own share isn't known until it has
+//! been decoded.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde::de;
+
+/// The request type that put a frame over its cap.
+#[derive(Debug)]
+pub(crate) struct Exceeded {
+    pub(crate) request_type: String,
+    pub(crate) limit: usize,
+}
+
+struct Checking {
+    limits: HashMap<String, usize>,
+    len: usize,
+    exceeded: Option<Exceeded>,
+}
+
+thread_local! {
+    static CHECKING: RefCell<Option<Checking>> = const { RefCell::new(None) };
+}
+
+/// Runs `decode` on a payload of `len` bytes with `limits` checked against
+/// each request type named in it, returning which one was over, if any.
+pub(crate) fn checking<R>(
+    limits: &HashMap<String, usize>,
+    len: usize,
+    decode: impl FnOnce() -> R,
+) -> (R, Option<Exceeded>) {
+    // Most frames are under every cap, and need no checking at all.
+    if limits.values().all(|&limit| len <= limit) {
+        return (decode(), None);
+    }
+    struct Restore(Option<Checking>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CHECKING.set(self.0.take());
+        }
+    }
+    let restore = Restore(CHECKING.replace(Some(Checking {
+        limits: limits.clone(),
+        len,
+        exceeded: None,
+    })));
+    let decoded = decode();
+    let exceeded = CHECKING.with_borrow_mut(|checking| checking.as_mut()?.exceeded.take());
+    drop(restore);
+    (decoded, exceeded)
+}
+
+/// Whether request type names decoded on this thread need checking.
+pub(crate) fn is_checking() -> bool {
+    CHECKING.with_borrow(Option::is_some)
+}
+
+/// Fails if the payload being decoded is over `request_type`'s cap.
+pub(crate) fn check<E: de::Error>(request_type: &str) -> Result<(), E> {
+    CHECKING.with_borrow_mut(|checking| {
+        let Some(checking) = checking else {
+            return Ok(());
+        };
+        match checking.limits.get(request_type) {
+            Some(&limit) if checking.len > limit => {
+                checking.exceeded = Some(Exceeded {
+                    request_type: request_type.to_string(),
+                    limit,
+                });
+                Err(E::custom(format_args!(
+                    "{request_type} is limited to {limit} bytes"
+                )))
+            }
+            _ => Ok(()),
+        }
+    })
+}
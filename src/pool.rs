@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+
+use bytes::{Bytes, BytesMut};
+
+/// How much each thread's arena grows by when it runs out of room.
+const ARENA_CHUNK: usize = 64 * 1024;
+
+/// Encodes larger than this get an allocation of their own, so one big frame
+/// can't leave a thread holding megabytes it will rarely need again.
+const MAX_POOLED: usize = ARENA_CHUNK;
+
+thread_local! {
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static ARENA: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+/// Runs `write` against this thread's scratch buffer and returns what it
+/// wrote, carved out of a per-thread arena.
+///
+/// Consecutive encodes share one allocation, which `BytesMut` reclaims once
+/// every `Bytes` split off it has been dropped, so steady-state traffic
+/// stops allocating per frame. Serializers write into a plain `Vec` first
+/// because they make many tiny writes, which `Vec` handles fastest.
+pub(crate) fn encode<E>(write: impl FnOnce(&mut Vec<u8>) -> Result<(), E>) -> Result<Bytes, E> {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut scratch) => {
+            scratch.clear();
+            write(&mut scratch)?;
+            if scratch.len() > MAX_POOLED {
+                // Hand the allocation over rather than copying it, and start afresh.
+                return Ok(Bytes::from(std::mem::take(&mut *scratch)));
+            }
+            Ok(ARENA.with_borrow_mut(|arena| {
+                if arena.capacity() < scratch.len() {
+                    arena.reserve(ARENA_CHUNK);
+                }
+                arena.extend_from_slice(&scratch);
+                arena.split().freeze()
+            }))
+        }
+        // Encoding from inside a `Serialize` impl that is itself being encoded.
+        Err(_) => {
+            let mut buf = Vec::new();
+            write(&mut buf)?;
+            Ok(buf.into())
+        }
+    })
+}
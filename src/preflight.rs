@@ -0,0 +1,227 @@
+//! Checks made once at startup, so a mistake in how message types are
+//! registered or the server is configured stops it there with every problem
+//! listed, rather than failing whichever request first runs into it.
+//!
+//! ```ignore
+//! let server = Server::builder().build();
+//! server.preflight()?;
+//! ```
+
+use std::fmt;
+
+use anyhow::{Result, bail};
+
+use crate::builtin::{ServerTime, ServerTimeResponse};
+use crate::codec::Codec;
+use crate::envelope::{ClientMessage, NotifyFrame, ServerMessage};
+use crate::schema::Schema;
+use crate::versioning;
+use crate::{Request, Response, Server, StreamingRequest, UploadRequest, registry, type_ids};
+
+/// What `Server::preflight` found registered, for the startup log.
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    pub requests: Vec<&'static str>,
+    pub streaming_requests: Vec<&'static str>,
+    pub upload_requests: Vec<&'static str>,
+    pub responses: Vec<&'static str>,
+    /// Request types with a handler added through `ServerBuilder::route`.
+    pub routed: Vec<&'static str>,
+    pub codecs: Vec<Codec>,
+    pub middleware: usize,
+    pub modules: Vec<String>,
+}
+
+impl fmt::Display for Preflight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} streaming requests, {} upload requests, {} responses, \
+             {} routes, {} middleware, codecs {:?}",
+            self.requests.len(),
+            self.streaming_requests.len(),
+            self.upload_requests.len(),
+            self.responses.len(),
+            self.routed.len(),
+            self.middleware,
+            self.codecs,
+        )?;
+        if !self.modules.is_empty() {
+            write!(f, ", modules {}", self.modules.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Checks that no two types registered for a message trait share a name,
+    /// that each request's declared response is a registered response, that
+    /// every configured codec can encode and decode a message, and that the
+    /// per-type settings name types that exist; then logs what's registered.
+    ///
+    /// Returns every problem found at once as the error.
+    pub fn preflight(&self) -> Result<Preflight> {
+        let config = self.config();
+        let report = Preflight {
+            requests: registry::registered::<dyn Request>(),
+            streaming_requests: registry::registered::<dyn StreamingRequest>(),
+            upload_requests: registry::registered::<dyn UploadRequest>(),
+            responses: registry::registered::<dyn Response>(),
+            routed: self.router.routed(),
+            codecs: config.codecs.clone(),
+            middleware: self.middleware.len(),
+            modules: self
+                .modules
+                .iter()
+                .map(|module| module.name().to_string())
+                .collect(),
+        };
+        let mut problems = Vec::new();
+
+        for (kind, names) in [
+            ("request", &report.requests),
+            ("streaming request", &report.streaming_requests),
+            ("upload request", &report.upload_requests),
+            ("response", &report.responses),
+        ] {
+            for pair in names.windows(2) {
+                if pair[0] == pair[1] {
+                    problems.push(format!(
+                        "more than one {kind} type is registered as {:?}",
+                        pair[0]
+                    ));
+                }
+            }
+        }
+        // A repeated name would be reported once per extra registration.
+        problems.dedup();
+
+        let schema = Schema::registered();
+        for request in schema
+            .requests
+            .iter()
+            .chain(&schema.streaming_requests)
+            .chain(&schema.upload_requests)
+        {
+            if let Some(response) = &request.response
+                && report.responses.binary_search(&response.as_str()).is_err()
+            {
+                problems.push(format!(
+                    "{} is answered with {response}, which isn't a registered response",
+                    request.name
+                ));
+            }
+        }
+
+        for (kind, typetag, ids) in [
+            (
+                "request",
+                &report.requests,
+                type_ids::registered::<dyn Request>(),
+            ),
+            (
+                "streaming request",
+                &report.streaming_requests,
+                type_ids::registered::<dyn StreamingRequest>(),
+            ),
+            (
+                "upload request",
+                &report.upload_requests,
+                type_ids::registered::<dyn UploadRequest>(),
+            ),
+            (
+                "response",
+                &report.responses,
+                type_ids::registered::<dyn Response>(),
+            ),
+        ] {
+            for (id, name) in ids {
+                if typetag.binary_search(&name).is_err() {
+                    problems.push(format!(
+                        "type id {id} is given to {name:?}, which isn't a registered {kind}"
+                    ));
+                }
+            }
+        }
+
+        if self.multi_tenant && self.authenticator.is_none() {
+            problems.push(
+                "multi-tenant mode needs an authenticator to give connections tenants".to_string(),
+            );
+        }
+
+        if report.codecs.is_empty() {
+            problems.push("no codecs are configured, so no client can connect".to_string());
+        }
+        for &codec in &report.codecs {
+            if let Err(e) = round_trip(codec) {
+                problems.push(format!(
+                    "codec {codec:?} can't encode and decode messages: {e:#}"
+                ));
+            }
+        }
+
+        let known = |name: &str| {
+            [
+                &report.requests,
+                &report.streaming_requests,
+                &report.upload_requests,
+            ]
+            .into_iter()
+            .flatten()
+            .any(|&registered| registered == name || versioning::base_name(registered) == name)
+        };
+        for (setting, names) in [
+            (
+                "request_timeouts",
+                config.request_timeouts.keys().collect::<Vec<_>>(),
+            ),
+            (
+                "max_payload_lengths",
+                config.max_payload_lengths.keys().collect(),
+            ),
+        ] {
+            for name in names {
+                if !known(name) {
+                    problems.push(format!(
+                        "{setting} names {name:?}, which isn't a registered request type"
+                    ));
+                }
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("preflight check failed:\n  {}", problems.join("\n  "));
+        }
+
+        tracing::info!("Registered {report}");
+        tracing::debug!(
+            requests = ?report.requests,
+            streaming_requests = ?report.streaming_requests,
+            upload_requests = ?report.upload_requests,
+            responses = ?report.responses,
+            routed = ?report.routed,
+            "Registered types"
+        );
+        Ok(report)
+    }
+}
+
+/// Encodes and decodes a message each way with `codec`.
+fn round_trip(codec: Codec) -> Result<()> {
+    let notify = ClientMessage::Notify(NotifyFrame {
+        trace: None,
+        channel: 0,
+        requests: vec![Box::new(ServerTime)],
+    });
+    let bytes = codec.encode_bytes(&notify)?;
+    codec.decode::<ClientMessage>(&bytes)?;
+
+    let push = ServerMessage::Push(Box::new(ServerTimeResponse {
+        unix_time_us: 0,
+        monotonic: Default::default(),
+    }));
+    let bytes = codec.encode_bytes(&push)?;
+    codec.decode::<ServerMessage>(&bytes)?;
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! The framing and message encoding past the handshake as a plain state
+//! machine, free of sockets and async runtimes: hand it the bytes that
+//! arrive and take the messages they decode to, queue messages and take the
+//! bytes to write.
+//!
+//! `Server` and `Client` drive it over tokio `Framed` transports through its
+//! `Decoder` and `Encoder` impls; anything else that moves bytes can drive it
+//! with [`Protocol::receive`], [`Protocol::poll_inbound`], [`Protocol::send`]
+//! and [`Protocol::transmit`]. It only turns frames into messages and back:
+//! matching replies to calls, tracking what's in flight, keeping replies in
+//! order and running handlers are left to the caller, as the server's
+//! sessions and the client do them.
+//!
+//! With [`Protocol::dump_frames`], or `MYPROTO_DUMP_FRAMES` set, every frame
+//! past the handshake is logged under the `myproto::frames` target with its
+//! direction, length, message kind and the start of its payload in hex.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::codec::Codec;
+use crate::envelope::{ClientMessage, ServerMessage};
+use crate::frame::{Encoded, Frame, FrameCodec};
+use crate::payload_limit::Exceeded;
+use crate::{payload_limit, shared_bytes, untyped};
+
+/// How much of each payload a frame dump shows.
+const DUMP_BYTES: usize = 64;
+
+/// A message either end of a connection exchanges.
+pub trait Message: Serialize + DeserializeOwned {
+    /// What the message is, e.g. `Call(Echo, Add)`, for frame dumps.
+    fn kind(&self) -> String;
+}
+
+impl Message for ClientMessage {
+    fn kind(&self) -> String {
+        self.kind()
+    }
+}
+
+impl Message for ServerMessage {
+    fn kind(&self) -> String {
+        self.kind()
+    }
+}
+
+/// Whether `MYPROTO_DUMP_FRAMES` asks for frame dumps by default.
+pub(crate) fn dump_frames_from_env() -> bool {
+    std::env::var_os("MYPROTO_DUMP_FRAMES").is_some_and(|value| value != "0" && !value.is_empty())
+}
+
+/// The server's end: reads `ClientMessage`s and writes `ServerMessage`s.
+pub type ServerProtocol = Protocol<ClientMessage, ServerMessage>;
+
+/// The client's end: reads `ServerMessage`s and writes `ClientMessage`s.
+pub type ClientProtocol = Protocol<ServerMessage, ClientMessage>;
+
+/// What a frame from the peer amounted to.
+#[derive(Debug)]
+pub enum Inbound<M> {
+    /// A message, along with the payload it was decoded from.
+    Message(M, Bytes),
+    /// A frame declaring more than the length limit; its bytes were skipped.
+    Oversized(usize),
+    /// A frame whose payload isn't a message this side understands.
+    Malformed {
+        error: anyhow::Error,
+        /// The payload's length.
+        len: usize,
+        /// About where in the payload decoding failed, if the codec can tell.
+        offset: Option<usize>,
+    },
+    /// A frame of this many bytes that failed its checksum; it was skipped.
+    Corrupt(usize),
+    /// A frame longer than the limit on the type of a request in it, which
+    /// was rejected once the type's name was read.
+    PayloadTooLarge {
+        request_type: String,
+        len: usize,
+        limit: usize,
+    },
+}
+
+/// One end of a connection, reading messages of type `R` and writing `W`.
+#[derive(Debug)]
+pub struct Protocol<R, W> {
+    frames: FrameCodec,
+    codec: Codec,
+    untyped: bool,
+    dump_frames: bool,
+    max_payload_lengths: HashMap<String, usize>,
+    inbound: BytesMut,
+    outbound: BytesMut,
+    _messages: PhantomData<fn(W) -> R>,
+}
+
+impl<R: Message, W: Message> Protocol<R, W> {
+    /// Carries on from a handshake that settled on `frames`' length limit and
+    /// compression and on `codec` for payloads.
+    pub fn new(frames: FrameCodec, codec: Codec) -> Self {
+        Self {
+            frames,
+            codec,
+            untyped: false,
+            dump_frames: false,
+            max_payload_lengths: HashMap::new(),
+            inbound: BytesMut::new(),
+            outbound: BytesMut::new(),
+            _messages: PhantomData,
+        }
+    }
+
+    /// Decodes responses inside messages as `Untyped` rather than their Rust types.
+    pub fn untyped(mut self) -> Self {
+        self.untyped = true;
+        self
+    }
+
+    /// Logs every frame sent and received; see the module docs.
+    pub fn dump_frames(mut self, enabled: bool) -> Self {
+        self.dump_frames = enabled;
+        self
+    }
+
+    /// Rejects frames carrying requests of these types, by type name, when
+    /// they are longer than the given number of bytes.
+    pub fn max_payload_lengths(mut self, limits: HashMap<String, usize>) -> Self {
+        self.max_payload_lengths = limits;
+        self
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Takes bytes read from the peer.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.inbound.extend_from_slice(data);
+    }
+
+    /// The next frame among the bytes received so far, or `None` until more
+    /// arrive. Fails only when the framing itself is broken, after which the
+    /// connection can't be read any further.
+    pub fn poll_inbound(&mut self) -> io::Result<Option<Inbound<R>>> {
+        let mut inbound = std::mem::take(&mut self.inbound);
+        let next = self.decode(&mut inbound);
+        self.inbound = inbound;
+        next
+    }
+
+    /// Queues `message` to be written to the peer.
+    pub fn send(&mut self, message: &W) -> Result<()> {
+        let payload = self.codec.encode_bytes(message)?;
+        self.send_payload(payload)?;
+        Ok(())
+    }
+
+    /// Queues a message already encoded with this connection's codec.
+    pub fn send_payload(&mut self, payload: Bytes) -> io::Result<()> {
+        let mut outbound = std::mem::take(&mut self.outbound);
+        let result = self.encode(payload, &mut outbound);
+        self.outbound = outbound;
+        result
+    }
+
+    /// The bytes queued for the peer since the last call, if any.
+    pub fn transmit(&mut self) -> Option<Bytes> {
+        (!self.outbound.is_empty()).then(|| self.outbound.split().freeze())
+    }
+
+    fn decode_payload(&self, payload: Bytes) -> Inbound<R> {
+        let (decoded, exceeded) =
+            payload_limit::checking(&self.max_payload_lengths, payload.len(), || {
+                shared_bytes::sharing(&payload, || self.decode_message::<R>(&payload))
+            });
+        if let Some(Exceeded {
+            request_type,
+            limit,
+        }) = exceeded
+        {
+            if self.dump_frames {
+                dump("received", &payload, "too large");
+            }
+            return Inbound::PayloadTooLarge {
+                request_type,
+                len: payload.len(),
+                limit,
+            };
+        }
+        match decoded {
+            Ok(message) => {
+                if self.dump_frames {
+                    dump("received", &payload, &message.kind());
+                }
+                Inbound::Message(message, payload)
+            }
+            Err(e) => {
+                if self.dump_frames {
+                    dump("received", &payload, "malformed");
+                }
+                Inbound::Malformed {
+                    offset: self.typed(|| self.codec.error_offset::<R>(&payload)),
+                    len: payload.len(),
+                    error: e,
+                }
+            }
+        }
+    }
+
+    fn decode_message<M: Message>(&self, payload: &[u8]) -> Result<M> {
+        self.typed(|| self.codec.decode(payload))
+    }
+
+    /// Runs `decode` the way this end reads request and response types.
+    fn typed<T>(&self, decode: impl FnOnce() -> T) -> T {
+        if self.untyped {
+            untyped::decoding(decode)
+        } else {
+            decode()
+        }
+    }
+}
+
+/// Logs one frame's payload, truncated to `DUMP_BYTES`.
+fn dump(direction: &'static str, payload: &[u8], kind: &str) {
+    let shown = &payload[..payload.len().min(DUMP_BYTES)];
+    let mut hex = String::with_capacity(shown.len() * 3 + 16);
+    for (i, byte) in shown.iter().enumerate() {
+        if i > 0 {
+            hex.push(' ');
+        }
+        let _ = write!(hex, "{byte:02x}");
+    }
+    if payload.len() > shown.len() {
+        let _ = write!(hex, " .. (+{} bytes)", payload.len() - shown.len());
+    }
+    tracing::info!(target: "myproto::frames", direction, len = payload.len(), kind, %hex, "Frame");
+}
+
+impl<R: Message, W: Message> Decoder for Protocol<R, W> {
+    type Item = Inbound<R>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Inbound<R>>> {
+        Ok(self.frames.decode(src)?.map(|frame| match frame {
+            Frame::Payload(payload) => self.decode_payload(payload),
+            Frame::Oversized { len } => Inbound::Oversized(len),
+            Frame::Corrupt { len } => Inbound::Corrupt(len),
+        }))
+    }
+}
+
+impl<R: Message, W: Message> Encoder<Bytes> for Protocol<R, W> {
+    type Error = io::Error;
+
+    fn encode(&mut self, payload: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode(Encoded::from(payload), dst)
+    }
+}
+
+impl<R: Message, W: Message> Encoder<Encoded> for Protocol<R, W> {
+    type Error = io::Error;
+
+    fn encode(&mut self, payload: Encoded, dst: &mut BytesMut) -> io::Result<()> {
+        if self.dump_frames {
+            // Payloads arrive already encoded, so they are decoded again to be named.
+            let kind = self
+                .decode_message::<W>(&payload.bytes)
+                .map_or_else(|_| "unknown".to_string(), |message| message.kind());
+            dump("sent", &payload.bytes, &kind);
+        }
+        self.frames.encode_message(payload, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+
+    use super::*;
+    use crate::builtin::ServerTime;
+    use crate::envelope::NotifyFrame;
+
+    fn notify() -> ClientMessage {
+        ClientMessage::Notify(NotifyFrame {
+            trace: None,
+            channel: 0,
+            requests: vec![Box::new(ServerTime)],
+        })
+    }
+
+    fn ends(max_frame_length: usize) -> (ClientProtocol, ServerProtocol) {
+        let client = Protocol::new(FrameCodec::new(), Codec::Json);
+        let server = Protocol::new(
+            FrameCodec::with_max_frame_length(max_frame_length),
+            Codec::Json,
+        );
+        (client, server)
+    }
+
+    /// A frame with no flags set around `payload`.
+    fn raw_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.put_u32(payload.len() as u32 + 1);
+        frame.put_u8(0);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn message_arriving_a_byte_at_a_time() {
+        let (mut client, mut server) = ends(1024);
+        assert!(client.transmit().is_none());
+        client.send(&notify()).unwrap();
+        let bytes = client.transmit().unwrap();
+        assert!(client.transmit().is_none());
+
+        let (last, rest) = bytes.split_last().unwrap();
+        for byte in rest {
+            server.receive(&[*byte]);
+            assert!(server.poll_inbound().unwrap().is_none());
+        }
+        server.receive(&[*last]);
+        match server.poll_inbound().unwrap() {
+            Some(Inbound::Message(message, _)) => assert_eq!(message.kind(), notify().kind()),
+            other => panic!("expected a message, got {other:?}"),
+        }
+        assert!(server.poll_inbound().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frame_is_skipped() {
+        let (mut client, mut server) = ends(128);
+        let padding = "x".repeat(256);
+        server.receive(&raw_frame(
+            format!("{{\"padding\":\"{padding}\"}}").as_bytes(),
+        ));
+        client.send(&notify()).unwrap();
+        server.receive(&client.transmit().unwrap());
+
+        assert!(matches!(
+            server.poll_inbound().unwrap(),
+            Some(Inbound::Oversized(len)) if len > 256
+        ));
+        assert!(matches!(
+            server.poll_inbound().unwrap(),
+            Some(Inbound::Message(..))
+        ));
+    }
+
+    #[test]
+    fn malformed_payload_is_reported() {
+        let (_, mut server) = ends(1024);
+        server.receive(&raw_frame(b"not a message"));
+        match server.poll_inbound().unwrap() {
+            Some(Inbound::Malformed { len, .. }) => assert_eq!(len, 13),
+            other => panic!("expected a malformed frame, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn frame_without_flags_breaks_the_connection() {
+        let (_, mut server) = ends(1024);
+        server.receive(&0u32.to_be_bytes());
+        assert!(server.poll_inbound().is_err());
+    }
+}
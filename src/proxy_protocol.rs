@@ -0,0 +1,225 @@
+//! The PROXY protocol header a load balancer sends ahead of a connection to
+//! say which client it is relaying, in either version 1 (text) or 2 (binary).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a connection may take to send its header before it is dropped.
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header the specification allows, line ending included.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the header off the start of `stream`, leaving the stream at the
+/// first byte of the relayed connection. Returns the client's address, or
+/// `None` when the balancer says the connection is its own, e.g. a health check.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>> {
+    // Twelve bytes are the v2 signature and shorter than any v1 header, so
+    // reading them never takes bytes that belong to the connection.
+    let mut start = [0; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .context("connection closed before its PROXY header")?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        bail!("connection did not start with a PROXY header")
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("PROXY header is not text")?;
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            "TCP4" | "TCP6",
+            source,
+            _destination,
+            port,
+            _destination_port,
+        ] => {
+            let ip: IpAddr = source
+                .parse()
+                .with_context(|| format!("invalid PROXY source address {source:?}"))?;
+            let port: u16 = port
+                .parse()
+                .with_context(|| format!("invalid PROXY source port {port:?}"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("malformed PROXY header {line:?}"),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut body = vec![0; usize::from(length)];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection; the body, if any, is ignored.
+        0 => return Ok(None),
+        1 => {}
+        command => bail!("unknown PROXY command {command}"),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family >> 4 {
+        // AF_INET: source and destination address, then source and destination port.
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        // AF_UNSPEC, or AF_UNIX, which has no address to report.
+        0 | 3 => Ok(None),
+        _ => bail!("malformed PROXY v2 address block"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header off `bytes`, returning what is left for the connection.
+    async fn read(bytes: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    /// A v2 header with the given command, family and address block.
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_gives_the_source_and_leaves_the_connection() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 443\r\nhello").await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:5000".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6_gives_the_source() {
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 443\r\n").await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:5000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_has_no_address() {
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nhello").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v1_longer_than_the_specification_allows_is_refused() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(200, b'1');
+        line.extend_from_slice(b"\r\n");
+        assert!(read(&line).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_with_a_bad_address_is_refused() {
+        let (header, _) = read(b"PROXY TCP4 not-an-ip 198.51.100.2 5000 443\r\n").await;
+        assert!(header.is_err());
+        let (header, _) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 70000 443\r\n").await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_gives_the_source_and_leaves_the_connection() {
+        let body = [192, 0, 2, 1, 198, 51, 100, 2, 0x13, 0x88, 0x01, 0xbb];
+        let mut bytes = v2(1, 0x11, &body);
+        bytes.extend_from_slice(b"hello");
+        let (header, rest) = read(&bytes).await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:5000".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_gives_the_source() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut body = source.octets().to_vec();
+        body.extend_from_slice(&destination.octets());
+        body.extend_from_slice(&[0x13, 0x88, 0x01, 0xbb]);
+        let (header, _) = read(&v2(1, 0x21, &body)).await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:5000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_has_no_address_and_skips_its_body() {
+        let mut bytes = v2(0, 0x11, &[0; 12]);
+        bytes.extend_from_slice(b"hello");
+        let (header, rest) = read(&bytes).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_address_block_shorter_than_its_family_is_refused() {
+        let (header, _) = read(&v2(1, 0x11, &[192, 0, 2, 1])).await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_with_another_version_or_command_is_refused() {
+        let mut bytes = v2(1, 0x11, &[0; 12]);
+        bytes[12] = 0x11;
+        assert!(read(&bytes).await.0.is_err());
+        assert!(read(&v2(2, 0x11, &[0; 12])).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_refused() {
+        assert!(read(b"PROXY TCP4").await.0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.2").await.0.is_err());
+        let bytes = v2(1, 0x11, &[0; 12]);
+        assert!(read(&bytes[..bytes.len() - 1]).await.0.is_err());
+        assert!(read(&bytes[..14]).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_without_a_header_is_refused() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+    }
+}
@@ -0,0 +1,1152 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Cluster;
+use crate::config::{Overflow, ServerConfig};
+use crate::connection;
+use crate::dead_letter::{DeadLetters, Letter};
+use crate::envelope::ServerMessage;
+use crate::filter::{self, Filter};
+use crate::journal::{Journal, ScheduledEntry};
+use crate::push_queue::PushQueue;
+use crate::rt::{self, SystemTime, UNIX_EPOCH};
+use crate::schema::Describe;
+use crate::tenant;
+use crate::timer_wheel::TimerWheel;
+use crate::topic::{self, Trie};
+use crate::{
+    Connection, Context, ErrorCode, Priority, ProtocolError, Request, Response, TypedRequest,
+    ValidationError,
+};
+
+/// How many journaled messages `SubscribeFrom` reads at a time while catching up.
+const REPLAY_BATCH: usize = 256;
+
+/// How finely scheduled messages are timed, and how many ticks the wheel
+/// holding them spans before items wait a whole turn.
+const SCHEDULE_TICK: Duration = Duration::from_millis(10);
+const SCHEDULE_SLOTS: usize = 4096;
+
+/// Which connections are subscribed to which topics, by name or by a
+/// [pattern](crate::topic) matching many.
+#[derive(Clone)]
+pub struct TopicRegistry {
+    topics: Arc<Mutex<Trie<Subscriber>>>,
+    acked: Arc<Mutex<HashMap<SubscriberKey, AckedSubscriber>>>,
+    /// Each subscribing connection's queue of messages on their way to it.
+    queues: Arc<Mutex<HashMap<u64, PushQueue>>>,
+    queue_capacity: usize,
+    overflow: Overflow,
+    journal: Option<Arc<Journal>>,
+    cluster: Option<Arc<Cluster>>,
+    scheduled: Arc<Mutex<Scheduled>>,
+    dead_letters: Arc<DeadLetters>,
+    /// Whether topic names start with their tenant, which pushes leave out.
+    multi_tenant: bool,
+}
+
+/// Messages published for later, and whether a task is publishing them.
+struct Scheduled {
+    wheel: TimerWheel<ScheduledEntry>,
+    next_id: u64,
+    running: bool,
+    /// Past this many waiting, `publish_at` refuses more.
+    max_pending: usize,
+    /// How far ahead `publish_at` takes messages.
+    max_delay: Duration,
+}
+
+/// A connection subscribed to a topic, and what it wants of it.
+#[derive(Clone)]
+struct Subscriber {
+    connection: Connection,
+    filter: Option<Arc<Filter>>,
+}
+
+/// The identity an acked subscriber subscribed under, if any, and its name,
+/// so clients can't take over each other's subscribers.
+pub(crate) type SubscriberKey = (Option<String>, String);
+
+/// A subscriber whose messages are kept until it acks them, whether or not
+/// a connection is attached to it at the moment.
+struct AckedSubscriber {
+    topics: BTreeSet<String>,
+    connection: Option<Connection>,
+    next_id: u64,
+    /// Messages not yet acked, oldest first.
+    unacked: VecDeque<Unacked>,
+    limit: usize,
+    multi_tenant: bool,
+}
+
+struct Unacked {
+    id: u64,
+    topic: String,
+    /// The message in bincode.
+    message: Bytes,
+    /// How many times it has been sent to the subscriber.
+    attempts: u32,
+    /// The reason given with the last `Nack` of it.
+    failure: Option<String>,
+}
+
+impl Unacked {
+    /// Why the message is being given up on after its last attempt.
+    fn reason(&mut self) -> String {
+        self.failure
+            .take()
+            .unwrap_or_else(|| format!("not acked after {} deliveries", self.attempts))
+    }
+}
+
+impl AckedSubscriber {
+    fn message(&self, name: &str, unacked: &Unacked) -> Result<ServerMessage> {
+        Ok(ServerMessage::Push(Box::new(AckedMessage {
+            subscriber: name.to_string(),
+            topic: shown(&unacked.topic, self.multi_tenant).to_string(),
+            id: unacked.id,
+            message: bincode::deserialize(&unacked.message)?,
+        })))
+    }
+
+    /// Sends the subscriber's unacked messages again, if it is attached,
+    /// counting an attempt for each; those with `max_attempts` used up go
+    /// to `dead_letters` instead. Only messages `resend` picks are looked at.
+    fn redeliver(
+        &mut self,
+        key: &SubscriberKey,
+        max_attempts: Option<u32>,
+        dead_letters: &DeadLetters,
+        resend: impl Fn(&Unacked) -> bool,
+    ) -> Vec<ServerMessage> {
+        if self.connection.is_none() {
+            return Vec::new();
+        }
+        let mut redeliveries = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.unacked.len());
+        for mut unacked in std::mem::take(&mut self.unacked) {
+            if !resend(&unacked) {
+                kept.push_back(unacked);
+            } else if max_attempts.is_some_and(|max| unacked.attempts >= max) {
+                let reason = unacked.reason();
+                dead_letters.insert(
+                    key.clone(),
+                    unacked.topic,
+                    unacked.message,
+                    unacked.attempts,
+                    reason,
+                );
+            } else {
+                unacked.attempts += 1;
+                if let Ok(message) = self.message(&key.1, &unacked) {
+                    redeliveries.push(message);
+                }
+                kept.push_back(unacked);
+            }
+        }
+        self.unacked = kept;
+        redeliveries
+    }
+}
+
+impl TopicRegistry {
+    pub(crate) fn new(
+        journal: Option<Arc<Journal>>,
+        cluster: Option<Arc<Cluster>>,
+        config: &ServerConfig,
+        multi_tenant: bool,
+    ) -> Self {
+        Self {
+            topics: Arc::default(),
+            acked: Arc::default(),
+            queues: Arc::default(),
+            queue_capacity: config.subscriber_queue,
+            overflow: config.subscriber_overflow,
+            journal,
+            cluster,
+            scheduled: Arc::new(Mutex::new(Scheduled {
+                wheel: TimerWheel::new(SCHEDULE_TICK, SCHEDULE_SLOTS),
+                next_id: 1,
+                running: false,
+                max_pending: config.max_scheduled_messages,
+                max_delay: config.max_publish_delay,
+            })),
+            dead_letters: Arc::new(DeadLetters::new(config.dead_letter_limit)),
+            multi_tenant,
+        }
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_deref()
+    }
+
+    /// Subscribes `connection` to `topic`, a topic name or a pattern.
+    /// Returns `false` if it was already subscribed to just that, in which
+    /// case any filter it subscribed with is dropped.
+    pub fn subscribe(&self, topic: &str, connection: Connection) -> bool {
+        self.insert(topic, connection, None)
+    }
+
+    /// Subscribes `connection` to just the messages on `topic` that pass
+    /// `filter`, replacing its filter if it was already subscribed. Returns
+    /// `false` if it was.
+    pub fn subscribe_filtered(&self, topic: &str, connection: Connection, filter: Filter) -> bool {
+        self.insert(topic, connection, Some(Arc::new(filter)))
+    }
+
+    fn insert(&self, topic: &str, connection: Connection, filter: Option<Arc<Filter>>) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .insert(topic, connection.id(), Subscriber { connection, filter })
+            .is_none()
+    }
+
+    /// Undoes subscribing to `topic`, the name or pattern subscribed to;
+    /// subscriptions to other patterns matching it stay. Returns `false` if
+    /// the connection wasn't subscribed.
+    pub fn unsubscribe(&self, topic: &str, connection_id: u64) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        topics.remove(topic, connection_id).is_some()
+    }
+
+    pub(crate) fn remove_connection(&self, connection_id: u64) {
+        if let Some(queue) = self.queues.lock().unwrap().remove(&connection_id) {
+            queue.close();
+        }
+        let mut topics = self.topics.lock().unwrap();
+        topics.retain(|subscribers| {
+            subscribers.remove(&connection_id);
+        });
+        drop(topics);
+        // Acked subscribers outlive the connection, keeping messages for the next one.
+        let mut acked = self.acked.lock().unwrap();
+        for subscriber in acked.values_mut() {
+            if subscriber
+                .connection
+                .as_ref()
+                .is_some_and(|connection| connection.id() == connection_id)
+            {
+                subscriber.connection = None;
+            }
+        }
+    }
+
+    /// Moves every subscription of the connection `from`, acked ones
+    /// included, over to `to`.
+    pub(crate) fn transfer(&self, from: u64, to: Connection) {
+        let mut queues = self.queues.lock().unwrap();
+        // A queue that disconnected its client is left behind; the next
+        // message starts a new one.
+        if let Some(queue) = queues.remove(&from)
+            && !queue.is_closed()
+        {
+            queue.reattach(to.clone());
+            queues.insert(to.id(), queue);
+        }
+        drop(queues);
+        let mut topics = self.topics.lock().unwrap();
+        topics.retain(|subscribers| {
+            if let Some(subscriber) = subscribers.remove(&from) {
+                let subscriber = Subscriber {
+                    connection: to.clone(),
+                    ..subscriber
+                };
+                subscribers.insert(to.id(), subscriber);
+            }
+        });
+        drop(topics);
+        let mut acked = self.acked.lock().unwrap();
+        for subscriber in acked.values_mut() {
+            if subscriber
+                .connection
+                .as_ref()
+                .is_some_and(|connection| connection.id() == from)
+            {
+                subscriber.connection = Some(to.clone());
+            }
+        }
+    }
+
+    /// Subscribes the acked subscriber `key` to `topic` and attaches it to
+    /// `connection`, resending everything it hasn't acked. Returns `false`
+    /// if it was already subscribed to `topic`.
+    fn subscribe_acked(
+        &self,
+        key: SubscriberKey,
+        topic: &str,
+        connection: Connection,
+        limit: usize,
+        max_attempts: Option<u32>,
+    ) -> (bool, Vec<ServerMessage>) {
+        let mut acked = self.acked.lock().unwrap();
+        let subscriber = acked.entry(key.clone()).or_insert_with(|| AckedSubscriber {
+            topics: BTreeSet::new(),
+            connection: None,
+            next_id: 1,
+            unacked: VecDeque::new(),
+            limit,
+            multi_tenant: self.multi_tenant,
+        });
+        let newly_subscribed = subscriber.topics.insert(topic.to_string());
+        let reattached = subscriber
+            .connection
+            .as_ref()
+            .is_none_or(|current| current.id() != connection.id());
+        subscriber.connection = Some(connection);
+        if !reattached {
+            return (newly_subscribed, Vec::new());
+        }
+        let redeliveries = subscriber.redeliver(&key, max_attempts, &self.dead_letters, |_| true);
+        (newly_subscribed, redeliveries)
+    }
+
+    /// Forgets the messages `ids` of the acked subscriber `key`, returning how many it had.
+    fn ack(&self, key: &SubscriberKey, ids: &[u64]) -> usize {
+        let mut acked = self.acked.lock().unwrap();
+        let Some(subscriber) = acked.get_mut(key) else {
+            return 0;
+        };
+        let before = subscriber.unacked.len();
+        subscriber
+            .unacked
+            .retain(|unacked| !ids.contains(&unacked.id));
+        before - subscriber.unacked.len()
+    }
+
+    /// Records that the acked subscriber `key` failed to handle the
+    /// messages `ids`, sending them again straight away if it is attached.
+    /// Returns how many it had, and the redeliveries for its connection.
+    fn nack(
+        &self,
+        key: &SubscriberKey,
+        ids: &[u64],
+        reason: &str,
+        max_attempts: Option<u32>,
+    ) -> (usize, Option<(Connection, Vec<ServerMessage>)>) {
+        let mut acked = self.acked.lock().unwrap();
+        let Some(subscriber) = acked.get_mut(key) else {
+            return (0, None);
+        };
+        let mut nacked = 0;
+        for unacked in &mut subscriber.unacked {
+            if ids.contains(&unacked.id) {
+                unacked.failure = Some(reason.to_string());
+                nacked += 1;
+            }
+        }
+        let redeliveries = subscriber.redeliver(key, max_attempts, &self.dead_letters, |unacked| {
+            ids.contains(&unacked.id)
+        });
+        let pushes = subscriber
+            .connection
+            .clone()
+            .map(|connection| (connection, redeliveries));
+        (nacked, pushes)
+    }
+
+    pub(crate) fn dead_letters(&self) -> &DeadLetters {
+        &self.dead_letters
+    }
+
+    /// Hands `letters` back to their subscribers as new messages, returning
+    /// those whose subscriber is gone and the pushes for those attached.
+    pub(crate) fn redrive(
+        &self,
+        letters: Vec<Letter>,
+    ) -> (Vec<Letter>, Vec<(Connection, ServerMessage)>) {
+        let mut acked = self.acked.lock().unwrap();
+        let mut stranded = Vec::new();
+        let mut pushes = Vec::new();
+        for letter in letters {
+            let Some(subscriber) = acked.get_mut(&letter.key) else {
+                stranded.push(letter);
+                continue;
+            };
+            let unacked = Unacked {
+                id: subscriber.next_id,
+                topic: letter.topic,
+                message: letter.message,
+                attempts: 0,
+                failure: None,
+            };
+            subscriber.next_id += 1;
+            if let Some(connection) = subscriber.connection.clone()
+                && let Ok(push) = subscriber.message(&letter.key.1, &unacked)
+            {
+                pushes.push((connection, push));
+            }
+            subscriber.unacked.push_back(Unacked {
+                attempts: u32::from(subscriber.connection.is_some()),
+                ..unacked
+            });
+        }
+        (stranded, pushes)
+    }
+
+    /// Unsubscribes acked subscribers attached to `connection_id` from
+    /// `topic`, forgetting any left with no topics. Returns whether there were any.
+    fn unsubscribe_acked(&self, topic: &str, connection_id: u64) -> bool {
+        let mut acked = self.acked.lock().unwrap();
+        let mut removed = false;
+        acked.retain(|_, subscriber| {
+            let attached = subscriber
+                .connection
+                .as_ref()
+                .is_some_and(|connection| connection.id() == connection_id);
+            if attached && subscriber.topics.remove(topic) {
+                removed = true;
+            }
+            !subscriber.topics.is_empty()
+        });
+        removed
+    }
+
+    /// The topics and patterns `connection_id` is subscribed to, each with
+    /// the filter it subscribed with; acked subscribers aren't included.
+    pub(crate) fn subscriptions(&self, connection_id: u64) -> Vec<(String, Option<Filter>)> {
+        let topics = self.topics.lock().unwrap();
+        topics
+            .patterns_of(connection_id)
+            .into_iter()
+            .map(|(topic, subscriber)| (topic, subscriber.filter.as_deref().cloned()))
+            .collect()
+    }
+
+    /// How many connections are subscribed to exactly `topic`, a name or a
+    /// pattern, leaving out those subscribed through other patterns.
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        let topics = self.topics.lock().unwrap();
+        topics.get(topic).map_or(0, HashMap::len)
+    }
+
+    /// Pushes `message` to every subscriber of `topic`, and of every pattern
+    /// matching it, as a [`TopicMessage`], returning how many connections it
+    /// was queued for; a connection matching more than once gets one copy.
+    /// Fails if `topic` is a pattern. With a journal, the
+    /// message is appended to it first, subscribers or not. In a cluster it
+    /// is published on every node this one can reach too.
+    pub async fn publish(&self, topic: &str, message: Box<dyn Response>) -> Result<usize> {
+        let Some(cluster) = &self.cluster else {
+            return self.publish_local(topic, message).await;
+        };
+        let encoded = bincode::serialize(&message)?;
+        let delivered = self.publish_local(topic, message).await?;
+        Ok(delivered + cluster.publish(topic, &encoded).await?)
+    }
+
+    /// Publishes `message` on `topic` as `publish` does once `at` comes,
+    /// returning an id for it; a time already past publishes it on the next
+    /// tick. With a journal, it is kept there until then, so it still goes
+    /// out if the server restarts in the meantime. Fails with `Overloaded`
+    /// once `max_scheduled_messages` are waiting, and with `InvalidRequest`
+    /// for a time further off than `max_publish_delay`.
+    pub async fn publish_at(
+        &self,
+        topic: &str,
+        message: Box<dyn Response>,
+        at: SystemTime,
+    ) -> Result<u64> {
+        topic::check_name(topic).map_err(ProtocolError::from)?;
+        let id = {
+            let mut scheduled = self.scheduled.lock().unwrap();
+            let ahead = at.duration_since(SystemTime::now()).unwrap_or_default();
+            if ahead > scheduled.max_delay {
+                return Err(ProtocolError::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "messages can be scheduled at most {:?} ahead",
+                        scheduled.max_delay
+                    ),
+                )
+                .into());
+            }
+            if scheduled.wheel.len() >= scheduled.max_pending {
+                return Err(ProtocolError::new(
+                    ErrorCode::Overloaded,
+                    "too many messages are scheduled",
+                )
+                .into());
+            }
+            scheduled.next_id += 1;
+            scheduled.next_id - 1
+        };
+        let entry = ScheduledEntry {
+            id,
+            topic: topic.to_string(),
+            deliver_at_us: at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_micros() as u64),
+            message: Bytes::from(bincode::serialize(&message)?),
+        };
+        if let Some(journal) = &self.journal {
+            journal.schedule(entry.clone()).await?;
+        }
+        self.insert_scheduled(&mut self.scheduled.lock().unwrap(), entry);
+        Ok(id)
+    }
+
+    /// How many messages are waiting for their time to be published.
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled.lock().unwrap().wheel.len()
+    }
+
+    /// Schedules again the messages the journal had waiting when it was
+    /// opened.
+    pub(crate) fn resume_scheduled(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let entries = journal.take_scheduled();
+        if entries.is_empty() {
+            return;
+        }
+        tracing::info!(scheduled = entries.len(), "Resuming scheduled messages");
+        let mut scheduled = self.scheduled.lock().unwrap();
+        for entry in entries {
+            scheduled.next_id = scheduled.next_id.max(entry.id + 1);
+            self.insert_scheduled(&mut scheduled, entry);
+        }
+    }
+
+    fn insert_scheduled(&self, scheduled: &mut Scheduled, entry: ScheduledEntry) {
+        let at = UNIX_EPOCH + Duration::from_micros(entry.deliver_at_us);
+        let ahead = at.duration_since(SystemTime::now()).unwrap_or_default();
+        scheduled.wheel.insert(Instant::now() + ahead, entry);
+        if !scheduled.running {
+            scheduled.running = true;
+            rt::spawn(self.clone().publish_scheduled());
+        }
+    }
+
+    /// Turns the wheel of scheduled messages, publishing each as its tick
+    /// comes, until none are left.
+    async fn publish_scheduled(self) {
+        loop {
+            let next = {
+                let mut scheduled = self.scheduled.lock().unwrap();
+                if scheduled.wheel.is_empty() {
+                    scheduled.running = false;
+                    return;
+                }
+                scheduled.wheel.next_tick()
+            };
+            rt::sleep(next.saturating_duration_since(Instant::now())).await;
+            let due = self.scheduled.lock().unwrap().wheel.advance();
+            for entry in due {
+                if let Err(e) = self.publish_entry(&entry).await {
+                    tracing::warn!(
+                        topic = entry.topic,
+                        id = entry.id,
+                        error = format!("{e:#}"),
+                        "Failed to publish scheduled message"
+                    );
+                }
+            }
+        }
+    }
+
+    async fn publish_entry(&self, entry: &ScheduledEntry) -> Result<()> {
+        let message: Box<dyn Response> = bincode::deserialize(&entry.message)?;
+        self.publish(&entry.topic, message).await?;
+        if let Some(journal) = &self.journal {
+            journal.published(entry.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Publishes `message` to this server's subscribers only.
+    pub(crate) async fn publish_local(
+        &self,
+        topic: &str,
+        message: Box<dyn Response>,
+    ) -> Result<usize> {
+        topic::check_name(topic).map_err(ProtocolError::from)?;
+        // Journaled before looking up subscribers, so one subscribing in the
+        // meantime finds the message in the journal if it misses it live.
+        let offset = match &self.journal {
+            Some(journal) => {
+                let encoded = Bytes::from(bincode::serialize(&message)?);
+                Some(journal.append(topic, encoded).await?)
+            }
+            None => None,
+        };
+        let subscribers: Vec<Subscriber> = {
+            let topics = self.topics.lock().unwrap();
+            topics.matching(topic).into_iter().cloned().collect()
+        };
+        let subscribers = passing(subscribers, &*message);
+
+        let acked = self.queue_acked(topic, &*message)?;
+        let push = ServerMessage::Push(Box::new(TopicMessage {
+            topic: shown(topic, self.multi_tenant).to_string(),
+            offset,
+            message,
+        }));
+        let mut delivered = 0;
+        for (connection, bytes) in connection::encode_each(subscribers, &push)? {
+            if self.queue(&connection).offer(bytes) {
+                delivered += 1;
+            }
+        }
+        for (connection, message) in acked {
+            if self.queue(&connection).offer(connection.encode(&message)?) {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// The queue of messages on their way to `connection`.
+    fn queue(&self, connection: &Connection) -> PushQueue {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(connection.id()).or_insert_with(|| {
+            PushQueue::new(connection.clone(), self.queue_capacity, self.overflow)
+        });
+        // One that disconnected its client stays closed.
+        queue.clone()
+    }
+
+    /// Keeps `message` for every acked subscriber of `topic`, returning the
+    /// pushes for those with a connection attached.
+    fn queue_acked(
+        &self,
+        topic: &str,
+        message: &dyn Response,
+    ) -> Result<Vec<(Connection, ServerMessage)>> {
+        let mut acked = self.acked.lock().unwrap();
+        let mut subscribers = acked
+            .iter_mut()
+            .filter(|(_, subscriber)| {
+                subscriber
+                    .topics
+                    .iter()
+                    .any(|pattern| topic::matches(pattern, topic))
+            })
+            .peekable();
+        if subscribers.peek().is_none() {
+            return Ok(Vec::new());
+        }
+        let encoded = Bytes::from(bincode::serialize(message)?);
+
+        let mut pushes = Vec::new();
+        for (key, subscriber) in subscribers {
+            if subscriber.unacked.len() >= subscriber.limit
+                && let Some(mut dropped) = subscriber.unacked.pop_front()
+            {
+                dropped
+                    .failure
+                    .get_or_insert_with(|| "unacked buffer full".to_string());
+                let reason = dropped.reason();
+                self.dead_letters.insert(
+                    key.clone(),
+                    dropped.topic,
+                    dropped.message,
+                    dropped.attempts,
+                    reason,
+                );
+            }
+            let unacked = Unacked {
+                id: subscriber.next_id,
+                topic: topic.to_string(),
+                message: encoded.clone(),
+                attempts: u32::from(subscriber.connection.is_some()),
+                failure: None,
+            };
+            subscriber.next_id += 1;
+            if let Some(connection) = &subscriber.connection {
+                let push = subscriber.message(&key.1, &unacked)?;
+                pushes.push((connection.clone(), push));
+            }
+            subscriber.unacked.push_back(unacked);
+        }
+        Ok(pushes)
+    }
+}
+
+/// `topic` as its subscribers know it, without the tenant in multi-tenant mode.
+pub(crate) fn shown(topic: &str, multi_tenant: bool) -> &str {
+    if multi_tenant {
+        tenant::unscoped(topic)
+    } else {
+        topic
+    }
+}
+
+/// The connections of `subscribers` whose filters, if any, `message` passes,
+/// each once however many of its subscriptions it passes.
+fn passing(subscribers: Vec<Subscriber>, message: &dyn Response) -> Vec<Connection> {
+    let mut seen = HashSet::new();
+    let filtered = subscribers
+        .iter()
+        .any(|subscriber| subscriber.filter.is_some());
+    // Serialized once for every filter to look at.
+    let value = if filtered {
+        serde_json::to_value(message).unwrap_or_default()
+    } else {
+        serde_json::Value::Null
+    };
+    let fields = filter::fields(&value);
+    subscribers
+        .into_iter()
+        .filter(|subscriber| {
+            subscriber
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches_fields(fields))
+        })
+        .filter(|subscriber| seen.insert(subscriber.connection.id()))
+        .map(|subscriber| subscriber.connection)
+        .collect()
+}
+
+/// What subscribers receive on their notifications stream.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct TopicMessage {
+    pub topic: String,
+    /// The message's place in the server's journal, if it has one.
+    pub offset: Option<u64>,
+    pub message: Box<dyn Response>,
+}
+
+#[typetag::serde]
+impl Response for TopicMessage {}
+
+/// What acked subscribers receive on their notifications stream instead of
+/// a `TopicMessage`. It is sent again each time the subscriber resubscribes
+/// or nacks it until it is acked, so the same `id` may arrive more than
+/// once; past the server's `max_delivery_attempts` it goes to its
+/// [dead letters](crate::dead_letter) instead.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct AckedMessage {
+    pub subscriber: String,
+    pub topic: String,
+    /// Counts up from 1 for each subscriber.
+    pub id: u64,
+    pub message: Box<dyn Response>,
+}
+
+impl AckedMessage {
+    /// The request acking just this message.
+    pub fn ack(&self) -> Ack {
+        Ack {
+            subscriber: self.subscriber.clone(),
+            ids: vec![self.id],
+        }
+    }
+
+    /// The request reporting that handling just this message failed.
+    pub fn nack(&self, reason: impl Into<String>) -> Nack {
+        Nack {
+            subscriber: self.subscriber.clone(),
+            ids: vec![self.id],
+            reason: reason.into(),
+        }
+    }
+}
+
+#[typetag::serde]
+impl Response for AckedMessage {}
+
+/// Subscribes to `topic`, a topic name or a [pattern](crate::topic) such as
+/// `sensors/+/temp` or `sensors/#`.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SubscribeResponse)]
+pub struct Subscribe {
+    pub topic: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct SubscribeResponse {
+    pub topic: String,
+    pub newly_subscribed: bool,
+}
+
+#[typetag::serde]
+impl Response for SubscribeResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Subscribe {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let topic = ctx.scoped(&self.topic)?;
+        let newly_subscribed = ctx.topics().subscribe(&topic, ctx.connection().clone());
+        Ok(Box::new(SubscribeResponse {
+            topic: self.topic.clone(),
+            newly_subscribed,
+        }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_pattern(&self.topic)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for Subscribe {
+    type Response = SubscribeResponse;
+}
+
+/// Subscribes to just the messages on `topic` that pass `filter`, checked
+/// on the server so the rest are never sent. Subscribing again, filtered
+/// or not, replaces the filter.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SubscribeResponse)]
+pub struct SubscribeFiltered {
+    pub topic: String,
+    pub filter: Filter,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for SubscribeFiltered {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let topic = ctx.scoped(&self.topic)?;
+        let newly_subscribed =
+            ctx.topics()
+                .subscribe_filtered(&topic, ctx.connection().clone(), self.filter.clone());
+        Ok(Box::new(SubscribeResponse {
+            topic: self.topic.clone(),
+            newly_subscribed,
+        }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_pattern(&self.topic)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for SubscribeFiltered {
+    type Response = SubscribeResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = UnsubscribeResponse)]
+pub struct Unsubscribe {
+    pub topic: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct UnsubscribeResponse {
+    pub topic: String,
+    pub was_subscribed: bool,
+}
+
+#[typetag::serde]
+impl Response for UnsubscribeResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Unsubscribe {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let topic = ctx.scoped(&self.topic)?;
+        let topics = ctx.topics();
+        let was_subscribed = topics.unsubscribe(&topic, ctx.connection_id)
+            | topics.unsubscribe_acked(&topic, ctx.connection_id);
+        Ok(Box::new(UnsubscribeResponse {
+            topic: self.topic.clone(),
+            was_subscribed,
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for Unsubscribe {
+    type Response = UnsubscribeResponse;
+}
+
+/// Subscribes to `topic` with delivery guaranteed at least once: messages
+/// arrive as [`AckedMessage`]s and are kept, up to the server's
+/// `unacked_push_limit`, until acked with [`Ack`]. Subscribing again under
+/// the same `subscriber` name, say after reconnecting, picks up where the
+/// last connection left off and resends what it didn't ack. `Unsubscribe`
+/// on the connection ends it.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SubscribeResponse)]
+pub struct SubscribeAcked {
+    pub topic: String,
+    /// Names the subscriber for as long as it stays subscribed to any topic.
+    pub subscriber: String,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for SubscribeAcked {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let key = subscriber_key(ctx, &self.subscriber);
+        let topic = ctx.scoped(&self.topic)?;
+        let config = ctx.server().config();
+        let (newly_subscribed, redeliveries) = ctx.topics().subscribe_acked(
+            key,
+            &topic,
+            ctx.connection().clone(),
+            config.unacked_push_limit,
+            config.max_delivery_attempts,
+        );
+        for message in &redeliveries {
+            ctx.connection().send_message(message).await?;
+        }
+        Ok(Box::new(SubscribeResponse {
+            topic: self.topic.clone(),
+            newly_subscribed,
+        }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_pattern(&self.topic)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for SubscribeAcked {
+    type Response = SubscribeResponse;
+}
+
+/// Tells the server the acked subscriber `subscriber` has handled the
+/// messages `ids`, so they aren't sent again.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = AckResponse)]
+pub struct Ack {
+    pub subscriber: String,
+    pub ids: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct AckResponse {
+    /// How many of the ids were still waiting to be acked.
+    pub acked: usize,
+}
+
+#[typetag::serde]
+impl Response for AckResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Ack {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let key = subscriber_key(ctx, &self.subscriber);
+        let acked = ctx.topics().ack(&key, &self.ids);
+        Ok(Box::new(AckResponse { acked }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+}
+
+impl TypedRequest for Ack {
+    type Response = AckResponse;
+}
+
+/// Tells the server the acked subscriber `subscriber` failed to handle the
+/// messages `ids`, for `reason`. They are sent again straight away, or go
+/// to its dead letters, with `reason`, if that was their last attempt.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = NackResponse)]
+pub struct Nack {
+    pub subscriber: String,
+    pub ids: Vec<u64>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct NackResponse {
+    /// How many of the ids were still waiting to be acked.
+    pub nacked: usize,
+}
+
+#[typetag::serde]
+impl Response for NackResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Nack {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let key = subscriber_key(ctx, &self.subscriber);
+        let max_attempts = ctx.server().config().max_delivery_attempts;
+        let (nacked, redeliveries) = ctx
+            .topics()
+            .nack(&key, &self.ids, &self.reason, max_attempts);
+        if let Some((connection, messages)) = redeliveries {
+            for message in &messages {
+                connection.send_message(message).await?;
+            }
+        }
+        Ok(Box::new(NackResponse { nacked }))
+    }
+}
+
+impl TypedRequest for Nack {
+    type Response = NackResponse;
+}
+
+/// Subscribes to `topic` like `Subscribe`, first resending every message
+/// in the server's journal on it with an offset after `after`, so a
+/// subscriber that remembers the last offset it saw misses nothing while
+/// away. Pass 0 to replay the whole journal. A message published while
+/// catching up can arrive twice; its offset tells the copies apart. Fails
+/// with `Unsupported` if the server keeps no journal. The journal is kept
+/// by topic name, so `topic` can't be a pattern.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SubscribeResponse)]
+pub struct SubscribeFrom {
+    pub topic: String,
+    pub after: u64,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for SubscribeFrom {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let topics = ctx.topics();
+        let Some(journal) = topics.journal() else {
+            return Err(
+                ProtocolError::new(ErrorCode::Unsupported, "the server keeps no journal").into(),
+            );
+        };
+        let topic = ctx.scoped(&self.topic)?;
+        let newly_subscribed = topics.subscribe(&topic, ctx.connection().clone());
+        // Everything from here on reaches the connection live.
+        let until = journal.next_offset();
+        let mut after = self.after;
+        loop {
+            let batch = journal.read(&topic, after, until, REPLAY_BATCH).await?;
+            let Some((last, _)) = batch.last() else {
+                break;
+            };
+            after = *last;
+            for (offset, message) in batch {
+                let push = ServerMessage::Push(Box::new(TopicMessage {
+                    topic: self.topic.clone(),
+                    offset: Some(offset),
+                    message: bincode::deserialize(&message)?,
+                }));
+                ctx.connection().send_message(&push).await?;
+            }
+        }
+        Ok(Box::new(SubscribeResponse {
+            topic: self.topic.clone(),
+            newly_subscribed,
+        }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_name(&self.topic)
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for SubscribeFrom {
+    type Response = SubscribeResponse;
+}
+
+fn subscriber_key(ctx: &Context, subscriber: &str) -> SubscriberKey {
+    (
+        ctx.identity().map(|identity| identity.key().into_owned()),
+        subscriber.to_string(),
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = PublishResponse)]
+pub struct Publish {
+    pub topic: String,
+    pub message: Box<dyn Response>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct PublishResponse {
+    pub delivered: usize,
+}
+
+#[typetag::serde]
+impl Response for PublishResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Publish {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        // The message has to be owned to be re-encoded, and trait objects
+        // can't be cloned, so round-trip it through its wire form.
+        let message: Box<dyn Response> = bincode::deserialize(&bincode::serialize(&self.message)?)?;
+        let delivered = ctx
+            .topics()
+            .publish(&ctx.scoped(&self.topic)?, message)
+            .await?;
+        Ok(Box::new(PublishResponse { delivered }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_name(&self.topic)
+    }
+}
+
+impl TypedRequest for Publish {
+    type Response = PublishResponse;
+}
+
+/// Publishes `message` on `topic` once the server's clock reaches
+/// `deliver_at_us`, in microseconds since the Unix epoch as
+/// `ServerTimeResponse::unix_time_us` gives it.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = PublishAtResponse)]
+pub struct PublishAt {
+    pub topic: String,
+    pub message: Box<dyn Response>,
+    pub deliver_at_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct PublishAtResponse {
+    /// Tells scheduled messages apart in the server's logs.
+    pub id: u64,
+}
+
+#[typetag::serde]
+impl Response for PublishAtResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for PublishAt {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let message: Box<dyn Response> = bincode::deserialize(&bincode::serialize(&self.message)?)?;
+        let at = UNIX_EPOCH + Duration::from_micros(self.deliver_at_us);
+        let id = ctx
+            .topics()
+            .publish_at(&ctx.scoped(&self.topic)?, message, at)
+            .await?;
+        Ok(Box::new(PublishAtResponse { id }))
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        topic::check_name(&self.topic)
+    }
+}
+
+impl TypedRequest for PublishAt {
+    type Response = PublishAtResponse;
+}
@@ -0,0 +1,130 @@
+//! The bounded queue between publishers and one subscribing connection.
+//!
+//! Publishing only ever adds to a subscriber's queue, applying its
+//! [`Overflow`] policy when the queue is full, and a task per queue moves
+//! messages on into the connection's outbound queue as it has room. A
+//! client that stops reading costs at most `subscriber_queue` messages, and
+//! publishers never wait for it.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::config::Overflow;
+use crate::frame::Encoded;
+use crate::{Connection, metrics, rt};
+
+#[derive(Clone)]
+pub(crate) struct PushQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    ready: Notify,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+struct State {
+    queued: VecDeque<Encoded>,
+    /// Where messages go, swapped when a session is parked or resumed.
+    connection: Connection,
+    closed: bool,
+}
+
+impl PushQueue {
+    pub(crate) fn new(connection: Connection, capacity: usize, overflow: Overflow) -> Self {
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                queued: VecDeque::new(),
+                connection,
+                closed: false,
+            }),
+            ready: Notify::new(),
+            capacity: capacity.max(1),
+            overflow,
+        });
+        rt::spawn(forward(inner.clone()));
+        Self { inner }
+    }
+
+    /// Queues an encoded message, returning `false` if it was dropped
+    /// instead.
+    pub(crate) fn offer(&self, bytes: Encoded) -> bool {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.queued.len() >= inner.capacity {
+            metrics::push_dropped(inner.overflow.as_str());
+            let connection_id = state.connection.id();
+            match inner.overflow {
+                Overflow::DropOldest => {
+                    tracing::debug!(connection_id, "Dropping oldest push: subscriber queue full");
+                    state.queued.pop_front();
+                }
+                Overflow::DropNew => {
+                    tracing::debug!(connection_id, "Dropping push: subscriber queue full");
+                    return false;
+                }
+                Overflow::Disconnect => {
+                    tracing::warn!(connection_id, "Disconnecting slow subscriber: queue full");
+                    state.connection.close();
+                    state.queued.clear();
+                    state.closed = true;
+                    drop(state);
+                    inner.ready.notify_one();
+                    return false;
+                }
+            }
+        }
+        state.queued.push_back(bytes);
+        drop(state);
+        inner.ready.notify_one();
+        true
+    }
+
+    /// Sends what is queued, and what is queued from now on, to `connection` instead.
+    pub(crate) fn reattach(&self, connection: Connection) {
+        self.inner.state.lock().unwrap().connection = connection;
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().closed
+    }
+
+    /// Drops what is queued and stops forwarding.
+    pub(crate) fn close(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.queued.clear();
+        state.closed = true;
+        drop(state);
+        self.inner.ready.notify_one();
+    }
+}
+
+async fn forward(inner: Arc<Inner>) {
+    loop {
+        let next = {
+            let mut state = inner.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            state
+                .queued
+                .pop_front()
+                .map(|bytes| (bytes, state.connection.clone()))
+        };
+        match next {
+            // Waits on the connection's own `SlowClient` policy; the
+            // subscriber's queue fills up meanwhile.
+            Some((bytes, connection)) => {
+                let _ = connection.push_encoded(bytes).await;
+            }
+            None => inner.ready.notified().await,
+        }
+    }
+}
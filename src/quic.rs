@@ -0,0 +1,205 @@
+//! The protocol over QUIC, so calls don't queue behind one another when
+//! packets are lost on the way.
+//!
+//! Every bidirectional QUIC stream carries a session of its own, handshake
+//! and all, while the QUIC connection does the encryption and loss recovery.
+//! [`QuicClient`] keeps a stream per call in flight, reusing each once its
+//! call returns, so a lost packet only holds up the call whose stream it was
+//! on. Build the [`quinn::Endpoint`]s with TLS set up as usual, advertising
+//! [`ALPN`], or have [`server_config`] build the server's from PEM files.
+//!
+//! [`reload_certificate`] hands an endpoint a renewed certificate and key,
+//! e.g. from a `ServerBuilder::reload` hook run by SIGHUP or the admin
+//! socket, so short-lived certificates are rotated without a restart.
+//!
+//! The server's `max_connections` doesn't apply to QUIC clients; limit them
+//! with the endpoint's `TransportConfig::max_concurrent_bidi_streams`.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context as _, Result};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls;
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::{Connection, Endpoint, Incoming, RecvStream, SendStream};
+use tokio::io::Join;
+use tokio::task::JoinSet;
+
+use crate::client::downcast_response;
+use crate::supervisor::Connections;
+use crate::{Client, ClientConfig, Request, Response, Server, TypedRequest};
+
+/// The ALPN protocol name for the protocol over QUIC.
+pub const ALPN: &[u8] = b"myproto";
+
+/// One QUIC stream, as the byte stream a session runs over.
+pub type QuicStream = Join<RecvStream, SendStream>;
+
+/// Serves QUIC clients arriving at `endpoint` until `shutdown` resolves or
+/// `server` starts shutting down, then drains their sessions as
+/// `Server::serve` does.
+pub async fn serve(
+    server: Server,
+    endpoint: Endpoint,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let name = format!("quic:{}", endpoint.local_addr()?);
+    let server = server.child();
+    let mut connections = Connections::new(&name);
+    tokio::pin!(shutdown);
+    tracing::info!(listener = %name, "Listening");
+
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else { break };
+                let addr = incoming.remote_address();
+                if !server.permits(addr) {
+                    incoming.refuse();
+                    continue;
+                }
+                connections.spawn(addr, serve_connection(server.clone(), incoming, addr));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+            _ = server.shutdown.cancelled() => break,
+        }
+    }
+
+    server.shutdown.cancel();
+    tracing::info!(listener = %name, active = connections.len(), "Draining connections");
+    connections.drain(server.config().drain_timeout).await;
+    endpoint.close(0u32.into(), b"shutting down");
+    Ok(())
+}
+
+/// A server config presenting the certificate chain in the PEM file `cert`
+/// with the private key in `key`, advertising [`ALPN`].
+pub fn server_config(cert: &Path, key: &Path) -> Result<quinn::ServerConfig> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("failed to read a private key from {}", key.display()))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(chain, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(tls)?,
+    )))
+}
+
+/// Rereads the certificate chain and key from `cert` and `key`, as
+/// [`server_config`] does, and presents them to clients connecting to
+/// `endpoint` from now on; connections already up keep the certificate they
+/// were shown. When the files don't load, the endpoint keeps the one it has.
+pub fn reload_certificate(endpoint: &Endpoint, cert: &Path, key: &Path) -> Result<()> {
+    endpoint.set_server_config(Some(server_config(cert, key)?));
+    tracing::info!(cert = %cert.display(), "QUIC certificate reloaded");
+    Ok(())
+}
+
+/// Runs a session on every stream the client opens until it disconnects or
+/// the server shuts down.
+async fn serve_connection(server: Server, incoming: Incoming, addr: SocketAddr) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::warn!(%addr, error = %e, "QUIC handshake failed");
+            return;
+        }
+    };
+    tracing::info!(%addr, "Client connected over QUIC");
+
+    let mut sessions = JoinSet::new();
+    loop {
+        tokio::select! {
+            stream = connection.accept_bi() => {
+                let Ok((send, recv)) = stream else { break };
+                let server = server.clone();
+                sessions.spawn(async move {
+                    if let Err(e) = server.handle_client(tokio::io::join(recv, send), addr).await {
+                        tracing::error!(%addr, error = %e, "Error handling client");
+                    }
+                });
+            }
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            _ = server.shutdown.cancelled() => break,
+        }
+    }
+    while sessions.join_next().await.is_some() {}
+    connection.close(0u32.into(), b"");
+}
+
+/// A client calling over one QUIC connection, on a stream per call in flight.
+#[derive(Clone)]
+pub struct QuicClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connection: Connection,
+    config: ClientConfig,
+    /// Sessions whose calls have returned, ready for the next.
+    idle: Mutex<Vec<Client>>,
+}
+
+impl QuicClient {
+    /// Connects `endpoint` to the server at `addr`, whose certificate must be
+    /// valid for `server_name`.
+    pub async fn connect(
+        endpoint: &Endpoint,
+        addr: SocketAddr,
+        server_name: &str,
+        config: ClientConfig,
+    ) -> Result<Self> {
+        let connection = endpoint
+            .connect(addr, server_name)?
+            .await
+            .with_context(|| format!("failed to connect to {addr} over QUIC"))?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                connection,
+                config,
+                idle: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// A session on a stream of its own, for subscriptions, streaming calls
+    /// and uploads, or anything else that outlives a call.
+    pub async fn session(&self) -> Result<Client> {
+        let (send, recv) = self.inner.connection.open_bi().await?;
+        Client::with_config(tokio::io::join(recv, send), self.inner.config.clone()).await
+    }
+
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        let idle = self.inner.idle.lock().unwrap().pop();
+        let client = match idle {
+            Some(client) if !client.is_closed() => client,
+            _ => self.session().await?,
+        };
+        let response = client.call(request).await;
+        if !client.is_closed() {
+            self.inner.idle.lock().unwrap().push(client);
+        }
+        response
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response(response)
+    }
+
+    /// The underlying QUIC connection, for its statistics or to close it.
+    pub fn connection(&self) -> &Connection {
+        &self.inner.connection
+    }
+}
@@ -0,0 +1,221 @@
+//! Usage quotas per authenticated identity, counted in fixed windows: a
+//! number of requests each minute and of bytes each day, in both directions.
+//! On a multi-tenant server identities are counted within their tenant.
+//!
+//! Counts live in a [`QuotaStore`], in memory unless the server is given
+//! another, such as one shared by every server in a cluster. Connections
+//! without an identity are never charged; the rate limit still covers them.
+//! Over the HTTP gateway only request bodies count towards the bytes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::metrics;
+use crate::{ErrorCode, Identity, ProtocolError};
+
+/// How much one identity may use; a missing limit isn't enforced.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    pub requests_per_minute: Option<u64>,
+    /// Request and response bytes together, as they crossed the wire.
+    pub bytes_per_day: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Counter {
+    Requests,
+    Bytes,
+}
+
+impl Counter {
+    pub fn window(self) -> Duration {
+        match self {
+            Counter::Requests => Duration::from_secs(60),
+            Counter::Bytes => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Counter::Requests => "requests",
+            Counter::Bytes => "bytes",
+        }
+    }
+}
+
+/// Where usage is counted. `window` numbers the counter's windows since the
+/// Unix epoch, so a store only ever needs the current one.
+#[async_trait]
+pub trait QuotaStore: Send + Sync + 'static {
+    /// Adds `amount` to `subject`'s usage in `window` and returns the total.
+    async fn add(&self, subject: &str, counter: Counter, window: u64, amount: u64) -> Result<u64>;
+}
+
+/// Counts usage in this process only.
+#[derive(Default)]
+pub struct MemoryQuotaStore {
+    usage: Mutex<HashMap<(String, Counter), (u64, u64)>>,
+}
+
+impl MemoryQuotaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn add(&self, subject: &str, counter: Counter, window: u64, amount: u64) -> Result<u64> {
+        let mut usage = self.usage.lock().unwrap();
+        let (current, total) = usage
+            .entry((subject.to_string(), counter))
+            .or_insert((window, 0));
+        if *current != window {
+            *current = window;
+            *total = 0;
+        }
+        *total = total.saturating_add(amount);
+        Ok(*total)
+    }
+}
+
+/// The quota each identity gets and the store its usage is counted in.
+pub struct Quotas {
+    default: Quota,
+    subjects: HashMap<String, Quota>,
+    tenants: HashMap<String, Quota>,
+    store: Box<dyn QuotaStore>,
+}
+
+impl Quotas {
+    /// Gives every identity `default`, counted in a `MemoryQuotaStore`.
+    pub fn new(default: Quota) -> Self {
+        Self {
+            default,
+            subjects: HashMap::new(),
+            tenants: HashMap::new(),
+            store: Box::new(MemoryQuotaStore::new()),
+        }
+    }
+
+    /// Gives the identity whose subject is `subject` its own quota instead of
+    /// the default. On a multi-tenant server, name it `tenant/subject`.
+    pub fn subject(mut self, subject: impl Into<String>, quota: Quota) -> Self {
+        self.subjects.insert(subject.into(), quota);
+        self
+    }
+
+    /// Gives each identity of `tenant` without a quota of its own `quota`
+    /// instead of the default; each is still counted on its own.
+    pub fn tenant(mut self, tenant: impl Into<String>, quota: Quota) -> Self {
+        self.tenants.insert(tenant.into(), quota);
+        self
+    }
+
+    pub fn store(mut self, store: impl QuotaStore) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    pub fn quota(&self, identity: &Identity) -> Quota {
+        let tenant = || {
+            identity
+                .tenant
+                .as_ref()
+                .and_then(|tenant| self.tenants.get(tenant))
+        };
+        self.subjects
+            .get(identity.key().as_ref())
+            .or_else(tenant)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Charges `requests` requests of `bytes` bytes to `identity`, failing
+    /// with `QuotaExceeded` if either takes it past its quota. A store that
+    /// fails lets the requests through.
+    pub(crate) async fn charge(
+        &self,
+        identity: Option<&Identity>,
+        requests: usize,
+        bytes: usize,
+    ) -> Result<(), ProtocolError> {
+        let Some(identity) = identity else {
+            return Ok(());
+        };
+        let quota = self.quota(identity);
+        let charges = [
+            (Counter::Requests, requests, quota.requests_per_minute),
+            (Counter::Bytes, bytes, quota.bytes_per_day),
+        ];
+        for (counter, amount, limit) in charges {
+            if let Err(err) = self.add(identity, counter, amount, limit).await {
+                tracing::debug!(
+                    subject = identity.subject,
+                    tenant = identity.tenant,
+                    quota = counter.as_str(),
+                    "Quota exceeded"
+                );
+                metrics::quota_exceeded(counter.as_str(), identity.tenant.as_deref());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts bytes sent in reply to requests already let through.
+    pub(crate) async fn record_bytes(&self, identity: Option<&Identity>, bytes: usize) {
+        if let Some(identity) = identity {
+            let limit = self.quota(identity).bytes_per_day;
+            let _ = self.add(identity, Counter::Bytes, bytes, limit).await;
+        }
+    }
+
+    async fn add(
+        &self,
+        identity: &Identity,
+        counter: Counter,
+        amount: usize,
+        limit: Option<u64>,
+    ) -> Result<(), ProtocolError> {
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        if amount == 0 {
+            return Ok(());
+        }
+        let window_secs = counter.window().as_secs();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let window = now / window_secs;
+        let total = match self
+            .store
+            .add(&identity.key(), counter, window, amount as u64)
+            .await
+        {
+            Ok(total) => total,
+            Err(e) => {
+                tracing::warn!(error = %format!("{e:#}"), "Quota store failed; not enforcing quotas");
+                return Ok(());
+            }
+        };
+        if total <= limit {
+            return Ok(());
+        }
+
+        let resets_in = Duration::from_secs((window + 1) * window_secs - now);
+        Err(ProtocolError::new(
+            ErrorCode::QuotaExceeded,
+            format!("{} quota of {limit} exceeded", counter.as_str()),
+        )
+        .with_details(format!("resets in {resets_in:?}")))
+    }
+}
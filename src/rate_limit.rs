@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::Identity;
+
+/// Buckets are pruned once the table grows past this many peers, at most
+/// once per refill period and never more often than `MIN_PRUNE_INTERVAL`.
+const PRUNE_THRESHOLD: usize = 4096;
+
+const MIN_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket allowing `burst` requests at once, refilled at `per_second`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Whose budget a request is charged to: the authenticated identity, within
+/// its tenant, if there is one, otherwise the peer's IP address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum RateKey {
+    Identity(String),
+    Ip(IpAddr),
+}
+
+impl RateKey {
+    pub(crate) fn new(identity: Option<&Identity>, ip: IpAddr) -> Self {
+        match identity {
+            Some(identity) => RateKey::Identity(identity.key().into_owned()),
+            None => RateKey::Ip(ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets shared by every session on a server.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    /// How long an empty bucket takes to fill, after which any bucket not
+    /// used since is full and can be dropped.
+    prune_interval: Duration,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    by_key: HashMap<RateKey, Bucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        let refill = Duration::try_from_secs_f64(f64::from(limit.burst) / limit.per_second)
+            .unwrap_or(Duration::MAX);
+        Self {
+            limit,
+            prune_interval: refill.max(MIN_PRUNE_INTERVAL),
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `cost` tokens from `key`'s bucket, or returns how long until
+    /// enough have accumulated.
+    pub(crate) fn acquire(&self, key: &RateKey, cost: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.by_key.len() >= PRUNE_THRESHOLD
+            && now.duration_since(buckets.pruned) >= self.prune_interval
+        {
+            // A bucket that would be full again is indistinguishable from a new one.
+            buckets
+                .by_key
+                .retain(|_, bucket| self.refill(bucket, now) < burst);
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_key.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.refilled = now;
+
+        let cost = f64::from(cost);
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            return Ok(());
+        }
+        if cost > burst || self.limit.per_second <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64(
+            (cost - bucket.tokens) / self.limit.per_second,
+        ))
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second).min(f64::from(self.limit.burst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn ip(n: usize) -> RateKey {
+        RateKey::Ip(IpAddr::V4(Ipv4Addr::from(n as u32)))
+    }
+
+    #[test]
+    fn prunes_at_most_once_per_interval() {
+        let limiter = RateLimiter::new(RateLimit {
+            per_second: 1_000_000.0,
+            burst: 1,
+        });
+        for n in 0..=PRUNE_THRESHOLD {
+            limiter.acquire(&ip(n), 1).unwrap();
+        }
+        // Every bucket has refilled by now, but the table was only just made.
+        assert_eq!(
+            limiter.buckets.lock().unwrap().by_key.len(),
+            PRUNE_THRESHOLD + 1
+        );
+
+        limiter.buckets.lock().unwrap().pruned -= MIN_PRUNE_INTERVAL;
+        limiter.acquire(&ip(usize::MAX), 1).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), 1);
+    }
+}
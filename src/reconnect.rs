@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use futures::future::{AbortHandle, abortable};
+use tokio::sync::{broadcast, watch};
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::client::{Notifications, downcast_response};
+use crate::filter::Filter;
+use crate::pubsub::{
+    Ack, AckResponse, AckedMessage, Subscribe, SubscribeAcked, SubscribeFiltered,
+    SubscribeResponse, Unsubscribe, UnsubscribeResponse,
+};
+use crate::rt;
+use crate::{Client, ClientConfig, Request, Response, RetryPolicy, TypedRequest};
+
+/// Jittered exponential backoff between attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// Each delay is shortened by a random fraction up to this, so clients
+    /// that lost the same server don't all come back at once.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before retry number `attempt`, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        Duration::from_secs_f64(base * (1.0 - jitter))
+    }
+}
+
+pub(crate) fn random_fraction() -> f64 {
+    let mut bytes = [0; 8];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectConfig {
+    pub client: ClientConfig,
+    pub backoff: Backoff,
+    /// Consecutive failed attempts after which the client gives up and every
+    /// call fails; `None` keeps trying forever.
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Clone)]
+enum State {
+    Connected(Client),
+    Reconnecting,
+    Failed(Arc<str>),
+}
+
+/// A [`Client`] that reconnects with backoff whenever its connection drops,
+/// resubscribing to the topics it was subscribed to through
+/// [`subscribe`](Self::subscribe), [`subscribe_filtered`](Self::subscribe_filtered)
+/// or [`subscribe_acked`](Self::subscribe_acked).
+/// Against a server that keeps sessions for resumption it resumes the old
+/// session instead, getting the pushes sent while it was away.
+///
+/// A call that was in flight when the connection dropped still fails; calls
+/// made while reconnecting wait for the new connection.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    shared: Arc<Shared>,
+    _supervisor: Arc<Supervisor>,
+}
+
+struct Shared {
+    addr: String,
+    config: ReconnectConfig,
+    /// Taken out of the client config, so calls retry across connections
+    /// rather than on one and one breaker covers them all.
+    retry: Option<RetryPolicy>,
+    breaker: Option<CircuitBreaker>,
+    state: watch::Sender<State>,
+    /// Each topic subscribed to, and how.
+    topics: Mutex<BTreeMap<String, Subscription>>,
+    /// The last connection's session token, to resume it on the next.
+    session: Mutex<Option<String>>,
+    notifications: broadcast::Sender<Arc<dyn Response>>,
+}
+
+/// How a topic was subscribed to, to do it the same way again.
+#[derive(Clone)]
+enum Subscription {
+    Plain,
+    Filtered(Filter),
+    /// The acked subscriber's name.
+    Acked(String),
+}
+
+/// Stops reconnecting once the last handle is dropped.
+struct Supervisor(AbortHandle);
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl ReconnectingClient {
+    /// Connects to `addr`; the first connection must succeed for this to return.
+    pub async fn connect(addr: impl Into<String>, mut config: ReconnectConfig) -> Result<Self> {
+        let addr = addr.into();
+        let retry = config.client.retry.take();
+        let breaker = config
+            .client
+            .circuit_breaker
+            .take()
+            .map(CircuitBreaker::new);
+        let client = Client::connect_with(&addr, config.client.clone()).await?;
+        let session = Mutex::new(client.session().map(str::to_string));
+        let (notifications, _) = broadcast::channel(config.client.notification_buffer);
+        let shared = Arc::new(Shared {
+            addr,
+            config,
+            retry,
+            breaker,
+            state: watch::Sender::new(State::Connected(client)),
+            topics: Mutex::default(),
+            session,
+            notifications,
+        });
+        let (supervise, supervisor) = abortable(supervise(shared.clone()));
+        rt::spawn(async move {
+            let _ = supervise.await;
+        });
+        Ok(Self {
+            shared,
+            _supervisor: Arc::new(Supervisor(supervisor)),
+        })
+    }
+
+    /// The current connection, waiting out a reconnect in progress.
+    pub async fn client(&self) -> Result<Client> {
+        let mut state = self.shared.state.subscribe();
+        let state = state
+            .wait_for(|state| !matches!(state, State::Reconnecting))
+            .await
+            .map_err(|_| anyhow!("client shut down"))?;
+        match &*state {
+            State::Connected(client) => Ok(client.clone()),
+            State::Failed(reason) => Err(anyhow!("gave up reconnecting: {reason}")),
+            State::Reconnecting => unreachable!("waited for another state"),
+        }
+    }
+
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        match &self.shared.retry {
+            Some(retry) => {
+                retry
+                    .run(request, true, |request| self.call_once(request, None))
+                    .await
+            }
+            None => self.call_once(request, None).await,
+        }
+    }
+
+    /// As `Client::call_with_idempotency_key`; the key makes the call safe
+    /// to retry on a new connection.
+    pub async fn call_with_idempotency_key(
+        &self,
+        request: Box<dyn Request>,
+        key: impl Into<String>,
+    ) -> Result<Box<dyn Response>> {
+        let key = key.into();
+        match &self.shared.retry {
+            Some(retry) => {
+                retry
+                    .run_any(request, true, |request| self.call_once(request, Some(&key)))
+                    .await
+            }
+            None => self.call_once(request, Some(&key)).await,
+        }
+    }
+
+    async fn call_once(
+        &self,
+        request: Box<dyn Request>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Box<dyn Response>> {
+        let call = async {
+            let client = self.client().await?;
+            match idempotency_key {
+                Some(key) => client.call_with_idempotency_key(request, key).await,
+                None => client.call(request).await,
+            }
+        };
+        match &self.shared.breaker {
+            Some(breaker) => breaker.run(call).await,
+            None => call.await,
+        }
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response(response)
+    }
+
+    /// Subscribes to `topic` now and again after every reconnect.
+    pub async fn subscribe(&self, topic: impl Into<String>) -> Result<SubscribeResponse> {
+        let topic = topic.into();
+        self.shared
+            .topics
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), Subscription::Plain);
+        self.call_typed(Subscribe { topic }).await
+    }
+
+    /// Subscribes to the messages on `topic` that pass `filter`, now and
+    /// again after every reconnect.
+    pub async fn subscribe_filtered(
+        &self,
+        topic: impl Into<String>,
+        filter: Filter,
+    ) -> Result<SubscribeResponse> {
+        let topic = topic.into();
+        self.shared
+            .topics
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), Subscription::Filtered(filter.clone()));
+        self.call_typed(SubscribeFiltered { topic, filter }).await
+    }
+
+    /// Subscribes to `topic` as the acked subscriber `subscriber`, now and
+    /// again after every reconnect, so messages not acked with
+    /// [`ack`](Self::ack) before a connection drops arrive again on the next.
+    pub async fn subscribe_acked(
+        &self,
+        topic: impl Into<String>,
+        subscriber: impl Into<String>,
+    ) -> Result<SubscribeResponse> {
+        let topic = topic.into();
+        let subscriber = subscriber.into();
+        self.shared
+            .topics
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), Subscription::Acked(subscriber.clone()));
+        self.call_typed(SubscribeAcked { topic, subscriber }).await
+    }
+
+    pub async fn ack(&self, message: &AckedMessage) -> Result<AckResponse> {
+        self.call_typed::<Ack>(message.ack()).await
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<UnsubscribeResponse> {
+        self.shared.topics.lock().unwrap().remove(topic);
+        self.call_typed(Unsubscribe {
+            topic: topic.to_string(),
+        })
+        .await
+    }
+
+    /// Messages pushed by the server on any connection from now on; the
+    /// stream carries on across reconnects.
+    pub fn notifications(&self) -> Notifications {
+        BroadcastStream::new(self.shared.notifications.subscribe())
+            .filter_map(|item| async move { item.ok() })
+            .boxed()
+    }
+
+    /// Whether the client is connected right now rather than reconnecting or given up.
+    pub fn is_connected(&self) -> bool {
+        matches!(&*self.shared.state.borrow(), State::Connected(_))
+    }
+}
+
+/// Forwards the current connection's pushes until it drops, then reconnects.
+async fn supervise(shared: Arc<Shared>) {
+    loop {
+        let State::Connected(client) = shared.state.borrow().clone() else {
+            return;
+        };
+        let mut pushes = client.notifications();
+        loop {
+            tokio::select! {
+                Some(message) = pushes.next() => {
+                    let _ = shared.notifications.send(message);
+                }
+                _ = client.closed() => break,
+            }
+        }
+        let go_away = client.go_away();
+        drop(client);
+
+        let retry_after = match go_away {
+            Some(go_away) => {
+                tracing::info!(addr = %shared.addr, %go_away, "Server closed the connection, reconnecting");
+                go_away.retry_after
+            }
+            None => {
+                tracing::warn!(addr = %shared.addr, "Connection lost, reconnecting");
+                None
+            }
+        };
+        shared.state.send_replace(State::Reconnecting);
+        // A draining server says when it expects to be back; trying sooner
+        // would only use up attempts.
+        if let Some(retry_after) = retry_after {
+            rt::sleep(retry_after).await;
+        }
+        shared.state.send_replace(reconnect(&shared).await);
+    }
+}
+
+async fn reconnect(shared: &Shared) -> State {
+    let mut attempt = 0;
+    loop {
+        rt::sleep(shared.config.backoff.delay(attempt)).await;
+        attempt += 1;
+        let mut config = shared.config.client.clone();
+        config.session = shared.session.lock().unwrap().clone();
+        let error = match Client::connect_with(&shared.addr, config).await {
+            Ok(client) => {
+                *shared.session.lock().unwrap() = client.session().map(str::to_string);
+                let resubscribed = if client.resumed() {
+                    Ok(())
+                } else {
+                    resubscribe(shared, &client).await
+                };
+                match resubscribed {
+                    Ok(()) => {
+                        tracing::info!(
+                            addr = %shared.addr,
+                            attempt,
+                            resumed = client.resumed(),
+                            "Reconnected"
+                        );
+                        return State::Connected(client);
+                    }
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        tracing::debug!(addr = %shared.addr, attempt, error = format!("{error:#}"), "Reconnect attempt failed");
+        if shared.config.max_attempts.is_some_and(|max| attempt >= max) {
+            tracing::error!(addr = %shared.addr, attempt, "Giving up reconnecting");
+            return State::Failed(format!("{error:#}").into());
+        }
+    }
+}
+
+async fn resubscribe(shared: &Shared, client: &Client) -> Result<()> {
+    let topics: Vec<_> = shared.topics.lock().unwrap().clone().into_iter().collect();
+    for (topic, subscription) in topics {
+        match subscription {
+            Subscription::Plain => client.call_typed(Subscribe { topic }).await?,
+            Subscription::Filtered(filter) => {
+                client
+                    .call_typed(SubscribeFiltered { topic, filter })
+                    .await?
+            }
+            Subscription::Acked(subscriber) => {
+                client
+                    .call_typed(SubscribeAcked { topic, subscriber })
+                    .await?
+            }
+        };
+    }
+    Ok(())
+}
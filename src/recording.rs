@@ -0,0 +1,285 @@
+//! Recording sessions frame by frame, handshake included, and playing the
+//! client's side of a recording back at a server.
+//!
+//! A recording is a JSON Lines file with one [`Record`] per frame, written by
+//! a server built with `ServerBuilder::record_sessions` and replayed with
+//! [`replay`] or `myproto-cli replay`. Sessions that authenticate can't be
+//! replayed, as the server's challenge differs every time.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+/// How long `replay` waits for the server once it has sent everything, if
+/// fewer frames than were recorded have come back.
+const REPLY_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// One frame as it crossed the wire, length prefix included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since the session started.
+    pub at_us: u64,
+    pub from: Side,
+    #[serde(with = "hex")]
+    pub frame: Bytes,
+}
+
+impl Record {
+    pub fn at(&self) -> Duration {
+        Duration::from_micros(self.at_us)
+    }
+}
+
+/// Reads every record from the file at `path`.
+pub async fn read(path: &Path) -> Result<Vec<Record>> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+    let mut records = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        let record = serde_json::from_str(&line).with_context(|| {
+            format!("invalid record {} in {}", records.len() + 1, path.display())
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// How a server's replies to a replayed session compared with the recording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub sent: usize,
+    /// Frames the server sent in the recording.
+    pub expected: usize,
+    pub received: usize,
+    /// Positions among the server's frames where what came back differs from
+    /// the recording, byte for byte.
+    pub mismatched: Vec<usize>,
+}
+
+/// Sends the client's frames from `records` over `stream`, spaced as they
+/// were recorded unless `pace` is false, and compares what the server sends
+/// back with the frames it sent originally.
+pub async fn replay<S>(records: &[Record], stream: S, pace: bool) -> Result<ReplayReport>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let expected: Vec<&Bytes> = records
+        .iter()
+        .filter(|record| record.from == Side::Server)
+        .map(|record| &record.frame)
+        .collect();
+    let outgoing: Vec<&Record> = records
+        .iter()
+        .filter(|record| record.from == Side::Client)
+        .collect();
+    let mut report = ReplayReport {
+        sent: outgoing.len(),
+        expected: expected.len(),
+        ..ReplayReport::default()
+    };
+
+    let send = async {
+        let started = Instant::now();
+        for record in outgoing {
+            if pace {
+                tokio::time::sleep_until((started + record.at()).into()).await;
+            }
+            writer.write_all(&record.frame).await?;
+        }
+        writer.flush().await?;
+        anyhow::Ok(())
+    };
+    tokio::pin!(send);
+
+    let mut frames = Frames::default();
+    let mut buf = vec![0; 16 * 1024];
+    let mut sent = false;
+    loop {
+        let read = tokio::select! {
+            result = &mut send, if !sent => {
+                result.context("failed to send the recorded frames")?;
+                sent = true;
+                continue;
+            }
+            read = reader.read(&mut buf) => read?,
+            _ = tokio::time::sleep(REPLY_WAIT), if sent => break,
+        };
+        if read == 0 {
+            break;
+        }
+        for frame in frames.feed(&buf[..read]) {
+            if expected.get(report.received) != Some(&&frame) {
+                report.mismatched.push(report.received);
+            }
+            report.received += 1;
+        }
+        if sent && report.received >= report.expected {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// Splits a byte stream into whole length-prefixed frames.
+#[derive(Default)]
+struct Frames(BytesMut);
+
+impl Frames {
+    fn feed(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.0.extend_from_slice(data);
+        let mut frames = Vec::new();
+        while self.0.len() >= 4 {
+            let len = (&self.0[..4]).get_u32() as usize;
+            if self.0.len() < 4 + len {
+                break;
+            }
+            frames.push(self.0.split_to(4 + len).freeze());
+        }
+        frames
+    }
+}
+
+/// A session's stream that copies every frame it carries to a recording file.
+pub(crate) struct Recorded<S> {
+    inner: S,
+    started: Instant,
+    from_client: Frames,
+    from_server: Frames,
+    records: mpsc::UnboundedSender<Record>,
+}
+
+impl<S> Recorded<S> {
+    /// Starts recording to a new file in `dir`, named after the time and `peer_addr`.
+    pub(crate) fn new(inner: S, dir: &Path, peer_addr: SocketAddr) -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{millis}-{peer_addr}.jsonl").replace(':', "_");
+        let (records, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(dir.join(name), rx));
+        Self {
+            inner,
+            started: Instant::now(),
+            from_client: Frames::default(),
+            from_server: Frames::default(),
+            records,
+        }
+    }
+
+    fn record(&mut self, from: Side, data: &[u8]) {
+        let at_us = self.started.elapsed().as_micros() as u64;
+        let frames = match from {
+            Side::Client => self.from_client.feed(data),
+            Side::Server => self.from_server.feed(data),
+        };
+        for frame in frames {
+            let _ = self.records.send(Record { at_us, from, frame });
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.record(Side::Client, &buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.record(Side::Server, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_records(path: PathBuf, mut records: mpsc::UnboundedReceiver<Record>) {
+    let result = async {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = tokio::fs::File::create(&path).await?;
+        let mut file = tokio::io::BufWriter::new(file);
+        while let Some(record) = records.recv().await {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            file.write_all(&line).await?;
+            // Frames come in bursts, so the file is complete whenever the session pauses.
+            if records.is_empty() {
+                file.flush().await?;
+            }
+        }
+        file.flush().await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::warn!(path = %path.display(), error = %format!("{e:#}"), "Failed to record session");
+    }
+}
+
+mod hex {
+    use bytes::Bytes;
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        bytes: &Bytes,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Bytes, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(D::Error::custom("expected an even number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect::<Result<Vec<u8>, _>>()
+            .map(Bytes::from)
+    }
+}
@@ -0,0 +1,75 @@
+//! Keeping secrets a request carries out of spans and audit records.
+//!
+//! Fields marked `#[redact]` on a type declared with `#[request]`, or on
+//! one deriving [`Redact`], have their values replaced with
+//! [`REDACTED`] wherever the server shows the request, leaving the rest of
+//! its structure as it was:
+//!
+//! ```ignore
+//! #[myproto::request(response = Session)]
+//! pub struct Login {
+//!     pub user: String,
+//!     #[redact]
+//!     pub password: String,
+//! }
+//! ```
+//!
+//! Hand-written `Request` impls name theirs in `Request::redacted_fields`,
+//! usually from a `#[derive(Redact)]` on the type.
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+pub use myproto_macros::Redact;
+
+/// What stands in for a redacted field's value.
+pub const REDACTED: &str = "[redacted]";
+
+/// A type whose fields of the given names are masked when it is shown,
+/// implemented with `#[derive(Redact)]` and `#[redact]` on the fields.
+pub trait Redact {
+    /// The fields' names as serialized, after any `#[serde(rename)]`.
+    fn redacted_fields() -> &'static [&'static str];
+}
+
+/// `value` as JSON, with its `fields` masked. Requests serialize tagged
+/// with their type, `{"Login": {...}}`; the fields are looked for inside.
+pub fn redacted<T: Serialize + ?Sized>(value: &T, fields: &[&str]) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(value)?;
+    if fields.is_empty() {
+        return Ok(value);
+    }
+    let target = match &mut value {
+        Value::Object(tagged) if tagged.len() == 1 => tagged.values_mut().next(),
+        _ => None,
+    };
+    if let Some(Value::Object(object)) = target {
+        for field in fields {
+            if let Some(value) = object.get_mut(*field) {
+                *value = Value::String(REDACTED.to_string());
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Shows requests as JSON with their redacted fields masked, for span fields.
+pub(crate) struct Redacted<'a>(pub(crate) &'a [Box<dyn crate::Request>]);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, request) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match redacted(request.as_ref(), request.redacted_fields()) {
+                Ok(value) => write!(f, "{value}")?,
+                Err(_) => f.write_str(request.typetag_name())?,
+            }
+        }
+        f.write_str("]")
+    }
+}
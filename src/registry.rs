@@ -0,0 +1,94 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, MapAccess, Visitor};
+
+use crate::untyped;
+
+/// Names of every type registered with `#[typetag::serde]` for the trait
+/// object `T`, e.g. `registered::<dyn Request>()`, sorted.
+///
+/// typetag keeps its registry private, so this asks it to deserialize a tag
+/// no type uses and reads the list of valid tags off the resulting error.
+pub fn registered<T: ?Sized>() -> Vec<&'static str>
+where
+    Box<T>: DeserializeOwned,
+{
+    match Box::<T>::deserialize(Probe) {
+        Err(ProbeError::UnknownVariant(names)) => {
+            let mut names = names.to_vec();
+            names.retain(|&name| name != untyped::REGISTERED_NAME);
+            names.sort_unstable();
+            names
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The tag looked up in the probe; no Rust type can be named this.
+const UNUSED_TAG: &str = "\0";
+
+struct Probe;
+
+impl<'de> Deserializer<'de> for Probe {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        visitor.visit_map(ProbeMap)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct ProbeMap;
+
+impl<'de> MapAccess<'de> for ProbeMap {
+    type Error = ProbeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ProbeError> {
+        seed.deserialize(UNUSED_TAG.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        _: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(de::Error::custom("the probe has no values"))
+    }
+}
+
+#[derive(Debug)]
+enum ProbeError {
+    UnknownVariant(&'static [&'static str]),
+    Other(String),
+}
+
+impl de::Error for ProbeError {
+    fn custom<M: fmt::Display>(msg: M) -> Self {
+        ProbeError::Other(msg.to_string())
+    }
+
+    fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+        ProbeError::UnknownVariant(expected)
+    }
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::UnknownVariant(names) => {
+                write!(f, "unknown tag, expected one of {names:?}")
+            }
+            ProbeError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
@@ -0,0 +1,295 @@
+//! Requests from one client to another, relayed by the server.
+//!
+//! A client takes a name with [`Register`], or `ClientConfig::peer_name` on
+//! connecting, and other clients reach it with [`SendTo`]. The target gets
+//! a [`RelayedRequest`] on its notifications stream and answers it with the
+//! [`RelayReply`] from [`RelayedRequest::reply`]; the server hands that back
+//! as `SendTo`'s response. A target that isn't connected, or disconnects
+//! before answering, fails the call with [`ErrorCode::PeerUnavailable`].
+//! In a cluster, targets connected to another node are reached through it.
+//! On a multi-tenant server, names are kept under the tenant's prefix, so
+//! clients only reach peers of their own tenant.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::envelope::ServerMessage;
+use crate::schema::Describe;
+use crate::tenant;
+use crate::{
+    Connection, Context, ErrorCode, Priority, ProtocolError, Request, Response, ResponseResult,
+    TypedRequest,
+};
+
+/// Registered peer names and the relayed requests waiting for an answer.
+#[derive(Clone, Default)]
+pub(crate) struct PeerRegistry {
+    inner: Arc<Mutex<Peers>>,
+    next_id: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Peers {
+    names: HashMap<String, Connection>,
+    /// Each waiting request's id, with the connection it went to.
+    waiting: HashMap<u64, (u64, oneshot::Sender<ResponseResult>)>,
+}
+
+impl PeerRegistry {
+    /// Gives `connection` the name `name`, failing if another connection has it.
+    fn register(&self, name: &str, connection: Connection) -> Result<(), ProtocolError> {
+        let mut peers = self.inner.lock().unwrap();
+        match peers.names.get(name) {
+            Some(holder) if holder.id() != connection.id() && !holder.is_closed() => {
+                Err(ProtocolError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("peer name {name} is taken"),
+                ))
+            }
+            _ => {
+                peers.names.insert(name.to_string(), connection);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.inner.lock().unwrap().names.keys().cloned().collect()
+    }
+
+    fn name_of(&self, connection_id: u64) -> Option<String> {
+        let peers = self.inner.lock().unwrap();
+        peers
+            .names
+            .iter()
+            .find(|(_, connection)| connection.id() == connection_id)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Forgets the connection's names, returning them, and fails the requests waiting on it.
+    pub(crate) fn remove_connection(&self, connection_id: u64) -> Vec<String> {
+        let mut peers = self.inner.lock().unwrap();
+        let mut removed = Vec::new();
+        peers.names.retain(|name, connection| {
+            let keep = connection.id() != connection_id;
+            if !keep {
+                removed.push(name.clone());
+            }
+            keep
+        });
+        peers
+            .waiting
+            .retain(|_, (target, _)| *target != connection_id);
+        removed
+    }
+
+    fn forget(&self, id: u64) {
+        self.inner.lock().unwrap().waiting.remove(&id);
+    }
+}
+
+/// Removes a relayed request from the waiting list when its caller stops
+/// waiting, answered or not.
+struct Waiting<'a> {
+    peers: &'a PeerRegistry,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.peers.forget(self.id);
+    }
+}
+
+pub(crate) fn unavailable(peer: &str, why: &str) -> anyhow::Error {
+    ProtocolError::new(ErrorCode::PeerUnavailable, format!("peer {peer} {why}")).into()
+}
+
+/// Takes `name` for this connection until it disconnects, so other clients
+/// can send it requests with [`SendTo`].
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = RegisterResponse)]
+pub struct Register {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct RegisterResponse {
+    pub name: String,
+}
+
+#[typetag::serde]
+impl Response for RegisterResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Register {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let name = ctx.scoped(&self.name)?;
+        ctx.server()
+            .peers
+            .register(&name, ctx.connection().clone())?;
+        if let Some(cluster) = &ctx.server().cluster {
+            cluster.announce(&name, true);
+        }
+        Ok(Box::new(RegisterResponse {
+            name: self.name.clone(),
+        }))
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl TypedRequest for Register {
+    type Response = RegisterResponse;
+}
+
+/// Relays `request` to the client registered as `peer` and answers with its
+/// response, or its error.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct SendTo {
+    pub peer: String,
+    pub request: Box<dyn Request>,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for SendTo {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let peers = &ctx.server().peers;
+        let peer = ctx.scoped(&self.peer)?;
+        let from = peers
+            .name_of(ctx.connection_id)
+            .map(|name| tenant::unscoped(&name).to_string());
+        let identity = ctx.identity().map(|identity| identity.key().into_owned());
+        // Trait objects can't be cloned, so the request is passed on through its wire form.
+        let request: Box<dyn Request> = bincode::deserialize(&bincode::serialize(&self.request)?)?;
+        let local = peers.inner.lock().unwrap().names.contains_key(&*peer);
+        match &ctx.server().cluster {
+            Some(cluster) if !local => cluster.relay(&peer, from, identity, request).await,
+            _ => relay_local(ctx, &peer, from, identity, request).await,
+        }
+    }
+}
+
+/// Relays `request` to `peer` if it is connected to this server.
+pub(crate) async fn relay_local(
+    ctx: &Context,
+    peer: &str,
+    from: Option<String>,
+    identity: Option<String>,
+    request: Box<dyn Request>,
+) -> Result<Box<dyn Response>> {
+    let peers = &ctx.server().peers;
+    let Some(target) = peers.inner.lock().unwrap().names.get(peer).cloned() else {
+        return Err(unavailable(peer, "is not connected"));
+    };
+
+    let id = peers.next_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    peers
+        .inner
+        .lock()
+        .unwrap()
+        .waiting
+        .insert(id, (target.id(), tx));
+    let _waiting = Waiting { peers, id };
+
+    let relayed = ServerMessage::Push(Box::new(RelayedRequest {
+        id,
+        from,
+        identity,
+        request,
+    }));
+    if target.send_message(&relayed).await.is_err() {
+        return Err(unavailable(peer, "is not connected"));
+    }
+
+    tokio::select! {
+        reply = rx => match reply {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(unavailable(peer, "disconnected before answering")),
+        },
+        _ = ctx.cancelled() => Err(ProtocolError::new(ErrorCode::Cancelled, "cancelled").into()),
+    }
+}
+
+/// A request another client sent this one with [`SendTo`].
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct RelayedRequest {
+    pub id: u64,
+    /// The sender's peer name, if it registered one.
+    pub from: Option<String>,
+    /// The subject the sender authenticated as, if the server authenticates
+    /// clients; `tenant/subject` on a multi-tenant server.
+    pub identity: Option<String>,
+    pub request: Box<dyn Request>,
+}
+
+impl RelayedRequest {
+    /// The request answering this one with `result`.
+    pub fn reply(&self, result: ResponseResult) -> RelayReply {
+        RelayReply {
+            id: self.id,
+            result,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Response for RelayedRequest {}
+
+/// Answers the [`RelayedRequest`] `id`.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = RelayReplyResponse)]
+pub struct RelayReply {
+    pub id: u64,
+    pub result: ResponseResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct RelayReplyResponse {
+    /// Whether the sender was still waiting for the answer.
+    pub delivered: bool,
+}
+
+#[typetag::serde]
+impl Response for RelayReplyResponse {}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for RelayReply {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let waiting = {
+            let mut peers = ctx.server().peers.inner.lock().unwrap();
+            match peers.waiting.get(&self.id) {
+                // Only the client the request went to may answer it.
+                Some((target, _)) if *target == ctx.connection_id => peers.waiting.remove(&self.id),
+                _ => None,
+            }
+        };
+        let delivered = match waiting {
+            Some((_, tx)) => {
+                let result = bincode::deserialize(&bincode::serialize(&self.result)?)?;
+                tx.send(result).is_ok()
+            }
+            None => false,
+        };
+        Ok(Box::new(RelayReplyResponse { delivered }))
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+}
+
+impl TypedRequest for RelayReply {
+    type Response = RelayReplyResponse;
+}
@@ -0,0 +1,88 @@
+//! Replies sent in the order their calls arrived, for sessions that
+//! negotiated `Features::ORDERED_REPLIES`.
+//!
+//! Every call gets a [`ReplySlot`] as it is read, and its reply waits in the
+//! slot until the replies of all the calls before it have been queued. A
+//! call that ends without a reply, cancelled say, drops its slot, which
+//! lets the ones behind it through.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::connection::Outbound;
+use crate::frame::Encoded;
+
+/// A call's reply, or `None` if it has none, with who to tell once it's queued.
+type Filled = (u64, Option<Encoded>, Option<oneshot::Sender<()>>);
+
+/// Hands out a slot to each call in the order they arrive.
+pub(crate) struct ReplyOrder {
+    tx: mpsc::UnboundedSender<Filled>,
+    next_seq: u64,
+}
+
+impl ReplyOrder {
+    /// Starts passing replies on to `outbound` in order.
+    pub(crate) fn new(outbound: Outbound) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(forward(rx, outbound));
+        Self { tx, next_seq: 0 }
+    }
+
+    /// The slot for the call that arrived just now.
+    pub(crate) fn slot(&mut self) -> ReplySlot {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        ReplySlot {
+            seq,
+            tx: Some(self.tx.clone()),
+        }
+    }
+}
+
+/// Where one call's reply goes.
+pub(crate) struct ReplySlot {
+    seq: u64,
+    tx: Option<mpsc::UnboundedSender<Filled>>,
+}
+
+impl ReplySlot {
+    /// Queues `bytes` for the client once every earlier reply is queued,
+    /// waiting until then so a slow client still holds up the call.
+    pub(crate) async fn send(mut self, bytes: impl Into<Encoded>) -> Result<()> {
+        let tx = self.tx.take().expect("a slot is only filled once");
+        let (done, queued) = oneshot::channel();
+        tx.send((self.seq, Some(bytes.into()), Some(done)))
+            .map_err(|_| anyhow!("connection closed"))?;
+        queued.await.map_err(|_| anyhow!("connection closed"))
+    }
+}
+
+impl Drop for ReplySlot {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send((self.seq, None, None));
+        }
+    }
+}
+
+async fn forward(mut rx: mpsc::UnboundedReceiver<Filled>, outbound: Outbound) {
+    let mut next = 0;
+    let mut ready = BTreeMap::new();
+    while let Some((seq, bytes, done)) = rx.recv().await {
+        ready.insert(seq, (bytes, done));
+        while let Some((bytes, done)) = ready.remove(&next) {
+            next += 1;
+            if let Some(bytes) = bytes
+                && outbound.send(bytes).await.is_err()
+            {
+                return;
+            }
+            if let Some(done) = done {
+                let _ = done.send(());
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! An append-only file of the requests that change a server's state, so
+//! state kept in memory can be rebuilt after a restart by running them
+//! again.
+//!
+//! Requests whose `Request::mutating` is true, `#[request(mutating)]` ones
+//! included, are appended once they have passed authorization, middleware
+//! and validation, just before their handler runs; a request that can't be
+//! journaled fails without running. `Server::replay_requests` hands each
+//! one back to its handler, in order and under the identity it was made
+//! with, before the server takes any clients:
+//!
+//! ```ignore
+//! let server = Server::builder()
+//!     .request_journal(RequestJournal::open("/var/lib/myproto/requests")?)
+//!     .build();
+//! server.replay_requests().await?;
+//! server.serve(listener, shutdown).await?;
+//! ```
+//!
+//! A handler can tell it is being replayed from `Context::is_replay`, e.g.
+//! to skip side effects outside the server. The file keeps growing, as the
+//! message journal's does.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::frame::Compression;
+use crate::{Connection, Context, Identity, Request, Server, middleware};
+
+/// Each record's length, before the record itself.
+const LEN_PREFIX: usize = 4;
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    identity: Option<&'a Identity>,
+    request: &'a dyn Request,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    identity: Option<Identity>,
+    request: Box<dyn Request>,
+}
+
+/// A request journal backed by one file.
+pub struct RequestJournal {
+    path: PathBuf,
+    sync: bool,
+    file: Mutex<File>,
+    /// Read when the file was opened, until replayed.
+    pending: Mutex<Vec<Entry>>,
+}
+
+impl RequestJournal {
+    /// Opens the journal at `path`, creating it if it doesn't exist, and
+    /// reads back what it holds for `Server::replay_requests`. A record cut
+    /// short by a crash is dropped from the end.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut pending = Vec::new();
+        let mut end = 0;
+        while let Some(record) = record(&contents[end..]) {
+            let entry = bincode::deserialize(record).with_context(|| {
+                format!(
+                    "unreadable record {} in {}",
+                    pending.len() + 1,
+                    path.display()
+                )
+            })?;
+            pending.push(entry);
+            end += LEN_PREFIX + record.len();
+        }
+        if end < contents.len() {
+            tracing::warn!(
+                path = %path.display(),
+                bytes = contents.len() - end,
+                "Dropping a torn record from the end of the request journal"
+            );
+            file.set_len(end as u64)?;
+        }
+        Ok(Self {
+            path,
+            sync: false,
+            file: Mutex::new(file),
+            pending: Mutex::new(pending),
+        })
+    }
+
+    /// Flushes every append to disk before the request runs, so requests
+    /// survive the machine going down and not just the process.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn append(&self, request: &dyn Request, identity: Option<&Identity>) -> Result<()> {
+        let entry = bincode::serialize(&EntryRef { identity, request })?;
+        let mut record = Vec::with_capacity(LEN_PREFIX + entry.len());
+        record.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        record.extend_from_slice(&entry);
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&record)
+            .with_context(|| format!("failed to append to {}", self.path.display()))?;
+        if self.sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Runs every request read when the journal was opened through its
+    /// handler again, returning how many there were. Only the first call
+    /// replays anything.
+    pub(crate) async fn replay(&self, server: &Server) -> Result<usize> {
+        let entries = std::mem::take(&mut *self.pending.lock().unwrap());
+        let replayed = entries.len();
+        let peer_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        for (i, entry) in entries.into_iter().enumerate() {
+            let connection = Connection::detached(server, peer_addr, Codec::Bincode);
+            let ctx =
+                Context::new(connection, Compression::None, entry.identity, server).replaying();
+            let request_type = entry.request.typetag_name();
+            // A request that failed the first time may well fail again; the
+            // state it left behind is the same either way.
+            if let Err(e) = middleware::dispatch(entry.request.as_ref(), &ctx).await {
+                tracing::debug!(
+                    record = i + 1,
+                    request_type,
+                    error = format!("{e:#}"),
+                    "Replayed request failed"
+                );
+            }
+        }
+        if replayed > 0 {
+            tracing::info!(path = %self.path.display(), replayed, "Replayed request journal");
+        }
+        Ok(replayed)
+    }
+}
+
+/// The first whole record's contents in `bytes`.
+fn record(bytes: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(bytes.get(..LEN_PREFIX)?.try_into().ok()?) as usize;
+    bytes.get(LEN_PREFIX..LEN_PREFIX + len)
+}
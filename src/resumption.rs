@@ -0,0 +1,236 @@
+//! Resuming a session on a new connection after the old one dropped.
+//!
+//! When both sides offer `Features::RESUMPTION`, the client follows the
+//! handshake with a [`Resume`] naming the session it had before, if any,
+//! and the server answers with a [`Resumed`] carrying a new session token.
+//! A server with a `session_resume_timeout` keeps a disconnected session's
+//! identity and subscriptions for that long, buffering pushes for it up to
+//! its `outbound_queue`; resuming it restores them and delivers the pushes,
+//! and skips authentication. Calls in flight when the connection dropped
+//! are not resumed. Only a client that negotiated the same codec can resume.
+//!
+//! Parked sessions are written to the server's
+//! [`SessionStore`](crate::session_store::SessionStore) too, so a client
+//! can resume on another server sharing it, without the buffered pushes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+use crate::codec::Codec;
+use crate::config::SlowClient;
+use crate::connection::Outbound;
+use crate::frame::{Encoded, FrameCodec};
+use crate::session_store::SessionState;
+use crate::{Connection, ErrorCode, Identity, ProtocolError, Server, handshake};
+
+const TOKEN_LEN: usize = 16;
+
+/// Sent by the client after the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resume {
+    /// The token of the session to resume; `None` starts a new one.
+    pub session: Option<String>,
+}
+
+/// The server's answer to [`Resume`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resumed {
+    /// The token to resume this session with next time. Each connection
+    /// gets a new one, even when it resumed a session.
+    pub session: String,
+    /// Whether the session asked for was restored.
+    pub resumed: bool,
+}
+
+/// What a disconnected session leaves behind for a client to resume.
+pub(crate) struct Parked {
+    pub(crate) connection_id: u64,
+    pub(crate) identity: Option<Identity>,
+    codec: Codec,
+    /// Pushes sent to the session since it disconnected, encoded for `codec`.
+    pub(crate) pending: mpsc::Receiver<Encoded>,
+}
+
+/// Sessions waiting to be resumed, by token.
+#[derive(Clone, Default)]
+pub(crate) struct ParkedSessions {
+    sessions: Arc<Mutex<HashMap<String, Parked>>>,
+}
+
+impl ParkedSessions {
+    fn take(&self, token: &str) -> Option<Parked> {
+        self.sessions.lock().unwrap().remove(token)
+    }
+}
+
+/// How a session started: the token it was issued and, if it resumed
+/// another, what that one left behind: in this process, `parked`, and in
+/// the session store, `restored`, which is all there is of a session
+/// parked by another process.
+pub(crate) struct Resumption {
+    pub(crate) token: String,
+    pub(crate) parked: Option<Parked>,
+    pub(crate) restored: Option<SessionState>,
+}
+
+impl Resumption {
+    pub(crate) fn resumed(&self) -> bool {
+        self.parked.is_some() || self.restored.is_some()
+    }
+
+    /// The identity the resumed session authenticated as, taken out of it.
+    pub(crate) fn take_identity(&mut self) -> Option<Option<Identity>> {
+        if let Some(parked) = &mut self.parked {
+            return Some(parked.identity.take());
+        }
+        self.restored
+            .as_mut()
+            .map(|restored| restored.identity.take())
+    }
+}
+
+impl Server {
+    /// Reads the client's [`Resume`] and answers it, taking the session it
+    /// names if that is waiting and was using `codec`.
+    pub(crate) async fn answer_resume<S>(
+        &self,
+        framed: &mut Framed<S, FrameCodec>,
+        codec: Codec,
+    ) -> Result<Resumption>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let bytes = handshake::recv(framed)
+            .await
+            .context("connection closed before resuming")?;
+        let resume = bincode::deserialize::<Resume>(&bytes).map_err(|e| {
+            ProtocolError::new(ErrorCode::Malformed, format!("malformed resume: {e}"))
+        });
+        let (parked, restored) = match &resume {
+            Ok(Resume {
+                session: Some(token),
+            }) => {
+                let parked = self.parked_sessions.take(token);
+                // Taken even when parked here, so no other server resumes it too.
+                let restored = self.session_store.take(token).await.unwrap_or_else(|e| {
+                    tracing::warn!(error = %format!("{e:#}"), "Failed to read a session from the session store");
+                    None
+                });
+                match (parked, restored) {
+                    (Some(parked), restored) if parked.codec == codec => (Some(parked), restored),
+                    (Some(parked), _) => {
+                        // The buffered pushes can't be read in another codec.
+                        tracing::debug!("Not resuming a session that used another codec");
+                        self.topics.remove_connection(parked.connection_id);
+                        (None, None)
+                    }
+                    (None, Some(restored)) if restored.codec == codec => (None, Some(restored)),
+                    (None, _) => (None, None),
+                }
+            }
+            _ => (None, None),
+        };
+
+        let token = new_token();
+        let resumption = Resumption {
+            token: token.clone(),
+            parked,
+            restored,
+        };
+        let reply = resume.map(|_| Resumed {
+            session: token,
+            resumed: resumption.resumed(),
+        });
+        framed.send(bincode::serialize(&reply)?.into()).await?;
+        reply?;
+        Ok(resumption)
+    }
+
+    /// Keeps a disconnected session's subscriptions under `token` for
+    /// `timeout`, buffering what is pushed to them, and forgets them after.
+    pub(crate) fn park_session(
+        &self,
+        token: String,
+        connection: &Connection,
+        identity: Option<Identity>,
+        timeout: Duration,
+    ) {
+        let state = SessionState {
+            subscriptions: self.topics.subscriptions(connection.id()),
+            ..SessionState::new(connection, identity.clone())
+        };
+        let (tx, pending) = mpsc::channel(self.config().outbound_queue);
+        let parked = Connection::new(
+            connection.id(),
+            connection.peer_addr(),
+            Outbound::new(tx, SlowClient::DropPushes),
+            connection.max_message_length(),
+            connection.codec(),
+        );
+        self.topics.transfer(connection.id(), parked);
+        self.parked_sessions.sessions.lock().unwrap().insert(
+            token.clone(),
+            Parked {
+                connection_id: connection.id(),
+                identity,
+                codec: connection.codec(),
+                pending,
+            },
+        );
+        tracing::debug!(?timeout, "Session parked for resumption");
+
+        let store = self.session_store.clone();
+        let key = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.put(&key, &state, timeout).await {
+                tracing::warn!(error = %format!("{e:#}"), "Failed to keep a session in the session store");
+            }
+        });
+
+        let sessions = self.parked_sessions.clone();
+        let topics = self.topics.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if let Some(parked) = sessions.take(&token) {
+                tracing::debug!(
+                    connection_id = parked.connection_id,
+                    "Parked session expired"
+                );
+                topics.remove_connection(parked.connection_id);
+            }
+        });
+    }
+}
+
+/// Asks the server to resume `session`, if given, returning its answer.
+pub(crate) async fn offer<S>(
+    framed: &mut Framed<S, FrameCodec>,
+    session: Option<String>,
+) -> Result<Resumed>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed
+        .send(bincode::serialize(&Resume { session })?.into())
+        .await?;
+    let bytes = handshake::recv(framed)
+        .await
+        .context("connection closed while resuming")?;
+    let reply: Result<Resumed, ProtocolError> =
+        bincode::deserialize(&bytes).context("malformed resume reply")?;
+    Ok(reply?)
+}
+
+fn new_token() -> String {
+    let mut token = [0; TOKEN_LEN];
+    getrandom::fill(&mut token).expect("the OS random number generator is available");
+    token.iter().map(|byte| format!("{byte:02x}")).collect()
+}
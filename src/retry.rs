@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::client::Disconnected;
+use crate::reconnect::Backoff;
+use crate::rt;
+use crate::type_ids::Tagged;
+use crate::{ErrorCode, ProtocolError, Request, Response};
+
+/// Which failed calls to try again, and how often.
+///
+/// Only requests whose `Request::idempotent` says so, or calls made with an
+/// idempotency key, are ever retried: a call that failed may still have run
+/// on the server, and running it again must not apply its effects twice.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, counting the first.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Server errors worth another attempt.
+    pub codes: Vec<ErrorCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Backoff {
+                initial: Duration::from_millis(50),
+                max: Duration::from_secs(2),
+                ..Backoff::default()
+            },
+            codes: vec![ErrorCode::Busy, ErrorCode::Overloaded, ErrorCode::Timeout],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `error` is worth another attempt; `reconnects` is whether the
+    /// next attempt gets a fresh connection, making transport failures retryable.
+    pub fn is_retryable(&self, error: &anyhow::Error, reconnects: bool) -> bool {
+        if let Some(error) = error.downcast_ref::<ProtocolError>() {
+            return self.codes.contains(&error.code);
+        }
+        reconnects
+            && error
+                .chain()
+                .any(|cause| cause.is::<Disconnected>() || cause.is::<std::io::Error>())
+    }
+
+    /// Runs `attempt` until it succeeds, fails for good or runs out of attempts.
+    pub(crate) async fn run<F, Fut>(
+        &self,
+        request: Box<dyn Request>,
+        reconnects: bool,
+        mut attempt: F,
+    ) -> Result<Box<dyn Response>>
+    where
+        F: FnMut(Box<dyn Request>) -> Fut,
+        Fut: Future<Output = Result<Box<dyn Response>>>,
+    {
+        if !request.idempotent() {
+            return attempt(request).await;
+        }
+        self.run_any(request, reconnects, attempt).await
+    }
+
+    /// Like `run`, for calls that are safe to repeat whatever the request
+    /// says, like ones carrying an idempotency key.
+    pub(crate) async fn run_any<F, Fut>(
+        &self,
+        request: Box<dyn Request>,
+        reconnects: bool,
+        mut attempt: F,
+    ) -> Result<Box<dyn Response>>
+    where
+        F: FnMut(Box<dyn Request>) -> Fut,
+        Fut: Future<Output = Result<Box<dyn Response>>>,
+    {
+        let mut request = request;
+        let mut attempts = 1;
+        loop {
+            if attempts >= self.max_attempts {
+                return attempt(request).await;
+            }
+            let next = duplicate(&*request)?;
+            match attempt(request).await {
+                Err(e) if self.is_retryable(&e, reconnects) => {
+                    let delay = self.backoff.delay(attempts - 1);
+                    tracing::debug!(error = %e, attempts, ?delay, "Retrying call");
+                    rt::sleep(delay).await;
+                    attempts += 1;
+                    request = next;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A copy of `request` for the next attempt, since sending one consumes it.
+pub(crate) fn duplicate(request: &dyn Request) -> Result<Box<dyn Request>> {
+    if let Some(untyped) = request.untyped() {
+        return Ok(Box::new(untyped.clone()));
+    }
+    // Trait objects can't be cloned, so round-trip it through its wire form.
+    Ok(bincode::deserialize(&bincode::serialize(request)?)?)
+}
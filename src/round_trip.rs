@@ -0,0 +1,339 @@
+//! Property tests that every registered request and response comes back
+//! from every codec as it went in, behind the `testing` feature:
+//!
+//! ```ignore
+//! #[test]
+//! fn messages_round_trip() {
+//!     let report = myproto::round_trip::check_registered(256).unwrap();
+//!     assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+//! }
+//! ```
+//!
+//! Instances are generated with proptest from each type's [`Schema`] entry,
+//! as the JSON serde would read the type from, then encoded and decoded
+//! with each [`Codec`]; what comes back must encode to the same JSON. A
+//! failure is shrunk to the smallest instance that still fails.
+//!
+//! Types that don't describe themselves, or whose fields refer to such a
+//! type or to any request or response, can't be generated and are skipped,
+//! as are types whose generated instances don't decode from JSON, usually
+//! because a `#[serde(with)]` gives a field a wire form other than its type's.
+
+use anyhow::{Result, anyhow, bail};
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+use proptest::strategy::{Union, ValueTree};
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::schema::{Schema, Shape, TypeRef, TypeSchema};
+use crate::{Codec, Request, Response, StreamingRequest, UploadRequest};
+
+/// How deep generated values nest: past it, options are `None` and lists
+/// and maps empty, so recursive types stay finite.
+const MAX_DEPTH: u32 = 4;
+
+/// Longest list or map generated.
+const MAX_LEN: usize = 4;
+
+/// What [`check_registered`] did with each type, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checked: Vec<String>,
+    /// Types left unchecked, with why.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Round-trips `cases` generated instances of every registered request,
+/// streaming request, upload request and response through every codec,
+/// failing with the first type that doesn't come back as it went in.
+pub fn check_registered(cases: u32) -> Result<Report> {
+    let schema = Schema::registered();
+    let mut report = Report::default();
+    let kinds: [(&[TypeSchema], Kind); 4] = [
+        (&schema.requests, Kind::Request),
+        (&schema.streaming_requests, Kind::StreamingRequest),
+        (&schema.upload_requests, Kind::UploadRequest),
+        (&schema.responses, Kind::Response),
+    ];
+    for (types, kind) in kinds {
+        for ty in types {
+            match check_type(&schema, ty, kind, cases)? {
+                None => report.checked.push(ty.name.clone()),
+                Some(reason) => report.skipped.push((ty.name.clone(), reason)),
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// As [`check_registered`], for the one message type tagged `name`; one
+/// that can't be generated fails rather than being skipped.
+pub fn check(name: &str, cases: u32) -> Result<()> {
+    let schema = Schema::registered();
+    let kinds = [
+        (&schema.requests, Kind::Request),
+        (&schema.streaming_requests, Kind::StreamingRequest),
+        (&schema.upload_requests, Kind::UploadRequest),
+        (&schema.responses, Kind::Response),
+    ];
+    let (ty, kind) = kinds
+        .into_iter()
+        .find_map(|(types, kind)| Some((types.iter().find(|ty| ty.name == name)?, kind)))
+        .ok_or_else(|| anyhow!("no message type {name} is registered"))?;
+    match check_type(&schema, ty, kind, cases)? {
+        None => Ok(()),
+        Some(reason) => bail!("{name} can't be checked: {reason}"),
+    }
+}
+
+/// Which trait a message type is registered under, and so what it decodes as.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Request,
+    StreamingRequest,
+    UploadRequest,
+    Response,
+}
+
+/// Checks one type, returning why it was skipped if it was.
+fn check_type(schema: &Schema, ty: &TypeSchema, kind: Kind, cases: u32) -> Result<Option<String>> {
+    let Some(shape) = &ty.shape else {
+        return Ok(Some("it doesn't describe itself".to_string()));
+    };
+    let fields = match shape_strategy(shape, schema, 0) {
+        Ok(fields) => fields,
+        Err(reason) => return Ok(Some(reason)),
+    };
+    let name = ty.name.clone();
+    // Tagged as typetag writes a boxed message.
+    let strategy = fields.prop_map(move |fields| {
+        let mut tagged = Map::new();
+        tagged.insert(name.clone(), fields);
+        Value::Object(tagged)
+    });
+    let round_trip = match kind {
+        Kind::Request => round_trip::<Box<dyn Request>>,
+        Kind::StreamingRequest => round_trip::<Box<dyn StreamingRequest>>,
+        Kind::UploadRequest => round_trip::<Box<dyn UploadRequest>>,
+        Kind::Response => round_trip::<Box<dyn Response>>,
+    };
+
+    let mut runner = TestRunner::new(Config {
+        cases,
+        failure_persistence: None,
+        ..Config::default()
+    });
+    // One that doesn't decode at all says the schema doesn't match the type,
+    // rather than that one instance is out of its range.
+    let sample = strategy
+        .new_tree(&mut runner)
+        .map_err(|reason| anyhow!("{}: {reason}", ty.name))?
+        .current();
+    if let Err(TestCaseError::Reject(reason)) = round_trip(&sample) {
+        return Ok(Some(reason.to_string()));
+    }
+    match runner.run(&strategy, |value| round_trip(&value)) {
+        Ok(()) => Ok(None),
+        Err(TestError::Abort(reason)) => Ok(Some(reason.to_string())),
+        Err(TestError::Fail(reason, value)) => {
+            bail!(
+                "{} doesn't round-trip: {reason}; smallest failing instance: {value}",
+                ty.name
+            )
+        }
+    }
+}
+
+/// Decodes `value` from JSON as a `T` and checks that every codec gives it back.
+fn round_trip<T: Serialize + DeserializeOwned>(value: &Value) -> Result<(), TestCaseError> {
+    let message: T = serde_json::from_value(value.clone())
+        .map_err(|e| TestCaseError::reject(format!("generated instances don't decode: {e}")))?;
+    let expected = serde_json::to_value(&message)
+        .map_err(|e| TestCaseError::fail(format!("it doesn't encode as JSON: {e}")))?;
+    for codec in Codec::ALL {
+        let bytes = codec
+            .encode(&message)
+            .map_err(|e| TestCaseError::fail(format!("{codec:?} can't encode it: {e:#}")))?;
+        let decoded: T = codec.decode(&bytes).map_err(|e| {
+            TestCaseError::fail(format!("{codec:?} can't decode what it encoded: {e:#}"))
+        })?;
+        let actual = serde_json::to_value(&decoded)
+            .map_err(|e| TestCaseError::fail(format!("it doesn't encode as JSON: {e}")))?;
+        if actual != expected {
+            return Err(TestCaseError::fail(format!(
+                "{codec:?} gave back {actual} for {expected}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn shape_strategy(
+    shape: &Shape,
+    schema: &Schema,
+    depth: u32,
+) -> Result<BoxedStrategy<Value>, String> {
+    Ok(match shape {
+        Shape::Unit => Just(Value::Null).boxed(),
+        Shape::Struct(fields) => {
+            let names: Vec<String> = fields.iter().map(|field| field.name.clone()).collect();
+            let values = fields
+                .iter()
+                .map(|field| type_strategy(&field.ty, schema, depth))
+                .collect::<Result<Vec<_>, _>>()?;
+            values
+                .prop_map(move |values| Value::Object(names.iter().cloned().zip(values).collect()))
+                .boxed()
+        }
+        Shape::Tuple(types) => tuple_strategy(types, schema, depth)?,
+        Shape::Enum(variants) => {
+            if variants.is_empty() {
+                return Err("it has no variants".to_string());
+            }
+            let variants = variants
+                .iter()
+                .map(|variant| {
+                    let name = variant.name.clone();
+                    if variant.shape == Shape::Unit {
+                        return Ok(Just(Value::String(name)).boxed());
+                    }
+                    Ok(shape_strategy(&variant.shape, schema, depth)?
+                        .prop_map(move |value| tagged(&name, value))
+                        .boxed())
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            Union::new(variants).boxed()
+        }
+    })
+}
+
+/// A tuple as serde writes it: a single field alone, several as a list.
+fn tuple_strategy(
+    types: &[TypeRef],
+    schema: &Schema,
+    depth: u32,
+) -> Result<BoxedStrategy<Value>, String> {
+    if let [ty] = types {
+        return type_strategy(ty, schema, depth);
+    }
+    let values = types
+        .iter()
+        .map(|ty| type_strategy(ty, schema, depth))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(values.prop_map(Value::Array).boxed())
+}
+
+fn type_strategy(
+    ty: &TypeRef,
+    schema: &Schema,
+    depth: u32,
+) -> Result<BoxedStrategy<Value>, String> {
+    let nested = depth + 1;
+    Ok(match ty {
+        TypeRef::Bool => any::<bool>().prop_map(Value::from).boxed(),
+        TypeRef::I8 => any::<i8>().prop_map(Value::from).boxed(),
+        TypeRef::I16 => any::<i16>().prop_map(Value::from).boxed(),
+        TypeRef::I32 => any::<i32>().prop_map(Value::from).boxed(),
+        // JSON numbers go no wider than 64 bits.
+        TypeRef::I64 | TypeRef::I128 => any::<i64>().prop_map(Value::from).boxed(),
+        TypeRef::U8 => any::<u8>().prop_map(Value::from).boxed(),
+        TypeRef::U16 => any::<u16>().prop_map(Value::from).boxed(),
+        TypeRef::U32 => any::<u32>().prop_map(Value::from).boxed(),
+        TypeRef::U64 | TypeRef::U128 => any::<u64>().prop_map(Value::from).boxed(),
+        // JSON has no NaN or infinities.
+        TypeRef::F32 => any::<f32>()
+            .prop_filter("finite", |f| f.is_finite())
+            .prop_map(Value::from)
+            .boxed(),
+        TypeRef::F64 => any::<f64>()
+            .prop_filter("finite", |f| f.is_finite())
+            .prop_map(Value::from)
+            .boxed(),
+        TypeRef::Char => any::<char>()
+            .prop_map(|c| Value::from(c.to_string()))
+            .boxed(),
+        TypeRef::String => any::<String>().prop_map(Value::from).boxed(),
+        TypeRef::Bytes => vec(any::<u8>(), 0..64).prop_map(Value::from).boxed(),
+        TypeRef::Duration => (any::<u64>(), 0..1_000_000_000u32)
+            .prop_map(|(secs, nanos)| serde_json::json!({ "secs": secs, "nanos": nanos }))
+            .boxed(),
+        TypeRef::Json => prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::from),
+        ]
+        .boxed(),
+        TypeRef::Request => return Err("it holds any request".to_string()),
+        TypeRef::Response => return Err("it holds any response".to_string()),
+        TypeRef::Unit => Just(Value::Null).boxed(),
+        TypeRef::Option(_) if depth >= MAX_DEPTH => Just(Value::Null).boxed(),
+        TypeRef::Option(inner) => {
+            let inner = type_strategy(inner, schema, nested)?;
+            prop_oneof![Just(Value::Null), inner].boxed()
+        }
+        TypeRef::List(_) if depth >= MAX_DEPTH => Just(Value::Array(Vec::new())).boxed(),
+        TypeRef::Map(..) if depth >= MAX_DEPTH => Just(Value::Object(Map::new())).boxed(),
+        TypeRef::List(inner) => vec(type_strategy(inner, schema, nested)?, 0..MAX_LEN)
+            .prop_map(Value::Array)
+            .boxed(),
+        TypeRef::Map(key, value) => {
+            // JSON keys are strings; serde reads numbers and the like back out of them.
+            let key = type_strategy(key, schema, nested)?.prop_map(|key| match key {
+                Value::String(key) => key,
+                key => key.to_string(),
+            });
+            btree_map(key, type_strategy(value, schema, nested)?, 0..MAX_LEN)
+                .prop_map(|map| Value::Object(map.into_iter().collect()))
+                .boxed()
+        }
+        TypeRef::Tuple(types) => tuple_strategy(types, schema, depth)?,
+        TypeRef::Result(ok, err) => {
+            let ok = type_strategy(ok, schema, nested)?.prop_map(|value| tagged("Ok", value));
+            let err = type_strategy(err, schema, nested)?.prop_map(|value| tagged("Err", value));
+            prop_oneof![ok, err].boxed()
+        }
+        TypeRef::Named(name) => {
+            if depth >= MAX_DEPTH * 2 {
+                return Err(format!("{name} nests too deeply to generate"));
+            }
+            let shape = schema
+                .get(name)
+                .and_then(|ty| ty.shape.as_ref())
+                .ok_or_else(|| format!("its field type {name} doesn't describe itself"))?;
+            shape_strategy(shape, schema, nested)?
+        }
+    })
+}
+
+/// `value` under `name`, as serde writes an enum variant.
+fn tagged(name: &str, value: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(name.to_string(), value);
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_messages_round_trip() {
+        let report = check_registered(64).unwrap();
+        assert!(!report.checked.is_empty());
+    }
+
+    #[test]
+    fn maps_past_max_depth_are_empty_objects() {
+        let map = TypeRef::Map(Box::new(TypeRef::String), Box::new(TypeRef::U32));
+        let strategy = type_strategy(&map, &Schema::registered(), MAX_DEPTH).unwrap();
+        let value = strategy
+            .new_tree(&mut TestRunner::default())
+            .unwrap()
+            .current();
+        assert_eq!(value, Value::Object(Map::new()));
+    }
+}
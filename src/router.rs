@@ -0,0 +1,77 @@
+use std::any::{Any, TypeId, type_name};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+use crate::{Context, Request, Response, TypedRequest};
+
+type Route =
+    dyn Fn(&dyn Request, Context) -> BoxFuture<'static, Result<Box<dyn Response>>> + Send + Sync;
+
+/// Handlers registered per request type, consulted before the type's own
+/// `Request::handle`. Installed with `ServerBuilder::router`.
+///
+/// Routed types still need `#[typetag::serde] impl Request` so they can be
+/// decoded, but may leave `handle` to its default, and different servers can
+/// route the same type differently or capture their own state.
+#[derive(Default, Clone)]
+pub struct Router {
+    /// Each route by its request type, with the type's name.
+    routes: HashMap<TypeId, (&'static str, Arc<Route>)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers every `T` with `handler`, replacing any earlier route for it.
+    ///
+    /// The handler gets its own copy of the request so its future can own it.
+    pub fn route<T, Fut>(
+        mut self,
+        handler: impl Fn(T, Context) -> Fut + Send + Sync + 'static,
+    ) -> Self
+    where
+        T: TypedRequest + Clone,
+        Fut: Future<Output = Result<T::Response>> + Send + 'static,
+    {
+        let route = move |req: &dyn Request, ctx: Context| {
+            let req = (req as &dyn Any)
+                .downcast_ref::<T>()
+                .expect("routes are keyed by their request type")
+                .clone();
+            handler(req, ctx)
+                .map(|result| result.map(|resp| Box::new(resp) as Box<dyn Response>))
+                .boxed()
+        };
+        self.routes
+            .insert(TypeId::of::<T>(), (type_name::<T>(), Arc::new(route)));
+        self
+    }
+
+    /// Whether there is a route for the request type `type_id`.
+    pub(crate) fn routes(&self, type_id: TypeId) -> bool {
+        self.routes.contains_key(&type_id)
+    }
+
+    /// The names of the request types with routes, sorted.
+    pub(crate) fn routed(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.routes.values().map(|&(name, _)| name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Runs the route for `req`'s type, or `None` if there isn't one.
+    pub(crate) fn dispatch(
+        &self,
+        req: &dyn Request,
+        ctx: &Context,
+    ) -> Option<BoxFuture<'static, Result<Box<dyn Response>>>> {
+        let (_, route) = self.routes.get(&(req as &dyn Any).type_id())?;
+        Some(route(req, ctx.clone()))
+    }
+}
@@ -0,0 +1,65 @@
+//! The little of an async runtime the client needs: tokio's natively, and
+//! the browser's on wasm32, where there is no tokio runtime to run on.
+
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio::time::Instant;
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A `timeout` that ran out before its future finished.
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+/// Runs `task` in the background, to completion or until the program ends.
+pub(crate) fn spawn(task: impl Future<Output = ()> + Send + 'static) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::spawn(task);
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_futures::spawn_local(task);
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+    // The browser's timers can't leave its thread, so one is set there and
+    // waited for through a channel, which keeps this future `Send`.
+    #[cfg(target_arch = "wasm32")]
+    {
+        let (mut tx, rx) = tokio::sync::oneshot::channel();
+        wasm_bindgen_futures::spawn_local(async move {
+            let timer = gloo_timers::future::sleep(duration);
+            // Clears the timer as soon as nobody is waiting for it.
+            let fired = matches!(
+                futures::future::select(timer, Box::pin(tx.closed())).await,
+                futures::future::Either::Left(_)
+            );
+            if fired {
+                let _ = tx.send(());
+            }
+        });
+        let _ = rx.await;
+    }
+}
+
+/// Runs `future`, giving up on it after `duration`.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| Elapsed);
+    #[cfg(target_arch = "wasm32")]
+    {
+        tokio::pin!(future);
+        tokio::select! {
+            output = &mut future => Ok(output),
+            _ = sleep(duration) => Err(Elapsed),
+        }
+    }
+}
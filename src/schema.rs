@@ -0,0 +1,201 @@
+//! A machine-readable description of the message types a program knows,
+//! for generating clients in other languages and for reviewing changes to
+//! the wire contract.
+//!
+//! Types declared with `#[request]` or `#[response]` describe themselves;
+//! anything else derives [`Describe`], including the plain types their
+//! fields refer to. [`Schema::registered`] lists every typetag-registered
+//! request and response, with the fields and doc comments of those that
+//! describe themselves, and every other described type alongside.
+//!
+//! Field types are read off the Rust source, so a type alias or a
+//! `#[serde(with)]` that changes a field's wire form isn't seen through.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::handshake::PROTOCOL_VERSION;
+use crate::{Request, Response, StreamingRequest, UploadRequest, registry, versioning};
+
+pub use myproto_macros::Describe;
+
+/// A type that can say what it looks like on the wire. Usually derived.
+pub trait Describe {
+    fn describe() -> TypeSchema;
+}
+
+/// Registers a described type for [`Schema::registered`]; `#[derive(Describe)]`
+/// submits one for every non-generic type.
+pub struct Described(pub fn() -> TypeSchema);
+
+inventory::collect!(Described);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub protocol_version: u16,
+    pub requests: Vec<TypeSchema>,
+    pub streaming_requests: Vec<TypeSchema>,
+    pub upload_requests: Vec<TypeSchema>,
+    pub responses: Vec<TypeSchema>,
+    /// Described types that aren't messages themselves, such as those the
+    /// messages' fields refer to.
+    pub types: Vec<TypeSchema>,
+}
+
+impl Schema {
+    /// Every message type registered in this program, sorted by name.
+    pub fn registered() -> Self {
+        let mut described: BTreeMap<String, TypeSchema> = inventory::iter::<Described>
+            .into_iter()
+            .map(|described| {
+                let schema = (described.0)();
+                (schema.name.clone(), schema)
+            })
+            .collect();
+        let mut messages = |names: Vec<&'static str>| -> Vec<TypeSchema> {
+            names
+                .into_iter()
+                .map(|name| {
+                    described
+                        .remove(name)
+                        .unwrap_or_else(|| TypeSchema::opaque(name))
+                })
+                .collect()
+        };
+        let mut requests = messages(registry::registered::<dyn Request>());
+        latest_versions(&mut requests);
+        let streaming_requests = messages(registry::registered::<dyn StreamingRequest>());
+        let upload_requests = messages(registry::registered::<dyn UploadRequest>());
+        let responses = messages(registry::registered::<dyn Response>());
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            requests,
+            streaming_requests,
+            upload_requests,
+            responses,
+            types: described.into_values().collect(),
+        }
+    }
+
+    /// Looks up a type by name among the messages and the other types; a
+    /// versioned request is found by its bare name too.
+    pub fn get(&self, name: &str) -> Option<&TypeSchema> {
+        [
+            &self.requests,
+            &self.streaming_requests,
+            &self.upload_requests,
+            &self.responses,
+            &self.types,
+        ]
+        .into_iter()
+        .flatten()
+        .find(|schema| schema.name == name || versioning::base_name(&schema.name) == name)
+    }
+}
+
+/// Drops the tags kept registered for previous versions of versioned requests.
+fn latest_versions(messages: &mut Vec<TypeSchema>) {
+    let mut latest: BTreeMap<String, u32> = BTreeMap::new();
+    for message in messages.iter() {
+        let (name, version) = versioning::parse_tag(&message.name);
+        let entry = latest.entry(name.to_string()).or_default();
+        *entry = (*entry).max(version);
+    }
+    messages.retain(|message| {
+        let (name, version) = versioning::parse_tag(&message.name);
+        latest.get(name) == Some(&version)
+    });
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TypeSchema {
+    /// The name typetag tags the type with: its bare identifier, followed by
+    /// `@N` from version 2 of a versioned request on.
+    pub name: String,
+    pub docs: Option<String>,
+    /// What a request is answered with, if it declares it.
+    pub response: Option<String>,
+    /// `None` for a registered type that doesn't implement [`Describe`].
+    pub shape: Option<Shape>,
+}
+
+impl TypeSchema {
+    fn opaque(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            docs: None,
+            response: None,
+            shape: None,
+        }
+    }
+}
+
+/// How a type's fields are laid out, in serde's terms.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Shape {
+    Unit,
+    Struct(Vec<Field>),
+    /// A tuple struct; serde writes one with a single field as that field alone.
+    Tuple(Vec<TypeRef>),
+    Enum(Vec<Variant>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub docs: Option<String>,
+    pub ty: TypeRef,
+    /// Whether the field has `#[serde(default)]`, so clients using a
+    /// self-describing codec may leave it out.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub docs: Option<String>,
+    /// Never an `Enum`.
+    pub shape: Shape,
+}
+
+/// A field's type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TypeRef {
+    Bool,
+    I8,
+    I16,
+    I32,
+    /// Also `isize`, which serde writes as an `i64`.
+    I64,
+    I128,
+    U8,
+    U16,
+    U32,
+    /// Also `usize`, which serde writes as a `u64`.
+    U64,
+    U128,
+    F32,
+    F64,
+    Char,
+    String,
+    /// `bytes::Bytes`, which serde writes as bytes rather than a list of `u8`.
+    Bytes,
+    /// `std::time::Duration`, as serde writes it: `secs` and `nanos`.
+    Duration,
+    /// A `serde_json::Value`, any JSON at all.
+    Json,
+    /// Any registered request, tagged with its name.
+    Request,
+    /// Any registered response, tagged with its name.
+    Response,
+    Unit,
+    Option(Box<TypeRef>),
+    List(Box<TypeRef>),
+    Map(Box<TypeRef>, Box<TypeRef>),
+    Tuple(Vec<TypeRef>),
+    Result(Box<TypeRef>, Box<TypeRef>),
+    /// Another type, by name; described in the schema if it implements [`Describe`].
+    Named(String),
+}
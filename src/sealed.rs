@@ -0,0 +1,327 @@
+//! End-to-end encryption of request and response payloads, for deployments
+//! that terminate TLS at a proxy but don't want it reading what passes.
+//!
+//! A client whose `ClientConfig::encryption` picks a key for a request's
+//! type sends it as a [`Sealed`] request instead: the request in bincode,
+//! encrypted with XChaCha20-Poly1305 under that key, beside the key's id.
+//! The server opens it with the key of that id from its own
+//! [`KeyProvider`], runs the request through its authorization and
+//! middleware as usual, and answers with the response sealed under the same
+//! key and bound to the request's nonce, which the client opens before
+//! handing it back. A request inside is refused unless it came under the
+//! key the server's provider picks for its type. Framing, headers and errors
+//! stay in the clear, as does the fact that a `Sealed` request was made;
+//! only the type inside it is hidden, so per-type timeouts and limits on the
+//! server apply to `Sealed` as a whole.
+//!
+//! Keys are per request type, or per tenant by giving each tenant's
+//! clients their own provider; a server refuses unsealed requests of any
+//! type its provider picks a key for.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::client::downcast_response;
+use crate::schema::Describe;
+use crate::server::pipeline;
+use crate::{Context, ErrorCode, ProtocolError, Request, Response, ResponseResult, TypedRequest};
+
+pub const KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 24;
+
+/// Where each side finds its payload keys. Its `Debug` should leave the
+/// keys themselves out.
+pub trait KeyProvider: Send + Sync + fmt::Debug + 'static {
+    /// The id of the key to seal requests of `request_type` with; `None`
+    /// sends them as they are.
+    fn key_id(&self, request_type: &str) -> Option<String>;
+
+    /// The key named `id`, if this side has it.
+    fn key(&self, id: &str) -> Option<[u8; KEY_LEN]>;
+}
+
+/// Keys known in advance, picked by request type with an optional default.
+#[derive(Clone, Default)]
+pub struct StaticKeys {
+    keys: HashMap<String, [u8; KEY_LEN]>,
+    by_type: HashMap<String, String>,
+    default: Option<String>,
+}
+
+impl StaticKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, id: impl Into<String>, key: [u8; KEY_LEN]) -> Self {
+        self.keys.insert(id.into(), key);
+        self
+    }
+
+    /// Seals requests of `request_type` with the key `id`.
+    pub fn seal(mut self, request_type: impl Into<String>, id: impl Into<String>) -> Self {
+        self.by_type.insert(request_type.into(), id.into());
+        self
+    }
+
+    /// Seals requests of every type not given its own key with the key `id`.
+    pub fn seal_all(mut self, id: impl Into<String>) -> Self {
+        self.default = Some(id.into());
+        self
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn key_id(&self, request_type: &str) -> Option<String> {
+        self.by_type
+            .get(request_type)
+            .or(self.default.as_ref())
+            .cloned()
+    }
+
+    fn key(&self, id: &str) -> Option<[u8; KEY_LEN]> {
+        self.keys.get(id).copied()
+    }
+}
+
+/// The keys are left out.
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("StaticKeys")
+            .field("keys", &ids)
+            .field("by_type", &self.by_type)
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+/// A random key, for `StaticKeys::key`.
+pub fn generate_key() -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    getrandom::fill(&mut key).expect("the OS random number generator is available");
+    key
+}
+
+/// A request encrypted under the key `key_id`; see the [module docs](self).
+#[derive(Serialize, Deserialize, Debug, Describe)]
+#[schema(response = SealedResponse)]
+pub struct Sealed {
+    pub key_id: String,
+    pub nonce: Bytes,
+    pub ciphertext: Bytes,
+}
+
+/// A response encrypted under the key its `Sealed` request came in.
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct SealedResponse {
+    pub key_id: String,
+    pub nonce: Bytes,
+    pub ciphertext: Bytes,
+}
+
+#[typetag::serde]
+impl Response for SealedResponse {
+    /// Ciphertext doesn't compress.
+    fn compressible(&self) -> bool {
+        false
+    }
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl Request for Sealed {
+    async fn handle(&self, ctx: &Context) -> Result<Box<dyn Response>> {
+        let Some(keys) = &ctx.server().key_provider else {
+            return Err(ProtocolError::new(
+                ErrorCode::Unsupported,
+                "this server takes no sealed requests",
+            )
+            .into());
+        };
+        let key = keys.key(&self.key_id).ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::PermissionDenied,
+                format!("unknown key {}", self.key_id),
+            )
+        })?;
+        let plaintext = open(
+            &key,
+            &self.key_id,
+            REQUEST,
+            &[],
+            &self.nonce,
+            &self.ciphertext,
+        )
+        .map_err(|e| ProtocolError::new(ErrorCode::InvalidRequest, format!("{e:#}")))?;
+        let request: Box<dyn Request> = bincode::deserialize(&plaintext)?;
+        if keys.key_id(request.typetag_name()).as_deref() != Some(&*self.key_id) {
+            return Err(ProtocolError::new(
+                ErrorCode::PermissionDenied,
+                format!(
+                    "{} isn't sealed with the key it takes",
+                    request.typetag_name()
+                ),
+            )
+            .into());
+        }
+        let middleware = ctx.server().middleware.clone();
+        let response = pipeline(request, ctx, &middleware).await?;
+
+        let (nonce, ciphertext) = seal(
+            &key,
+            &self.key_id,
+            RESPONSE,
+            &self.nonce,
+            &bincode::serialize(&response)?,
+        )?;
+        Ok(Box::new(SealedResponse {
+            key_id: self.key_id.clone(),
+            nonce,
+            ciphertext,
+        }))
+    }
+
+    fn compressible(&self) -> bool {
+        false
+    }
+}
+
+impl TypedRequest for Sealed {
+    type Response = SealedResponse;
+}
+
+/// Bound into each ciphertext, so a sealed response can't be passed off as
+/// a request or the other way round. A response's ciphertext is also bound
+/// to its request's nonce, so it can't be replayed as the answer to another.
+const REQUEST: &[u8] = b"request";
+const RESPONSE: &[u8] = b"response";
+
+/// `request` sealed under the key `keys` picks for its type, if any.
+pub(crate) fn seal_request(
+    keys: &dyn KeyProvider,
+    request: Box<dyn Request>,
+) -> Result<Box<dyn Request>> {
+    let Some(key_id) = keys.key_id(request.typetag_name()) else {
+        return Ok(request);
+    };
+    let key = keys
+        .key(&key_id)
+        .ok_or_else(|| anyhow!("no key {key_id} to seal {}", request.typetag_name()))?;
+    let (nonce, ciphertext) = seal(&key, &key_id, REQUEST, &[], &bincode::serialize(&request)?)?;
+    Ok(Box::new(Sealed {
+        key_id,
+        nonce,
+        ciphertext,
+    }))
+}
+
+/// The key id and nonce `request` was sealed with, if it was, to open its
+/// response with.
+pub(crate) fn sealed_with(request: &dyn Request) -> Option<(String, Bytes)> {
+    let sealed = (request as &dyn Any).downcast_ref::<Sealed>()?;
+    Some((sealed.key_id.clone(), sealed.nonce.clone()))
+}
+
+/// `result` with a sealed response opened, given what its request was
+/// sealed with.
+pub(crate) fn open_result(
+    keys: &dyn KeyProvider,
+    request: Option<&(String, Bytes)>,
+    result: ResponseResult,
+) -> ResponseResult {
+    let response = result?;
+    if !(&*response as &dyn Any).is::<SealedResponse>() {
+        return Ok(response);
+    }
+    let opened = (|| {
+        let sealed = downcast_response::<SealedResponse>(response)?;
+        let (key_id, request_nonce) = request.context("answer to an unsealed request is sealed")?;
+        if sealed.key_id != *key_id {
+            bail!("sealed with key {}, not {key_id}", sealed.key_id);
+        }
+        let key = keys.key(key_id).ok_or_else(|| anyhow!("no key {key_id}"))?;
+        let plaintext = open(
+            &key,
+            key_id,
+            RESPONSE,
+            request_nonce,
+            &sealed.nonce,
+            &sealed.ciphertext,
+        )?;
+        Ok(bincode::deserialize(&plaintext)?)
+    })();
+    opened.map_err(|e: anyhow::Error| {
+        ProtocolError::new(
+            ErrorCode::Malformed,
+            format!("could not open sealed response: {e:#}"),
+        )
+    })
+}
+
+/// The refusal of an unsealed request the server's keys say to seal.
+pub(crate) fn check_sealed(
+    keys: &dyn KeyProvider,
+    request_type: &str,
+) -> Result<(), ProtocolError> {
+    if request_type == "Sealed" || keys.key_id(request_type).is_none() {
+        return Ok(());
+    }
+    Err(ProtocolError::new(
+        ErrorCode::PermissionDenied,
+        format!("{request_type} must be sent sealed"),
+    ))
+}
+
+/// Encrypts `plaintext` with `kind`, `key_id` and `bound` as associated data.
+fn seal(
+    key: &[u8; KEY_LEN],
+    key_id: &str,
+    kind: &[u8],
+    bound: &[u8],
+    plaintext: &[u8],
+) -> Result<(Bytes, Bytes)> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).expect("the OS random number generator is available");
+    let aad = [kind, key_id.as_bytes(), bound].concat();
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| anyhow!("encryption failed"))?;
+    Ok((Bytes::copy_from_slice(&nonce), Bytes::from(ciphertext)))
+}
+
+fn open(
+    key: &[u8; KEY_LEN],
+    key_id: &str,
+    kind: &[u8],
+    bound: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().context("bad nonce length")?;
+    let aad = [kind, key_id.as_bytes(), bound].concat();
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| anyhow!("the payload does not open with key {key_id}"))
+}
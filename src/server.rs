@@ -0,0 +1,1308 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::future::join_all;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::access_log::{AccessLog, RequestKind};
+#[cfg(unix)]
+use crate::admin;
+use crate::audit::{self, Audit};
+use crate::auth::Authenticator;
+use crate::authorization::Authorization;
+use crate::ban::{Ban, Bans, Violation};
+use crate::cluster::{Cluster, ClusterConfig};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::config::OverLimit;
+use crate::connection::{self, ConnectionRegistry};
+use crate::envelope::{self, RequestFrame, ResponseFrame, ServerMessage};
+use crate::extensions::Extensions;
+use crate::idempotency::IdempotencyKeys;
+use crate::ip_filter::IpFilter;
+use crate::jobs::Jobs;
+use crate::journal::Journal;
+use crate::kv::Store;
+use crate::latency::{Latencies, LatencySummary};
+use crate::lifecycle::ConnectionHandler;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::listener::{Accepted, Binding, Listener};
+use crate::load::Load;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::load::Running;
+use crate::metrics;
+use crate::middleware::{Middleware, Next};
+use crate::module::{Module, Registrar};
+use crate::namespace::{Namespace, Namespaces, Scope};
+use crate::panic;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::proxy_protocol;
+use crate::pubsub::TopicRegistry;
+use crate::quota::Quotas;
+use crate::rate_limit::RateLimiter;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::recording::Recorded;
+use crate::relay::PeerRegistry;
+use crate::request_journal::RequestJournal;
+use crate::resumption::ParkedSessions;
+use crate::router::Router;
+use crate::session_store::{MemorySessionStore, SessionStore};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::supervisor::Connections;
+use crate::{
+    Connection, Context, ErrorCode, ProtocolError, Request, Response, ResponseResult, ServerConfig,
+};
+
+/// Permission bits `PutFile` may give a file unless the server is built
+/// with others.
+const DEFAULT_FILE_MODE: u32 = 0o755;
+
+/// Longest file `PutFile` writes unless the server is built with another limit.
+const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+
+#[derive(Clone)]
+pub struct Server {
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+    open_connections: Load,
+    pub(crate) state: Arc<dyn Any + Send + Sync>,
+    pub(crate) extensions: Arc<Extensions>,
+    pub(crate) next_connection_id: Arc<AtomicU64>,
+    pub(crate) shutdown: CancellationToken,
+    pub(crate) topics: TopicRegistry,
+    pub(crate) connections: ConnectionRegistry,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) multi_tenant: bool,
+    authorization: Option<Arc<Authorization>>,
+    pub(crate) quotas: Option<Arc<Quotas>>,
+    pub(crate) idempotency_keys: IdempotencyKeys,
+    pub(crate) latencies: Latencies,
+    pub(crate) parked_sessions: ParkedSessions,
+    pub(crate) peers: PeerRegistry,
+    pub(crate) jobs: Arc<Jobs>,
+    pub(crate) cluster: Option<Arc<Cluster>>,
+    rate_limiter: Arc<RwLock<Option<Arc<RateLimiter>>>>,
+    pub(crate) ip_filter: Option<Arc<IpFilter>>,
+    rejected_connections: Arc<AtomicU64>,
+    bans: Arc<Bans>,
+    pub(crate) middleware: Arc<[Arc<dyn Middleware>]>,
+    pub(crate) namespaces: Arc<Namespaces>,
+    pub(crate) connection_handler: Option<Arc<dyn ConnectionHandler>>,
+    pub(crate) modules: Arc<[Arc<dyn Module>]>,
+    started: Instant,
+    drain_requested: CancellationToken,
+    /// Cancelled once `serve_all` has drained and returned.
+    drained: CancellationToken,
+    serving: Arc<AtomicBool>,
+    pub(crate) drain_retry_after: Arc<RwLock<Option<Duration>>>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) log_filter: Option<Arc<LogFilter>>,
+    reload: Option<Arc<Reload>>,
+    pub(crate) access_log: Option<Arc<dyn AccessLog>>,
+    pub(crate) audit: Option<Arc<Audit>>,
+    pub(crate) request_journal: Option<Arc<RequestJournal>>,
+    kv_store: Option<Arc<dyn Store>>,
+    pub(crate) session_store: Arc<dyn SessionStore>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    record_dir: Option<Arc<Path>>,
+    file_root: Option<Arc<Path>>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    file_mode: u32,
+    max_file_size: u64,
+    #[cfg(feature = "noise")]
+    noise: Arc<RwLock<Option<Arc<crate::noise::NoiseConfig>>>>,
+    #[cfg(feature = "encryption")]
+    pub(crate) key_provider: Option<Arc<dyn crate::sealed::KeyProvider>>,
+    pub(crate) load: Load,
+    pub(crate) router: Arc<Router>,
+    #[cfg(feature = "tower")]
+    pub(crate) stack: Option<crate::service::Stack>,
+    #[cfg(unix)]
+    pub(crate) handoff: crate::upgrade::Handoff,
+}
+
+/// Replaces the process's log filter with the given directives, e.g. `myproto=debug`.
+pub type LogFilter = dyn Fn(&str) -> Result<()> + Send + Sync;
+
+/// Re-reads the server's settings from wherever they came from and applies
+/// them, typically with `Server::reconfigure`.
+pub type Reload = dyn Fn(&Server) -> Result<()> + Send + Sync;
+
+pub struct ServerBuilder {
+    config: ServerConfig,
+    state: Arc<dyn Any + Send + Sync>,
+    extensions: Extensions,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    multi_tenant: bool,
+    authorization: Option<Authorization>,
+    quotas: Option<Quotas>,
+    ip_filter: Option<IpFilter>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    namespaces: HashMap<String, Namespace>,
+    connection_handler: Option<Arc<dyn ConnectionHandler>>,
+    modules: Vec<Arc<dyn Module>>,
+    log_filter: Option<Arc<LogFilter>>,
+    reload: Option<Arc<Reload>>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    audit: Option<Audit>,
+    record_dir: Option<PathBuf>,
+    journal: Option<Journal>,
+    request_journal: Option<RequestJournal>,
+    kv_store: Option<Arc<dyn Store>>,
+    session_store: Option<Arc<dyn SessionStore>>,
+    cluster: Option<ClusterConfig>,
+    file_root: Option<PathBuf>,
+    file_mode: u32,
+    max_file_size: u64,
+    #[cfg(feature = "noise")]
+    noise: Option<crate::noise::NoiseConfig>,
+    #[cfg(feature = "encryption")]
+    key_provider: Option<Arc<dyn crate::sealed::KeyProvider>>,
+    router: Router,
+    #[cfg(feature = "tower")]
+    stack: Option<crate::service::Stack>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: ServerConfig::default(),
+            state: Arc::new(()),
+            extensions: Extensions::new(),
+            authenticator: None,
+            multi_tenant: false,
+            authorization: None,
+            quotas: None,
+            ip_filter: None,
+            middleware: Vec::new(),
+            namespaces: HashMap::new(),
+            connection_handler: None,
+            modules: Vec::new(),
+            log_filter: None,
+            reload: None,
+            access_log: None,
+            audit: None,
+            record_dir: None,
+            journal: None,
+            request_journal: None,
+            kv_store: None,
+            session_store: None,
+            cluster: None,
+            file_root: None,
+            file_mode: DEFAULT_FILE_MODE,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            #[cfg(feature = "noise")]
+            noise: None,
+            #[cfg(feature = "encryption")]
+            key_provider: None,
+            router: Router::default(),
+            #[cfg(feature = "tower")]
+            stack: None,
+        }
+    }
+
+    /// The settings currently in force; `reconfigure` may replace them at any time.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Switches to `config` without dropping any session.
+    ///
+    /// Limits, timeouts and the rate limit apply from the next request or
+    /// connection on; a changed rate limit starts every client with a full
+    /// bucket. Settings fixed when a session starts, like codecs, frame length
+    /// and heartbeats, only apply to sessions accepted afterwards, and the
+    /// admin socket can't be moved.
+    pub fn reconfigure(&self, config: ServerConfig) {
+        let mut current = self.config.write().unwrap();
+        if config.admin_socket != current.admin_socket {
+            tracing::warn!("The admin socket can't be moved while running; keeping the old one");
+        }
+        if config.rate_limit != current.rate_limit {
+            *self.rate_limiter.write().unwrap() = config
+                .rate_limit
+                .map(|limit| Arc::new(RateLimiter::new(limit)));
+        }
+        let config = ServerConfig {
+            admin_socket: current.admin_socket.clone(),
+            ..config
+        };
+        *current = Arc::new(config);
+        drop(current);
+        self.open_connections.recheck();
+        tracing::info!("Configuration reloaded");
+    }
+
+    /// Runs the hook installed with `ServerBuilder::reload`, as the admin
+    /// socket's `reload` command does.
+    pub fn reload(&self) -> Result<()> {
+        let Some(reload) = &self.reload else {
+            anyhow::bail!("this server has no reload hook installed");
+        };
+        reload(self)
+    }
+
+    /// Swaps in `config`'s key pair and trusted keys for connections accepted
+    /// from now on, e.g. from a `ServerBuilder::reload` hook when keys are
+    /// rotated; sessions already running keep the keys they agreed on. Fails
+    /// on a server built without `ServerBuilder::noise`, whose clients expect
+    /// plaintext.
+    #[cfg(feature = "noise")]
+    pub fn set_noise(&self, config: crate::noise::NoiseConfig) -> Result<()> {
+        let mut noise = self.noise.write().unwrap();
+        if noise.is_none() {
+            anyhow::bail!("this server doesn't use Noise, so it has no keys to replace");
+        }
+        *noise = Some(Arc::new(config));
+        tracing::info!("Noise keys replaced");
+        Ok(())
+    }
+
+    pub(crate) fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.rate_limiter.read().unwrap().clone()
+    }
+
+    /// Handles to every currently connected session.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.connections.snapshot()
+    }
+
+    /// The session with id `connection_id`, if it is connected to this
+    /// server; ids are each cluster node's own, so never one on another node.
+    pub fn connection(&self, connection_id: u64) -> Option<Connection> {
+        self.connections.get(connection_id)
+    }
+
+    /// Pushes `message` to the session with id `connection_id`, failing with
+    /// `ErrorCode::PeerUnavailable` if no such session is connected here.
+    /// Pushes aren't forwarded through a cluster: a client on another node
+    /// is reached by the relay name it registered, with `SendTo`.
+    pub async fn push_to(&self, connection_id: u64, message: Box<dyn Response>) -> Result<()> {
+        let Some(connection) = self.connections.get(connection_id) else {
+            return Err(ProtocolError::new(
+                ErrorCode::PeerUnavailable,
+                format!("connection {connection_id} is not connected to this node"),
+            )
+            .into());
+        };
+        connection.push(message).await
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Pushes `message` to every connected client, returning how many
+    /// sessions it was queued for. A client slow to read doesn't hold up
+    /// the others, though under `SlowClient::Block` this waits for it.
+    pub async fn broadcast(&self, message: Box<dyn Response>) -> Result<usize> {
+        connection::fan_out(self.connections.snapshot(), &ServerMessage::Push(message)).await
+    }
+
+    /// Requests, streams and uploads currently running across every session.
+    pub fn in_flight(&self) -> usize {
+        self.load.running()
+    }
+
+    /// The extensions registered with `ServerBuilder::extension`.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// How long ago the server was built.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Whether `serve` has been asked to shut down and is draining sessions.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Makes `serve` stop accepting and drain its sessions, as if its
+    /// shutdown future had resolved.
+    pub fn begin_drain(&self) {
+        self.drain_requested.cancel();
+    }
+
+    /// Drains the server for a rolling restart: `serve` stops accepting,
+    /// tells every client that negotiated `Features::GO_AWAY` to come back
+    /// after `retry_after`, and waits up to `ServerConfig::drain_timeout` for
+    /// the calls in flight to finish. Resolves once `serve` has returned,
+    /// or at once if the server isn't serving.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn drain(&self, retry_after: Option<Duration>) {
+        *self.drain_retry_after.write().unwrap() = retry_after;
+        self.begin_drain();
+        if self.serving.load(Ordering::Acquire) {
+            self.drained.cancelled().await;
+        }
+    }
+
+    /// The peers banned for breaking the protocol, soonest to be let back first.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.list()
+    }
+
+    /// Lets `ip` connect again, returning whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans.unban(ip)
+    }
+
+    /// Lifts every ban, returning how many there were.
+    pub fn clear_bans(&self) -> usize {
+        self.bans.clear()
+    }
+
+    /// Counts `violation` against `addr`'s IP under `ServerConfig::ban`,
+    /// closing the peer's sessions if that gets it banned.
+    pub(crate) fn misbehaved(&self, addr: SocketAddr, violation: Violation) {
+        let Some(policy) = self.config().ban else {
+            return;
+        };
+        // Unix socket peers and detached connections have no address of their own.
+        if addr.port() == 0 {
+            return;
+        }
+        let ip = addr.ip().to_canonical();
+        if !self.bans.record(&policy, ip) {
+            return;
+        }
+        tracing::warn!(
+            %ip,
+            violation = violation.as_str(),
+            duration = ?policy.duration,
+            "Banning peer: too many protocol violations"
+        );
+        metrics::peer_banned(violation.as_str());
+        for connection in self.connections() {
+            if connection.peer_addr().ip().to_canonical() == ip {
+                connection.close();
+            }
+        }
+    }
+
+    /// Connections closed straight after accept, by the IP filter, a ban or
+    /// the connection limit, since the server started.
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// How long each request type has taken to handle since the server
+    /// started, by type name.
+    pub fn latencies(&self) -> Vec<LatencySummary> {
+        self.latencies.summaries()
+    }
+
+    pub(crate) fn file_root(&self) -> Option<&Path> {
+        self.file_root.as_deref()
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn file_mode(&self) -> u32 {
+        self.file_mode
+    }
+
+    pub(crate) fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// The store behind the key-value requests, if the server has one.
+    pub fn kv_store(&self) -> Option<&dyn Store> {
+        self.kv_store.as_deref()
+    }
+
+    /// The pub/sub registry shared by every session, for publishing from outside handlers.
+    pub fn topics(&self) -> &TopicRegistry {
+        &self.topics
+    }
+
+    /// Runs the requests in the server's `RequestJournal` through their
+    /// handlers again, oldest first, returning how many there were; call it
+    /// before serving so clients find the state they left. Without a
+    /// journal, or on a second call, there is nothing to replay.
+    pub async fn replay_requests(&self) -> Result<usize> {
+        match &self.request_journal {
+            Some(journal) => journal.replay(self).await,
+            None => Ok(0),
+        }
+    }
+
+    /// Accepts connections until `shutdown` resolves or `begin_drain` or
+    /// `drain` is called, then stops accepting, asks every session to finish
+    /// its current frame, and waits up to `ServerConfig::drain_timeout`
+    /// before aborting the stragglers.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn serve(
+        &self,
+        listener: impl Into<Listener>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        self.serve_all(vec![Binding::new(listener)], shutdown).await
+    }
+
+    /// Like `serve`, but accepts on every one of `bindings` at once, each on
+    /// its own task so that shards from `Listener::bind_shards` spread over
+    /// the runtime's threads. A binding whose own shutdown resolves stops and
+    /// drains on its own; the rest keep serving until `shutdown`,
+    /// `begin_drain`, or their own shutdown.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn serve_all(
+        &self,
+        bindings: Vec<Binding>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        self.serving.store(true, Ordering::Release);
+        let served = self.serve_bindings(bindings, shutdown).await;
+        self.serving.store(false, Ordering::Release);
+        self.drained.cancel();
+        served
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn serve_bindings(
+        &self,
+        bindings: Vec<Binding>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        self.start_modules().await?;
+        self.topics.resume_scheduled();
+        let admin = self.start_admin().await?;
+        let links = match &self.cluster {
+            Some(cluster) => cluster.start(self),
+            None => Vec::new(),
+        };
+
+        let mut listening = tokio::task::JoinSet::new();
+        for binding in bindings {
+            let server = self.clone();
+            listening.spawn(async move { server.listen(binding).await }.in_current_span());
+        }
+        let listeners = listening.join_all();
+        tokio::pin!(listeners);
+        tokio::select! {
+            _ = &mut listeners => {}
+            _ = async {
+                tokio::select! {
+                    _ = shutdown => {}
+                    _ = self.drain_requested.cancelled() => {}
+                }
+            } => {
+                self.shutdown.cancel();
+                listeners.await;
+            }
+        }
+
+        for link in links {
+            link.abort();
+        }
+        if let Some(admin) = admin {
+            admin.abort();
+            #[cfg(unix)]
+            let handed_off = self.handoff.handed_off();
+            #[cfg(not(unix))]
+            let handed_off = false;
+            if let Some(path) = &self.config().admin_socket
+                && !handed_off
+            {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        self.stop_modules(self.modules.len()).await;
+        Ok(())
+    }
+
+    /// Runs every module's `on_start`, stopping the ones already started if one fails.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn start_modules(&self) -> Result<()> {
+        for (started, module) in self.modules.iter().enumerate() {
+            if let Err(e) = module.on_start(self).await {
+                self.stop_modules(started).await;
+                return Err(e.context(format!("module {} failed to start", module.name())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `on_shutdown` for the first `started` modules, last first.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn stop_modules(&self, started: usize) {
+        for module in self.modules[..started].iter().rev() {
+            module.on_shutdown(self).await;
+        }
+    }
+
+    /// Serves one binding until it or the whole server shuts down, then drains its sessions.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn listen(&self, binding: Binding) {
+        #[cfg(unix)]
+        let offered = self.handoff.register(&binding).inspect_err(|e| {
+            tracing::warn!(listener = %binding.name, error = %e, "Listener can't be handed over")
+        });
+        let Binding {
+            listener,
+            name,
+            tcp,
+            proxy_protocol,
+            websocket,
+            inherited,
+            shutdown,
+        } = binding;
+        // Sessions accepted here see this listener's shutdown as the server's.
+        let server = self.child();
+        let mut connections = Connections::new(&name);
+        tokio::pin!(shutdown);
+        tracing::info!(listener = %name, "Listening");
+
+        loop {
+            tokio::select! {
+                accepted = server.admit(&listener) => {
+                    let (stream, addr, slot) = match accepted {
+                        Ok(accepted) => {
+                            metrics::connection_accepted(&name);
+                            accepted
+                        }
+                        Err(e) => {
+                            tracing::warn!(listener = %name, error = %e, "Failed to accept connection");
+                            continue;
+                        }
+                    };
+                    // Peers on a Unix socket have no address to filter by.
+                    if matches!(stream, Accepted::Tcp(_)) && !server.permits(addr) {
+                        continue;
+                    }
+                    tracing::info!(%addr, listener = %name, "Client connected");
+
+                    let session = server.clone();
+                    match stream {
+                        Accepted::Tcp(stream) => {
+                            let options = tcp.clone().unwrap_or_else(|| server.config().tcp.clone());
+                            if let Err(e) = options.apply(&stream) {
+                                tracing::warn!(%addr, error = %e, "Failed to set TCP options");
+                            }
+                            connections.spawn(addr, async move {
+                                session.run_client(stream, addr, proxy_protocol, websocket).await;
+                                drop(slot);
+                            });
+                        }
+                        #[cfg(unix)]
+                        Accepted::Unix(stream) => {
+                            connections.spawn(addr, async move {
+                                session.run_client(stream, addr, proxy_protocol, websocket).await;
+                                drop(slot);
+                            });
+                        }
+                        #[cfg(windows)]
+                        Accepted::Pipe(stream) => {
+                            connections.spawn(addr, async move {
+                                session.run_client(stream, addr, proxy_protocol, websocket).await;
+                                drop(slot);
+                            });
+                        }
+                    }
+                }
+
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+
+                _ = &mut shutdown => break,
+                _ = server.shutdown.cancelled() => break,
+            }
+        }
+
+        // Closing the listener alone would leave the copy kept for upgrades accepting.
+        #[cfg(unix)]
+        drop(offered);
+        #[cfg(unix)]
+        let inherited = inherited || self.handoff.handed_off();
+        if !inherited {
+            listener.cleanup();
+        }
+        server.shutdown.cancel();
+        tracing::info!(listener = %name, active = connections.len(), "Draining connections");
+
+        connections.drain(self.config().drain_timeout).await;
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn run_client<S>(
+        &self,
+        mut stream: S,
+        addr: SocketAddr,
+        proxy_protocol: bool,
+        websocket: bool,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let peer_addr = if proxy_protocol {
+            let header = proxy_protocol::read_header(&mut stream);
+            match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, header).await {
+                Ok(Ok(Some(client))) => {
+                    tracing::info!(%addr, %client, "Client relayed by PROXY header");
+                    if !self.permits(client) {
+                        return;
+                    }
+                    client
+                }
+                Ok(Ok(None)) => addr,
+                Ok(Err(e)) => {
+                    tracing::warn!(%addr, error = %format!("{e:#}"), "Rejecting connection");
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!(%addr, "Rejecting connection: no PROXY header in time");
+                    return;
+                }
+            }
+        } else {
+            addr
+        };
+        #[cfg(feature = "websocket")]
+        if websocket {
+            let result = match crate::websocket::accept(stream).await {
+                Ok(stream) => self.handle_client(stream, peer_addr).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::error!(%addr, error = %e, "Error handling client");
+            }
+            return;
+        }
+        #[cfg(not(feature = "websocket"))]
+        let _ = websocket;
+        if let Err(e) = self.handle_client(stream, peer_addr).await {
+            tracing::error!(%addr, error = %e, "Error handling client");
+        }
+    }
+
+    #[cfg(unix)]
+    async fn start_admin(&self) -> Result<Option<tokio::task::JoinHandle<()>>> {
+        let config = self.config();
+        let Some(path) = &config.admin_socket else {
+            return Ok(None);
+        };
+        // The process being upgraded still listens on the socket, so it is
+        // replaced rather than refused.
+        if crate::upgrade::started_by_upgrade() {
+            let _ = std::fs::remove_file(path);
+        }
+        let listener = crate::listener::bind_unix(path).await?;
+        tracing::info!(path = %path.display(), "Admin socket listening");
+        Ok(Some(tokio::spawn(admin::serve(listener, self.clone()))))
+    }
+
+    #[cfg(all(not(unix), not(target_arch = "wasm32")))]
+    async fn start_admin(&self) -> Result<Option<tokio::task::JoinHandle<()>>> {
+        if self.config().admin_socket.is_some() {
+            tracing::warn!("Admin sockets are only supported on Unix; ignoring admin_socket");
+        }
+        Ok(None)
+    }
+
+    /// Checks `request_type` against the authorization policy, if there is one.
+    pub(crate) fn authorize(&self, ctx: &Context, request_type: &str) -> Result<(), ProtocolError> {
+        if let Some(policy) = &self.authorization {
+            policy.check(ctx.identity(), request_type)?;
+        }
+        match self.namespaces.get(request_type) {
+            Some(Scope {
+                authorization: Some(policy),
+                ..
+            }) => policy.check(ctx.identity(), request_type),
+            _ => Ok(()),
+        }
+    }
+
+    /// A handle whose shutdown can be cancelled without the rest of the server's.
+    pub(crate) fn child(&self) -> Server {
+        Server {
+            shutdown: self.shutdown.child_token(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the IP filter and bans let `addr` in, counting and logging it if not.
+    pub(crate) fn permits(&self, addr: SocketAddr) -> bool {
+        if let Some(filter) = &self.ip_filter
+            && !filter.permits(addr.ip())
+        {
+            tracing::warn!(%addr, "Refusing connection: address not allowed");
+            self.reject("ip_filter");
+            return false;
+        }
+        if self.bans.is_banned(addr.ip()) {
+            tracing::warn!(%addr, "Refusing connection: peer is banned");
+            self.reject("banned");
+            return false;
+        }
+        true
+    }
+
+    fn reject(&self, reason: &'static str) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        metrics::connection_rejected(reason);
+    }
+
+    /// Accepts the next connection that fits under `max_connections`.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn admit(&self, listener: &Listener) -> io::Result<(Accepted, SocketAddr, Running)> {
+        let max_connections = || self.config().max_connections;
+        loop {
+            match self.config().over_limit {
+                OverLimit::Wait => {
+                    let slot = self.open_connections.start(max_connections).await;
+                    let (stream, addr) = listener.accept().await?;
+                    return Ok((stream, addr, slot));
+                }
+                OverLimit::Refuse => {
+                    let (stream, addr) = listener.accept().await?;
+                    match self.open_connections.try_start(1, max_connections()) {
+                        Some(slot) => return Ok((stream, addr, slot)),
+                        None => {
+                            tracing::warn!(
+                                %addr,
+                                max_connections = max_connections(),
+                                "Refusing connection: connection limit reached"
+                            );
+                            self.reject("connection_limit");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn handle_client<S>(&self, stream: S, peer_addr: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let span = tracing::info_span!("client_session", %peer_addr);
+        async {
+            #[cfg(feature = "noise")]
+            let noise = self.noise.read().unwrap().clone();
+            #[cfg(feature = "noise")]
+            if let Some(noise) = noise {
+                let stream = tokio::time::timeout(
+                    crate::noise::HANDSHAKE_TIMEOUT,
+                    crate::noise::accept(stream, &noise),
+                )
+                .await
+                .map_err(|_| anyhow::anyhow!("Noise handshake timed out"))??;
+                tracing::debug!("Noise handshake complete");
+                return self.recorded_session(stream, peer_addr).await;
+            }
+            self.recorded_session(stream, peer_addr).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Runs the session, recording it first if the server records sessions.
+    async fn recorded_session<S>(&self, stream: S, peer_addr: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(dir) = &self.record_dir {
+            let stream = Recorded::new(stream, dir, peer_addr);
+            return self.run_session(stream, peer_addr).await;
+        }
+        self.run_session(stream, peer_addr).await
+    }
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Gives requests of type `request_type` `timeout` in place of
+    /// `ServerConfig::request_timeout`, e.g. a short one for `Ping` and a
+    /// long one for batch jobs. A later `config` call replaces it.
+    pub fn request_timeout(
+        mut self,
+        request_type: impl Into<String>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        self.config
+            .request_timeouts
+            .insert(request_type.into(), timeout);
+        self
+    }
+
+    /// Application state made available to handlers through `Context::state`.
+    pub fn state<T: Send + Sync + 'static>(mut self, state: Arc<T>) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Adds `value` to the extensions handlers look up by type with
+    /// `Context::get`, replacing any earlier one of the same type. Unlike
+    /// `state`, any number of independent types can be registered.
+    pub fn extension<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Like `extension`, for a value that is already shared.
+    pub fn extension_arc<T: Send + Sync + 'static>(mut self, value: Arc<T>) -> Self {
+        self.extensions.insert_arc(value);
+        self
+    }
+
+    /// Requires every client to pass `authenticator` before any request is handled.
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Serves several customers from one server, keeping each one's data
+    /// apart by the tenant its identities belong to; see [`tenant`](crate::tenant).
+    pub fn multi_tenant(mut self) -> Self {
+        self.multi_tenant = true;
+        self
+    }
+
+    /// Refuses every request `policy` doesn't allow the caller's identity,
+    /// whatever its kind, with `PermissionDenied`.
+    pub fn authorization(mut self, policy: Authorization) -> Self {
+        self.authorization = Some(policy);
+        self
+    }
+
+    /// Charges authenticated clients' requests and bytes to `quotas`,
+    /// refusing them with `QuotaExceeded` once one runs out.
+    pub fn quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Closes connections from peers `filter` doesn't permit as soon as they
+    /// are accepted. Behind a PROXY protocol balancer, the client it names is
+    /// checked as well.
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = Some(filter);
+        self
+    }
+
+    /// Appends `middleware` to the chain every unary request passes through.
+    pub fn middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Applies `namespace`'s middleware and authorization to the request
+    /// types tagged `name.Type`, replacing any earlier settings for `name`.
+    pub fn namespace(mut self, name: impl Into<String>, namespace: Namespace) -> Self {
+        self.namespaces.insert(name.into(), namespace);
+        self
+    }
+
+    /// Adds `module`'s routes, middleware and extensions, and runs its
+    /// hooks as `serve` starts and stops.
+    pub fn module(mut self, module: impl Module) -> Self {
+        module.register(&mut Registrar {
+            name: module.name(),
+            router: &mut self.router,
+            middleware: &mut self.middleware,
+            namespaces: &mut self.namespaces,
+            extensions: &mut self.extensions,
+        });
+        self.modules.push(Arc::new(module));
+        self
+    }
+
+    pub fn connection_handler(mut self, handler: impl ConnectionHandler) -> Self {
+        self.connection_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Lets the admin socket's `log` command change the log filter at runtime.
+    pub fn log_filter(
+        mut self,
+        set_filter: impl Fn(&str) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.log_filter = Some(Arc::new(set_filter));
+        self
+    }
+
+    /// Lets SIGHUP handlers and the admin socket's `reload` command, through
+    /// `Server::reload`, re-read the server's settings.
+    pub fn reload(
+        mut self,
+        reload: impl Fn(&Server) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// Records every finished request to `log`, e.g. `JsonLines::stdout()`.
+    pub fn access_log(mut self, log: impl AccessLog) -> Self {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Records the requests `audit` covers, with who sent them and how they
+    /// went, to its sink.
+    pub fn audit(mut self, audit: Audit) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Records every session's frames to a file of its own in `dir`, for
+    /// replaying with `recording::replay`. Everything the client sends is
+    /// kept, credentials included.
+    pub fn record_sessions(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record_dir = Some(dir.into());
+        self
+    }
+
+    /// Appends every published message to `journal`, letting subscribers
+    /// catch up on what they missed with `SubscribeFrom`.
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Appends every mutating request to `journal` before it runs, for
+    /// `Server::replay_requests` to run again after a restart.
+    pub fn request_journal(mut self, journal: RequestJournal) -> Self {
+        self.request_journal = Some(journal);
+        self
+    }
+
+    /// Serves the built-in key-value requests out of `store`; without it
+    /// they are refused.
+    pub fn kv_store(mut self, store: impl Store) -> Self {
+        self.kv_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Keeps sessions waiting to be resumed in `store` as well as in the
+    /// process, so that with a store other servers share, a client can
+    /// resume on any of them; see [`session_store`](crate::session_store).
+    /// Without it they're kept in a `MemorySessionStore`.
+    pub fn session_store(mut self, store: impl SessionStore) -> Self {
+        self.session_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Joins this server to the nodes in `config`, sharing relay peer names
+    /// and publishes with them once it is serving.
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Serves `GetFile` and `PutFile` out of `dir`; without it both are refused.
+    pub fn file_root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.file_root = Some(dir.into());
+        self
+    }
+
+    /// Permission bits `PutFile` may give the files it writes, `0o755` by
+    /// default; bits a client asks for outside them are dropped, as setuid,
+    /// setgid and sticky always are.
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = mode & 0o777;
+        self
+    }
+
+    /// Longest file `PutFile` writes, 1 GiB by default; uploads are cut off
+    /// and refused with `ErrorCode::PayloadTooLarge` once they pass it,
+    /// whatever size the client declared.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Requires every connection to open with a Noise handshake from a peer
+    /// `config` trusts, encrypting the session from then on.
+    #[cfg(feature = "noise")]
+    pub fn noise(mut self, config: crate::noise::NoiseConfig) -> Self {
+        self.noise = Some(config);
+        self
+    }
+
+    /// Opens `sealed::Sealed` requests with the keys from `keys`, and refuses
+    /// unsealed requests of the types it picks keys for.
+    #[cfg(feature = "encryption")]
+    pub fn key_provider(mut self, keys: impl crate::sealed::KeyProvider) -> Self {
+        self.key_provider = Some(Arc::new(keys));
+        self
+    }
+
+    /// Answers the request types `router` has routes for with those routes
+    /// instead of their `Request::handle`.
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Wraps every unary request's middleware chain and handler, seen as
+    /// [`Pipeline`](crate::service::Pipeline), in a tower layer stack.
+    #[cfg(feature = "tower")]
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: tower::Layer<crate::service::Pipeline>,
+        L::Service: tower::Service<Box<dyn Request>, Response = Box<dyn Response>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <L::Service as tower::Service<Box<dyn Request>>>::Error: Into<tower::BoxError>,
+        <L::Service as tower::Service<Box<dyn Request>>>::Future: Send + 'static,
+    {
+        use tower::ServiceExt;
+
+        let service = layer.layer(crate::service::Pipeline).map_err(Into::into);
+        self.stack = Some(tower::util::BoxCloneSyncService::new(service));
+        self
+    }
+
+    pub fn build(self) -> Server {
+        metrics::describe();
+        let cluster = self.cluster.map(|config| Arc::new(Cluster::new(config)));
+        let topics = TopicRegistry::new(
+            self.journal.map(Arc::new),
+            cluster.clone(),
+            &self.config,
+            self.multi_tenant,
+        );
+        let jobs = Jobs::new(self.config.max_running_jobs, self.config.max_queued_jobs);
+        Server {
+            open_connections: Load::default(),
+            rate_limiter: Arc::new(RwLock::new(
+                self.config
+                    .rate_limit
+                    .map(|limit| Arc::new(RateLimiter::new(limit))),
+            )),
+            config: Arc::new(RwLock::new(Arc::new(self.config))),
+            state: self.state,
+            extensions: Arc::new(self.extensions),
+            next_connection_id: Arc::new(AtomicU64::new(1)),
+            shutdown: CancellationToken::new(),
+            topics,
+            connections: ConnectionRegistry::default(),
+            authenticator: self.authenticator,
+            multi_tenant: self.multi_tenant,
+            authorization: self.authorization.map(Arc::new),
+            quotas: self.quotas.map(Arc::new),
+            idempotency_keys: IdempotencyKeys::default(),
+            latencies: Latencies::default(),
+            parked_sessions: ParkedSessions::default(),
+            peers: PeerRegistry::default(),
+            jobs: Arc::new(jobs),
+            cluster,
+            ip_filter: self.ip_filter.map(Arc::new),
+            rejected_connections: Arc::new(AtomicU64::new(0)),
+            bans: Arc::default(),
+            namespaces: Arc::new(Namespaces::new(self.namespaces, &self.middleware)),
+            middleware: self.middleware.into(),
+            connection_handler: self.connection_handler,
+            modules: self.modules.into(),
+            started: Instant::now(),
+            drain_requested: CancellationToken::new(),
+            drained: CancellationToken::new(),
+            serving: Arc::default(),
+            drain_retry_after: Arc::default(),
+            log_filter: self.log_filter,
+            reload: self.reload,
+            access_log: self.access_log,
+            audit: self.audit.map(Arc::new),
+            request_journal: self.request_journal.map(Arc::new),
+            kv_store: self.kv_store,
+            session_store: self
+                .session_store
+                .unwrap_or_else(|| Arc::new(MemorySessionStore::default())),
+            record_dir: self.record_dir.map(Arc::from),
+            file_root: self.file_root.map(Arc::from),
+            file_mode: self.file_mode,
+            max_file_size: self.max_file_size,
+            #[cfg(feature = "noise")]
+            noise: Arc::new(RwLock::new(self.noise.map(Arc::new))),
+            #[cfg(feature = "encryption")]
+            key_provider: self.key_provider,
+            load: Load::default(),
+            router: Arc::new(self.router),
+            #[cfg(feature = "tower")]
+            stack: self.stack,
+            #[cfg(unix)]
+            handoff: Default::default(),
+        }
+    }
+}
+
+/// Handles every request in `frame`, returning the reply alongside each
+/// request's type name and time spent in its handler.
+pub(crate) async fn handle_call(
+    frame: RequestFrame,
+    ctx: &Context,
+    middleware: &[Arc<dyn Middleware>],
+) -> (ResponseFrame, Vec<(&'static str, Duration)>) {
+    let mut limit = None;
+    if let Some(deadline) = frame.deadline {
+        let Some(remaining) = envelope::time_remaining(deadline) else {
+            tracing::debug!(id = frame.id, "Skipping requests past their deadline");
+            let results = frame
+                .requests
+                .iter()
+                .map(|_| {
+                    Err(ProtocolError::new(
+                        ErrorCode::Timeout,
+                        "Deadline passed before the request was handled",
+                    ))
+                })
+                .collect();
+            let timings = frame
+                .requests
+                .iter()
+                .map(|req| (req.typetag_name(), Duration::ZERO))
+                .collect();
+            let resp = ResponseFrame {
+                id: Some(frame.id),
+                results,
+                metadata: BTreeMap::new(),
+            };
+            return (resp, timings);
+        };
+        limit = Some(remaining);
+    }
+
+    let (results, timings) = if frame.sequential {
+        let mut handled = Vec::with_capacity(frame.requests.len());
+        for req in frame.requests {
+            handled.push(dispatch(req, ctx, limit, middleware).await);
+        }
+        handled.into_iter().unzip()
+    } else {
+        let futures = frame
+            .requests
+            .into_iter()
+            .map(|req| dispatch(req, ctx, limit, middleware));
+        join_all(futures).await.into_iter().unzip()
+    };
+    let resp = ResponseFrame {
+        id: Some(frame.id),
+        results,
+        metadata: ctx.take_response_metadata(),
+    };
+    (resp, timings)
+}
+
+/// Handles one request under its type's timeout, cut short to `limit` when
+/// the client's deadline is sooner.
+pub(crate) async fn dispatch(
+    req: Box<dyn Request>,
+    ctx: &Context,
+    limit: Option<Duration>,
+    middleware: &[Arc<dyn Middleware>],
+) -> (ResponseResult, (&'static str, Duration)) {
+    let request_type = req.typetag_name();
+    let timeout = match (
+        ctx.server().config().request_timeout_for(request_type),
+        limit,
+    ) {
+        (Some(timeout), Some(limit)) => Some(timeout.min(limit)),
+        (timeout, limit) => timeout.or(limit),
+    };
+    let audited = audit::begin(
+        ctx,
+        RequestKind::Call,
+        request_type,
+        req.as_ref(),
+        req.redacted_fields(),
+    );
+    let started = Instant::now();
+    let ctx = &ctx.with_deadline(timeout.map(|timeout| started + timeout));
+    let handle = async {
+        #[cfg(feature = "encryption")]
+        if let Some(keys) = &ctx.server().key_provider {
+            crate::sealed::check_sealed(&**keys, request_type)?;
+        }
+        tokio::select! {
+            result = panic::isolate(request_type, pipeline(req, ctx, middleware)) => {
+                result.and_then(|result| result.map_err(ProtocolError::from_handler))
+            }
+            _ = ctx.cancelled() => Err(ProtocolError::new(
+                ErrorCode::Cancelled,
+                "Request was cancelled by the client",
+            )),
+        }
+    };
+    let result = match timeout {
+        None => handle.await,
+        Some(timeout) => match tokio::time::timeout(timeout, handle).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!(request_type, ?timeout, "Request timed out");
+                Err(ProtocolError::new(
+                    ErrorCode::Timeout,
+                    format!("Request did not complete within {timeout:?}"),
+                ))
+            }
+        },
+    };
+
+    let elapsed = started.elapsed();
+    let error = result.as_ref().err().map(|err| err.code);
+    metrics::request_handled(request_type, ctx.tenant(), elapsed, error);
+    ctx.server().latencies.record(request_type, elapsed);
+    if error.is_some() {
+        ctx.connection().count_error();
+    }
+    warn_if_slow(ctx, request_type, elapsed, error);
+    if let Some(audited) = audited {
+        audited.finish(ctx, error, elapsed);
+    }
+    (result, (request_type, elapsed))
+}
+
+/// The server's tower stack if it has one, otherwise straight through the
+/// middleware chain to the handler.
+pub(crate) async fn pipeline(
+    req: Box<dyn Request>,
+    ctx: &Context,
+    middleware: &[Arc<dyn Middleware>],
+) -> Result<Box<dyn Response>> {
+    let request_type = req.typetag_name();
+    ctx.server().authorize(ctx, request_type)?;
+    #[cfg(feature = "tower")]
+    if let Some(stack) = &ctx.server().stack {
+        return crate::service::call(stack, req, ctx).await;
+    }
+    let chain = match ctx.server().namespaces.get(request_type) {
+        Some(scope) => &scope.chain,
+        None => middleware,
+    };
+    Next::new(chain).run(req.as_ref(), ctx).await
+}
+
+/// Flags handlers that ran past `ServerConfig::slow_request_threshold`;
+/// timeouts are already reported on their own.
+pub(crate) fn warn_if_slow(
+    ctx: &Context,
+    request_type: &'static str,
+    elapsed: Duration,
+    error: Option<ErrorCode>,
+) {
+    if let Some(threshold) = ctx.server().config().slow_request_threshold
+        && elapsed >= threshold
+        && error != Some(ErrorCode::Timeout)
+    {
+        tracing::warn!(
+            request_type,
+            peer_addr = %ctx.peer_addr,
+            ?elapsed,
+            "Slow request"
+        );
+    }
+}
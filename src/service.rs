@@ -0,0 +1,96 @@
+//! `tower::Service` adapters, behind the `tower` feature.
+//!
+//! [`Client`] is a service sending each request as a call, and the server's
+//! pipeline can be wrapped in tower layers with `ServerBuilder::layer`, so
+//! timeouts, concurrency limits and retries from the tower ecosystem work on
+//! either side.
+
+use std::task::{Context as TaskContext, Poll};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tower::util::BoxCloneSyncService;
+use tower::{BoxError, Service, ServiceExt};
+
+use crate::middleware::Next;
+use crate::{Client, Context, ProtocolError, Request, Response};
+
+/// A server's layered pipeline, as stored once type-erased.
+pub(crate) type Stack = BoxCloneSyncService<Box<dyn Request>, Box<dyn Response>, BoxError>;
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// The innermost service of a server's tower stack: the middleware chain
+/// followed by the request's route or handler.
+///
+/// It takes the request's `Context` from the task the server calls the
+/// stack on, so layers that move calls onto other tasks, like `Buffer`, are
+/// not supported.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pipeline;
+
+impl Service<Box<dyn Request>> for Pipeline {
+    type Response = Box<dyn Response>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Box<dyn Response>, BoxError>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Box<dyn Request>) -> Self::Future {
+        let Ok(ctx) = CONTEXT.try_with(Context::clone) else {
+            return futures::future::ready(Err("Pipeline called outside a server request".into()))
+                .boxed();
+        };
+        async move {
+            let server = ctx.server();
+            let middleware = match server.namespaces.get(req.typetag_name()) {
+                Some(scope) => scope.chain.clone(),
+                None => server.middleware.clone(),
+            };
+            Ok(Next::new(&middleware).run(req.as_ref(), &ctx).await?)
+        }
+        .boxed()
+    }
+}
+
+/// Runs `req` through `stack` on behalf of `ctx`.
+pub(crate) fn call(
+    stack: &Stack,
+    req: Box<dyn Request>,
+    ctx: &Context,
+) -> BoxFuture<'static, anyhow::Result<Box<dyn Response>>> {
+    // Boxed here so the future's `Send`-ness is checked at this concrete type
+    // rather than through the caller's `async fn`.
+    CONTEXT
+        .scope(ctx.clone(), stack.clone().oneshot(req))
+        .map(|result| result.map_err(into_anyhow))
+        .boxed()
+}
+
+/// Keeps a `ProtocolError` from a handler or layer recognisable once it has
+/// been through tower's boxed errors.
+fn into_anyhow(err: BoxError) -> anyhow::Error {
+    match err.downcast::<ProtocolError>() {
+        Ok(err) => (*err).into(),
+        Err(err) => anyhow::anyhow!(err),
+    }
+}
+
+impl Service<Box<dyn Request>> for Client {
+    type Response = Box<dyn Response>;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, anyhow::Result<Box<dyn Response>>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<anyhow::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Box<dyn Request>) -> Self::Future {
+        let client = self.clone();
+        async move { client.call(req).await }.boxed()
+    }
+}
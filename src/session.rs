@@ -0,0 +1,1494 @@
+use std::cmp::Ordering as Order;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::future::join_all;
+use futures::stream::SplitSink;
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+use crate::access_log::{AccessRecord, RequestKind};
+use crate::audit;
+use crate::auth::{self, Identity};
+use crate::ban::Violation;
+use crate::channels::{Channels, Queues};
+use crate::codec::Codec;
+use crate::config::FlushPolicy;
+use crate::connection::{Counters, Outbound};
+use crate::envelope::{
+    self, ClientMessage, GoAway, GoAwayReason, NotifyFrame, Priority, RequestFrame, ResponseFrame,
+    ServerMessage, StreamFrame, StreamItem, StreamRequestFrame, TraceContext, UploadFrame,
+    UploadItem, UploadRequestFrame, WindowUpdate,
+};
+use crate::frame::{Encoded, FrameCodec};
+use crate::handshake::{Features, HelloAck};
+use crate::heartbeat::Heartbeat;
+use crate::idempotency::{Claim, Completion};
+use crate::load::Running;
+use crate::metrics;
+use crate::panic;
+use crate::protocol::{Inbound, ServerProtocol};
+use crate::rate_limit::RateKey;
+use crate::redact::Redacted;
+use crate::registry;
+use crate::reply_order::{ReplyOrder, ReplySlot};
+use crate::resumption::Resumption;
+use crate::server::{Server, dispatch, handle_call, warn_if_slow};
+use crate::trace;
+use crate::{Connection, Context, ErrorCode, ProtocolError, Request, ServerConfig, handshake};
+
+const UPLOAD_QUEUE: usize = 16;
+
+/// The mutable half of a session: everything the read loop keeps track of
+/// between frames.
+struct Session {
+    ctx: Context,
+    codec: Codec,
+    outbound: Outbound,
+    /// Calls, streams, uploads and notifications currently running, each
+    /// yielding its id, if it has one, when done.
+    tasks: JoinSet<Option<u64>>,
+    /// The channel each of `tasks` was sent on.
+    task_channels: HashMap<task::Id, u32>,
+    /// How many of `tasks` each channel is running.
+    running: HashMap<u32, usize>,
+    in_flight: HashMap<u64, InFlight>,
+    uploads: HashMap<u64, mpsc::Sender<Result<Bytes>>>,
+    /// The bytes granted so far to each stream the client opened with a window.
+    windows: HashMap<u64, watch::Sender<u64>>,
+    /// Calls, streams, uploads and notifications that arrived while their
+    /// channel was running as many as `max_concurrent_calls`.
+    queued: HashMap<u32, BinaryHeap<Queued>>,
+    next_seq: u64,
+    /// Set when the client asked for replies in the order it made its calls.
+    ordered_replies: bool,
+    /// The order of each channel's replies, which is kept apart from the others'.
+    replies: HashMap<u32, ReplyOrder>,
+    /// Whether the client negotiated `Features::PROGRESS`.
+    progress: bool,
+    rate_key: RateKey,
+}
+
+/// A message waiting for a free slot to start its call, stream or upload.
+struct Queued {
+    /// `None` for a notification, which can't be cancelled.
+    id: Option<u64>,
+    priority: Priority,
+    /// Arrival order, so messages of the same priority start first come, first served.
+    seq: u64,
+    message: ClientMessage,
+    bytes: Bytes,
+    slot: Option<ReplySlot>,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Order {
+        (self.priority, other.seq).cmp(&(other.priority, self.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Order> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Queued {}
+
+/// A running call, stream or upload, counted against the server's load until it finishes.
+struct InFlight {
+    cancellation: CancellationToken,
+    _running: Running,
+}
+
+impl Server {
+    pub(crate) async fn run_session<S>(&self, stream: S, peer_addr: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let config = self.config();
+
+        let mut framed = Framed::new(
+            stream,
+            FrameCodec::with_max_frame_length(config.max_frame_length),
+        );
+
+        let negotiated = tokio::time::timeout(
+            config.handshake_timeout,
+            self.negotiate(&mut framed, peer_addr),
+        );
+        let (ack, identity, resumption) = tokio::select! {
+            negotiated = negotiated => negotiated.map_err(|_| {
+                anyhow!("handshake not finished within {:?}", config.handshake_timeout)
+            })??,
+            _ = self.shutdown.cancelled() => return Ok(()),
+        };
+        if ack.chunking() {
+            framed
+                .codec_mut()
+                .set_max_message_length(config.max_message_length);
+        }
+        let max_message_length = framed.codec().max_message_length();
+        tracing::debug!(
+            version = ack.version,
+            codec = ?ack.codec,
+            features = ?ack.features,
+            identity = identity.as_ref().map(|identity| &identity.subject),
+            resumed = resumption.as_ref().is_some_and(Resumption::resumed),
+            "Handshake complete"
+        );
+
+        let (token, parked, restored) = match resumption {
+            Some(resumption) => (
+                Some(resumption.token),
+                resumption.parked,
+                resumption.restored,
+            ),
+            None => (None, None, None),
+        };
+
+        let framed = framed.map_codec(|frames| {
+            ServerProtocol::new(frames, ack.codec)
+                .dump_frames(config.dump_frames)
+                .max_payload_lengths(config.max_payload_lengths.clone())
+        });
+        let (sink, mut frames) = framed.split();
+        let (channels, rx) = Channels::new(config.outbound_queue);
+        let outbound = Outbound::with_channels(channels, config.slow_client);
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Connection::new(
+            connection_id,
+            peer_addr,
+            outbound.clone(),
+            max_message_length,
+            ack.codec,
+        );
+        let writer_done = CancellationToken::new();
+        let writer = tokio::spawn(
+            write_frames(
+                sink,
+                rx,
+                writer_done.clone(),
+                connection.counters(),
+                config.flush,
+            )
+            .in_current_span(),
+        );
+
+        let rate_key = RateKey::new(identity.as_ref(), peer_addr.ip());
+        let mut session = Session {
+            ctx: Context::new(connection.clone(), ack.compression, identity, self),
+            codec: ack.codec,
+            outbound,
+            tasks: JoinSet::new(),
+            task_channels: HashMap::new(),
+            running: HashMap::new(),
+            in_flight: HashMap::new(),
+            uploads: HashMap::new(),
+            windows: HashMap::new(),
+            queued: HashMap::new(),
+            next_seq: 0,
+            ordered_replies: ack.ordered_replies(),
+            replies: HashMap::new(),
+            progress: ack.progress(),
+            rate_key,
+        };
+
+        if let Some(handler) = &self.connection_handler
+            && let Err(e) = handler.on_connect(&session.ctx).await
+        {
+            tracing::warn!(error = %e, "Connection rejected by on_connect");
+            if let Some(parked) = parked {
+                self.topics.remove_connection(parked.connection_id);
+            }
+            writer_done.cancel();
+            let _ = writer.await;
+            return Ok(());
+        }
+        self.connections.insert(session.ctx.connection().clone());
+        metrics::connection_opened();
+        if let Some(mut parked) = parked {
+            self.topics
+                .transfer(parked.connection_id, connection.clone());
+            while let Ok(bytes) = parked.pending.try_recv() {
+                session.send(bytes).await?;
+            }
+            tracing::info!(
+                previous_connection_id = parked.connection_id,
+                "Session resumed"
+            );
+        } else if let Some(restored) = &restored {
+            for (topic, filter) in &restored.subscriptions {
+                match filter {
+                    Some(filter) => {
+                        self.topics
+                            .subscribe_filtered(topic, connection.clone(), filter.clone())
+                    }
+                    None => self.topics.subscribe(topic, connection.clone()),
+                };
+            }
+            tracing::info!(
+                subscriptions = restored.subscriptions.len(),
+                "Session resumed from the session store"
+            );
+        }
+        if let Some(restored) = &restored {
+            connection.carry_over(restored.requests, restored.errors, restored.malformed);
+        }
+
+        let mut heartbeat = Heartbeat::new(config.heartbeat_interval, config.heartbeat_timeout);
+        let idle_timeout = config.idle_timeout.unwrap_or(Duration::MAX);
+        let idle = tokio::time::sleep(idle_timeout);
+        tokio::pin!(idle);
+
+        // Set once the client has shut down its write half: nothing more is
+        // read, but the calls it already sent are still answered.
+        let mut half_closed = false;
+        let half_close = tokio::time::sleep(Duration::MAX);
+        tokio::pin!(half_close);
+
+        // Why the server is closing the session, for a client that asked to be told.
+        let mut go_away = None;
+        let result: Result<()> = async {
+            loop {
+                let frame = tokio::select! {
+                    frame = frames.next(), if !half_closed && session.reads_frames(&config) => frame,
+                    _ = self.shutdown.cancelled() => {
+                        tracing::info!("Closing session for shutdown");
+                        go_away = Some(
+                            GoAway::new(GoAwayReason::Shutdown, "server shutting down")
+                                .retry_after(*self.drain_retry_after.read().unwrap()),
+                        );
+                        return Ok(());
+                    }
+                    _ = connection.closed() => {
+                        tracing::info!("Closing session: disconnected by the server");
+                        go_away = Some(GoAway::new(GoAwayReason::Disconnected, "disconnected by the server"));
+                        return Ok(());
+                    }
+                    seq = heartbeat.tick(), if !half_closed => {
+                        let Some(seq) = seq else {
+                            tracing::info!("Closing session: heartbeat timed out");
+                            go_away = Some(GoAway::new(GoAwayReason::HeartbeatTimeout, "heartbeat timed out"));
+                            return Ok(());
+                        };
+                        session.send(envelope::encode_message(session.codec, &ServerMessage::Ping(seq))).await?;
+                        continue;
+                    }
+                    _ = &mut idle, if config.idle_timeout.is_some() && !half_closed => {
+                        tracing::info!(?idle_timeout, "Closing session: idle timeout");
+                        go_away = Some(GoAway::new(
+                            GoAwayReason::IdleTimeout,
+                            format!("no calls for {idle_timeout:?}"),
+                        ));
+                        return Ok(());
+                    }
+                    Some(done) = session.tasks.join_next_with_id(), if !session.tasks.is_empty() => {
+                        let (task, id) = match done {
+                            Ok((task, id)) => (task, id),
+                            Err(e) => (e.id(), None),
+                        };
+                        let channel = session.finished(task);
+                        if let Some(id) = id {
+                            session.in_flight.remove(&id);
+                            session.uploads.remove(&id);
+                            session.windows.remove(&id);
+                        }
+                        session.start_queued(channel, config.max_concurrent_calls).await?;
+                        if half_closed && session.is_finished() {
+                            tracing::info!("Closing half-closed session: every call answered");
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    _ = session.outbound.disconnected(), if half_closed => {
+                        tracing::info!("Client disconnected");
+                        return Ok(());
+                    }
+                    _ = &mut half_close, if half_closed => {
+                        tracing::info!(
+                            timeout = ?config.half_close_timeout,
+                            "Closing half-closed session: calls still running"
+                        );
+                        return Ok(());
+                    }
+                };
+                let Some(frame) = frame else {
+                    if session.is_finished() {
+                        tracing::info!("Client disconnected");
+                        return Ok(());
+                    }
+                    // The client may only have shut down its write half, and
+                    // still be waiting for what it has sent; if it has gone
+                    // entirely, the writer finds out.
+                    tracing::info!(
+                        running = session.tasks.len(),
+                        queued = session.queued_calls(),
+                        "Client stopped sending; finishing its calls"
+                    );
+                    session.cut_off_uploads().await;
+                    half_closed = true;
+                    half_close
+                        .as_mut()
+                        .reset(Instant::now() + config.half_close_timeout);
+                    continue;
+                };
+                heartbeat.saw_frame();
+
+                let frame = frame.inspect_err(|e| {
+                    go_away = Some(GoAway::new(GoAwayReason::ProtocolError, e.to_string()));
+                })?;
+                let (message, bytes) = match frame {
+                    Inbound::Message(message, bytes) => {
+                        metrics::bytes_received(bytes.len());
+                        connection.counters().received(bytes.len());
+                        (message, bytes)
+                    }
+                    Inbound::Oversized(len) => {
+                        tracing::warn!(len, "Rejected oversized frame");
+                        metrics::frame_rejected(ErrorCode::FrameTooLarge);
+                        connection.count_error();
+                        self.misbehaved(peer_addr, Violation::OversizedFrame);
+                        let resp = envelope::error_frame(
+                            session.codec,
+                            None,
+                            ProtocolError::new(
+                                ErrorCode::FrameTooLarge,
+                                format!(
+                                    "Message of {len} bytes exceeds the {max_message_length} byte limit"
+                                ),
+                            ),
+                        );
+                        session.send(resp).await?;
+                        continue;
+                    }
+                    Inbound::PayloadTooLarge { request_type, len, limit } => {
+                        tracing::warn!(%request_type, len, limit, "Rejected oversized request");
+                        metrics::frame_rejected(ErrorCode::PayloadTooLarge);
+                        connection.count_error();
+                        let resp = envelope::error_frame(
+                            session.codec,
+                            None,
+                            ProtocolError::new(
+                                ErrorCode::PayloadTooLarge,
+                                format!(
+                                    "{request_type} request of {len} bytes exceeds its {limit} byte limit"
+                                ),
+                            ),
+                        );
+                        session.send(resp).await?;
+                        continue;
+                    }
+                    Inbound::Corrupt(len) => {
+                        tracing::warn!(len, "Dropped frame that failed its checksum");
+                        metrics::frame_rejected(ErrorCode::ChecksumMismatch);
+                        connection.count_error();
+                        let resp = envelope::error_frame(
+                            session.codec,
+                            None,
+                            ProtocolError::new(
+                                ErrorCode::ChecksumMismatch,
+                                format!("Frame of {len} bytes failed its checksum"),
+                            ),
+                        );
+                        session.send(resp).await?;
+                        continue;
+                    }
+                    Inbound::Malformed { error, len, offset } => {
+                        metrics::frame_rejected(ErrorCode::Malformed);
+                        let malformed = connection.count_malformed();
+                        self.misbehaved(peer_addr, Violation::MalformedFrame);
+                        tracing::debug!(len, ?offset, error = %error, "Rejected malformed frame");
+                        let resp = envelope::error_frame(
+                            session.codec,
+                            None,
+                            ProtocolError::new(
+                                ErrorCode::Malformed,
+                                format!("Failed to parse request: {error}"),
+                            )
+                            .with_details(malformed_details(session.codec, len, offset)),
+                        );
+                        session.send(resp).await?;
+                        if config.max_malformed_frames.is_some_and(|max| malformed > max) {
+                            tracing::warn!(malformed, "Closing session: too many malformed frames");
+                            go_away = Some(GoAway::new(
+                                GoAwayReason::ProtocolError,
+                                format!("{malformed} frames could not be parsed"),
+                            ));
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
+
+                let starts_task = matches!(
+                    message,
+                    ClientMessage::Call(_)
+                        | ClientMessage::OpenStream(_)
+                        | ClientMessage::OpenUpload(_)
+                        | ClientMessage::Notify(_)
+                );
+                if starts_task && config.idle_timeout.is_some() {
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
+
+                let channel = message.channel();
+                if channel >= config.max_channels {
+                    let err = ProtocolError::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Channel {channel} is past the server's {} channels", config.max_channels),
+                    );
+                    session.refuse(&message, id_and_cost(&message), err, None).await?;
+                    continue;
+                }
+
+                let slot = match &message {
+                    ClientMessage::Call(_) if session.ordered_replies => Some(session.reply_slot(channel)),
+                    _ => None,
+                };
+                if starts_task && session.running(channel) >= config.max_concurrent_calls {
+                    session.queue(message, bytes, slot);
+                } else {
+                    session.handle_message(message, &bytes, slot).await?;
+                }
+            }
+        }
+        .await;
+
+        if let Some(go_away) = go_away
+            && ack.go_away()
+        {
+            // Best effort: a client too slow to have room for it is being dropped anyway.
+            let _ = session.outbound.try_send(envelope::encode_message(
+                session.codec,
+                &ServerMessage::GoAway(go_away),
+            ));
+        }
+
+        // Nobody is left to read the body of an unfinished upload.
+        session.uploads.clear();
+        if !self.shutdown.is_cancelled() {
+            // The client is gone, so nobody wants these results either; on
+            // shutdown they're left to finish within the drain timeout.
+            for InFlight {
+                cancellation: token,
+                ..
+            } in session.in_flight.values()
+            {
+                token.cancel();
+            }
+        }
+        while session.tasks.join_next().await.is_some() {}
+
+        match (token, self.config().session_resume_timeout) {
+            (Some(token), Some(timeout)) if !self.shutdown.is_cancelled() => {
+                self.park_session(token, &connection, session.ctx.identity().cloned(), timeout)
+            }
+            _ => self.topics.remove_connection(connection_id),
+        }
+        let names = self.peers.remove_connection(connection_id);
+        if let Some(cluster) = &self.cluster {
+            cluster.remove_connection(connection_id);
+            for name in &names {
+                cluster.announce(name, false);
+            }
+        }
+        self.connections.remove(connection_id);
+        if let Some(handler) = &self.connection_handler {
+            handler.on_disconnect(&session.ctx).await;
+        }
+        metrics::connection_closed();
+
+        // Handles to this connection may live on elsewhere, so the writer is
+        // told to flush and stop rather than waiting for every sender to drop.
+        writer_done.cancel();
+        match writer.await {
+            Ok(Err(e)) if result.is_ok() => return Err(e.into()),
+            _ => {}
+        }
+        result
+    }
+}
+
+impl Server {
+    /// Runs the handshake and, if the server requires it, authentication.
+    async fn negotiate<S>(
+        &self,
+        framed: &mut Framed<S, FrameCodec>,
+        peer_addr: SocketAddr,
+    ) -> Result<(HelloAck, Option<Identity>, Option<Resumption>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = self.config();
+        let features = Features::empty()
+            .with(
+                Features::CHUNKING,
+                config.max_message_length > config.max_frame_length,
+            )
+            .with(Features::CHECKSUMS, config.checksums)
+            .with(
+                Features::RESUMPTION,
+                config.session_resume_timeout.is_some(),
+            )
+            | Features::ORDERED_REPLIES
+            | Features::GO_AWAY
+            | Features::PROGRESS;
+        let challenge = self.authenticator.as_ref().map(|_| auth::challenge());
+        let ack = handshake::accept(
+            framed,
+            features,
+            &config.codecs,
+            &config.compression,
+            challenge,
+        )
+        .await?;
+        let mut resumption = None;
+        if ack.resumption() {
+            resumption = Some(self.answer_resume(framed, ack.codec).await?);
+        }
+
+        // A resumed session keeps the identity it authenticated as.
+        if let Some(identity) = resumption.as_mut().and_then(Resumption::take_identity) {
+            return Ok((ack, self.admit_identity(identity)?, resumption));
+        }
+        let identity = match (&self.authenticator, &ack.challenge) {
+            (Some(authenticator), Some(challenge)) => {
+                let verified = auth::verify(
+                    framed,
+                    authenticator.as_ref(),
+                    challenge,
+                    peer_addr,
+                    self.multi_tenant,
+                )
+                .await;
+                // Only a credential that was refused counts, not a dropped connection.
+                if verified
+                    .as_ref()
+                    .is_err_and(|e| e.downcast_ref::<ProtocolError>().is_some())
+                {
+                    self.misbehaved(peer_addr, Violation::AuthFailure);
+                }
+                Some(verified?)
+            }
+            _ => self.admit_identity(None)?,
+        };
+        Ok((ack, identity, resumption))
+    }
+}
+
+impl Session {
+    async fn send(&self, bytes: impl Into<Encoded>) -> Result<()> {
+        self.outbound.send(bytes).await
+    }
+
+    /// Registers a cancellable task for `id` and returns the context it runs
+    /// with, carrying the `metadata` it came with.
+    fn start(&mut self, id: u64, running: Running, metadata: BTreeMap<String, String>) -> Context {
+        let token = CancellationToken::new();
+        let task = InFlight {
+            cancellation: token.clone(),
+            _running: running,
+        };
+        if let Some(previous) = self.in_flight.insert(id, task) {
+            tracing::warn!(id, "Client reused an in-flight request id");
+            previous.cancellation.cancel();
+        }
+        self.ctx.with_cancellation(token).with_metadata(metadata)
+    }
+
+    /// The span a call, stream or upload runs in, if it's sampled.
+    fn span(&self, trace: &Option<TraceContext>, span: impl FnOnce() -> Span) -> Span {
+        trace::sampled(self.ctx.server().config().trace_sample_rate, trace, span)
+    }
+
+    fn identity(&self) -> Option<&str> {
+        self.ctx
+            .identity()
+            .map(|identity| identity.subject.as_str())
+    }
+
+    /// Counts `cost` requests against the server's load, failing with `Busy`
+    /// past `max_in_flight`.
+    fn check_load(&self, id: u64, cost: usize) -> Result<Running, ProtocolError> {
+        let load = &self.ctx.server().load;
+        load.try_start(cost, self.ctx.server().config().max_in_flight)
+            .ok_or_else(|| {
+                tracing::debug!(
+                    id,
+                    running = load.running(),
+                    "Shedding request: server busy"
+                );
+                ProtocolError::new(ErrorCode::Busy, "Server is busy")
+            })
+    }
+
+    /// Charges `cost` requests to this client's rate limit, failing once it is exhausted.
+    fn check_rate(&self, id: u64, cost: usize) -> Result<(), ProtocolError> {
+        let Some(limiter) = self.ctx.server().rate_limiter() else {
+            return Ok(());
+        };
+        let Err(retry_after) =
+            limiter.acquire(&self.rate_key, u32::try_from(cost).unwrap_or(u32::MAX))
+        else {
+            return Ok(());
+        };
+
+        tracing::debug!(id, key = ?self.rate_key, ?retry_after, "Rate limited request");
+        let mut err = ProtocolError::new(ErrorCode::RateLimited, "Rate limit exceeded");
+        if retry_after != Duration::MAX {
+            err = err.with_details(format!("retry after {retry_after:?}"));
+        }
+        Err(err)
+    }
+
+    /// Answers every request in `message` with `err` instead of running them.
+    fn reject(&self, message: &ClientMessage, id: u64, cost: usize, err: ProtocolError) -> Encoded {
+        metrics::frame_rejected(err.code);
+        self.ctx.connection().count_error();
+        match message {
+            ClientMessage::OpenStream(_) => envelope::encode_message(
+                self.codec,
+                &ServerMessage::Stream(StreamFrame {
+                    id,
+                    item: StreamItem::Error(err),
+                }),
+            )
+            .into(),
+            _ => envelope::encode_reply(
+                self.codec,
+                ResponseFrame {
+                    id: Some(id),
+                    results: (0..cost).map(|_| Err(err.clone())).collect(),
+                    metadata: BTreeMap::new(),
+                },
+                self.ctx.connection().max_message_length(),
+            ),
+        }
+    }
+
+    /// Whether every call the client has sent has been answered.
+    fn is_finished(&self) -> bool {
+        self.tasks.is_empty() && self.queued_calls() == 0
+    }
+
+    fn queued_calls(&self) -> usize {
+        self.queued.values().map(BinaryHeap::len).sum()
+    }
+
+    /// How many calls, streams, uploads and notifications `channel` is running.
+    fn running(&self, channel: u32) -> usize {
+        self.running.get(&channel).copied().unwrap_or_default()
+    }
+
+    /// Runs `task` for a message sent on `channel`, in one of its slots.
+    fn spawn(&mut self, channel: u32, task: impl Future<Output = Option<u64>> + Send + 'static) {
+        let task = self.tasks.spawn(task);
+        self.task_channels.insert(task.id(), channel);
+        *self.running.entry(channel).or_default() += 1;
+    }
+
+    /// Frees the slot of `task`, which has finished, returning its channel.
+    fn finished(&mut self, task: task::Id) -> u32 {
+        let channel = self.task_channels.remove(&task).unwrap_or_default();
+        if let Some(running) = self.running.get_mut(&channel) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(&channel);
+            }
+        }
+        channel
+    }
+
+    /// The slot for the reply to the call that just arrived on `channel`.
+    fn reply_slot(&mut self, channel: u32) -> ReplySlot {
+        let outbound = &self.outbound;
+        self.replies
+            .entry(channel)
+            .or_insert_with(|| ReplyOrder::new(outbound.channel(channel)))
+            .slot()
+    }
+
+    /// Ends the bodies of unfinished uploads with an error, once the client
+    /// can no longer send the rest of them.
+    async fn cut_off_uploads(&mut self) {
+        for (_, body) in self.uploads.drain() {
+            let _ = body
+                .send(Err(anyhow!("upload cut off: the client stopped sending")))
+                .await;
+        }
+    }
+
+    /// Whether to read another frame: only as long as every channel's queue
+    /// has room and holds no upload, whose body can't be read until it starts.
+    fn reads_frames(&self, config: &ServerConfig) -> bool {
+        self.queued.values().all(|queued| {
+            queued.len() < config.max_queued_calls
+                && !queued
+                    .iter()
+                    .any(|queued| matches!(queued.message, ClientMessage::OpenUpload(_)))
+        })
+    }
+
+    /// Holds a call, stream or upload until a slot frees up.
+    fn queue(&mut self, message: ClientMessage, bytes: Bytes, slot: Option<ReplySlot>) {
+        let (id, priority) = match &message {
+            ClientMessage::Call(call) => (Some(call.id), call.priority),
+            ClientMessage::OpenStream(open) => (Some(open.id), Priority::Normal),
+            ClientMessage::OpenUpload(open) => (Some(open.id), Priority::Normal),
+            ClientMessage::Notify(notify) => {
+                let priority = notify.requests.iter().map(|r| r.priority()).max();
+                (None, priority.unwrap_or_default())
+            }
+            _ => unreachable!("only messages that start a task are queued"),
+        };
+        let channel = message.channel();
+        tracing::debug!(
+            id,
+            channel,
+            ?priority,
+            "Queued request behind max_concurrent_calls"
+        );
+        self.queued.entry(channel).or_default().push(Queued {
+            id,
+            priority,
+            seq: self.next_seq,
+            message,
+            bytes,
+            slot,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Starts `channel`'s queued messages, highest priority first, while it
+    /// has slots free.
+    async fn start_queued(&mut self, channel: u32, max_concurrent_calls: usize) -> Result<()> {
+        while self.running(channel) < max_concurrent_calls
+            && let Some(queue) = self.queued.get_mut(&channel)
+            && let Some(queued) = queue.pop()
+        {
+            if queue.is_empty() {
+                self.queued.remove(&channel);
+            }
+            self.handle_message(queued.message, &queued.bytes, queued.slot)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Answers `message` with `err`, after the replies before it if it has a slot.
+    async fn refuse(
+        &mut self,
+        message: &ClientMessage,
+        (id, cost): (u64, usize),
+        err: ProtocolError,
+        slot: Option<ReplySlot>,
+    ) -> Result<()> {
+        if let ClientMessage::Notify(notify) = message {
+            tracing::debug!(code = %err.code, requests = %RequestTypes(&notify.requests), "Dropped notification");
+            self.ctx.connection().count_error();
+            return Ok(());
+        }
+        let bytes = self.reject(message, id, cost, err);
+        let channel = message.channel();
+        match slot {
+            // From a task, so the read loop doesn't wait on the calls before it.
+            Some(slot) => {
+                self.spawn(channel, async move {
+                    let _ = slot.send(bytes).await;
+                    Some(id)
+                });
+                Ok(())
+            }
+            None => self.outbound.channel(channel).send(bytes).await,
+        }
+    }
+
+    async fn handle_message(
+        &mut self,
+        message: ClientMessage,
+        bytes: &Bytes,
+        slot: Option<ReplySlot>,
+    ) -> Result<()> {
+        let (id, cost) = id_and_cost(&message);
+        let channel = message.channel();
+        self.ctx.connection().count_requests(cost);
+
+        let running = match self.check_load(id, cost) {
+            Ok(running) => running,
+            Err(err) => return self.refuse(&message, (id, cost), err, slot).await,
+        };
+        if cost > 0
+            && let Err(err) = self.check_rate(id, cost)
+        {
+            return self.refuse(&message, (id, cost), err, slot).await;
+        }
+        if cost > 0
+            && let Some(quotas) = &self.ctx.server().quotas
+            && let Err(err) = quotas.charge(self.ctx.identity(), cost, bytes.len()).await
+        {
+            return self.refuse(&message, (id, cost), err, slot).await;
+        }
+
+        match message {
+            ClientMessage::Call(mut call) => {
+                let ctx = self.start(id, running, std::mem::take(&mut call.metadata));
+                let ctx = if self.progress {
+                    ctx.with_progress(id)
+                } else {
+                    ctx
+                };
+                let msg_span = self.span(&call.trace, || {
+                    tracing::info_span!(
+                        "handle_message",
+                        id,
+                        len = bytes.len(),
+                        requests = %RequestTypes(&call.requests),
+                        identity = self.identity(),
+                        code = Empty,
+                        traceparent = traceparent(&call.trace),
+                    )
+                });
+                // What the requests carry is only shown when asked for.
+                msg_span.in_scope(|| {
+                    tracing::debug!(requests = %Redacted(&call.requests), "Call payload");
+                });
+                let outbound = self.outbound.channel(channel);
+                self.spawn(
+                    channel,
+                    run_call(call, ctx, outbound, slot, bytes.len())
+                        .map(Some)
+                        .instrument(msg_span),
+                );
+            }
+            ClientMessage::OpenStream(open) => {
+                let ctx = self.start(open.id, running, BTreeMap::new());
+                let span = self.span(&open.trace, || {
+                    tracing::info_span!(
+                        "handle_stream",
+                        id = open.id,
+                        len = bytes.len(),
+                        request = open.request.typetag_name(),
+                        identity = self.identity(),
+                        code = Empty,
+                        traceparent = traceparent(&open.trace),
+                    )
+                });
+                let window = open.window.map(|window| {
+                    let (granted, window) = watch::channel(window);
+                    self.windows.insert(open.id, granted);
+                    window
+                });
+                let outbound = self.outbound.channel(channel);
+                self.spawn(
+                    channel,
+                    run_stream(
+                        open,
+                        window,
+                        ctx,
+                        outbound,
+                        self.ctx.connection().max_message_length(),
+                        bytes.len(),
+                    )
+                    .map(Some)
+                    .instrument(span),
+                );
+            }
+            ClientMessage::OpenUpload(open) => {
+                let ctx = self.start(open.id, running, BTreeMap::new());
+                let span = self.span(&open.trace, || {
+                    tracing::info_span!(
+                        "handle_upload",
+                        id = open.id,
+                        len = bytes.len(),
+                        request = open.request.typetag_name(),
+                        identity = self.identity(),
+                        code = Empty,
+                        traceparent = traceparent(&open.trace),
+                    )
+                });
+                let (body, rx) = mpsc::channel(UPLOAD_QUEUE);
+                self.uploads.insert(open.id, body);
+                let outbound = self.outbound.channel(channel);
+                self.spawn(
+                    channel,
+                    run_upload(
+                        open,
+                        rx,
+                        ctx,
+                        outbound,
+                        self.ctx.connection().max_message_length(),
+                    )
+                    .map(Some)
+                    .instrument(span),
+                );
+            }
+            ClientMessage::UploadChunk(UploadFrame { id, item }) => match item {
+                UploadItem::Data(chunk) => {
+                    if let Some(body) = self.uploads.get(&id) {
+                        // A handler that has stopped reading just never sees the rest.
+                        if body.send(Ok(chunk)).await.is_err() {
+                            self.uploads.remove(&id);
+                        }
+                    }
+                }
+                UploadItem::End => {
+                    self.uploads.remove(&id);
+                }
+                UploadItem::Abort(reason) => {
+                    if let Some(body) = self.uploads.remove(&id) {
+                        let _ = body.send(Err(anyhow!("upload aborted: {reason}"))).await;
+                    }
+                }
+            },
+            ClientMessage::Cancel(id) => {
+                if let Some(task) = self.in_flight.get(&id) {
+                    tracing::debug!(id, "Client cancelled request");
+                    task.cancellation.cancel();
+                } else {
+                    // Nobody is waiting for the reply, so one still queued never starts.
+                    for queued in self.queued.values_mut() {
+                        queued.retain(|queued| queued.id != Some(id));
+                    }
+                    self.queued.retain(|_, queued| !queued.is_empty());
+                }
+            }
+            ClientMessage::Ping(seq) => {
+                self.send(envelope::encode_message(
+                    self.codec,
+                    &ServerMessage::Pong(seq),
+                ))
+                .await?;
+            }
+            ClientMessage::Pong(_) => {}
+            ClientMessage::WindowUpdate(WindowUpdate { id, bytes }) => {
+                if let Some(granted) = self.windows.get(&id) {
+                    granted.send_modify(|granted| *granted = granted.saturating_add(bytes));
+                }
+            }
+            ClientMessage::Notify(notify) => {
+                let span = self.span(&notify.trace, || {
+                    tracing::info_span!(
+                        "handle_notify",
+                        len = bytes.len(),
+                        requests = %RequestTypes(&notify.requests),
+                        identity = self.identity(),
+                        code = Empty,
+                        traceparent = traceparent(&notify.trace),
+                    )
+                });
+                let ctx = self.ctx.with_metadata(BTreeMap::new());
+                let len = bytes.len();
+                self.spawn(
+                    channel,
+                    async move {
+                        let _running = running;
+                        run_notify(notify, ctx, len).await;
+                        None
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The id a message is answered under and how many requests it carries.
+fn id_and_cost(message: &ClientMessage) -> (u64, usize) {
+    match message {
+        ClientMessage::Call(call) => (call.id, call.requests.len()),
+        ClientMessage::OpenStream(open) => (open.id, 1),
+        ClientMessage::OpenUpload(open) => (open.id, 1),
+        ClientMessage::Notify(notify) => (0, notify.requests.len()),
+        _ => (0, 0),
+    }
+}
+
+/// Shows requests by type name alone, e.g. `Echo,Add`, for span fields.
+struct RequestTypes<'a>(&'a [Box<dyn Request>]);
+
+impl fmt::Display for RequestTypes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, request) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(request.typetag_name())?;
+        }
+        Ok(())
+    }
+}
+
+fn traceparent(trace: &Option<TraceContext>) -> Option<&str> {
+    trace.as_ref().map(|trace| trace.traceparent.as_str())
+}
+
+/// Fills in the `code` field of the span a call, stream or upload runs in
+/// once it has finished: its first error's code, or `Ok`.
+fn record_code(error: Option<ErrorCode>) {
+    match error {
+        Some(code) => Span::current().record("code", tracing::field::display(code)),
+        None => Span::current().record("code", "Ok"),
+    };
+}
+
+async fn write_frames<S>(
+    mut sink: SplitSink<Framed<S, ServerProtocol>, Encoded>,
+    mut rx: Queues<Encoded>,
+    done: CancellationToken,
+    counters: Arc<Counters>,
+    flush: FlushPolicy,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Frames fed to the sink since it was last flushed, and when the first of them was.
+    let mut unflushed = 0;
+    let mut flush_at = Instant::now();
+    loop {
+        let bytes = if unflushed == 0 {
+            tokio::select! {
+                biased;
+                bytes = rx.recv() => bytes,
+                _ = done.cancelled() => break,
+            }
+        } else {
+            match rx.try_recv() {
+                Ok(bytes) => Some(bytes),
+                Err(TryRecvError::Empty) if flush.max_delay.is_zero() => {
+                    sink.flush().await?;
+                    unflushed = 0;
+                    continue;
+                }
+                Err(TryRecvError::Empty) => tokio::select! {
+                    biased;
+                    bytes = rx.recv() => bytes,
+                    _ = tokio::time::sleep_until(flush_at) => {
+                        sink.flush().await?;
+                        unflushed = 0;
+                        continue;
+                    }
+                    _ = done.cancelled() => break,
+                },
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        let Some(bytes) = bytes else { break };
+        metrics::bytes_sent(bytes.len());
+        counters.sent(bytes.len());
+        sink.feed(bytes).await?;
+        if unflushed == 0 {
+            flush_at = Instant::now() + flush.max_delay;
+        }
+        unflushed += 1;
+        if unflushed >= flush.max_frames {
+            sink.flush().await?;
+            unflushed = 0;
+        }
+    }
+    while let Ok(bytes) = rx.try_recv() {
+        metrics::bytes_sent(bytes.len());
+        counters.sent(bytes.len());
+        sink.feed(bytes).await?;
+    }
+    sink.close().await
+}
+
+async fn run_call(
+    mut call: RequestFrame,
+    ctx: Context,
+    outbound: Outbound,
+    slot: Option<ReplySlot>,
+    request_bytes: usize,
+) -> u64 {
+    let id = call.id;
+    tracing::debug!("Processing message");
+    let server = ctx.server();
+    let claim = match call.idempotency_key.take() {
+        Some(key) => {
+            let key = (
+                ctx.identity().map(|identity| identity.key().into_owned()),
+                key,
+            );
+            tokio::select! {
+                claim = server.idempotency_keys.claim(key) => Some(claim),
+                _ = ctx.cancelled() => return id,
+            }
+        }
+        None => None,
+    };
+    let (resp, timings) = match claim {
+        Some(Claim::Replay(results)) => {
+            tracing::debug!(id, "Answering a repeated call with its first results");
+            (replay(id, &results, call.requests.len()), Vec::new())
+        }
+        claim => {
+            let config = server.config();
+            let (mut resp, timings) = handle_call(call, &ctx, &server.middleware).await;
+            if let Some(Claim::Run(completion)) = claim {
+                remember(completion, &mut resp, config.idempotency_ttl);
+            }
+            (resp, timings)
+        }
+    };
+    let errors: Vec<_> = resp
+        .results
+        .iter()
+        .map(|result| result.as_ref().err().map(|err| err.code))
+        .collect();
+    record_code(errors.iter().flatten().next().copied());
+    let bytes = envelope::encode_reply(
+        ctx.connection().codec(),
+        resp,
+        ctx.connection().max_message_length(),
+    );
+
+    if let Some(log) = &server.access_log {
+        for ((request_type, elapsed), error) in timings.into_iter().zip(errors) {
+            let mut record = AccessRecord::new(&ctx, id, RequestKind::Call, request_type);
+            record.error = error;
+            record.request_bytes = request_bytes;
+            record.response_bytes = bytes.len();
+            record.duration = elapsed;
+            log.record(&record);
+        }
+    }
+    if let Some(quotas) = &server.quotas {
+        quotas.record_bytes(ctx.identity(), bytes.len()).await;
+    }
+    let _ = match slot {
+        Some(slot) => slot.send(bytes).await,
+        None => outbound.send(bytes).await,
+    };
+    id
+}
+
+/// Handles every request in `notify` and keeps the results to itself.
+async fn run_notify(notify: NotifyFrame, ctx: Context, request_bytes: usize) {
+    tracing::debug!("Processing notification");
+    let server = ctx.server();
+    let handled = notify
+        .requests
+        .into_iter()
+        .map(|req| dispatch(req, &ctx, None, &server.middleware));
+    let handled = join_all(handled).await;
+    // Nobody hears about failures but the logs.
+    for (result, (request_type, _)) in &handled {
+        if let Err(err) = result {
+            tracing::debug!(request_type, error = %err, "Notification failed");
+        }
+    }
+    record_code(
+        handled
+            .iter()
+            .find_map(|(result, _)| result.as_ref().err().map(|err| err.code)),
+    );
+    if let Some(log) = &server.access_log {
+        for (result, (request_type, elapsed)) in handled {
+            let mut record = AccessRecord::new(&ctx, 0, RequestKind::Notify, request_type);
+            record.error = result.err().map(|err| err.code);
+            record.request_bytes = request_bytes;
+            record.duration = elapsed;
+            log.record(&record);
+        }
+    }
+}
+
+/// Keeps the results of a call with an idempotency key for repeats of it,
+/// unless it was cancelled and so may not have run.
+fn remember(completion: Completion, resp: &mut ResponseFrame, ttl: Duration) {
+    let cancelled = resp
+        .results
+        .iter()
+        .any(|result| matches!(result, Err(err) if err.code == ErrorCode::Cancelled));
+    if cancelled {
+        return;
+    }
+    let id = resp.id.take();
+    let encoded = Codec::Bincode.encode(&*resp);
+    resp.id = id;
+    match encoded {
+        Ok(results) => completion.finish(results.into(), ttl),
+        Err(e) => tracing::warn!(error = %e, "Failed to keep results for an idempotency key"),
+    }
+}
+
+/// The reply to call `id` from the results `remember` kept.
+fn replay(id: u64, results: &[u8], requests: usize) -> ResponseFrame {
+    let mut resp = Codec::Bincode
+        .decode::<ResponseFrame>(results)
+        .unwrap_or_else(|e| ResponseFrame {
+            id: None,
+            results: (0..requests)
+                .map(|_| {
+                    Err(ProtocolError::new(
+                        ErrorCode::Internal,
+                        format!("failed to read the kept results: {e}"),
+                    ))
+                })
+                .collect(),
+            metadata: BTreeMap::new(),
+        });
+    resp.id = Some(id);
+    resp
+}
+
+async fn run_stream(
+    open: StreamRequestFrame,
+    window: Option<watch::Receiver<u64>>,
+    ctx: Context,
+    outbound: Outbound,
+    max_message_length: usize,
+    request_bytes: usize,
+) -> u64 {
+    let id = open.id;
+    let request_type = open.request.typetag_name();
+    let audited = audit::begin(
+        &ctx,
+        RequestKind::Stream,
+        request_type,
+        open.request.as_ref(),
+        open.request.redacted_fields(),
+    );
+    let started = Instant::now();
+    let mut sent = 0;
+    let error = forward_stream(
+        &open,
+        window,
+        &ctx,
+        &outbound,
+        max_message_length,
+        &mut sent,
+    )
+    .await;
+    let elapsed = started.elapsed();
+    record_code(error);
+    // Unlike calls and uploads, not warned about as slow: a stream runs as
+    // long as its client goes on reading it.
+    metrics::request_handled(request_type, ctx.tenant(), elapsed, error);
+    ctx.server().latencies.record(request_type, elapsed);
+    if error.is_some() {
+        ctx.connection().count_error();
+    }
+    if let Some(audited) = audited {
+        audited.finish(&ctx, error, elapsed);
+    }
+
+    if let Some(log) = &ctx.server().access_log {
+        let mut record = AccessRecord::new(&ctx, id, RequestKind::Stream, request_type);
+        record.error = error;
+        record.request_bytes = request_bytes;
+        record.response_bytes = sent;
+        record.duration = elapsed;
+        log.record(&record);
+    }
+    if let Some(quotas) = &ctx.server().quotas {
+        quotas.record_bytes(ctx.identity(), sent).await;
+    }
+    id
+}
+
+/// Sends the stream's items to the client, counting the bytes queued in
+/// `sent` and keeping within the client's `window`, and returns the code it
+/// ended with if it didn't finish cleanly.
+async fn forward_stream(
+    open: &StreamRequestFrame,
+    mut window: Option<watch::Receiver<u64>>,
+    ctx: &Context,
+    outbound: &Outbound,
+    max_message_length: usize,
+    sent: &mut usize,
+) -> Option<ErrorCode> {
+    let id = open.id;
+    let codec = ctx.connection().codec();
+    let emit = |item| {
+        let message = ServerMessage::Stream(StreamFrame { id, item });
+        envelope::try_encode(codec, &message, max_message_length)
+    };
+    let fail = |err| envelope::encode_stream_error(codec, id, err, max_message_length);
+    let mut send = async |bytes: Encoded| {
+        *sent += bytes.len();
+        outbound.send(bytes).await.is_ok()
+    };
+
+    let request_type = open.request.typetag_name();
+    let handler = async {
+        ctx.server().authorize(ctx, request_type)?;
+        open.request.validate()?;
+        panic::isolate(request_type, open.request.handle(ctx))
+            .await?
+            .map_err(ProtocolError::from_handler)
+    };
+    let items = tokio::select! {
+        items = handler => items,
+        _ = ctx.cancelled() => return Some(ErrorCode::Cancelled),
+    };
+    let mut items = match items {
+        Ok(items) => items,
+        Err(err) => {
+            let code = err.code;
+            send(fail(err)).await;
+            return Some(code);
+        }
+    };
+
+    // Bytes of items sent, to hold against the window.
+    let mut streamed = 0;
+    loop {
+        let item = tokio::select! {
+            item = panic::isolate(request_type, items.next()) => item,
+            _ = ctx.cancelled() => return Some(ErrorCode::Cancelled),
+        };
+        let item = match item {
+            Ok(item) => item.map(|item| item.map_err(ProtocolError::from_handler)),
+            Err(err) => Some(Err(err)),
+        };
+        let Some(item) = item else {
+            break;
+        };
+        // Hold the item until the client has granted more than it was sent.
+        if let Some(window) = &mut window {
+            let credit = tokio::select! {
+                credit = window.wait_for(|granted| *granted > streamed) => credit.is_ok(),
+                _ = ctx.cancelled() => false,
+            };
+            if !credit {
+                return Some(ErrorCode::Cancelled);
+            }
+        }
+        let (bytes, error) = match item {
+            Ok(resp) => match emit(StreamItem::Data(resp)) {
+                Ok(bytes) => (bytes, None),
+                Err(err) => (fail(err.clone()), Some(err.code)),
+            },
+            Err(err) => (fail(err.clone()), Some(err.code)),
+        };
+        streamed += bytes.len() as u64;
+        if !send(bytes).await {
+            return Some(ErrorCode::Cancelled);
+        }
+        if error.is_some() {
+            return error;
+        }
+    }
+
+    if let Ok(bytes) = emit(StreamItem::End) {
+        send(bytes).await;
+    }
+    None
+}
+
+async fn run_upload(
+    open: UploadRequestFrame,
+    body: mpsc::Receiver<Result<Bytes>>,
+    ctx: Context,
+    outbound: Outbound,
+    max_message_length: usize,
+) -> u64 {
+    let id = open.id;
+    let started = Instant::now();
+    let received = Arc::new(AtomicUsize::new(0));
+    let counted = received.clone();
+    let body = ReceiverStream::new(body)
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counted.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+        })
+        .boxed();
+    let request_type = open.request.typetag_name();
+    let audited = audit::begin(
+        &ctx,
+        RequestKind::Upload,
+        request_type,
+        open.request.as_ref(),
+        open.request.redacted_fields(),
+    );
+    let handler = async {
+        ctx.server().authorize(&ctx, request_type)?;
+        open.request.validate()?;
+        panic::isolate(request_type, open.request.handle(&ctx, body))
+            .await?
+            .map_err(ProtocolError::from_handler)
+    };
+    let result = tokio::select! {
+        result = handler => Some(result),
+        _ = ctx.cancelled() => None,
+    };
+    let elapsed = started.elapsed();
+    let error = match &result {
+        Some(result) => result.as_ref().err().map(|err| err.code),
+        None => Some(ErrorCode::Cancelled),
+    };
+    record_code(error);
+    metrics::request_handled(request_type, ctx.tenant(), elapsed, error);
+    ctx.server().latencies.record(request_type, elapsed);
+    if error.is_some() {
+        ctx.connection().count_error();
+    }
+    warn_if_slow(&ctx, request_type, elapsed, error);
+    if let Some(audited) = audited {
+        audited.finish(&ctx, error, elapsed);
+    }
+
+    let bytes = result.map(|result| {
+        let resp = ResponseFrame {
+            id: Some(id),
+            results: vec![result],
+            metadata: ctx.take_response_metadata(),
+        };
+        envelope::encode_reply(ctx.connection().codec(), resp, max_message_length)
+    });
+
+    if let Some(log) = &ctx.server().access_log {
+        let mut record = AccessRecord::new(&ctx, id, RequestKind::Upload, request_type);
+        record.error = error;
+        record.request_bytes = received.load(Ordering::Relaxed);
+        record.response_bytes = bytes.as_ref().map_or(0, Encoded::len);
+        record.duration = elapsed;
+        log.record(&record);
+    }
+    if let Some(quotas) = &ctx.server().quotas {
+        let sent = bytes.as_ref().map_or(0, Encoded::len);
+        quotas
+            .record_bytes(ctx.identity(), received.load(Ordering::Relaxed) + sent)
+            .await;
+    }
+    if let Some(bytes) = bytes {
+        let _ = outbound.send(bytes).await;
+    }
+    id
+}
+
+/// What a client needs to work out why the server couldn't read its frame.
+fn malformed_details(codec: Codec, len: usize, offset: Option<usize>) -> String {
+    let at = offset.map_or(String::new(), |offset| {
+        format!(", failing at byte {offset}")
+    });
+    let known = registry::registered::<dyn Request>().len();
+    format!(
+        "{len} byte {codec:?} payload{at}; this server knows {known} request types, listed by ServerInfo"
+    )
+}
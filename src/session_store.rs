@@ -0,0 +1,151 @@
+//! Where a server keeps what a disconnected session leaves behind for a
+//! client to resume it, so that with a store the processes of a cluster
+//! share, like [`RedisSessionStore`], the client can resume on any of them.
+//!
+//! A session resumed by the process it left gets back everything
+//! [`resumption`](crate::resumption) describes, pushes sent while it was
+//! away included. One resumed by another process gets back its identity,
+//! subscriptions and counters from the store, but not those pushes, nor its
+//! acked subscribers, which stay with the process keeping their messages.
+//!
+//! ```ignore
+//! let server = Server::builder()
+//!     .config(ServerConfig {
+//!         session_resume_timeout: Some(Duration::from_secs(60)),
+//!         ..ServerConfig::default()
+//!     })
+//!     .session_store(RedisSessionStore::connect("redis://sessions:6379").await?)
+//!     .build();
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::filter::Filter;
+use crate::rt::Instant;
+use crate::{Connection, Identity};
+
+/// A disconnected session, as any process can restore it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionState {
+    pub identity: Option<Identity>,
+    /// Only a client that negotiated the same codec can resume the session.
+    pub codec: Codec,
+    /// The topics and patterns subscribed to, each with the filter it was
+    /// subscribed with.
+    pub subscriptions: Vec<(String, Option<Filter>)>,
+    /// As in `ConnectionStats`, carried over to the connection resuming it.
+    pub requests: u64,
+    pub errors: u64,
+    pub malformed: u64,
+}
+
+impl SessionState {
+    pub(crate) fn new(connection: &Connection, identity: Option<Identity>) -> Self {
+        let stats = connection.stats();
+        Self {
+            identity,
+            codec: connection.codec(),
+            subscriptions: Vec::new(),
+            requests: stats.requests,
+            errors: stats.errors,
+            malformed: stats.malformed,
+        }
+    }
+}
+
+/// Keeps disconnected sessions by their resumption token.
+#[async_trait::async_trait]
+pub trait SessionStore: Send + Sync + 'static {
+    /// Keeps `state` under `token` for `ttl`, replacing what was there.
+    async fn put(&self, token: &str, state: &SessionState, ttl: Duration) -> Result<()>;
+
+    /// Removes the state under `token` and returns it, unless it expired.
+    async fn take(&self, token: &str) -> Result<Option<SessionState>>;
+}
+
+/// Keeps sessions in the process, so only it can resume them. The default.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionState, Instant)>>,
+}
+
+#[async_trait::async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn put(&self, token: &str, state: &SessionState, ttl: Duration) -> Result<()> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(token.to_string(), (state.clone(), now + ttl));
+        Ok(())
+    }
+
+    async fn take(&self, token: &str) -> Result<Option<SessionState>> {
+        let taken = self.sessions.lock().unwrap().remove(token);
+        Ok(taken
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(state, _)| state))
+    }
+}
+
+/// Keeps sessions in Redis, for servers behind one address to share.
+#[cfg(feature = "redis")]
+pub struct RedisSessionStore {
+    connection: redis::aio::MultiplexedConnection,
+    prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionStore {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1:6379`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            connection: client.get_multiplexed_async_connection().await?,
+            prefix: "myproto:session:".to_string(),
+        })
+    }
+
+    /// Puts keys under `prefix` instead of `myproto:session:`, for several
+    /// services sharing one Redis.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn key(&self, token: &str) -> String {
+        format!("{}{token}", self.prefix)
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait::async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn put(&self, token: &str, state: &SessionState, ttl: Duration) -> Result<()> {
+        let mut connection = self.connection.clone();
+        redis::cmd("SET")
+            .arg(self.key(token))
+            .arg(bincode::serialize(state)?)
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async::<()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn take(&self, token: &str) -> Result<Option<SessionState>> {
+        let mut connection = self.connection.clone();
+        let state: Option<Vec<u8>> = redis::cmd("GETDEL")
+            .arg(self.key(token))
+            .query_async(&mut connection)
+            .await?;
+        Ok(state
+            .map(|state| bincode::deserialize(&state))
+            .transpose()?)
+    }
+}
@@ -0,0 +1,88 @@
+//! A serde `with` module for `Bytes` fields that share the buffer of the
+//! frame they were read from instead of being copied out of it:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize, Debug)]
+//! pub struct PutBlob {
+//!     #[serde(with = "myproto::shared_bytes")]
+//!     pub data: Bytes,
+//! }
+//! ```
+//!
+//! The field is written exactly as a plain `Bytes` is, so either end can
+//! use it without the other. Decoded by a connection from bincode or
+//! MessagePack, which carry bytes as they are, it is a slice of the frame;
+//! from JSON, which writes bytes as a list of numbers, or outside a
+//! connection, it is copied as usual. A slice keeps the whole frame alive,
+//! so copy out of it anything kept long after the request.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use bytes::Bytes;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+
+thread_local! {
+    static FRAME: RefCell<Option<Bytes>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `frame` as the buffer fields decoded on this thread slice into.
+pub(crate) fn sharing<R>(frame: &Bytes, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Bytes>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FRAME.set(self.0.take());
+        }
+    }
+    let _restore = Restore(FRAME.replace(Some(frame.clone())));
+    f()
+}
+
+pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    deserializer.deserialize_bytes(SharedVisitor)
+}
+
+struct SharedVisitor;
+
+impl<'de> Visitor<'de> for SharedVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, bytes: &'de [u8]) -> Result<Bytes, E> {
+        Ok(FRAME.with_borrow(|frame| match frame {
+            Some(frame) if within(frame, bytes) => frame.slice_ref(bytes),
+            _ => Bytes::copy_from_slice(bytes),
+        }))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes::copy_from_slice(bytes))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes::from(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes::from(bytes))
+    }
+}
+
+/// Whether `bytes` lies inside `frame`'s buffer.
+fn within(frame: &Bytes, bytes: &[u8]) -> bool {
+    let start = frame.as_ptr() as usize;
+    let at = bytes.as_ptr() as usize;
+    at >= start && at + bytes.len() <= start + frame.len()
+}
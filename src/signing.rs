@@ -0,0 +1,367 @@
+//! Signatures over requests, so a server can tell which client produced one
+//! even when it arrives through other nodes.
+//!
+//! A client whose `ClientConfig::signing` holds a [`SigningKey`] sends every
+//! request as a [`Signed`] one instead: the request in bincode, when it was
+//! signed, and an HMAC-SHA256 or, with the `ed25519` feature, Ed25519
+//! signature over both under the key's id. The [`VerifySignatures`]
+//! middleware checks the signature against the key of that id, then runs the
+//! request inside through the rest of the chain with its [`Signer`] on the
+//! context, as `Context::signer`. A server without it refuses `Signed`
+//! requests, and one with it can refuse unsigned requests of any type.
+//!
+//! The signed bytes are carried as they are, so a `Signed` request put in a
+//! `SendTo` reaches its peer still checkable there with
+//! `VerifySignatures::verify`. Sharded requests a cluster sends on to the
+//! node owning their key take their `Signer` with them, which that node
+//! trusts as it trusts the rest of what its peers forward.
+//!
+//! The time of signing bounds replays to within `VerifySignatures::max_age`
+//! but doesn't rule them out; pair signing with idempotency keys for requests
+//! that must not run twice.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::builtin::unix_time_us;
+use crate::rt::SystemTime;
+use crate::schema::Describe;
+use crate::{Context, ErrorCode, Middleware, Next, ProtocolError, Request, Response};
+
+/// How old a signature may be, unless set with `VerifySignatures::max_age`.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Bound into every signature, so one can't be passed off as anything else.
+const DOMAIN: &[u8] = b"myproto signed request\0";
+
+/// Which kind of key made a signature.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Describe)]
+pub enum SignatureAlgorithm {
+    HmacSha256,
+    Ed25519,
+}
+
+/// Who signed a request, as `VerifySignatures` found it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Describe)]
+pub struct Signer {
+    pub key_id: String,
+    pub algorithm: SignatureAlgorithm,
+    /// When the request was signed, in microseconds since the Unix epoch.
+    pub signed_at_us: u64,
+}
+
+/// What a client signs its requests with.
+#[derive(Clone)]
+pub struct SigningKey {
+    key_id: String,
+    secret: Secret,
+}
+
+#[derive(Clone)]
+enum Secret {
+    Hmac(Vec<u8>),
+    #[cfg(feature = "ed25519")]
+    Ed25519([u8; 32]),
+}
+
+impl SigningKey {
+    /// Signs with HMAC-SHA256 under a secret the server shares.
+    pub fn hmac(key_id: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key_id: key_id.into(),
+            secret: Secret::Hmac(secret.into()),
+        }
+    }
+
+    /// Signs with the Ed25519 key pair of `seed`; the server only needs its
+    /// `public_key`.
+    #[cfg(feature = "ed25519")]
+    pub fn ed25519(key_id: impl Into<String>, seed: [u8; 32]) -> Self {
+        Self {
+            key_id: key_id.into(),
+            secret: Secret::Ed25519(seed),
+        }
+    }
+
+    /// A new random Ed25519 key pair.
+    #[cfg(feature = "ed25519")]
+    pub fn generate_ed25519(key_id: impl Into<String>) -> Self {
+        let mut seed = [0; 32];
+        getrandom::fill(&mut seed).expect("the OS random number generator is available");
+        Self::ed25519(key_id, seed)
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self.secret {
+            Secret::Hmac(_) => SignatureAlgorithm::HmacSha256,
+            #[cfg(feature = "ed25519")]
+            Secret::Ed25519(_) => SignatureAlgorithm::Ed25519,
+        }
+    }
+
+    /// The Ed25519 public key, for `VerifySignatures::ed25519`; `None` for
+    /// an HMAC key.
+    #[cfg(feature = "ed25519")]
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        use ring::signature::KeyPair;
+        match &self.secret {
+            Secret::Hmac(_) => None,
+            Secret::Ed25519(seed) => {
+                let pair = ed25519_pair(seed);
+                pair.public_key().as_ref().try_into().ok()
+            }
+        }
+    }
+
+    /// `request` signed now, e.g. to put in a `SendTo` for its peer to check.
+    pub fn sign(&self, request: &dyn Request) -> Result<Signed> {
+        let request = Bytes::from(bincode::serialize(request)?);
+        let signed_at_us = unix_time_us(SystemTime::now());
+        let message = signed_bytes(&self.key_id, signed_at_us, &request);
+        let signature = match &self.secret {
+            Secret::Hmac(secret) => hmac(secret, &message).finalize().into_bytes().to_vec(),
+            #[cfg(feature = "ed25519")]
+            Secret::Ed25519(seed) => ed25519_pair(seed).sign(&message).as_ref().to_vec(),
+        };
+        Ok(Signed {
+            key_id: self.key_id.clone(),
+            algorithm: self.algorithm(),
+            signed_at_us,
+            request,
+            signature: Bytes::from(signature),
+        })
+    }
+}
+
+/// The secret is left out.
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("key_id", &self.key_id)
+            .field("algorithm", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A request with its signature; see the [module docs](self).
+#[derive(Serialize, Deserialize, Debug, Describe)]
+pub struct Signed {
+    pub key_id: String,
+    pub algorithm: SignatureAlgorithm,
+    /// When the request was signed, in microseconds since the Unix epoch.
+    pub signed_at_us: u64,
+    /// The request in bincode.
+    pub request: Bytes,
+    pub signature: Bytes,
+}
+
+#[typetag::serde]
+#[async_trait]
+impl Request for Signed {
+    /// Reached only when no `VerifySignatures` took the request apart first.
+    async fn handle(&self, _ctx: &Context) -> Result<Box<dyn Response>> {
+        Err(ProtocolError::new(
+            ErrorCode::Unsupported,
+            "this server does not verify signed requests",
+        )
+        .into())
+    }
+}
+
+/// Checks `Signed` requests and unwraps them for the rest of the chain;
+/// register it with `ServerBuilder::middleware` ahead of anything that
+/// should see the request inside, or trust its `Context::signer`.
+///
+/// The server authorizes the request inside as well as the `Signed` one
+/// around it, but picks a namespace's middleware by the outer type only.
+pub struct VerifySignatures {
+    keys: HashMap<String, VerifyingKey>,
+    required: HashSet<String>,
+    require_all: bool,
+    max_age: Duration,
+}
+
+enum VerifyingKey {
+    Hmac(Vec<u8>),
+    #[cfg(feature = "ed25519")]
+    Ed25519([u8; 32]),
+}
+
+impl Default for VerifySignatures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VerifySignatures {
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+            required: HashSet::new(),
+            require_all: false,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    /// Takes HMAC-SHA256 signatures under `secret` as made by `key_id`.
+    pub fn hmac(mut self, key_id: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        self.keys
+            .insert(key_id.into(), VerifyingKey::Hmac(secret.into()));
+        self
+    }
+
+    /// Takes Ed25519 signatures by `public_key` as made by `key_id`.
+    #[cfg(feature = "ed25519")]
+    pub fn ed25519(mut self, key_id: impl Into<String>, public_key: [u8; 32]) -> Self {
+        self.keys
+            .insert(key_id.into(), VerifyingKey::Ed25519(public_key));
+        self
+    }
+
+    /// Refuses requests of `request_type` that come unsigned.
+    pub fn require(mut self, request_type: impl Into<String>) -> Self {
+        self.required.insert(request_type.into());
+        self
+    }
+
+    /// Refuses every request that comes unsigned.
+    pub fn require_all(mut self) -> Self {
+        self.require_all = true;
+        self
+    }
+
+    /// How far a signature's time may be from now, either way, before it's
+    /// refused; five minutes unless set.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Who signed `signed`, and the request inside it, if its signature
+    /// holds under one of these keys.
+    pub fn verify(&self, signed: &Signed) -> Result<(Signer, Box<dyn Request>), ProtocolError> {
+        let denied = |reason: &str| {
+            ProtocolError::new(
+                ErrorCode::PermissionDenied,
+                format!("signature by {} refused: {reason}", signed.key_id),
+            )
+        };
+        let key = self
+            .keys
+            .get(&signed.key_id)
+            .ok_or_else(|| denied("unknown key"))?;
+        let message = signed_bytes(&signed.key_id, signed.signed_at_us, &signed.request);
+        let valid = match (key, signed.algorithm) {
+            (VerifyingKey::Hmac(secret), SignatureAlgorithm::HmacSha256) => hmac(secret, &message)
+                .verify_slice(&signed.signature)
+                .is_ok(),
+            #[cfg(feature = "ed25519")]
+            (VerifyingKey::Ed25519(public_key), SignatureAlgorithm::Ed25519) => {
+                ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+                    .verify(&message, &signed.signature)
+                    .is_ok()
+            }
+            _ => return Err(denied("wrong algorithm for the key")),
+        };
+        if !valid {
+            return Err(denied("signature mismatch"));
+        }
+        let now = unix_time_us(SystemTime::now());
+        if now.abs_diff(signed.signed_at_us) > self.max_age.as_micros() as u64 {
+            return Err(denied("signed too long ago, or in the future"));
+        }
+        let request = bincode::deserialize(&signed.request).map_err(|e| {
+            ProtocolError::new(
+                ErrorCode::InvalidRequest,
+                format!("signed request does not decode: {e}"),
+            )
+        })?;
+        Ok((
+            Signer {
+                key_id: signed.key_id.clone(),
+                algorithm: signed.algorithm,
+                signed_at_us: signed.signed_at_us,
+            },
+            request,
+        ))
+    }
+
+    fn requires(&self, request_type: &str) -> bool {
+        self.require_all || self.required.contains(request_type)
+    }
+}
+
+#[async_trait]
+impl Middleware for VerifySignatures {
+    async fn call(
+        &self,
+        req: &dyn Request,
+        ctx: &Context,
+        next: Next<'_>,
+    ) -> Result<Box<dyn Response>> {
+        let Some(signed) = (req as &dyn std::any::Any).downcast_ref::<Signed>() else {
+            let request_type = req.typetag_name();
+            if self.requires(request_type) {
+                return Err(ProtocolError::new(
+                    ErrorCode::PermissionDenied,
+                    format!("{request_type} must be sent signed"),
+                )
+                .into());
+            }
+            return next.run(req, ctx).await;
+        };
+        let (signer, request) = self.verify(signed)?;
+        ctx.server().authorize(ctx, request.typetag_name())?;
+        let ctx = ctx.with_signer(Arc::new(signer));
+        next.run(request.as_ref(), &ctx).await
+    }
+}
+
+impl fmt::Debug for VerifySignatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("VerifySignatures")
+            .field("keys", &ids)
+            .field("required", &self.required)
+            .field("require_all", &self.require_all)
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+/// What a signature covers: the key id, the time and the request.
+fn signed_bytes(key_id: &str, signed_at_us: u64, request: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(DOMAIN.len() + 4 + key_id.len() + 8 + request.len());
+    bytes.extend_from_slice(DOMAIN);
+    bytes.extend_from_slice(&(key_id.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(key_id.as_bytes());
+    bytes.extend_from_slice(&signed_at_us.to_be_bytes());
+    bytes.extend_from_slice(request);
+    bytes
+}
+
+fn hmac(secret: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac
+}
+
+#[cfg(feature = "ed25519")]
+fn ed25519_pair(seed: &[u8; 32]) -> ring::signature::Ed25519KeyPair {
+    ring::signature::Ed25519KeyPair::from_seed_unchecked(seed)
+        .expect("any 32 bytes make an Ed25519 seed")
+}
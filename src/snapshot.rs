@@ -0,0 +1,459 @@
+//! Golden-file checks that registered messages still look the same on the
+//! wire, so a field change that would break clients already deployed fails
+//! a test instead of going out unnoticed.
+//!
+//! Every request and response in the [`Schema`] gets a sample value built
+//! from its shape, and the bytes each [`Codec`] writes for it are compared
+//! with a snapshot file in a directory committed with the code:
+//!
+//! ```no_run
+//! #[test]
+//! fn wire_format() {
+//!     myproto::snapshot::Snapshots::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots")).check().unwrap();
+//! }
+//! ```
+//!
+//! Run with `MYPROTO_BLESS=1` after a change meant to alter the wire format
+//! to rewrite the snapshots, then commit them.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::schema::{Schema, Shape, TypeRef, TypeSchema};
+use crate::{Codec, Request, Response, StreamingRequest, UploadRequest};
+
+/// Setting this to anything but `0` makes [`Snapshots::check`] rewrite the
+/// snapshots instead of comparing with them.
+pub const BLESS_VAR: &str = "MYPROTO_BLESS";
+
+const EXTENSION: &str = "snap";
+
+/// A directory of snapshots, one file per message type.
+pub struct Snapshots {
+    dir: PathBuf,
+    schema: Schema,
+    bless: bool,
+}
+
+impl Snapshots {
+    /// Checks the messages registered in this program against the snapshots in `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let bless = std::env::var(BLESS_VAR).is_ok_and(|value| value != "0");
+        Self {
+            dir: dir.into(),
+            schema: Schema::registered(),
+            bless,
+        }
+    }
+
+    /// Checks the messages of `schema` instead of every registered one.
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Rewrites the snapshots rather than comparing with them, whatever `MYPROTO_BLESS` says.
+    pub fn bless(mut self, bless: bool) -> Self {
+        self.bless = bless;
+        self
+    }
+
+    /// Compares every message with its snapshot, failing with what changed
+    /// if any differs, has none, or has one but is no longer registered.
+    pub fn check(&self) -> Result<Report> {
+        let report = self.run()?;
+        if !report.is_ok() {
+            bail!("{report}rerun with {BLESS_VAR}=1 if these changes are intended");
+        }
+        Ok(report)
+    }
+
+    /// Like [`check`](Self::check), but returns the report whatever it says.
+    pub fn run(&self) -> Result<Report> {
+        if self.bless {
+            std::fs::create_dir_all(&self.dir)
+                .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        }
+        let mut report = Report::default();
+        let mut seen = BTreeSet::new();
+        let groups: [(&[TypeSchema], Encoder); 4] = [
+            (&self.schema.requests, encode_all::<dyn Request>),
+            (
+                &self.schema.streaming_requests,
+                encode_all::<dyn StreamingRequest>,
+            ),
+            (
+                &self.schema.upload_requests,
+                encode_all::<dyn UploadRequest>,
+            ),
+            (&self.schema.responses, encode_all::<dyn Response>),
+        ];
+        for (messages, encode) in groups {
+            for ty in messages {
+                seen.insert(ty.name.clone());
+                self.check_one(ty, encode, &mut report)?;
+            }
+        }
+        for stale in self.snapshot_names()? {
+            if !seen.contains(&stale) {
+                if self.bless {
+                    std::fs::remove_file(self.path(&stale))?;
+                    report.blessed.push(stale);
+                } else {
+                    report.removed.push(stale);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn check_one(&self, ty: &TypeSchema, encode: Encoder, report: &mut Report) -> Result<()> {
+        let name = ty.name.clone();
+        let Some(sample) = sample(&self.schema, ty) else {
+            report
+                .skipped
+                .push((name, "no sample can be built from its schema".into()));
+            return Ok(());
+        };
+        let tagged = json!({ &name: sample });
+        let encoded = match encode(&tagged) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                report.skipped.push((name, format!("{e:#}")));
+                return Ok(());
+            }
+        };
+        let snapshot = Snapshot { sample, encoded };
+        let path = self.path(&name);
+        let recorded = match std::fs::read_to_string(&path) {
+            Ok(text) => Some(
+                Snapshot::parse(&text)
+                    .with_context(|| format!("bad snapshot {}", path.display()))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        if recorded.as_ref() == Some(&snapshot) {
+            report.unchanged.push(name);
+        } else if self.bless {
+            std::fs::write(&path, snapshot.render(&name))
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            report.blessed.push(name);
+        } else if let Some(recorded) = recorded {
+            let changes = snapshot.changes(&recorded, &tagged);
+            report.changed.push(Change { name, changes });
+        } else {
+            report.missing.push(name);
+        }
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.{EXTENSION}"))
+    }
+
+    fn snapshot_names(&self) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", self.dir.display()));
+            }
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION)
+                && let Some(stem) = path.file_stem()
+            {
+                names.push(stem.to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// What [`Snapshots::run`] found.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub unchanged: Vec<String>,
+    /// Snapshots written or deleted because blessing was on.
+    pub blessed: Vec<String>,
+    pub changed: Vec<Change>,
+    /// Messages with no snapshot yet.
+    pub missing: Vec<String>,
+    /// Snapshots of messages no longer registered.
+    pub removed: Vec<String>,
+    /// Messages that couldn't be checked, with why; usually a field of a type
+    /// that doesn't derive `Describe`.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl Report {
+    /// Whether nothing differed from the snapshots.
+    pub fn is_ok(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changed {
+            writeln!(f, "{} changed:", change.name)?;
+            for line in &change.changes {
+                writeln!(f, "  {line}")?;
+            }
+        }
+        for name in &self.missing {
+            writeln!(f, "{name} has no snapshot")?;
+        }
+        for name in &self.removed {
+            writeln!(f, "{name} has a snapshot but is no longer registered")?;
+        }
+        for (name, why) in &self.skipped {
+            writeln!(f, "{name} skipped: {why}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A message whose encoding differs from its snapshot.
+#[derive(Debug)]
+pub struct Change {
+    pub name: String,
+    /// One line per difference, saying whether old payloads still decode.
+    pub changes: Vec<String>,
+}
+
+type Encoder = fn(&Value) -> Result<Vec<(Codec, Vec<u8>)>>;
+
+/// A tagged message's encoding under every codec, read in as a `Box<T>`.
+fn encode_all<T: ?Sized>(tagged: &Value) -> Result<Vec<(Codec, Vec<u8>)>>
+where
+    Box<T>: Serialize + DeserializeOwned,
+{
+    let message: Box<T> = serde_json::from_value(tagged.clone())
+        .map_err(|e| anyhow!("its sample doesn't deserialize: {e}"))?;
+    Codec::ALL
+        .into_iter()
+        .map(|codec| Ok((codec, codec.encode(&message)?)))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    sample: Value,
+    encoded: Vec<(Codec, Vec<u8>)>,
+}
+
+impl Snapshot {
+    fn render(&self, name: &str) -> String {
+        let mut out = format!(
+            "# {name}, as each codec writes the sample. Rewritten by {BLESS_VAR}=1.\nsample {}\n",
+            self.sample
+        );
+        for (codec, bytes) in &self.encoded {
+            let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            out.push_str(&format!("{codec:?} {hex}\n"));
+        }
+        out
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut sample = None;
+        let mut encoded = Vec::new();
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            if key == "sample" {
+                sample = Some(serde_json::from_str(value)?);
+                continue;
+            }
+            let codec = Codec::ALL
+                .into_iter()
+                .find(|codec| format!("{codec:?}") == key)
+                .ok_or_else(|| anyhow!("unknown codec {key}"))?;
+            encoded.push((codec, unhex(value)?));
+        }
+        Ok(Self {
+            sample: sample.context("no sample line")?,
+            encoded,
+        })
+    }
+
+    /// How `self` differs from the `recorded` snapshot, checking whether the
+    /// recorded bytes still decode to what `tagged` does now.
+    fn changes(&self, recorded: &Snapshot, tagged: &Value) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.sample != recorded.sample {
+            changes.push(format!(
+                "sample was {}, now {}",
+                recorded.sample, self.sample
+            ));
+        }
+        for (codec, bytes) in &self.encoded {
+            match recorded.encoded.iter().find(|(c, _)| c == codec) {
+                None => changes.push(format!("{codec:?}: no recorded bytes")),
+                Some((_, old)) if old == bytes => {}
+                Some((_, old)) => {
+                    let decodes = match decode_as(tagged, *codec, old) {
+                        Ok(()) => "old payloads still decode",
+                        Err(_) => "old payloads no longer decode",
+                    };
+                    changes.push(format!("{codec:?}: encoding differs, {decodes}"));
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Whether `bytes` decode as the message type `tagged` is tagged with.
+fn decode_as(tagged: &Value, codec: Codec, bytes: &[u8]) -> Result<()> {
+    let name = tagged
+        .as_object()
+        .and_then(|tagged| tagged.keys().next())
+        .context("untagged")?;
+    // The bytes carry their type's tag, so decoding them as any of the
+    // message traits and getting that type back is enough.
+    let decoded: Value = match codec.decode::<Box<dyn Request>>(bytes) {
+        Ok(message) => serde_json::to_value(&message)?,
+        Err(_) => match codec.decode::<Box<dyn Response>>(bytes) {
+            Ok(message) => serde_json::to_value(&message)?,
+            Err(_) => match codec.decode::<Box<dyn StreamingRequest>>(bytes) {
+                Ok(message) => serde_json::to_value(&message)?,
+                Err(_) => serde_json::to_value(&codec.decode::<Box<dyn UploadRequest>>(bytes)?)?,
+            },
+        },
+    };
+    match decoded
+        .as_object()
+        .and_then(|decoded| decoded.keys().next())
+    {
+        Some(decoded) if decoded == name => Ok(()),
+        _ => bail!("decoded as another type"),
+    }
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// A value of `ty` as JSON, the same every time; `None` if it refers to a type the schema doesn't describe.
+pub fn sample(schema: &Schema, ty: &TypeSchema) -> Option<Value> {
+    Sampler { schema, depth: 0 }.shape(ty.shape.as_ref()?)
+}
+
+struct Sampler<'a> {
+    schema: &'a Schema,
+    depth: usize,
+}
+
+impl Sampler<'_> {
+    /// How deep samples of types within types go, so recursive types end.
+    const MAX_DEPTH: usize = 8;
+
+    fn shape(&mut self, shape: &Shape) -> Option<Value> {
+        Some(match shape {
+            Shape::Unit => Value::Null,
+            Shape::Struct(fields) => {
+                let mut object = serde_json::Map::new();
+                for field in fields {
+                    object.insert(field.name.clone(), self.type_ref(&field.ty)?);
+                }
+                Value::Object(object)
+            }
+            Shape::Tuple(elems) if elems.len() == 1 => self.type_ref(&elems[0])?,
+            Shape::Tuple(elems) => Value::Array(
+                elems
+                    .iter()
+                    .map(|ty| self.type_ref(ty))
+                    .collect::<Option<_>>()?,
+            ),
+            Shape::Enum(variants) => {
+                let variant = variants.first()?;
+                match &variant.shape {
+                    Shape::Unit => Value::String(variant.name.clone()),
+                    shape => json!({ &variant.name: self.shape(shape)? }),
+                }
+            }
+        })
+    }
+
+    fn type_ref(&mut self, ty: &TypeRef) -> Option<Value> {
+        Some(match ty {
+            TypeRef::Bool => json!(true),
+            TypeRef::I8
+            | TypeRef::I16
+            | TypeRef::I32
+            | TypeRef::I64
+            | TypeRef::I128
+            | TypeRef::U8
+            | TypeRef::U16
+            | TypeRef::U32
+            | TypeRef::U64
+            | TypeRef::U128 => json!(7),
+            TypeRef::F32 | TypeRef::F64 => json!(1.5),
+            TypeRef::Char => json!("c"),
+            TypeRef::String => json!("text"),
+            TypeRef::Bytes => json!([1, 2, 3]),
+            TypeRef::Duration => json!({ "secs": 1, "nanos": 500 }),
+            TypeRef::Json => json!({ "key": [1, "two"] }),
+            TypeRef::Request => self.any(&self.schema.requests)?,
+            TypeRef::Response => self.any(&self.schema.responses)?,
+            TypeRef::Unit => Value::Null,
+            TypeRef::Option(inner) => self.type_ref(inner)?,
+            TypeRef::List(inner) => json!([self.type_ref(inner)?]),
+            TypeRef::Map(key, value) => {
+                let key = match self.type_ref(key)? {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                json!({ key: self.type_ref(value)? })
+            }
+            TypeRef::Tuple(elems) => Value::Array(
+                elems
+                    .iter()
+                    .map(|ty| self.type_ref(ty))
+                    .collect::<Option<_>>()?,
+            ),
+            TypeRef::Result(ok, _) => json!({ "Ok": self.type_ref(ok)? }),
+            TypeRef::Named(name) => {
+                let shape = self.schema.get(name)?.shape.as_ref()?;
+                self.nested(|sampler| sampler.shape(shape))?
+            }
+        })
+    }
+
+    /// A sample of the first unit message in `messages`, tagged with its name.
+    fn any(&self, messages: &[TypeSchema]) -> Option<Value> {
+        let unit = messages
+            .iter()
+            .find(|ty| matches!(ty.shape, Some(Shape::Unit)))?;
+        Some(json!({ &unit.name: null }))
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Option<Value>) -> Option<Value> {
+        if self.depth >= Self::MAX_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let value = f(self);
+        self.depth -= 1;
+        value
+    }
+}
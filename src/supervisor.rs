@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::task::{Id, JoinError, JoinSet};
+
+use crate::metrics;
+use crate::panic;
+
+/// The connection tasks one listener has spawned. Each is reported as it
+/// ends, a panic included, and `drain` waits for the rest on shutdown.
+pub(crate) struct Connections {
+    listener: String,
+    tasks: JoinSet<()>,
+    /// Each running task's client, to say whose connection a panic was on.
+    addrs: HashMap<Id, SocketAddr>,
+}
+
+impl Connections {
+    pub(crate) fn new(listener: impl Into<String>) -> Self {
+        Self {
+            listener: listener.into(),
+            tasks: JoinSet::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn spawn(
+        &mut self,
+        addr: SocketAddr,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let handle = self.tasks.spawn(task);
+        self.addrs.insert(handle.id(), addr);
+        metrics::connection_task_started();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Waits for a task to end and reports how it did; `None` once there are none.
+    pub(crate) async fn join_next(&mut self) -> Option<()> {
+        let ended = self.tasks.join_next_with_id().await?;
+        metrics::connection_task_ended();
+        match ended {
+            Ok((id, ())) => {
+                self.addrs.remove(&id);
+            }
+            Err(e) => self.report(e),
+        }
+        Some(())
+    }
+
+    fn report(&mut self, error: JoinError) {
+        let addr = self.addrs.remove(&error.id());
+        let listener = &self.listener;
+        if error.is_panic() {
+            let panic = error.into_panic();
+            let message = panic::panic_message(&*panic);
+            tracing::error!(?addr, listener, panic = message, "Connection task panicked");
+            metrics::connection_panicked();
+        } else {
+            tracing::debug!(?addr, listener, "Connection task aborted");
+        }
+    }
+
+    /// Waits up to `timeout` for every task to end, then aborts the rest.
+    pub(crate) async fn drain(&mut self, timeout: Duration) {
+        let drain = async { while self.join_next().await.is_some() {} };
+        if tokio::time::timeout(timeout, drain).await.is_ok() {
+            return;
+        }
+        tracing::warn!(
+            listener = %self.listener,
+            remaining = self.len(),
+            "Drain timeout elapsed, aborting connections"
+        );
+        self.tasks.abort_all();
+        while self.join_next().await.is_some() {}
+    }
+}
@@ -0,0 +1,83 @@
+//! Running as a systemd service: listeners handed over by socket activation
+//! and readiness reported through `sd_notify`.
+//!
+//! With `ListenStream=` in a `.socket` unit, systemd binds the sockets itself
+//! and starts the server with them open, named by `LISTEN_FDS`; [`listeners`]
+//! takes them over. With `Type=notify` in the service unit, systemd waits for
+//! [`notify`]`("READY=1")` before counting the server as started.
+
+use std::env;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+use crate::listener::{Binding, Listener};
+use anyhow::{Context as _, Result};
+
+/// The first descriptor systemd passes; the rest follow it.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening sockets systemd passed this process, each named by
+/// `FileDescriptorName=` if the socket unit sets one. Empty when the process
+/// wasn't socket activated, or the sockets were meant for another process.
+///
+/// The socket files of inherited Unix listeners belong to systemd, so they
+/// are left in place when the server stops serving them.
+pub fn listeners() -> Result<Vec<Binding>> {
+    let Some(count) = passed_fds()? else {
+        return Ok(Vec::new());
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (0..count)
+        .map(|i| {
+            let fd = LISTEN_FDS_START + i;
+            // SAFETY: systemd passes each of the `LISTEN_FDS` descriptors
+            // from 3 open, and only this process, named by `LISTEN_PID`,
+            // takes them over, once.
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+            let listener =
+                Listener::inherit(socket).with_context(|| format!("inherited socket {fd}"))?;
+            let binding = Binding::new(listener).inherited();
+            Ok(match names.next() {
+                Some(name) if !name.is_empty() && name != "unknown" => binding.name(name),
+                _ => binding,
+            })
+        })
+        .collect()
+}
+
+/// How many sockets were passed to this process, from `LISTEN_PID` and `LISTEN_FDS`.
+fn passed_fds() -> Result<Option<RawFd>> {
+    let (Ok(pid), Ok(fds)) = (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds = fds
+        .parse()
+        .with_context(|| format!("LISTEN_FDS is not a number: {fds:?}"))?;
+    Ok(Some(fds))
+}
+
+/// Sends `state`, such as `READY=1` or `STOPPING=1`, to the service manager
+/// named by `NOTIFY_SOCKET`. Returns whether there was one to tell.
+pub fn notify(state: &str) -> Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let sent = match path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        Some(_) => anyhow::bail!("abstract notify sockets are only supported on Linux"),
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    sent.with_context(|| format!("failed to notify {}", path.display()))?;
+    Ok(true)
+}
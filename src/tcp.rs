@@ -0,0 +1,46 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::io;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::net::TcpStream;
+
+/// Socket options applied to every TCP connection as it is accepted or
+/// opened. Anything left unset keeps the operating system's default. A
+/// browser opens its own sockets, so on wasm32 these are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Sends small frames straight away instead of letting Nagle's algorithm
+    /// batch them, trading bandwidth for latency.
+    pub nodelay: bool,
+    /// Idle time before the first keepalive probe; `None` leaves keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes once they have started.
+    pub keepalive_interval: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TcpOptions {
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Multi-tenant mode, for one server to serve several customers without
+//! any of them reaching another's data.
+//!
+//! Turned on with `ServerBuilder::multi_tenant`, it has every connection
+//! authenticate as an [`Identity`] with a tenant, which the `Authenticator`
+//! sets, and refuses those without one. What the server keeps by name is
+//! then kept apart per tenant:
+//!
+//! - rate limit buckets, quota counters, acked subscribers, jobs and
+//!   idempotency keys are keyed by tenant and subject, and `Quotas::tenant`
+//!   gives a tenant its own quota;
+//! - `Authorization::grant` and `Quotas::subject` name identities as
+//!   `tenant/subject`, as do access log and audit records;
+//! - request and quota metrics carry a `tenant` label;
+//! - the built-in pub/sub and key-value requests work on names under the
+//!   tenant's prefix, so one tenant's `orders` topic and `config` key are
+//!   not another's, and subscribers are pushed topic names without it;
+//! - relay peer names are kept the same way, so `SendTo` only reaches
+//!   peers of the sender's tenant;
+//! - a parked session keeps its identity, tenant included, and its
+//!   subscriptions under their prefixed names, so resuming it from the
+//!   session store puts it back in its tenant.
+//!
+//! Outside the mode, an identity's tenant is ignored. Handlers keeping
+//! their own state per tenant name it with `Context::scoped`.
+//!
+//! ```ignore
+//! let tokens = StaticTokens::new()
+//!     .token("acme-secret", Identity::new("alice").with_tenant("acme"))
+//!     .token("globex-secret", Identity::new("bob").with_tenant("globex"));
+//! let server = Server::builder()
+//!     .authenticator(tokens)
+//!     .multi_tenant()
+//!     .build();
+//! ```
+
+use crate::{ErrorCode, Identity, ProtocolError, Server};
+
+/// Separates the tenant from the name it prefixes; also the topic level
+/// separator, so a tenant's topics are a subtree of their own.
+const SEPARATOR: char = '/';
+
+/// `name` under `tenant`'s prefix, as the server keeps it.
+pub fn scoped(tenant: &str, name: &str) -> String {
+    format!("{tenant}{SEPARATOR}{name}")
+}
+
+/// A name kept under a tenant's prefix, as the tenant knows it.
+pub(crate) fn unscoped(name: &str) -> &str {
+    name.split_once(SEPARATOR).map_or(name, |(_, name)| name)
+}
+
+/// Checks `identity` against the server's mode: in multi-tenant mode it
+/// must have a tenant fit to prefix names with; otherwise its tenant is
+/// dropped, so nothing downstream keys anything by it.
+pub(crate) fn admit(mut identity: Identity, multi_tenant: bool) -> Result<Identity, ProtocolError> {
+    if !multi_tenant {
+        identity.tenant = None;
+        return Ok(identity);
+    }
+    match identity.tenant.as_deref() {
+        None => Err(missing()),
+        Some(tenant) if tenant.is_empty() || tenant.contains([SEPARATOR, '+', '#']) => {
+            tracing::warn!(
+                subject = identity.subject,
+                tenant,
+                "Authenticator gave an identity an invalid tenant"
+            );
+            Err(ProtocolError::new(
+                ErrorCode::Unauthenticated,
+                "authentication failed",
+            ))
+        }
+        Some(_) => Ok(identity),
+    }
+}
+
+/// The error for a connection or request without a tenant in multi-tenant mode.
+pub(crate) fn missing() -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::PermissionDenied,
+        "this server only serves identities with a tenant",
+    )
+}
+
+impl Server {
+    /// Whether the server was built with `ServerBuilder::multi_tenant`.
+    pub fn is_multi_tenant(&self) -> bool {
+        self.multi_tenant
+    }
+
+    /// Like [`admit`], for a connection or request that may not have
+    /// authenticated, which multi-tenant mode refuses.
+    pub(crate) fn admit_identity(
+        &self,
+        identity: Option<Identity>,
+    ) -> Result<Option<Identity>, ProtocolError> {
+        match identity {
+            Some(identity) => admit(identity, self.multi_tenant).map(Some),
+            None if self.multi_tenant => Err(missing()),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Running a server and its clients in process over in-memory streams, so
+//! integration tests of handlers need no network ports, or skipping the
+//! connection altogether with [`MockClient`]. [`FaultInjection`] makes a
+//! server slow and unreliable on purpose, to see how clients' retries and
+//! timeouts cope. Built with the `testing` feature, for dev-dependencies.
+//!
+//! ```ignore
+//! let router = Router::new().route(|req: Add, _ctx| async move { Ok(AddResponse { sum: req.a + req.b }) });
+//! let (client, _server) = spawn_test_server(router).await?;
+//! assert_eq!(client.call_typed(Add { a: 2, b: 3 }).await?.sum, 5);
+//! ```
+
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::io::DuplexStream;
+
+use crate::client::downcast_response;
+use crate::codec::Codec;
+use crate::frame::Compression;
+use crate::reconnect::random_fraction;
+use crate::rt;
+use crate::server;
+use crate::{
+    Client, ClientConfig, Connection, Context, ErrorCode, Identity, Middleware, Next,
+    ProtocolError, Request, Response, Router, Server, TypedRequest,
+};
+
+/// Bytes either direction of an in-memory connection buffers before writes wait.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Two connected in-memory streams: what one writes, the other reads.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(BUFFER_SIZE)
+}
+
+/// A server whose sessions run over in-memory streams; they are shut down
+/// when it's dropped.
+pub struct TestServer {
+    server: Server,
+}
+
+impl TestServer {
+    pub fn new(server: Server) -> Self {
+        Self { server }
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Opens a session and a client connected to it, which appears to the
+    /// server as `127.0.0.1:0`.
+    pub async fn connect(&self) -> Result<Client> {
+        self.connect_with(ClientConfig::default()).await
+    }
+
+    pub async fn connect_with(&self, config: ClientConfig) -> Result<Client> {
+        let (client, session) = duplex();
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            let peer_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            if let Err(e) = server.handle_client(session, peer_addr).await {
+                tracing::debug!(error = %e, "Test session ended with an error");
+            }
+        });
+        Client::with_config(client, config).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.shutdown.cancel();
+    }
+}
+
+/// Starts a default server answering with `router` and returns a client
+/// connected to it, alongside the server, which must be kept alive.
+pub async fn spawn_test_server(router: Router) -> Result<(Client, TestServer)> {
+    let server = TestServer::new(Server::builder().router(router).build());
+    let client = server.connect().await?;
+    Ok((client, server))
+}
+
+/// Sends requests straight through a server's middleware, validation and
+/// handlers, with no session or codec in between, so tests can check what a
+/// handler does with the context and state it is given.
+pub struct MockClient {
+    server: Server,
+    peer_addr: SocketAddr,
+    identity: Option<Identity>,
+}
+
+impl MockClient {
+    pub fn new(server: Server) -> Self {
+        Self {
+            server,
+            peer_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            identity: None,
+        }
+    }
+
+    pub fn peer_addr(mut self, peer_addr: SocketAddr) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    /// Makes calls as if the client had authenticated as `identity`.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Handles `request` under the server's request timeout. Pushes sent to
+    /// the connection it is handled on go nowhere.
+    pub async fn call(&self, request: Box<dyn Request>) -> Result<Box<dyn Response>> {
+        let connection = Connection::detached(&self.server, self.peer_addr, Codec::Bincode);
+        let ctx = Context::new(
+            connection,
+            Compression::None,
+            self.identity.clone(),
+            &self.server,
+        );
+        let (result, _) = server::dispatch(request, &ctx, None, &self.server.middleware).await;
+        Ok(result?)
+    }
+
+    pub async fn call_typed<R: TypedRequest>(&self, request: R) -> Result<R::Response> {
+        let response = self.call(Box::new(request)).await?;
+        downcast_response(response)
+    }
+}
+
+/// Middleware that delays requests, fails them and drops their connections
+/// at random, for testing clients against a misbehaving server. Registered
+/// with `ServerBuilder::middleware` like any other, and never meant for a
+/// server in production.
+///
+/// ```ignore
+/// let faults = FaultInjection::new()
+///     .latency(Duration::from_millis(50), Duration::from_millis(500))
+///     .error_rate(0.1, ErrorCode::Busy)
+///     .drop_rate(0.01);
+/// let server = Server::builder().router(router).middleware(faults).build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct FaultInjection {
+    latency: Option<(Duration, Duration)>,
+    error_rate: f64,
+    error_code: ErrorCode,
+    drop_rate: f64,
+    only: HashSet<String>,
+}
+
+impl Default for FaultInjection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FaultInjection {
+    /// Injects nothing until told what to.
+    pub fn new() -> Self {
+        Self {
+            latency: None,
+            error_rate: 0.0,
+            error_code: ErrorCode::Busy,
+            drop_rate: 0.0,
+            only: HashSet::new(),
+        }
+    }
+
+    /// Holds every request for between `min` and `max` before handling it.
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = Some((min, max.max(min)));
+        self
+    }
+
+    /// Fails this fraction of requests with `code`, which is `Busy` unless
+    /// set, instead of handling them.
+    pub fn error_rate(mut self, rate: f64, code: ErrorCode) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self.error_code = code;
+        self
+    }
+
+    /// Closes the connection of this fraction of requests instead of
+    /// answering them, as a server that crashed or a network that failed
+    /// mid-call would.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Injects faults only into requests of `request_type`; with none
+    /// given, every request is fair game.
+    pub fn only(mut self, request_type: impl Into<String>) -> Self {
+        self.only.insert(request_type.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Middleware for FaultInjection {
+    async fn call(
+        &self,
+        req: &dyn Request,
+        ctx: &Context,
+        next: Next<'_>,
+    ) -> Result<Box<dyn Response>> {
+        let request_type = req.typetag_name();
+        if !self.only.is_empty() && !self.only.contains(request_type) {
+            return next.run(req, ctx).await;
+        }
+        if let Some((min, max)) = self.latency {
+            let delay = min + (max - min).mul_f64(random_fraction());
+            tokio::select! {
+                _ = rt::sleep(delay) => {}
+                _ = ctx.cancelled() => {
+                    return Err(ProtocolError::new(ErrorCode::Cancelled, "cancelled").into());
+                }
+            }
+        }
+        if random_fraction() < self.drop_rate {
+            tracing::debug!(request_type, "Injecting a dropped connection");
+            ctx.connection().close();
+            // The session cancels the call as it closes; a detached connection has none to.
+            let _ = rt::timeout(Duration::from_secs(1), ctx.cancelled()).await;
+            return Err(ProtocolError::new(ErrorCode::Cancelled, "connection dropped").into());
+        }
+        if random_fraction() < self.error_rate {
+            tracing::debug!(request_type, code = %self.error_code, "Injecting an error");
+            return Err(ProtocolError::new(self.error_code, "injected fault").into());
+        }
+        next.run(req, ctx).await
+    }
+}
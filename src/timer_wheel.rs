@@ -0,0 +1,160 @@
+//! A hashed timer wheel: items are dropped into the slot of the tick they
+//! are due on and taken out a slot at a time, so scheduling many of them
+//! costs no more than scheduling one.
+
+use std::time::{Duration, Instant};
+
+pub(crate) struct TimerWheel<T> {
+    /// Each slot's items, with how many more turns of the wheel to wait.
+    slots: Vec<Vec<(u64, T)>>,
+    tick: Duration,
+    current: usize,
+    /// When the current tick was.
+    now: Instant,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub(crate) fn new(tick: Duration, slots: usize) -> Self {
+        Self {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            tick,
+            current: 0,
+            now: Instant::now(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// When `advance` is next due.
+    pub(crate) fn next_tick(&self) -> Instant {
+        self.now + self.tick
+    }
+
+    /// Schedules `item` for the first tick at or after `at`, or the next
+    /// tick if that has passed.
+    pub(crate) fn insert(&mut self, at: Instant, item: T) {
+        if self.is_empty() {
+            // An idle wheel has no ticks to catch up on.
+            self.now = Instant::now();
+        }
+        let ahead = at.saturating_duration_since(self.now);
+        let ticks = ahead.as_nanos().div_ceil(self.tick.as_nanos()).max(1) as u64;
+        let slots = self.slots.len() as u64;
+        let slot = (self.current as u64 + ticks) % slots;
+        self.slots[slot as usize].push(((ticks - 1) / slots, item));
+        self.len += 1;
+    }
+
+    /// Moves on a tick, returning the items due on it.
+    pub(crate) fn advance(&mut self) -> Vec<T> {
+        self.current = (self.current + 1) % self.slots.len();
+        self.now += self.tick;
+        let slot = std::mem::take(&mut self.slots[self.current]);
+        let mut due = Vec::new();
+        for (turns, item) in slot {
+            match turns.checked_sub(1) {
+                Some(turns) => self.slots[self.current].push((turns, item)),
+                None => due.push(item),
+            }
+        }
+        self.len -= due.len();
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long enough that the time a test takes never shifts an item a tick.
+    const TICK: Duration = Duration::from_secs(3600);
+
+    /// A wheel of `slots` with `items` scheduled that many ticks ahead.
+    fn wheel(slots: usize, items: &[u32]) -> TimerWheel<u32> {
+        let mut wheel = TimerWheel::new(TICK, slots);
+        let start = Instant::now();
+        for &ticks in items {
+            wheel.insert(start + TICK * ticks, ticks);
+        }
+        wheel
+    }
+
+    /// The items fired on each of the next `ticks` ticks.
+    fn run(wheel: &mut TimerWheel<u32>, ticks: usize) -> Vec<Vec<u32>> {
+        (0..ticks)
+            .map(|_| {
+                let mut due = wheel.advance();
+                due.sort_unstable();
+                due
+            })
+            .collect()
+    }
+
+    #[test]
+    fn items_fire_on_their_tick_in_order() {
+        let mut wheel = wheel(8, &[3, 1, 2, 2]);
+        assert_eq!(wheel.len(), 4);
+        assert_eq!(run(&mut wheel, 4), [vec![1], vec![2, 2], vec![3], vec![]]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn items_beyond_one_turn_wait_for_later_turns() {
+        let mut wheel = wheel(4, &[2, 6, 10, 4, 8]);
+        // 2, 6 and 10 share a slot, as do 4 and 8.
+        assert_eq!(
+            run(&mut wheel, 10),
+            [
+                vec![],
+                vec![2],
+                vec![],
+                vec![4],
+                vec![],
+                vec![6],
+                vec![],
+                vec![8],
+                vec![],
+                vec![10],
+            ]
+        );
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn item_due_a_whole_turn_ahead_fires_on_that_turn() {
+        let mut wheel = wheel(4, &[4]);
+        assert_eq!(run(&mut wheel, 4), [vec![], vec![], vec![], vec![4]]);
+    }
+
+    #[test]
+    fn item_already_due_fires_on_the_next_tick() {
+        let mut wheel = TimerWheel::new(TICK, 4);
+        wheel.insert(Instant::now() - TICK, 1);
+        wheel.insert(Instant::now(), 2);
+        let mut due = wheel.advance();
+        due.sort_unstable();
+        assert_eq!(due, [1, 2]);
+    }
+
+    #[test]
+    fn items_inserted_later_count_from_the_current_tick() {
+        let mut wheel = wheel(4, &[1, 8]);
+        assert_eq!(run(&mut wheel, 3), [vec![1], vec![], vec![]]);
+        // Three ticks in, one more tick ahead is the fourth.
+        let at = wheel.next_tick();
+        wheel.insert(at, 4);
+        assert_eq!(wheel.len(), 2);
+        assert_eq!(
+            run(&mut wheel, 5),
+            [vec![4], vec![], vec![], vec![], vec![8]]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Hierarchical topic names and the patterns that subscribe to many at once.
+//!
+//! A topic name is split into levels by `/`, like `sensors/kitchen/temp`.
+//! A subscription may name one topic or, MQTT-style, a pattern: `+` stands
+//! for any one level and a trailing `#` for any number of them, none
+//! included, so `sensors/+/temp` matches `sensors/kitchen/temp` and
+//! `sensors/#` matches `sensors` and everything under it. Messages are
+//! published to names, never to patterns.
+
+use std::collections::HashMap;
+
+use crate::ValidationError;
+
+/// Stands for any one level.
+pub const SINGLE_LEVEL: &str = "+";
+/// Stands for the rest of the levels, however many.
+pub const MULTI_LEVEL: &str = "#";
+
+/// Whether `topic` has wildcards in it.
+pub fn is_pattern(topic: &str) -> bool {
+    topic.contains(['+', '#'])
+}
+
+/// Checks `pattern` may be subscribed to: wildcards take a whole level,
+/// and `#` only the last.
+pub fn check_pattern(pattern: &str) -> Result<(), ValidationError> {
+    let mut levels = pattern.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            MULTI_LEVEL if levels.peek().is_some() => {
+                return Err(ValidationError::field(
+                    "topic",
+                    "`#` must be the last level",
+                ));
+            }
+            SINGLE_LEVEL | MULTI_LEVEL => {}
+            _ if is_pattern(level) => {
+                return Err(ValidationError::field(
+                    "topic",
+                    "wildcards must take a whole level",
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks `topic` is a name that can be published to, not a pattern.
+pub fn check_name(topic: &str) -> Result<(), ValidationError> {
+    if is_pattern(topic) {
+        return Err(ValidationError::field(
+            "topic",
+            "must be a topic name, without wildcards",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a message published on `topic` reaches subscribers of `pattern`.
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in pattern.split('/') {
+        if level == MULTI_LEVEL {
+            return true;
+        }
+        match topic.next() {
+            Some(name) if level == SINGLE_LEVEL || level == name => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+/// Values keyed by id under topics and patterns, found by the topic names
+/// they match with one walk down the levels.
+pub(crate) struct Trie<V> {
+    root: Node<V>,
+}
+
+struct Node<V> {
+    values: HashMap<u64, V>,
+    children: HashMap<String, Node<V>>,
+}
+
+impl<V> Default for Trie<V> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.children.is_empty()
+    }
+
+    /// Gathers the values below this node matching the rest of a topic's `levels`.
+    fn collect<'a>(&'a self, levels: &[&str], found: &mut Vec<&'a V>) {
+        if let Some(rest) = self.children.get(MULTI_LEVEL) {
+            found.extend(rest.values.values());
+        }
+        let Some((level, levels)) = levels.split_first() else {
+            found.extend(self.values.values());
+            return;
+        };
+        if let Some(child) = self.children.get(*level) {
+            child.collect(levels, found);
+        }
+        if let Some(child) = self.children.get(SINGLE_LEVEL) {
+            child.collect(levels, found);
+        }
+    }
+
+    fn remove(&mut self, levels: &[&str], id: u64) -> Option<V> {
+        let Some((level, levels)) = levels.split_first() else {
+            return self.values.remove(&id);
+        };
+        let child = self.children.get_mut(*level)?;
+        let removed = child.remove(levels, id);
+        if child.is_empty() {
+            self.children.remove(*level);
+        }
+        removed
+    }
+
+    /// Gathers the patterns below this node, whose own is `path`, that `id`
+    /// has a value under.
+    fn patterns_of<'a>(
+        &'a self,
+        id: u64,
+        path: &mut Vec<&'a str>,
+        found: &mut Vec<(String, &'a V)>,
+    ) {
+        if let Some(value) = self.values.get(&id) {
+            found.push((path.join("/"), value));
+        }
+        for (level, child) in &self.children {
+            path.push(level);
+            child.patterns_of(id, path, found);
+            path.pop();
+        }
+    }
+
+    fn retain(&mut self, f: &mut impl FnMut(&mut HashMap<u64, V>)) {
+        f(&mut self.values);
+        self.children.retain(|_, child| {
+            child.retain(f);
+            !child.is_empty()
+        });
+    }
+}
+
+impl<V> Trie<V> {
+    /// Puts `value` under `pattern` for `id`, returning the one it replaced.
+    pub(crate) fn insert(&mut self, pattern: &str, id: u64, value: V) -> Option<V> {
+        let node = pattern.split('/').fold(&mut self.root, |node, level| {
+            node.children.entry(level.to_string()).or_default()
+        });
+        node.values.insert(id, value)
+    }
+
+    pub(crate) fn remove(&mut self, pattern: &str, id: u64) -> Option<V> {
+        let levels: Vec<&str> = pattern.split('/').collect();
+        self.root.remove(&levels, id)
+    }
+
+    /// The values under exactly `pattern`, not those matching it.
+    pub(crate) fn get(&self, pattern: &str) -> Option<&HashMap<u64, V>> {
+        pattern
+            .split('/')
+            .try_fold(&self.root, |node, level| node.children.get(level))
+            .map(|node| &node.values)
+    }
+
+    /// Every value under a topic or pattern that `topic` matches.
+    pub(crate) fn matching(&self, topic: &str) -> Vec<&V> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut found = Vec::new();
+        self.root.collect(&levels, &mut found);
+        found
+    }
+
+    /// Every topic and pattern `id` has a value under, with the value.
+    pub(crate) fn patterns_of(&self, id: u64) -> Vec<(String, &V)> {
+        let mut found = Vec::new();
+        self.root.patterns_of(id, &mut Vec::new(), &mut found);
+        found
+    }
+
+    /// Runs `f` on the values under each topic and pattern, dropping those
+    /// left with none.
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&mut HashMap<u64, V>)) {
+        self.root.retain(&mut f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: &[&str] = &[
+        "#", "+", "a", "a/#", "a/+", "a/b", "a/+/c", "+/b/#", "a//c", "a/+/", "/a", "+/+",
+    ];
+
+    const TOPICS: &[&str] = &[
+        "", "a", "b", "a/b", "a/b/c", "a//c", "a/b/", "/a", "b/b", "a/x/c",
+    ];
+
+    /// A trie with each of `PATTERNS` subscribed under the id of its index.
+    fn trie() -> Trie<usize> {
+        let mut trie = Trie::default();
+        for (id, pattern) in PATTERNS.iter().enumerate() {
+            trie.insert(pattern, id as u64, id);
+        }
+        trie
+    }
+
+    fn matching(trie: &Trie<usize>, topic: &str) -> Vec<&'static str> {
+        let mut found: Vec<&str> = trie
+            .matching(topic)
+            .into_iter()
+            .map(|&id| PATTERNS[id])
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    #[test]
+    fn single_level_wildcard_takes_exactly_one_level() {
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(!matches("a/+/c", "a/c"));
+        assert!(!matches("a/+/c", "a/b/b/c"));
+        assert!(matches("+", "a"));
+        assert!(!matches("+", "a/b"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_takes_the_rest_or_nothing() {
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(!matches("a/#", "b"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_at_the_root_matches_everything() {
+        for topic in TOPICS {
+            assert!(matches("#", topic), "{topic:?}");
+        }
+        let trie = trie();
+        for topic in TOPICS {
+            assert!(matching(&trie, topic).contains(&"#"), "{topic:?}");
+        }
+    }
+
+    #[test]
+    fn empty_levels_are_levels_of_their_own() {
+        assert!(matches("a//c", "a//c"));
+        assert!(!matches("a//c", "a/b/c"));
+        assert!(matches("a/+/c", "a//c"));
+        assert!(matches("a/+", "a/"));
+        assert!(matches("+/a", "/a"));
+        assert!(!matches("a", "a/"));
+        assert!(matches("+", ""));
+    }
+
+    #[test]
+    fn trie_agrees_with_matches() {
+        let trie = trie();
+        for topic in TOPICS {
+            let mut expected: Vec<&str> = PATTERNS
+                .iter()
+                .copied()
+                .filter(|pattern| matches(pattern, topic))
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(matching(&trie, topic), expected, "{topic:?}");
+        }
+    }
+
+    #[test]
+    fn unsubscribing_prunes_emptied_levels() {
+        let mut trie = Trie::default();
+        trie.insert("a/b/c", 1, ());
+        trie.insert("a/b/c", 2, ());
+        trie.insert("a/+", 1, ());
+
+        assert_eq!(trie.remove("a/b/c", 1), Some(()));
+        assert!(trie.get("a/b/c").is_some());
+        assert_eq!(trie.remove("a/b/c", 2), Some(()));
+        assert!(trie.get("a/b/c").is_none());
+        assert!(trie.get("a/b").is_none());
+        assert!(trie.get("a/+").is_some());
+
+        assert_eq!(trie.remove("a/+", 1), Some(()));
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn removing_what_is_not_there_changes_nothing() {
+        let mut trie = Trie::default();
+        trie.insert("a/b", 1, ());
+        assert_eq!(trie.remove("a/b", 2), None);
+        assert_eq!(trie.remove("a/b/c", 1), None);
+        assert_eq!(trie.remove("a", 1), None);
+        assert_eq!(trie.matching("a/b").len(), 1);
+    }
+
+    #[test]
+    fn retain_prunes_topics_left_empty() {
+        let mut trie = Trie::default();
+        trie.insert("a/b", 1, ());
+        trie.insert("a/#", 2, ());
+        trie.retain(|values| values.retain(|&id, _| id == 2));
+        assert!(trie.get("a/b").is_none());
+        assert_eq!(trie.patterns_of(2), vec![("a/#".to_string(), &())]);
+        trie.retain(|values| values.clear());
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn patterns_must_use_whole_level_wildcards() {
+        assert!(check_pattern("a/+/#").is_ok());
+        assert!(check_pattern("#").is_ok());
+        assert!(check_pattern("a/#/b").is_err());
+        assert!(check_pattern("a/b+").is_err());
+        assert!(check_name("a/+").is_err());
+        assert!(check_name("a/b").is_ok());
+    }
+}
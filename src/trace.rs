@@ -0,0 +1,71 @@
+use tracing::Span;
+
+use crate::envelope::TraceContext;
+use crate::reconnect::random_fraction;
+
+/// The span made by `span` for `rate` of the requests that ask, and for
+/// every one carrying the client's trace context, so distributed traces
+/// stay whole; parented to the client's span where there is one.
+pub(crate) fn sampled(
+    rate: f64,
+    trace: &Option<TraceContext>,
+    span: impl FnOnce() -> Span,
+) -> Span {
+    if trace.is_none() && (rate <= 0.0 || (rate < 1.0 && random_fraction() >= rate)) {
+        return Span::none();
+    }
+    let span = span();
+    if let Some(trace) = trace {
+        set_parent(&span, trace);
+    }
+    span
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::collections::HashMap;
+
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use super::*;
+
+    const TRACEPARENT: &str = "traceparent";
+    const TRACESTATE: &str = "tracestate";
+
+    /// The W3C trace context of the caller's current span.
+    pub(crate) fn current() -> Option<TraceContext> {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+        Some(TraceContext {
+            traceparent: carrier.remove(TRACEPARENT)?,
+            tracestate: carrier.remove(TRACESTATE).filter(|state| !state.is_empty()),
+        })
+    }
+
+    /// Makes `span` a child of the remote span described by `trace`.
+    pub(crate) fn set_parent(span: &Span, trace: &TraceContext) {
+        let mut carrier = HashMap::from([(TRACEPARENT.to_string(), trace.traceparent.clone())]);
+        if let Some(state) = &trace.tracestate {
+            carrier.insert(TRACESTATE.to_string(), state.clone());
+        }
+        let parent = TraceContextPropagator::new().extract(&carrier);
+        if let Err(e) = span.set_parent(parent) {
+            tracing::debug!(error = %e, "Could not link to the client's trace");
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod otel {
+    use super::*;
+
+    pub(crate) fn current() -> Option<TraceContext> {
+        None
+    }
+
+    pub(crate) fn set_parent(_: &Span, _: &TraceContext) {}
+}
+
+pub(crate) use otel::*;
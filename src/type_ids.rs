@@ -0,0 +1,468 @@
+//! Compact numeric tags for request and response types.
+//!
+//! typetag writes every trait object as its type's name followed by its
+//! fields, so a unit request like `Ping` costs more in tag than in payload.
+//! Types registered here with [`type_ids!`](crate::type_ids!) are written as a
+//! `u32` instead whenever a connection negotiates [`Codec::CompactBincode`];
+//! anything unregistered still travels under its name. Both ends must
+//! register the same ids, much like field numbers in a schema.
+//!
+//! [`Codec::CompactBincode`]: crate::Codec::CompactBincode
+
+use std::any::{Any, TypeId};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{LazyLock, RwLock};
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+    VariantAccess, Visitor,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::payload_limit;
+use crate::untyped::{self, Untyped};
+use crate::{Request, Response, StreamingRequest, UploadRequest};
+
+#[doc(hidden)]
+pub use erased_serde;
+
+/// Builds a registered type from its fields, boxed as the trait object `T`.
+pub type DeserializeFn<T> = fn(&mut dyn erased_serde::Deserializer) -> erased_serde::Result<Box<T>>;
+
+/// Registers request and response types under stable numeric ids.
+///
+/// Ids are scoped per trait, so a request and a response may share one.
+/// Types are named by their bare identifier, which must be the name typetag
+/// tags them with.
+///
+/// ```ignore
+/// myproto::type_ids! {
+///     Request { Ping = 1, Echo = 2 }
+///     Response { PingResponse = 1, EchoResponse = 2 }
+/// }
+/// ```
+#[macro_export]
+macro_rules! type_ids {
+    ($($trait:ident { $($ty:ident = $id:expr),* $(,)? })*) => {
+        $($(
+            $crate::type_ids::register::<dyn $crate::$trait>($id, stringify!($ty), |deserializer| {
+                let value: $ty = $crate::type_ids::erased_serde::deserialize(deserializer)?;
+                Ok(Box::new(value))
+            });
+        )*)*
+    };
+}
+
+/// Trait objects that can be tagged by id.
+pub trait Tagged: 'static {
+    fn tag(&self) -> &'static str;
+    fn fields(&self) -> &dyn erased_serde::Serialize;
+
+    /// This value, if it is an [`Untyped`] standing in for another type.
+    fn untyped(&self) -> Option<&Untyped> {
+        None
+    }
+
+    /// Boxes `value` as this trait object, if [`Untyped`] implements it.
+    fn from_untyped(value: Untyped) -> Option<Box<Self>> {
+        let _ = value;
+        None
+    }
+}
+
+macro_rules! impl_tagged {
+    ($($trait:ident $(+ $untyped:ident)?),*) => {
+        $(impl Tagged for dyn $trait {
+            fn tag(&self) -> &'static str {
+                self.typetag_name()
+            }
+
+            fn fields(&self) -> &dyn erased_serde::Serialize {
+                self
+            }
+
+            $(impl_tagged!(@$untyped);)?
+        })*
+    };
+    (@Untyped) => {
+        fn untyped(&self) -> Option<&Untyped> {
+            (self as &dyn Any).downcast_ref()
+        }
+
+        fn from_untyped(value: Untyped) -> Option<Box<Self>> {
+            Some(Box::new(value))
+        }
+    };
+}
+
+impl_tagged!(
+    Request + Untyped,
+    StreamingRequest,
+    UploadRequest,
+    Response + Untyped
+);
+
+struct Table {
+    ids: HashMap<&'static str, u32>,
+    /// A `HashMap<u32, DeserializeFn<T>>` for the table's trait object `T`.
+    constructors: Box<dyn Any + Send + Sync>,
+}
+
+static REGISTRY: LazyLock<RwLock<HashMap<TypeId, Table>>> = LazyLock::new(Default::default);
+
+/// Registers `name` under `id` for the trait object `T`; usually called
+/// through [`type_ids!`](crate::type_ids!).
+///
+/// # Panics
+///
+/// If `id` or `name` is already registered for `T` as something else.
+pub fn register<T: ?Sized + Tagged>(id: u32, name: &'static str, constructor: DeserializeFn<T>) {
+    let mut registry = REGISTRY.write().unwrap();
+    let table = registry.entry(TypeId::of::<T>()).or_insert_with(|| Table {
+        ids: HashMap::new(),
+        constructors: Box::new(HashMap::<u32, DeserializeFn<T>>::new()),
+    });
+    let constructors = table
+        .constructors
+        .downcast_mut::<HashMap<u32, DeserializeFn<T>>>()
+        .expect("tables are keyed by their trait object");
+
+    match table.ids.get(name) {
+        Some(&existing) if existing == id => return,
+        Some(&existing) => panic!("{name} is already registered as type id {existing}"),
+        None => {}
+    }
+    if let Some(other) = table.ids.iter().find(|&(_, &other)| other == id) {
+        panic!("type id {id} is already registered for {}", other.0);
+    }
+    table.ids.insert(name, id);
+    constructors.insert(id, constructor);
+}
+
+/// Every name registered for `T`, with its id, sorted by id.
+pub(crate) fn registered<T: ?Sized + Tagged>() -> Vec<(u32, &'static str)> {
+    let registry = REGISTRY.read().unwrap();
+    let mut ids: Vec<_> = registry
+        .get(&TypeId::of::<T>())
+        .map(|table| table.ids.iter().map(|(&name, &id)| (id, name)).collect())
+        .unwrap_or_default();
+    ids.sort_unstable();
+    ids
+}
+
+fn id_of<T: ?Sized + Tagged>(name: &str) -> Option<u32> {
+    let registry = REGISTRY.read().unwrap();
+    registry.get(&TypeId::of::<T>())?.ids.get(name).copied()
+}
+
+fn name_of<T: ?Sized + Tagged>(id: u32) -> Option<&'static str> {
+    let registry = REGISTRY.read().unwrap();
+    let table = registry.get(&TypeId::of::<T>())?;
+    table
+        .ids
+        .iter()
+        .find_map(|(&name, &other)| (other == id).then_some(name))
+}
+
+fn constructor<T: ?Sized + Tagged>(id: u32) -> Option<DeserializeFn<T>> {
+    let registry = REGISTRY.read().unwrap();
+    let constructors = registry
+        .get(&TypeId::of::<T>())?
+        .constructors
+        .downcast_ref::<HashMap<u32, DeserializeFn<T>>>()?;
+    constructors.get(&id).copied()
+}
+
+thread_local! {
+    static COMPACT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with id tags switched on for this thread, as `Codec::CompactBincode` does.
+pub(crate) fn compact<R>(f: impl FnOnce() -> R) -> R {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            COMPACT.set(self.0);
+        }
+    }
+    let _restore = Restore(COMPACT.replace(true));
+    f()
+}
+
+const TAG: &str = "Tag";
+const VARIANTS: &[&str] = &["Id", "Name"];
+
+/// Serializes as typetag does, or as an id when compact tags are on and
+/// the type is registered.
+struct Compact<'a, T: ?Sized>(&'a T);
+
+impl<T: ?Sized + Tagged> Serialize for Compact<'_, T>
+where
+    T: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(untyped) = self.0.untyped() {
+            return if COMPACT.get() {
+                serializer.serialize_newtype_variant(TAG, 1, VARIANTS[1], untyped)
+            } else {
+                untyped.serialize(serializer)
+            };
+        }
+        if !COMPACT.get() {
+            return self.0.serialize(serializer);
+        }
+        match id_of::<T>(self.0.tag()) {
+            Some(id) => serializer.serialize_newtype_variant(
+                TAG,
+                0,
+                VARIANTS[0],
+                &(id, Fields(self.0.fields())),
+            ),
+            None => serializer.serialize_newtype_variant(TAG, 1, VARIANTS[1], self.0),
+        }
+    }
+}
+
+struct Fields<'a>(&'a dyn erased_serde::Serialize);
+
+impl Serialize for Fields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        erased_serde::serialize(self.0, serializer)
+    }
+}
+
+/// The owned counterpart of [`Compact`].
+struct Decompact<T: ?Sized>(Box<T>);
+
+impl<'de, T: ?Sized + Tagged> Deserialize<'de> for Decompact<T>
+where
+    Box<T>: DeserializeOwned,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if untyped::is_decoding() {
+            let value = Untyped::deserialize(deserializer)?;
+            return T::from_untyped(value)
+                .map(Decompact)
+                .ok_or_else(|| de::Error::custom("this type can't be decoded untyped"));
+        }
+        if !COMPACT.get() {
+            return Named(PhantomData).deserialize(deserializer).map(Decompact);
+        }
+        deserializer
+            .deserialize_enum(TAG, VARIANTS, TagVisitor(PhantomData))
+            .map(Decompact)
+    }
+}
+
+struct TagVisitor<T: ?Sized>(PhantomData<fn() -> Box<T>>);
+
+impl<'de, T: ?Sized + Tagged> Visitor<'de> for TagVisitor<T>
+where
+    Box<T>: DeserializeOwned,
+{
+    type Value = Box<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a type id or name")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Box<T>, A::Error> {
+        let (variant, access) = data.variant::<u32>()?;
+        match variant {
+            0 => access.tuple_variant(2, IdVisitor(PhantomData)),
+            1 => access.newtype_variant_seed(Named(PhantomData)),
+            other => Err(de::Error::invalid_value(
+                de::Unexpected::Unsigned(other.into()),
+                &"a tag variant",
+            )),
+        }
+    }
+}
+
+struct IdVisitor<T: ?Sized>(PhantomData<fn() -> Box<T>>);
+
+impl<'de, T: ?Sized + Tagged> Visitor<'de> for IdVisitor<T> {
+    type Value = Box<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a type id followed by its fields")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Box<T>, A::Error> {
+        let id: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let constructor = constructor::<T>(id)
+            .ok_or_else(|| de::Error::custom(format!("unknown type id {id}")))?;
+        if let Some(name) = name_of::<T>(id).filter(|_| payload_limit::is_checking()) {
+            payload_limit::check(name)?;
+        }
+        seq.next_element_seed(FieldsSeed(constructor))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+}
+
+/// Deserializes a value tagged with its type name as typetag does, first
+/// checking the name against the payload limits when they apply.
+struct Named<T: ?Sized>(PhantomData<fn() -> Box<T>>);
+
+impl<'de, T: ?Sized> DeserializeSeed<'de> for Named<T>
+where
+    Box<T>: DeserializeOwned,
+{
+    type Value = Box<T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Box<T>, D::Error> {
+        if payload_limit::is_checking() {
+            deserializer.deserialize_map(self)
+        } else {
+            Box::<T>::deserialize(deserializer)
+        }
+    }
+}
+
+impl<'de, T: ?Sized> Visitor<'de> for Named<T>
+where
+    Box<T>: DeserializeOwned,
+{
+    type Value = Box<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a type name tagging its fields")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Box<T>, A::Error> {
+        let name: String = map
+            .next_key()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        payload_limit::check(&name)?;
+        Box::<T>::deserialize(MapAccessDeserializer::new(Peeked {
+            name: Some(name),
+            map,
+        }))
+    }
+}
+
+/// A map whose first key has already been read, handing it out again.
+struct Peeked<A> {
+    name: Option<String>,
+    map: A,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for Peeked<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        match self.name.take() {
+            Some(name) => seed.deserialize(name.into_deserializer()).map(Some),
+            None => self.map.next_key_seed(seed),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.map.next_value_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.map.size_hint()
+    }
+}
+
+struct FieldsSeed<T: ?Sized>(DeserializeFn<T>);
+
+impl<'de, T: ?Sized> DeserializeSeed<'de> for FieldsSeed<T> {
+    type Value = Box<T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Box<T>, D::Error> {
+        let mut erased = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0)(&mut erased).map_err(de::Error::custom)
+    }
+}
+
+/// `#[serde(with)]` helpers for the envelope's trait-object fields.
+pub(crate) mod with {
+    use super::*;
+
+    pub(crate) mod boxed {
+        use super::*;
+
+        // serde hands `with` functions a reference to the field as declared.
+        #[allow(clippy::borrowed_box)]
+        pub(crate) fn serialize<S, T>(value: &Box<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: ?Sized + Tagged + Serialize,
+        {
+            Compact(&**value).serialize(serializer)
+        }
+
+        pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<Box<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: ?Sized + Tagged,
+            Box<T>: DeserializeOwned,
+        {
+            Decompact::deserialize(deserializer).map(|Decompact(value)| value)
+        }
+    }
+
+    pub(crate) mod vec {
+        use super::*;
+
+        pub(crate) fn serialize<S, T>(values: &[Box<T>], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: ?Sized + Tagged + Serialize,
+        {
+            serializer.collect_seq(values.iter().map(|value| Compact(&**value)))
+        }
+
+        pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<Box<T>>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: ?Sized + Tagged,
+            Box<T>: DeserializeOwned,
+        {
+            let values = Vec::<Decompact<T>>::deserialize(deserializer)?;
+            Ok(values.into_iter().map(|Decompact(value)| value).collect())
+        }
+    }
+
+    pub(crate) mod results {
+        use super::*;
+        use crate::{ProtocolError, ResponseResult};
+
+        pub(crate) fn serialize<S>(
+            results: &[ResponseResult],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.collect_seq(
+                results
+                    .iter()
+                    .map(|result| result.as_ref().map(|value| Compact(&**value))),
+            )
+        }
+
+        pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<ResponseResult>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let results =
+                Vec::<Result<Decompact<dyn Response>, ProtocolError>>::deserialize(deserializer)?;
+            Ok(results
+                .into_iter()
+                .map(|result| result.map(|Decompact(value)| value))
+                .collect())
+        }
+    }
+}
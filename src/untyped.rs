@@ -0,0 +1,100 @@
+//! Requests and responses handled by name, for tools that don't link the
+//! application's types.
+//!
+//! An [`Untyped`] value travels as exactly what the typed value it stands for
+//! would: its type name tagging its fields. A client built with
+//! `ClientConfig::untyped` decodes every response as one. Only self-describing
+//! codecs carry enough information to do that, so it needs `Codec::Json` or
+//! `Codec::MessagePack`.
+
+use std::cell::Cell;
+use std::fmt;
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{Request, Response};
+
+/// A request or response as its type's name and fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Untyped {
+    pub type_name: String,
+    pub fields: Value,
+}
+
+impl Untyped {
+    pub fn new(type_name: impl Into<String>, fields: Value) -> Self {
+        Self {
+            type_name: type_name.into(),
+            fields,
+        }
+    }
+}
+
+/// Serialized like typetag's tagging rather than as a struct of its own.
+impl Serialize for Untyped {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(&self.type_name, &self.fields)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Untyped {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(TaggedVisitor)
+    }
+}
+
+struct TaggedVisitor;
+
+impl<'de> Visitor<'de> for TaggedVisitor {
+    type Value = Untyped;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a type name tagging its fields")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Untyped, A::Error> {
+        let (type_name, fields) = map
+            .next_entry::<String, Value>()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(2, &self));
+        }
+        Ok(Untyped { type_name, fields })
+    }
+}
+
+/// The name `Untyped` is registered with typetag under, which no application
+/// type can take. It is only registered so it can be boxed as a trait object;
+/// the envelope writes it under `type_name` instead.
+pub(crate) const REGISTERED_NAME: &str = "myproto::Untyped";
+
+#[typetag::serde(name = "myproto::Untyped")]
+impl Request for Untyped {}
+
+#[typetag::serde(name = "myproto::Untyped")]
+impl Response for Untyped {}
+
+thread_local! {
+    static DECODING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with responses decoded as [`Untyped`] on this thread.
+pub(crate) fn decoding<R>(f: impl FnOnce() -> R) -> R {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DECODING.set(self.0);
+        }
+    }
+    let _restore = Restore(DECODING.replace(true));
+    f()
+}
+
+pub(crate) fn is_decoding() -> bool {
+    DECODING.get()
+}
@@ -0,0 +1,224 @@
+//! Replacing the running server with a new process without refusing a
+//! single connection.
+//!
+//! [`Server::upgrade`] starts the server's command line again with the
+//! listening sockets open, so a new binary installed at the same path takes
+//! over from the old one. The new process takes the sockets over with
+//! [`listeners`] and accepts from them straight away, then says it is up with
+//! [`ready`]; only then does the old process stop accepting and drain its
+//! sessions. If the new process fails to start, the old one carries on.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Write;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use socket2::Socket;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+use crate::Server;
+use crate::listener::{Binding, Listener};
+
+/// The listening sockets passed to the new process, as a JSON list of [`Passed`].
+const LISTEN_FDS: &str = "MYPROTO_LISTEN_FDS";
+/// The socket the new process says it is ready on.
+const READY_FD: &str = "MYPROTO_READY_FD";
+
+/// A listening socket passed to the new process, with how it was served.
+#[derive(Serialize, Deserialize)]
+struct Passed {
+    fd: RawFd,
+    name: String,
+    proxy_protocol: bool,
+    websocket: bool,
+}
+
+/// The sockets the server is listening on, to pass on in an upgrade.
+#[derive(Clone, Default)]
+pub(crate) struct Handoff {
+    /// Each listener, with its entry in `LISTEN_FDS` less the descriptor.
+    listeners: Arc<Mutex<BTreeMap<u64, (OwnedFd, Passed)>>>,
+    next_id: Arc<AtomicU64>,
+    upgrading: Arc<AtomicBool>,
+    handed_off: Arc<AtomicBool>,
+}
+
+impl Handoff {
+    /// Offers `binding`'s listener to upgrades until the returned guard is dropped.
+    pub(crate) fn register(&self, binding: &Binding) -> Result<Registered<'_>> {
+        let fd = binding.listener.as_fd().try_clone_to_owned()?;
+        let passed = Passed {
+            fd: -1,
+            name: binding.name.clone(),
+            proxy_protocol: binding.proxy_protocol,
+            websocket: binding.websocket,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.listeners.lock().unwrap().insert(id, (fd, passed));
+        Ok(Registered { handoff: self, id })
+    }
+
+    /// Whether a new process has taken the listeners over, so their socket
+    /// files are its to remove now.
+    pub(crate) fn handed_off(&self) -> bool {
+        self.handed_off.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct Registered<'a> {
+    handoff: &'a Handoff,
+    id: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.handoff.listeners.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Clears the in-progress flag however the upgrade ends.
+struct Upgrading<'a>(&'a AtomicBool);
+
+impl Drop for Upgrading<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Server {
+    /// Starts this process's command line again, handing it the sockets
+    /// being served, and once it is [`ready`] drains this server as
+    /// `begin_drain` does. Returns the new process's id.
+    ///
+    /// Fails, leaving this server serving, if the new process exits or
+    /// isn't ready within `ServerConfig::upgrade_timeout`. Under systemd,
+    /// the new process is reported as the service's main process, which
+    /// needs `NotifyAccess=all` in the unit.
+    pub async fn upgrade(&self) -> Result<u32> {
+        let handoff = &self.handoff;
+        if handoff.upgrading.swap(true, Ordering::Relaxed) {
+            bail!("an upgrade is already in progress");
+        }
+        let _upgrading = Upgrading(&handoff.upgrading);
+
+        let mut args = env::args_os().collect::<Vec<_>>().into_iter();
+        let program = args.next().context("the process has no command line")?;
+        // The new process's copies of the sockets, open across exec.
+        let mut sockets = Vec::new();
+        let mut list = Vec::new();
+        for (fd, passed) in handoff.listeners.lock().unwrap().values() {
+            let socket = inheritable(fd.try_clone()?)?;
+            list.push(Passed {
+                fd: socket.as_raw_fd(),
+                name: passed.name.clone(),
+                ..*passed
+            });
+            sockets.push(socket);
+        }
+        if sockets.is_empty() {
+            bail!("the server isn't listening on anything to hand over");
+        }
+        let (ready, theirs) = UnixStream::pair()?;
+        let theirs = inheritable(theirs.into())?;
+        let mut child = Command::new(&program)
+            .args(args)
+            .env(LISTEN_FDS, serde_json::to_string(&list)?)
+            .env(READY_FD, theirs.as_raw_fd().to_string())
+            .spawn()
+            .with_context(|| format!("failed to start {}", program.display()))?;
+        drop((sockets, theirs));
+        let pid = child.id().unwrap_or_default();
+        tracing::info!(
+            pid,
+            "Started new server process, waiting for it to be ready"
+        );
+
+        ready.set_nonblocking(true)?;
+        let mut ready = tokio::net::UnixStream::from_std(ready)?;
+        let timeout = self.config().upgrade_timeout;
+        let started = tokio::select! {
+            read = ready.read_u8() => read.map(drop).map_err(|_| anyhow!("the new process exited before it was ready")),
+            _ = tokio::time::sleep(timeout) => Err(anyhow!("the new process wasn't ready within {timeout:?}")),
+        };
+        if let Err(e) = started {
+            let _ = child.kill().await;
+            return Err(e);
+        }
+
+        handoff.handed_off.store(true, Ordering::Relaxed);
+        if let Err(e) = crate::systemd::notify(&format!("MAINPID={pid}")) {
+            tracing::warn!(
+                error = format!("{e:#}"),
+                "Failed to tell systemd about the new process"
+            );
+        }
+        tracing::info!(pid, "New server process is ready, draining");
+        self.begin_drain();
+        Ok(pid)
+    }
+}
+
+/// `fd` as a socket that stays open in the processes this one starts.
+fn inheritable(fd: OwnedFd) -> Result<Socket> {
+    let socket = Socket::from(fd);
+    socket.set_cloexec(false)?;
+    Ok(socket)
+}
+
+/// The listening sockets handed over by the server this process is
+/// upgrading, set up as it served them, to serve instead of binding its own.
+/// Empty when the process wasn't started by [`Server::upgrade`]. Call it
+/// once at most.
+pub fn listeners() -> Result<Vec<Binding>> {
+    let Ok(list) = env::var(LISTEN_FDS) else {
+        return Ok(Vec::new());
+    };
+    let list: Vec<Passed> =
+        serde_json::from_str(&list).with_context(|| format!("malformed {LISTEN_FDS}"))?;
+    list.into_iter()
+        .map(|passed| {
+            let socket = take(passed.fd);
+            let listener = Listener::inherit(socket)
+                .with_context(|| format!("inherited socket {}", passed.fd))?;
+            let mut binding = Binding::new(listener)
+                .name(passed.name)
+                .proxy_protocol(passed.proxy_protocol);
+            binding.websocket = passed.websocket;
+            Ok(binding)
+        })
+        .collect()
+}
+
+/// Whether this process was started by [`Server::upgrade`].
+pub(crate) fn started_by_upgrade() -> bool {
+    env::var_os(LISTEN_FDS).is_some()
+}
+
+/// Tells the server this process is upgrading that it is accepting
+/// connections, so the old one can drain. Returns whether there was one to
+/// tell.
+pub fn ready() -> Result<bool> {
+    let Ok(fd) = env::var(READY_FD) else {
+        return Ok(false);
+    };
+    let fd = fd
+        .parse()
+        .with_context(|| format!("malformed {READY_FD}: {fd:?}"))?;
+    let mut socket = UnixStream::from(take(fd));
+    socket
+        .write_all(&[1])
+        .context("failed to tell the old server process")?;
+    Ok(true)
+}
+
+fn take(fd: RawFd) -> OwnedFd {
+    // SAFETY: the old server process started this one with the descriptor
+    // open for it alone, and it is taken over once.
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
@@ -0,0 +1,90 @@
+//! Changing a message type without breaking the clients that still send
+//! its old layout.
+//!
+//! A field added with `#[serde(default)]` may be left out by clients using
+//! a self-describing codec, so JSON and MessagePack clients that don't know
+//! it keep working as they are. Bincode writes fields by position with no
+//! names to go by, so for those clients, or for any other change, the type
+//! gets a new version:
+//!
+//! ```ignore
+//! /// `Echo` as it was.
+//! #[derive(Serialize, Deserialize)]
+//! pub struct EchoV1 {
+//!     pub message: String,
+//! }
+//!
+//! #[myproto::request(response = EchoResponse, version = 2)]
+//! pub struct Echo {
+//!     pub message: String,
+//!     pub shout: bool,
+//! }
+//!
+//! impl Versioned for Echo {
+//!     type Previous = EchoV1;
+//!
+//!     fn from_previous_version(previous: EchoV1) -> Self {
+//!         Echo { message: previous.message, shout: false }
+//!     }
+//! }
+//! ```
+//!
+//! The version travels in the type's tag on the wire: version 1 is tagged
+//! with the bare name, as every type is before it has versions, and later
+//! ones as `Echo@2`. The server keeps the previous version's tag registered
+//! as well, reading what arrives under it as `Previous` and upgrading it
+//! before it is handled, so the handler only ever sees the current layout.
+//! That covers clients one version behind; a client sending `Echo@2` needs
+//! a server that has it, so servers are upgraded first.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// A request declared with `#[request(version = N)]`, readable from the
+/// layout of version N - 1.
+pub trait Versioned: Sized {
+    /// The layout clients of the previous version send.
+    type Previous: DeserializeOwned;
+
+    fn from_previous_version(previous: Self::Previous) -> Self;
+}
+
+/// The tag version `version` of the type `name` travels under.
+pub fn tag(name: &str, version: u32) -> String {
+    if version <= 1 {
+        name.to_string()
+    } else {
+        format!("{name}@{version}")
+    }
+}
+
+/// A tag's type name and version: `Echo@2` is `("Echo", 2)`, `Echo` is `("Echo", 1)`.
+pub fn parse_tag(tag: &str) -> (&str, u32) {
+    match tag.rsplit_once('@') {
+        Some((name, version)) => match version.parse() {
+            Ok(version) => (name, version),
+            Err(_) => (tag, 1),
+        },
+        None => (tag, 1),
+    }
+}
+
+/// A tag's type name, without its version.
+pub fn base_name(tag: &str) -> &str {
+    parse_tag(tag).0
+}
+
+#[doc(hidden)]
+pub fn upgrade<'de, T: Versioned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    let previous = T::Previous::deserialize(deserializer)?;
+    Ok(T::from_previous_version(previous))
+}
+
+/// Requests upgraded on arrival have no old layout to be written back in.
+#[doc(hidden)]
+pub fn reencode<S: Serializer>(serializer: S, tag: &str) -> Result<S::Ok, S::Error> {
+    let _ = serializer;
+    Err(serde::ser::Error::custom(format!(
+        "a request that arrived as {tag} was upgraded and can't be encoded as it was"
+    )))
+}
@@ -0,0 +1,124 @@
+//! The protocol over WebSocket, for browsers and for networks where only
+//! HTTP gets through.
+//!
+//! The frames are the same as on a socket, carried in binary messages: each
+//! message from the server holds one or more whole frames, and messages from
+//! the client may split them anywhere. A listener accepts WebSocket clients
+//! with `Binding::websocket`, and [`connect`] is the native client's way in.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use crate::{Client, ClientConfig};
+
+/// How long a client has to complete the WebSocket upgrade once connected.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A WebSocket read and written as the byte stream a session runs over.
+pub struct WebSocketIo<S> {
+    ws: WebSocketStream<S>,
+    /// What's left of the last message read.
+    read: Bytes,
+    /// Written since the last flush, sent as one message on the next.
+    write: BytesMut,
+}
+
+impl<S> WebSocketIo<S> {
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read: Bytes::new(),
+            write: BytesMut::new(),
+        }
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read.is_empty() {
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text WebSocket message; frames go in binary messages",
+                    )));
+                }
+                // Pings are answered by the WebSocket itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+        let n = self.read.len().min(buf.remaining());
+        buf.put_slice(&self.read[..n]);
+        self.read.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write.is_empty() {
+            ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(io_error)?;
+            let message = Message::Binary(self.write.split().freeze());
+            Pin::new(&mut self.ws)
+                .start_send(message)
+                .map_err(io_error)?;
+        }
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.ws).poll_close(cx).map_err(io_error)
+    }
+}
+
+/// Completes the WebSocket upgrade a client connected to a WebSocket listener starts with.
+pub(crate) async fn accept<S>(stream: S) -> Result<WebSocketIo<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws = tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("WebSocket upgrade timed out"))??;
+    Ok(WebSocketIo::new(ws))
+}
+
+/// Connects to the WebSocket listener at `url`, e.g. `ws://127.0.0.1:8444/`.
+pub async fn connect(url: &str, config: ClientConfig) -> Result<Client> {
+    let (ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .with_context(|| format!("failed to connect to {url}"))?;
+    Client::with_config(WebSocketIo::new(ws), config).await
+}
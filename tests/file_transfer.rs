@@ -0,0 +1,78 @@
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use myproto::file_transfer::PutFile;
+use myproto::{ErrorCode, ProtocolError, Server};
+
+fn code(err: anyhow::Error) -> ErrorCode {
+    err.downcast::<ProtocolError>().unwrap().code
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("myproto-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn put_file_drops_special_and_disallowed_mode_bits() {
+    let dir = scratch_dir("put-file-mode");
+    let root = dir.join("root");
+    std::fs::create_dir(&root).unwrap();
+    let local = dir.join("upload");
+    std::fs::write(&local, b"contents").unwrap();
+    std::fs::set_permissions(&local, std::fs::Permissions::from_mode(0o6777)).unwrap();
+
+    let server = Server::builder().file_root(&root).file_mode(0o750).build();
+    let handle = server.spawn().await.unwrap();
+    let client = handle.connect().await.unwrap();
+    client.put_file(&local, "uploaded").await.unwrap();
+    handle.shutdown().await.unwrap();
+
+    let mode = std::fs::metadata(root.join("uploaded"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o750);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn put(size: Option<u64>) -> Box<PutFile> {
+    Box::new(PutFile {
+        path: "big".into(),
+        size,
+        sha256: None,
+        mode: None,
+        overwrite: false,
+    })
+}
+
+#[tokio::test]
+async fn put_file_refuses_uploads_past_the_size_limit() {
+    let dir = scratch_dir("put-file-size");
+    let server = Server::builder()
+        .file_root(&dir)
+        .max_file_size(1024)
+        .build();
+    let handle = server.spawn().await.unwrap();
+    let client = handle.connect().await.unwrap();
+
+    let declared = client
+        .upload(put(Some(4096)), futures::stream::empty())
+        .await
+        .unwrap_err();
+    assert_eq!(code(declared), ErrorCode::PayloadTooLarge);
+
+    // Undeclared, the upload is cut off once it passes the limit.
+    let chunks = futures::stream::iter(vec![Bytes::from(vec![0; 512]); 4]);
+    let streamed = client.upload(put(None), chunks).await.unwrap_err();
+    assert_eq!(code(streamed), ErrorCode::PayloadTooLarge);
+    handle.shutdown().await.unwrap();
+
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "gateway")]
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use myproto::auth::StaticTokens;
+use myproto::{BanPolicy, Identity, IpFilter, Server, ServerConfig, gateway};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves `server`'s gateway on a port of its own.
+async fn serve(server: Server) -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(gateway::serve(server, listener, std::future::pending()));
+    addr
+}
+
+/// The status code of `POST /rpc/{type_name}` with an empty body.
+async fn post(addr: SocketAddr, type_name: &str, bearer: Option<&str>) -> u16 {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let auth = bearer.map_or(String::new(), |token| {
+        format!("Authorization: Bearer {token}\r\n")
+    });
+    let request = format!(
+        "POST /rpc/{type_name} HTTP/1.1\r\nHost: localhost\r\n{auth}\
+         Content-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ip_filter_applies_to_gateway_callers() {
+    let server = Server::builder()
+        .ip_filter(IpFilter::new().deny("127.0.0.0/8".parse().unwrap()))
+        .build();
+    let addr = serve(server).await;
+    assert_eq!(post(addr, "HealthCheck", None).await, 403);
+}
+
+#[tokio::test]
+async fn gateway_callers_outside_the_filter_are_served() {
+    let server = Server::builder()
+        .ip_filter(IpFilter::new().deny("10.0.0.0/8".parse().unwrap()))
+        .build();
+    let addr = serve(server).await;
+    assert_eq!(post(addr, "HealthCheck", None).await, 200);
+}
+
+#[tokio::test]
+async fn refused_tokens_get_gateway_callers_banned() {
+    let config = ServerConfig {
+        ban: Some(BanPolicy {
+            threshold: 2,
+            ..BanPolicy::default()
+        }),
+        ..ServerConfig::default()
+    };
+    let server = Server::builder()
+        .config(config)
+        .authenticator(StaticTokens::new().token("secret", Identity::new("alice")))
+        .build();
+    let addr = serve(server.clone()).await;
+
+    assert_eq!(post(addr, "HealthCheck", Some("secret")).await, 200);
+    for _ in 0..2 {
+        assert_eq!(post(addr, "HealthCheck", Some("guess")).await, 401);
+    }
+    assert_eq!(server.bans().len(), 1);
+    assert_eq!(post(addr, "HealthCheck", Some("secret")).await, 403);
+}
@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use myproto::{Server, ServerConfig};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn silent_connection_is_closed_after_the_handshake_timeout() {
+    let config = ServerConfig {
+        handshake_timeout: Duration::from_millis(100),
+        ..ServerConfig::default()
+    };
+    let handle = Server::builder()
+        .config(config)
+        .build()
+        .spawn()
+        .await
+        .unwrap();
+    let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+    // Closed, whether with a FIN or a reset, rather than left waiting.
+    let mut rest = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(5), socket.read_to_end(&mut rest)).await;
+    assert!(closed.is_ok(), "server kept a silent connection open");
+    handle.shutdown().await.unwrap();
+}
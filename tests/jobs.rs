@@ -0,0 +1,30 @@
+use myproto::builtin::HealthCheck;
+use myproto::jobs::{CancelJob, JobResult, JobStatus, SubmitJob};
+use myproto::{ErrorCode, ProtocolError, Server};
+
+fn code(err: anyhow::Error) -> ErrorCode {
+    err.downcast::<ProtocolError>().unwrap().code
+}
+
+#[tokio::test]
+async fn unauthenticated_jobs_belong_to_their_connection() {
+    let handle = Server::builder().build().spawn().await.unwrap();
+    let owner = handle.connect().await.unwrap();
+    let other = handle.connect().await.unwrap();
+
+    let submit = || SubmitJob {
+        request: Box::new(HealthCheck),
+    };
+    let first = owner.call_typed(submit()).await.unwrap().id;
+    let second = owner.call_typed(submit()).await.unwrap().id;
+    assert_ne!(second, first + 1, "job ids are guessable");
+
+    owner.call_typed(JobResult { id: first }).await.unwrap();
+    let status = other.call_typed(JobStatus { id: first }).await.unwrap_err();
+    assert_eq!(code(status), ErrorCode::InvalidRequest);
+    let result = other.call_typed(JobResult { id: first }).await.unwrap_err();
+    assert_eq!(code(result), ErrorCode::InvalidRequest);
+    let cancel = other.call_typed(CancelJob { id: first }).await.unwrap_err();
+    assert_eq!(code(cancel), ErrorCode::InvalidRequest);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,71 @@
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use myproto::builtin::{HealthCheckResponse, HealthStatus};
+use myproto::{Journal, Server};
+
+fn scratch_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("myproto-{name}-{}", std::process::id()));
+    remove(&path);
+    path
+}
+
+/// Removes the journal at `path` and the schedule beside it.
+fn remove(path: &PathBuf) {
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(path.with_extension("scheduled"));
+}
+
+/// Publishes `count` messages with a journal at `path`.
+async fn publish(path: &PathBuf, count: usize) {
+    let server = Server::builder()
+        .journal(Journal::open(path).unwrap())
+        .build();
+    for _ in 0..count {
+        let message = HealthCheckResponse {
+            status: HealthStatus::Serving,
+        };
+        server
+            .topics()
+            .publish("health", Box::new(message))
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn torn_record_is_dropped_from_the_end() {
+    let path = scratch_file("journal-torn");
+    publish(&path, 3).await;
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    // A record claiming 100 bytes with only 3 of them written.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(&[0, 0, 0, 100, 1, 2, 3]).unwrap();
+    drop(file);
+
+    let journal = Journal::open(&path).unwrap();
+    assert_eq!(journal.next_offset(), 4);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    remove(&path);
+}
+
+#[tokio::test]
+async fn corrupt_record_mid_file_fails_to_open() {
+    let path = scratch_file("journal-corrupt");
+    publish(&path, 3).await;
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    // The first record's topic length, after its length prefix and offset.
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(4 + 8)).unwrap();
+    file.write_all(&[0xff; 8]).unwrap();
+    drop(file);
+
+    assert!(Journal::open(&path).is_err());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    remove(&path);
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "noise")]
+
+use myproto::builtin::HealthCheck;
+use myproto::noise::{Keypair, NoiseConfig};
+use myproto::{Client, ClientConfig, Server};
+
+fn client_config(server_key: [u8; 32]) -> ClientConfig {
+    ClientConfig {
+        noise: Some(NoiseConfig::new(Keypair::generate().unwrap()).trust(server_key)),
+        ..ClientConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn set_noise_serves_new_keys_to_new_connections() {
+    let (old, new) = (Keypair::generate().unwrap(), Keypair::generate().unwrap());
+    let (old_public, new_public) = (old.public(), new.public());
+    let handle = Server::builder()
+        .noise(NoiseConfig::new(old).trust_any_peer())
+        .build()
+        .spawn()
+        .await
+        .unwrap();
+    let addr = handle.local_addr();
+    Client::connect_with(addr, client_config(old_public))
+        .await
+        .unwrap();
+
+    handle
+        .server()
+        .set_noise(NoiseConfig::new(new).trust_any_peer())
+        .unwrap();
+    assert!(
+        Client::connect_with(addr, client_config(old_public))
+            .await
+            .is_err()
+    );
+    let client = Client::connect_with(addr, client_config(new_public))
+        .await
+        .unwrap();
+    client.call_typed(HealthCheck).await.unwrap();
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn set_noise_leaves_a_plaintext_server_plaintext() {
+    let handle = Server::builder().build().spawn().await.unwrap();
+    let keys = NoiseConfig::new(Keypair::generate().unwrap()).trust_any_peer();
+    assert!(handle.server().set_noise(keys).is_err());
+
+    let client = Client::connect(handle.local_addr()).await.unwrap();
+    client.call_typed(HealthCheck).await.unwrap();
+    handle.shutdown().await.unwrap();
+}
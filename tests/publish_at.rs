@@ -0,0 +1,44 @@
+use std::time::{Duration, SystemTime};
+
+use myproto::builtin::{HealthCheckResponse, HealthStatus};
+use myproto::{ErrorCode, ProtocolError, Server, ServerConfig};
+
+fn message() -> Box<HealthCheckResponse> {
+    Box::new(HealthCheckResponse {
+        status: HealthStatus::Serving,
+    })
+}
+
+fn code(result: anyhow::Result<u64>) -> ErrorCode {
+    result
+        .unwrap_err()
+        .downcast::<ProtocolError>()
+        .expect("refused with a protocol error")
+        .code
+}
+
+#[tokio::test]
+async fn publish_at_is_limited_in_delay_and_count() {
+    let config = ServerConfig {
+        max_scheduled_messages: 1,
+        max_publish_delay: Duration::from_secs(60 * 60),
+        ..ServerConfig::default()
+    };
+    let server = Server::builder().config(config).build();
+    let topics = server.topics();
+    let now = SystemTime::now();
+
+    let too_late = now + Duration::from_secs(2 * 60 * 60);
+    assert_eq!(
+        code(topics.publish_at("later", message(), too_late).await),
+        ErrorCode::InvalidRequest
+    );
+
+    let soon = now + Duration::from_secs(60);
+    topics.publish_at("later", message(), soon).await.unwrap();
+    assert_eq!(
+        code(topics.publish_at("later", message(), soon).await),
+        ErrorCode::Overloaded
+    );
+    assert_eq!(topics.scheduled_count(), 1);
+}
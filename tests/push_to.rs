@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+use myproto::builtin::{HealthCheck, HealthCheckResponse, HealthStatus};
+use myproto::{ErrorCode, ProtocolError, Response, Server};
+
+fn message() -> Box<dyn Response> {
+    Box::new(HealthCheckResponse {
+        status: HealthStatus::Serving,
+    })
+}
+
+#[tokio::test]
+async fn push_to_reaches_a_local_connection() {
+    let handle = Server::builder().build().spawn().await.unwrap();
+    let client = handle.connect().await.unwrap();
+    let mut notifications = client.notifications();
+    client.call_typed(HealthCheck).await.unwrap();
+
+    let id = handle.server().connections()[0].id();
+    handle.server().push_to(id, message()).await.unwrap();
+    let pushed: Arc<dyn Response> = notifications.next().await.unwrap();
+    assert!((&*pushed as &dyn std::any::Any).is::<HealthCheckResponse>());
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn push_to_an_id_not_connected_here_fails() {
+    let handle = Server::builder().build().spawn().await.unwrap();
+    let err = handle.server().push_to(42, message()).await.unwrap_err();
+    let err = err.downcast::<ProtocolError>().unwrap();
+    assert_eq!(err.code, ErrorCode::PeerUnavailable);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,72 @@
+#![cfg(feature = "quic")]
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use myproto::builtin::HealthCheck;
+use myproto::quic::{self, QuicClient};
+use myproto::{ClientConfig, Server};
+use quinn::Endpoint;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::{self, RootCertStore};
+
+fn cert(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/certs/{name}"))
+}
+
+/// A client endpoint trusting only the certificate `name`.
+fn client_endpoint(name: &str) -> Endpoint {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(cert(&format!("{name}.pem"))).unwrap())
+        .unwrap();
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+    let mut endpoint = Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls).unwrap(),
+    )));
+    endpoint
+}
+
+async fn health_check(addr: SocketAddr, trusting: &str) -> anyhow::Result<()> {
+    let endpoint = client_endpoint(trusting);
+    let client = QuicClient::connect(&endpoint, addr, "localhost", ClientConfig::default()).await?;
+    client.call_typed(HealthCheck).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reloaded_certificate_is_shown_to_new_connections() {
+    let config = quic::server_config(&cert("first.pem"), &cert("first.key")).unwrap();
+    let endpoint = Endpoint::server(config, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let server = Server::builder().build();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(quic::serve(server, endpoint.clone(), async {
+        stopped.await.ok();
+    }));
+
+    health_check(addr, "first").await.unwrap();
+    quic::reload_certificate(&endpoint, &cert("second.pem"), &cert("second.key")).unwrap();
+    health_check(addr, "second").await.unwrap();
+    assert!(health_check(addr, "first").await.is_err());
+
+    // A pair that doesn't load leaves the endpoint with the one it has.
+    assert!(
+        quic::reload_certificate(&endpoint, &cert("second.pem"), &cert("missing.key")).is_err()
+    );
+    health_check(addr, "second").await.unwrap();
+
+    stop.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "encryption")]
+
+use std::sync::Arc;
+
+use myproto::builtin::HealthCheck;
+use myproto::sealed::{StaticKeys, generate_key};
+use myproto::{ClientConfig, ErrorCode, ProtocolError, Server};
+
+fn keys() -> StaticKeys {
+    let (a, b) = (generate_key(), generate_key());
+    StaticKeys::new().key("a", a).key("b", b)
+}
+
+async fn health_check(server: StaticKeys, client: StaticKeys) -> anyhow::Result<()> {
+    let handle = Server::builder()
+        .key_provider(server)
+        .build()
+        .spawn()
+        .await?;
+    let config = ClientConfig {
+        encryption: Some(Arc::new(client)),
+        ..ClientConfig::default()
+    };
+    let client = handle.connect_with(config).await?;
+    let response = client.call_typed(HealthCheck).await;
+    handle.shutdown().await?;
+    response.map(drop)
+}
+
+#[tokio::test]
+async fn sealed_request_round_trips() {
+    let keys = keys();
+    health_check(
+        keys.clone().seal("HealthCheck", "a"),
+        keys.seal("HealthCheck", "a"),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn request_sealed_under_another_key_is_refused() {
+    let keys = keys();
+    let err = health_check(
+        keys.clone().seal("HealthCheck", "a"),
+        keys.seal("HealthCheck", "b"),
+    )
+    .await
+    .unwrap_err();
+    let err = err.downcast::<ProtocolError>().unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+}
@@ -0,0 +1,23 @@
+use myproto::schema::Schema;
+use myproto::snapshot::Snapshots;
+
+/// Messages only builds with some features register, left out so every
+/// build checks the same snapshots.
+const OPTIONAL: &[&str] = &["Sealed", "SealedResponse"];
+
+#[test]
+fn wire_format() {
+    let mut schema = Schema::registered();
+    for messages in [
+        &mut schema.requests,
+        &mut schema.streaming_requests,
+        &mut schema.upload_requests,
+        &mut schema.responses,
+    ] {
+        messages.retain(|ty| !OPTIONAL.contains(&ty.name.as_str()));
+    }
+    Snapshots::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots"))
+        .schema(schema)
+        .check()
+        .unwrap();
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use futures::StreamExt;
+use myproto::{
+    Context, ErrorCode, ProtocolError, Response, ResponseStream, Server, ServerConfig,
+    StreamingRequest,
+};
+use serde::{Deserialize, Serialize};
+
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024;
+
+/// Fails with an error too long to fit in a frame, either before opening
+/// the stream or as its first item.
+#[derive(Serialize, Deserialize, Debug)]
+struct Oversized {
+    as_item: bool,
+}
+
+#[typetag::serde]
+#[async_trait::async_trait]
+impl StreamingRequest for Oversized {
+    async fn handle(&self, _ctx: &Context) -> Result<ResponseStream> {
+        let message = "x".repeat(4 * MAX_MESSAGE_LENGTH);
+        if !self.as_item {
+            bail!(message);
+        }
+        let item: Result<Box<dyn Response>> = Err(anyhow::anyhow!(message));
+        Ok(futures::stream::iter([item]).boxed())
+    }
+}
+
+async fn first_error(as_item: bool) -> ProtocolError {
+    let config = ServerConfig {
+        max_frame_length: MAX_MESSAGE_LENGTH,
+        max_message_length: MAX_MESSAGE_LENGTH,
+        ..ServerConfig::default()
+    };
+    let handle = Server::builder()
+        .config(config)
+        .build()
+        .spawn()
+        .await
+        .unwrap();
+    let client = handle.connect().await.unwrap();
+
+    let next = async {
+        let mut stream = client.call_stream(Box::new(Oversized { as_item })).await?;
+        match stream.next().await {
+            Some(Err(e)) => Ok(e),
+            other => bail!("expected an error item, got {other:?}"),
+        }
+    };
+    let err = tokio::time::timeout(Duration::from_secs(5), next)
+        .await
+        .expect("stream hung on an oversized error")
+        .unwrap();
+    handle.shutdown().await.unwrap();
+    err.downcast::<ProtocolError>()
+        .expect("stream ended with a protocol error")
+}
+
+#[tokio::test]
+async fn oversized_handler_error_ends_the_stream() {
+    let err = first_error(false).await;
+    assert_eq!(err.code, ErrorCode::FrameTooLarge);
+}
+
+#[tokio::test]
+async fn oversized_item_error_ends_the_stream() {
+    let err = first_error(true).await;
+    assert_eq!(err.code, ErrorCode::FrameTooLarge);
+}
+
+#[tokio::test]
+async fn failed_stream_is_counted_in_latencies() {
+    let handle = Server::builder().build().spawn().await.unwrap();
+    let client = handle.connect().await.unwrap();
+    let mut stream = client
+        .call_stream(Box::new(Oversized { as_item: true }))
+        .await
+        .unwrap();
+    while stream.next().await.is_some() {}
+
+    // The server records the stream just after sending its last frame.
+    let recorded = async {
+        loop {
+            let latencies = handle.server().latencies();
+            if let Some(summary) = latencies.iter().find(|s| s.request_type == "Oversized") {
+                return summary.count;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    let count = tokio::time::timeout(Duration::from_secs(5), recorded)
+        .await
+        .expect("stream never showed up in the latencies");
+    assert_eq!(count, 1);
+    handle.shutdown().await.unwrap();
+}
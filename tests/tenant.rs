@@ -0,0 +1,128 @@
+use std::any::Any;
+
+use bytes::Bytes;
+use myproto::auth::StaticTokens;
+use myproto::builtin::HealthCheck;
+use myproto::jobs::{JobStatus, SubmitJob};
+use myproto::kv::{KvGet, KvGetResponse, KvSet, MemoryStore};
+use myproto::relay::{Register, SendTo};
+use myproto::{
+    Authorization, Client, ClientConfig, Credentials, ErrorCode, Identity, ProtocolError, Server,
+    ServerHandle,
+};
+
+fn alice(tenant: &str) -> Identity {
+    Identity::new("alice").with_tenant(tenant)
+}
+
+/// A multi-tenant server where each of two tenants has an `alice`.
+async fn server(policy: Authorization) -> ServerHandle {
+    let tokens = StaticTokens::new()
+        .token("acme-secret", alice("acme"))
+        .token("globex-secret", alice("globex"));
+    Server::builder()
+        .authenticator(tokens)
+        .multi_tenant()
+        .authorization(policy)
+        .kv_store(MemoryStore::default())
+        .build()
+        .spawn()
+        .await
+        .unwrap()
+}
+
+async fn connect(handle: &ServerHandle, token: &str) -> Client {
+    let config = ClientConfig {
+        credentials: Some(Credentials::Bearer(token.to_string())),
+        ..ClientConfig::default()
+    };
+    handle.connect_with(config).await.unwrap()
+}
+
+fn code(err: anyhow::Error) -> ErrorCode {
+    err.downcast::<ProtocolError>().unwrap().code
+}
+
+#[test]
+fn grants_apply_to_one_tenant_only() {
+    let policy = Authorization::new()
+        .role("admin", ["ServerInfo"])
+        .grant("acme/alice", "admin");
+    assert!(policy.permits(Some(&alice("acme")), "ServerInfo"));
+    assert!(!policy.permits(Some(&alice("globex")), "ServerInfo"));
+    assert!(!policy.permits(Some(&Identity::new("alice")), "ServerInfo"));
+}
+
+#[tokio::test]
+async fn same_subject_in_another_tenant_cannot_see_a_job() {
+    let handle = server(Authorization::new().public(["*"])).await;
+    let acme = connect(&handle, "acme-secret").await;
+    let globex = connect(&handle, "globex-secret").await;
+
+    let job = acme
+        .call_typed(SubmitJob {
+            request: Box::new(HealthCheck),
+        })
+        .await
+        .unwrap();
+    acme.call_typed(JobStatus { id: job.id }).await.unwrap();
+    let err = globex
+        .call_typed(JobStatus { id: job.id })
+        .await
+        .unwrap_err();
+    assert_eq!(code(err), ErrorCode::InvalidRequest);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_kept_per_tenant() {
+    let handle = server(Authorization::new().public(["*"])).await;
+    let acme = connect(&handle, "acme-secret").await;
+    let globex = connect(&handle, "globex-secret").await;
+    for (client, value) in [(&acme, "acme"), (&globex, "globex")] {
+        client
+            .call_typed(KvSet {
+                key: "config".into(),
+                value: Bytes::from(value),
+            })
+            .await
+            .unwrap();
+    }
+
+    for (client, value) in [(&acme, "acme"), (&globex, "globex")] {
+        let request = Box::new(KvGet {
+            key: "config".into(),
+        });
+        let response = client
+            .call_with_idempotency_key(request, "same-key")
+            .await
+            .unwrap();
+        let response = (response as Box<dyn Any>)
+            .downcast::<KvGetResponse>()
+            .unwrap();
+        assert_eq!(response.value, Some(Bytes::from(value)));
+    }
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn peer_names_are_kept_per_tenant() {
+    let handle = server(Authorization::new().public(["*"])).await;
+    let acme = connect(&handle, "acme-secret").await;
+    let globex = connect(&handle, "globex-secret").await;
+    acme.call_typed(Register {
+        name: "printer".into(),
+    })
+    .await
+    .unwrap();
+
+    let err = globex
+        .call(Box::new(SendTo {
+            peer: "printer".into(),
+            request: Box::new(HealthCheck),
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(code(err), ErrorCode::PeerUnavailable);
+    handle.shutdown().await.unwrap();
+}